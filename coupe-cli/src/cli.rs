@@ -20,14 +20,163 @@ pub enum Commands {
     Deploy {
         #[arg(short, long, help = "Path to the coupe.yaml file")]
         path: Option<String>,
-        #[arg(short, long, help = "Remote host to deploy to")]
+        #[arg(short, long, help = "Target name or SSH host to deploy to")]
         remote: Option<String>,
+        #[arg(long, help = "Deploy images by the digest pulled instead of their tag")]
+        pin_digests: bool,
     },
     #[command(about = "Remove a deployed coupe stack")]
     Teardown {
         #[arg(short, long, help = "Path to the coupe.yaml file")]
         path: Option<String>,
-        #[arg(short, long, help = "Remote host to teardown")]
+        #[arg(short, long, help = "Target name or SSH host to teardown")]
         remote: Option<String>,
+        #[arg(long, help = "Also remove the stack's named volumes and their data")]
+        volumes: bool,
     },
+    #[command(about = "List the deployed revisions of a stack")]
+    History {
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Target name or SSH host the stack is deployed to")]
+        remote: Option<String>,
+    },
+    #[command(about = "Redeploy an earlier revision of a stack")]
+    Rollback {
+        #[arg(help = "Revision to redeploy, defaults to the one before the current revision")]
+        revision: Option<u32>,
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Target name or SSH host the stack is deployed to")]
+        remote: Option<String>,
+    },
+    #[command(about = "Shift a deployed function's requests to one of its versions")]
+    Promote {
+        function: String,
+        version: String,
+        #[arg(
+            short,
+            long,
+            value_parser = clap::value_parser!(u8).range(0..=100),
+            help = "Percentage of requests to send to the version, instead of replacing the function's image with it"
+        )]
+        weight: Option<u8>,
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Target name or SSH host the stack is deployed to")]
+        remote: Option<String>,
+    },
+    #[command(about = "Show the logs of the sentinel and functions")]
+    Logs {
+        #[arg(help = "Only show this function, and the sentinel's lines about it")]
+        function: Option<String>,
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Target name or SSH host to read logs from")]
+        remote: Option<String>,
+        #[arg(short, long, help = "Keep streaming new lines")]
+        follow: bool,
+        #[arg(
+            short,
+            long,
+            help = "Only lines since a duration ago such as 10m, or a timestamp"
+        )]
+        since: Option<String>,
+        #[arg(short, long, help = "Only lines matching this regular expression")]
+        grep: Option<String>,
+    },
+    #[command(about = "Show the state of a deployed stack")]
+    Status {
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Target name or SSH host the stack is deployed to")]
+        remote: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "Sentinel URL, defaults to the local or remote sentinel"
+        )]
+        url: Option<String>,
+        #[arg(short, long, help = "Keep refreshing the status")]
+        watch: bool,
+        #[arg(
+            short,
+            long,
+            default_value_t = 2,
+            help = "Seconds between refreshes with --watch"
+        )]
+        interval: u64,
+        #[arg(
+            long,
+            help = "Print the status as JSON, a line per refresh with --watch"
+        )]
+        json: bool,
+    },
+    #[command(about = "Call a function through the sentinel")]
+    Invoke {
+        function: String,
+        #[arg(help = "Path to call an HTTP function at, defaults to its trigger path")]
+        request_path: Option<String>,
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Sentinel URL, defaults to the local sentinel")]
+        url: Option<String>,
+        #[arg(
+            short,
+            long,
+            help = "Request body, @file to read a file or @- for stdin"
+        )]
+        data: Option<String>,
+        #[arg(short = 'H', long = "header", help = "Header as `Name: value`")]
+        headers: Vec<String>,
+        #[arg(
+            short = 'X',
+            long,
+            help = "HTTP method, defaults to the trigger's first method"
+        )]
+        method: Option<String>,
+    },
+    #[command(about = "Run the stack locally, reloading it as the config and sources change")]
+    Dev {
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+    },
+    #[command(about = "Manage API keys for functions using api_key auth")]
+    Keys {
+        #[arg(short, long, help = "Path to the coupe.yaml file")]
+        path: Option<String>,
+        #[arg(short, long, help = "Sentinel URL, defaults to the local sentinel")]
+        url: Option<String>,
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    #[command(about = "List API keys")]
+    List,
+    #[command(about = "Create an API key")]
+    Create {
+        #[arg(
+            short,
+            long = "function",
+            required = true,
+            help = "Function the key can invoke, or `*` for every function"
+        )]
+        functions: Vec<String>,
+        #[arg(short, long = "label", value_parser = parse_label, help = "Label as key=value")]
+        labels: Vec<(String, String)>,
+        #[arg(short, long, help = "Expiry as an RFC 3339 timestamp")]
+        expires_at: Option<String>,
+    },
+    #[command(about = "Revoke an API key")]
+    Revoke { id: String },
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("Invalid label {}, expected key=value", label))
 }
@@ -1,20 +1,22 @@
 use coupe::{Config, CoupeError, DeploymentTarget, Result, deploy_stack};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-pub async fn execute(path: Option<String>, remote: Option<String>) -> Result<()> {
+pub async fn execute(
+    path: Option<String>,
+    remote: Option<String>,
+    pin_digests: bool,
+) -> Result<()> {
     println!("Deploying coupe stack");
-    let deployment_target = if let Some(remote) = remote {
-        DeploymentTarget::Remote(remote)
-    } else {
-        DeploymentTarget::Local
-    };
-    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config_path = PathBuf::from(path.unwrap_or("coupe.yaml".to_string()));
     let config =
-        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+        Config::load(config_path.clone()).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+    let base_dir = config_path.parent().unwrap_or(Path::new("."));
 
-    deploy_stack(&config, &deployment_target)
+    let revision = deploy_stack(&config, &deployment_target, base_dir, pin_digests)
         .await
         .map_err(|e| CoupeError::Config(e.to_string()))?;
+    println!("Deployed revision {}", revision.number);
 
     Ok(())
 }
@@ -0,0 +1,503 @@
+use coupe::{
+    Config, CoupeError, DeploymentTarget, DevMode, Docker, LocalProcess, Result,
+    build_function_image, connect_docker, context_fingerprint, create_function_container,
+    create_network, create_volumes, ensure_image, host_gateway, remove_function_container,
+    teardown, validate_function_networks, validate_function_volumes,
+};
+use coupe_sentinel::{
+    ConfigChanges, Sentinel, delete_session, serve_api, set_db_dir, watch_events,
+    watch_rate_limits, watch_sessions, watch_webhook_nonces,
+};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    fs,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    pin,
+    process::{Child, Command},
+    select,
+    signal::ctrl_c,
+    spawn,
+    task::JoinHandle,
+    time::interval,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appended to the stack name, so dev containers, networks and volumes never replace those of
+/// a stack deployed to the same daemon.
+const DEV_SUFFIX: &str = "-dev";
+
+/// A free port on the loopback interface, picked by the OS.
+fn free_port() -> Result<u16> {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(CoupeError::Io)
+}
+
+/// Loads the config under the dev stack name and without function versions, which dev does
+/// not run, so every request goes to the function's own image or process.
+fn load_config(path: &Path) -> Result<Config> {
+    let mut config = Config::load(path.to_path_buf())?;
+    config.name.push_str(DEV_SUFFIX);
+    for function in config.functions.values_mut() {
+        function.versions.clear();
+    }
+    Ok(config)
+}
+
+/// Ports for the functions, keeping those of functions that were already running, and the
+/// gateway found before.
+fn dev_mode(config: &Config, previous: Option<&DevMode>) -> Result<DevMode> {
+    let mut dev = DevMode {
+        gateway: previous.and_then(|previous| previous.gateway),
+        ..Default::default()
+    };
+    for (function_name, function) in &config.functions {
+        let port = match function.dev.as_ref().and_then(|process| process.port) {
+            Some(port) => port,
+            None => match previous.and_then(|previous| previous.ports.get(function_name)) {
+                Some(port) => *port,
+                None => free_port()?,
+            },
+        };
+        dev.ports.insert(function_name.clone(), port);
+        if function.dev.is_some() {
+            dev.local_functions.insert(function_name.clone());
+        }
+    }
+    Ok(dev)
+}
+
+/// Functions that run in containers, in a stable order.
+fn container_functions(config: &Config) -> Vec<String> {
+    let mut function_names: Vec<String> = config
+        .functions
+        .iter()
+        .filter(|(_, function)| function.dev.is_none())
+        .map(|(name, _)| name.clone())
+        .collect();
+    function_names.sort();
+    function_names
+}
+
+/// What the stack's networks and volumes are made from. When it changes they are created again.
+fn docker_layout(config: &Config) -> Value {
+    let functions: Vec<Value> = container_functions(config)
+        .iter()
+        .map(|name| {
+            let function = &config.functions[name];
+            json!([name, function.network, function.volumes])
+        })
+        .collect();
+    json!([config.name, functions])
+}
+
+async fn prefix_lines(function_name: String, output: impl AsyncRead + Unpin) {
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        println!("{} | {}", function_name, line);
+    }
+}
+
+fn spawn_process(
+    config: &Config,
+    base_dir: &Path,
+    function_name: &str,
+    process: &LocalProcess,
+    port: u16,
+) -> Result<Child> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.args(["/C", &process.command]);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.args(["-c", &process.command]);
+        command
+    };
+    command
+        .current_dir(base_dir.join(process.cwd.as_deref().unwrap_or(".")))
+        .env("PORT", port.to_string())
+        .env("COUPE_STACK", &config.name)
+        .env("COUPE_FUNCTION", function_name)
+        .env(
+            "COUPE_SENTINEL_URL",
+            format!("http://127.0.0.1:{}", config.sentinel_internal_port()),
+        )
+        .envs(&process.env)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    let mut child = command.spawn().map_err(|e| {
+        CoupeError::InvalidInput(format!(
+            "Failed to run `{}` for function {}: {}",
+            process.command, function_name, e
+        ))
+    })?;
+    if let Some(stdout) = child.stdout.take() {
+        spawn(prefix_lines(function_name.to_string(), stdout));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn(prefix_lines(function_name.to_string(), stderr));
+    }
+    Ok(child)
+}
+
+/// Runs the sentinel's services until the task is aborted.
+fn spawn_sentinel(sentinel: Arc<Sentinel>) -> JoinHandle<()> {
+    spawn(async move {
+        let result = select! {
+            result = watch_sessions(Arc::clone(&sentinel)) => result,
+            result = watch_webhook_nonces() => result,
+            result = watch_rate_limits() => result,
+            result = watch_events(Arc::clone(&sentinel)) => result,
+            result = serve_api(sentinel) => result,
+        };
+        if let Err(e) = result {
+            eprintln!("Sentinel stopped: {}", e);
+        }
+    })
+}
+
+struct DevStack {
+    config_path: PathBuf,
+    base_dir: PathBuf,
+    /// Connected once a function runs in a container, so local processes do not need Docker.
+    client: Option<Docker>,
+    /// The config as written, to tell which functions changed.
+    source: Config,
+    /// The config the sentinel runs with, with dev ports and built images.
+    config: Arc<Config>,
+    /// Whether the stack's networks and volumes exist.
+    docker_ready: bool,
+    modified: Option<SystemTime>,
+    fingerprints: HashMap<String, String>,
+    processes: HashMap<String, Child>,
+    sentinel: Option<(Arc<Sentinel>, JoinHandle<()>)>,
+}
+
+impl DevStack {
+    fn client(&mut self) -> Result<Docker> {
+        match &self.client {
+            Some(client) => Ok(client.clone()),
+            None => {
+                let client = connect_docker(&DeploymentTarget::Local)?;
+                self.client = Some(client.clone());
+                Ok(client)
+            }
+        }
+    }
+
+    fn config_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.config_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    fn fingerprint(&self, config: &Config, function_name: &str) -> Option<String> {
+        let build = config.functions.get(function_name)?.build.as_ref()?;
+        context_fingerprint(&self.base_dir.join(&build.context), build.dockerfile()).ok()
+    }
+
+    /// Images for container functions: built from their context, or pulled.
+    async fn prepare_images(
+        &mut self,
+        config: &mut Config,
+        function_names: &[String],
+    ) -> Result<()> {
+        for function_name in function_names {
+            let Some(function) = config.functions.get(function_name) else {
+                continue;
+            };
+            if function.dev.is_some() {
+                continue;
+            }
+            if function.build.is_some() {
+                let client = self.client()?;
+                let tag =
+                    build_function_image(&client, config, function_name, &self.base_dir).await?;
+                if let Some(function) = config.functions.get_mut(function_name) {
+                    function.image = tag;
+                }
+            } else {
+                let image = config.function_image(function_name)?;
+                ensure_image(&self.client()?, config, &image).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the stack's networks and volumes, after removing any containers and networks of
+    /// the previous layout or left over from an earlier run.
+    async fn setup_docker(&mut self, config: &Config) -> Result<()> {
+        if self.docker_ready {
+            teardown(&self.client()?, &self.config).await?;
+            self.docker_ready = false;
+        }
+        if container_functions(config).is_empty() {
+            return Ok(());
+        }
+        validate_function_networks(config)?;
+        validate_function_volumes(config)?;
+        let client = self.client()?;
+        teardown(&client, config).await?;
+        create_network(&client, config).await?;
+        create_volumes(&client, config).await?;
+        self.docker_ready = true;
+        Ok(())
+    }
+
+    async fn stop_function(&mut self, function_name: &str) -> Result<()> {
+        if let Some(mut process) = self.processes.remove(function_name) {
+            process.kill().await.map_err(CoupeError::Io)?;
+        }
+        if self.docker_ready {
+            remove_function_container(&self.client()?, &self.config, function_name).await?;
+        }
+        Ok(())
+    }
+
+    /// Creates a function's container, which the sentinel starts on demand, or runs its process.
+    async fn start_function(&mut self, config: &Config, function_name: &str) -> Result<()> {
+        let function = &config.functions[function_name];
+        let port = config
+            .dev
+            .as_ref()
+            .and_then(|dev| dev.ports.get(function_name))
+            .copied()
+            .unwrap_or_default();
+        match &function.dev {
+            Some(process) => {
+                let child = spawn_process(config, &self.base_dir, function_name, process, port)?;
+                self.processes.insert(function_name.to_string(), child);
+                println!(
+                    "{} running `{}` on port {}",
+                    function_name, process.command, port
+                );
+            }
+            None => {
+                create_function_container(&self.client()?, config, function_name).await?;
+                println!("{} in container on port {}", function_name, port);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the config to the sentinel, which keeps serving requests in flight. A new sentinel
+    /// is started when its ports or the gateway it listens on changed.
+    async fn swap_sentinel(&mut self, config: Arc<Config>) -> Result<()> {
+        if let Some((sentinel, _)) = &self.sentinel {
+            let current = sentinel.config();
+            if current.sentinel_port() == config.sentinel_port()
+                && current.sentinel_internal_port() == config.sentinel_internal_port()
+                && current.dev.as_ref().and_then(|dev| dev.gateway)
+                    == config.dev.as_ref().and_then(|dev| dev.gateway)
+            {
+                sentinel.swap(config)?;
+                return Ok(());
+            }
+        }
+        let sentinel = Arc::new(Sentinel::new(config, None)?);
+        if let Some((_, task)) = self.sentinel.take() {
+            task.abort();
+            let _ = task.await;
+        }
+        self.sentinel = Some((Arc::clone(&sentinel), spawn_sentinel(sentinel)));
+        Ok(())
+    }
+
+    /// Moves to a new config, restarting the changed functions. Sessions of functions that did
+    /// not change are kept.
+    async fn update(&mut self, source: Config, changed: Vec<String>) -> Result<()> {
+        let mut config = source.clone();
+        let mut dev = dev_mode(&source, self.config.dev.as_ref())?;
+        if dev.gateway.is_none() && !container_functions(&source).is_empty() {
+            dev.gateway = host_gateway(&self.client()?).await?;
+        }
+        config.dev = Some(dev);
+        for (function_name, function) in config.functions.iter_mut() {
+            if changed.contains(function_name) {
+                continue;
+            }
+            if let Some(previous) = self.config.functions.get(function_name) {
+                function.image = previous.image.clone();
+            }
+        }
+        // A failed build or an invalid config leaves everything running as it was.
+        self.prepare_images(&mut config, &changed).await?;
+        let config = Arc::new(config);
+        self.swap_sentinel(Arc::clone(&config)).await?;
+
+        let result = self.restart_functions(&source, &config, changed).await;
+        self.source = source;
+        self.config = config;
+        result
+    }
+
+    async fn restart_functions(
+        &mut self,
+        source: &Config,
+        config: &Config,
+        mut changed: Vec<String>,
+    ) -> Result<()> {
+        let removed: Vec<String> = self
+            .source
+            .functions
+            .keys()
+            .filter(|name| !source.functions.contains_key(*name))
+            .cloned()
+            .collect();
+        for function_name in &removed {
+            println!("Removing {}", function_name);
+            self.stop_function(function_name).await?;
+            self.fingerprints.remove(function_name);
+            delete_session(function_name.clone()).await?;
+        }
+
+        if docker_layout(config) != docker_layout(&self.config) {
+            // Recreating the networks removes every container, so they are all created again.
+            for function_name in container_functions(config) {
+                if !changed.contains(&function_name) {
+                    changed.push(function_name);
+                }
+            }
+            self.setup_docker(config).await?;
+        }
+
+        changed.sort();
+        for function_name in &changed {
+            self.stop_function(function_name).await?;
+            delete_session(function_name.clone()).await?;
+            self.start_function(config, function_name).await?;
+            match self.fingerprint(config, function_name) {
+                Some(fingerprint) => self.fingerprints.insert(function_name.clone(), fingerprint),
+                None => self.fingerprints.remove(function_name),
+            };
+        }
+        Ok(())
+    }
+
+    /// Reloads the config when the file changed, otherwise rebuilds functions whose build
+    /// context changed.
+    async fn poll(&mut self) -> Result<()> {
+        let modified = self.config_modified();
+        if modified != self.modified {
+            self.modified = modified;
+            println!("{} changed, reloading", self.config_path.display());
+            let source = load_config(&self.config_path)?;
+            let changes = ConfigChanges::between(&self.source, &source);
+            let changed = changes.added.into_iter().chain(changes.changed).collect();
+            return self.update(source, changed).await;
+        }
+
+        let mut changed = Vec::new();
+        for function_name in self.source.functions.keys() {
+            let Some(fingerprint) = self.fingerprint(&self.source, function_name) else {
+                continue;
+            };
+            if self.fingerprints.get(function_name) != Some(&fingerprint) {
+                changed.push(function_name.clone());
+            }
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
+        changed.sort();
+        println!("Build context of {} changed", changed.join(", "));
+        for function_name in &changed {
+            // Recorded now so a failing build is not retried until the next change.
+            if let Some(fingerprint) = self.fingerprint(&self.source, function_name) {
+                self.fingerprints.insert(function_name.clone(), fingerprint);
+            }
+        }
+        self.update(self.source.clone(), changed).await
+    }
+
+    async fn shutdown(&mut self) -> Result<()> {
+        println!("Stopping");
+        if let Some((_, task)) = self.sentinel.take() {
+            task.abort();
+            let _ = task.await;
+        }
+        for (_, mut process) in self.processes.drain() {
+            let _ = process.kill().await;
+        }
+        if self.docker_ready {
+            teardown(&self.client()?, &self.config).await?;
+            self.docker_ready = false;
+        }
+        Ok(())
+    }
+}
+
+pub async fn execute(path: Option<String>) -> Result<()> {
+    // Functions' output is the interesting part, the sentinel only speaks up about problems
+    // unless `RUST_LOG` asks for more.
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("coupe_sentinel=warn,coupe=warn"));
+    tracing_subscriber::fmt()
+        .compact()
+        .with_env_filter(env_filter)
+        .init();
+
+    let config_path = PathBuf::from(path.unwrap_or("coupe.yaml".to_string()));
+    let source = load_config(&config_path).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let base_dir = config_path.parent().unwrap_or(Path::new(".")).to_path_buf();
+    set_db_dir(base_dir.join(".coupe").join("db"));
+
+    // Starting from a stack without functions, every function counts as changed.
+    let mut empty = source.clone();
+    empty.functions.clear();
+    let mut stack = DevStack {
+        config_path,
+        base_dir,
+        client: None,
+        source: empty.clone(),
+        config: Arc::new(empty),
+        docker_ready: false,
+        modified: None,
+        fingerprints: HashMap::new(),
+        processes: HashMap::new(),
+        sentinel: None,
+    };
+    stack.modified = stack.config_modified();
+    let function_names = source.functions.keys().cloned().collect();
+    if let Err(e) = stack.update(source, function_names).await {
+        stack.shutdown().await?;
+        return Err(e);
+    }
+
+    println!();
+    println!(
+        "Sentinel listening on http://localhost:{}",
+        stack.config.sentinel_port()
+    );
+    println!(
+        "Watching {} and build contexts, press Ctrl+C to stop",
+        stack.config_path.display()
+    );
+
+    let shutdown = ctrl_c();
+    pin!(shutdown);
+    let mut ticker = interval(POLL_INTERVAL);
+    loop {
+        select! {
+            _ = &mut shutdown => break,
+            _ = ticker.tick() => {
+                if let Err(e) = stack.poll().await {
+                    eprintln!("Error: {}", e);
+                }
+            }
+        }
+    }
+    stack.shutdown().await
+}
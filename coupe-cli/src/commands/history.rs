@@ -0,0 +1,65 @@
+use coupe::{Config, CoupeError, DeploymentTarget, Result, list_revisions};
+use std::path::PathBuf;
+
+pub async fn execute(path: Option<String>, remote: Option<String>) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+
+    let revisions = list_revisions(&config, &deployment_target).await?;
+    let Some(current) = revisions.last().map(|revision| revision.number) else {
+        println!("Stack {} has no recorded deploys", config.name);
+        return Ok(());
+    };
+
+    let headers = ["REVISION", "DEPLOYED", "USER", "VERSION", "NOTE"];
+    let rows: Vec<Vec<String>> = revisions
+        .iter()
+        .rev()
+        .map(|revision| {
+            let mut notes = Vec::new();
+            if revision.number == current {
+                notes.push("current".to_string());
+            }
+            if let Some(number) = revision.rollback_of {
+                notes.push(format!("rollback to {}", number));
+            }
+            vec![
+                revision.number.to_string(),
+                revision
+                    .deployed_at
+                    .strftime("%Y-%m-%d %H:%M:%S UTC")
+                    .to_string(),
+                revision.deployed_by.clone(),
+                revision.config.version.clone().unwrap_or("-".to_string()),
+                notes.join(", "),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..headers.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([headers[i].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(headers.to_vec()));
+    for row in &rows {
+        println!("{}", line(row.iter().map(String::as_str).collect()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use super::sentinel::Sentinel;
+use coupe::{Config, CoupeError, HttpMethod, HttpTrigger, Result, Trigger};
+use reqwest::{
+    Method, Response,
+    header::{CONTENT_TYPE, HOST, HeaderMap, HeaderName, HeaderValue},
+};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{Read, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+const COLD_START_HEADER: &str = "x-coupe-cold-start";
+
+/// Reads `--data`: `@file` for a file's contents, `@-` for stdin, anything else as is.
+fn read_data(data: &str) -> Result<Vec<u8>> {
+    match data.strip_prefix('@') {
+        Some("-") => {
+            let mut body = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut body)
+                .map_err(CoupeError::Io)?;
+            Ok(body)
+        }
+        Some(file) => fs::read(file).map_err(|e| {
+            CoupeError::InvalidInput(format!("Failed to read data file {}: {}", file, e))
+        }),
+        None => Ok(data.as_bytes().to_vec()),
+    }
+}
+
+fn parse_headers(headers: &[String]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let invalid =
+            || CoupeError::InvalidInput(format!("Invalid header {}, expected Name: value", header));
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| invalid())?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid())?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+fn to_method(method: &HttpMethod) -> Option<Method> {
+    match method {
+        HttpMethod::Any => None,
+        HttpMethod::Get => Some(Method::GET),
+        HttpMethod::Post => Some(Method::POST),
+        HttpMethod::Put => Some(Method::PUT),
+        HttpMethod::Delete => Some(Method::DELETE),
+        HttpMethod::Patch => Some(Method::PATCH),
+        HttpMethod::Head => Some(Method::HEAD),
+        HttpMethod::Options => Some(Method::OPTIONS),
+    }
+}
+
+/// The path to call an HTTP function at: the given one, or the trigger's when it has no params.
+fn request_path(
+    function_name: &str,
+    trigger: &HttpTrigger,
+    path: Option<String>,
+) -> Result<String> {
+    match path {
+        Some(path) if path.starts_with('/') => Ok(path),
+        Some(path) => Err(CoupeError::InvalidInput(format!(
+            "Path {} must start with `/`",
+            path
+        ))),
+        None if trigger.path == "*" => Ok("/".to_string()),
+        None if trigger.path.contains('{') => Err(CoupeError::InvalidInput(format!(
+            "Function {} has path params in {}, so pass the path to call",
+            function_name, trigger.path
+        ))),
+        None => Ok(trigger.path.clone()),
+    }
+}
+
+async fn print_response(response: Response, started: Instant) -> Result<()> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| CoupeError::Http(format!("Failed to read response: {}", e)))?;
+    let elapsed = started.elapsed();
+    let coldstarted = headers
+        .get(COLD_START_HEADER)
+        .is_some_and(|value| value == "true");
+
+    println!(
+        "{}  {} ms{}",
+        status,
+        elapsed.as_millis(),
+        if coldstarted { "  cold start" } else { "" }
+    );
+    for (name, value) in &headers {
+        println!("{}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+    println!();
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"));
+    match serde_json::from_slice::<Value>(&body) {
+        Ok(json) if is_json => println!(
+            "{}",
+            serde_json::to_string_pretty(&json).unwrap_or_default()
+        ),
+        _ => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&body).map_err(CoupeError::Io)?;
+            if !body.ends_with(b"\n") && !body.is_empty() {
+                println!();
+            }
+        }
+    }
+    Ok(())
+}
+
+pub async fn execute(
+    path: Option<String>,
+    url: Option<String>,
+    function_name: String,
+    request_path_arg: Option<String>,
+    data: Option<String>,
+    headers: Vec<String>,
+    method: Option<String>,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let function = config.functions.get(&function_name).ok_or_else(|| {
+        CoupeError::InvalidInput(format!("Function not found: {}", function_name))
+    })?;
+    let body = data.as_deref().map(read_data).transpose()?;
+    let mut headers = parse_headers(&headers)?;
+    let json_body = body
+        .as_ref()
+        .is_some_and(|body| serde_json::from_slice::<Value>(body).is_ok());
+    if json_body && !headers.contains_key(CONTENT_TYPE) {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+
+    let request = match &function.trigger {
+        Trigger::Http(trigger) => {
+            let sentinel = Sentinel::new(&config, url, None);
+            let request_path = request_path(&function_name, trigger, request_path_arg)?;
+            let method = match method {
+                Some(method) => Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| CoupeError::InvalidInput(format!("Invalid method {}", method)))?,
+                None => trigger
+                    .methods()
+                    .iter()
+                    .find_map(to_method)
+                    .unwrap_or(if body.is_some() {
+                        Method::POST
+                    } else {
+                        Method::GET
+                    }),
+            };
+            // Functions restricted to hosts are only routed for those hosts.
+            if !headers.contains_key(HOST) {
+                let hosts = trigger.hosts();
+                if !hosts.is_empty() {
+                    let host = hosts.iter().find(|host| !host.starts_with("*.")).ok_or_else(|| {
+                        CoupeError::InvalidInput(format!(
+                            "Function {} only answers on wildcard hosts, pass one with --header 'Host: ...'",
+                            function_name
+                        ))
+                    })?;
+                    headers.insert(
+                        HOST,
+                        HeaderValue::from_str(host).map_err(|_| {
+                            CoupeError::InvalidInput(format!("Invalid host {}", host))
+                        })?,
+                    );
+                }
+            }
+            println!("{} {}{}", method, sentinel.url(), request_path);
+            sentinel.request(method, &request_path)
+        }
+        _ => {
+            if request_path_arg.is_some() || method.is_some() {
+                return Err(CoupeError::InvalidInput(format!(
+                    "Function {} is not an HTTP function, it takes no path or method",
+                    function_name
+                )));
+            }
+            let admin_token = config
+                .admin_token()
+                .ok_or(CoupeError::Config(
+                    "sentinel.admin_token is required to invoke queue, stream and timer functions"
+                        .to_string(),
+                ))?
+                .to_string();
+            let sentinel = Sentinel::new(&config, url, Some(admin_token));
+            let invoke_path = format!("/system/functions/{}/invoke", function_name);
+            println!(
+                "Event to {} via {}{}",
+                function_name,
+                sentinel.url(),
+                invoke_path
+            );
+            sentinel.request(Method::POST, &invoke_path)
+        }
+    };
+
+    let mut request = request.headers(headers);
+    if let Some(body) = body {
+        request = request.body(body);
+    }
+    let started = Instant::now();
+    let response = request
+        .send()
+        .await
+        .map_err(|e| CoupeError::Http(format!("Failed to reach sentinel: {}", e)))?;
+    print_response(response, started).await
+}
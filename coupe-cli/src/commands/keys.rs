@@ -0,0 +1,103 @@
+use super::sentinel::Sentinel;
+use crate::KeysCommand;
+use coupe::{Config, CoupeError, Result};
+use reqwest::Method;
+use serde_json::{Value, json};
+use std::{collections::BTreeMap, path::PathBuf};
+
+fn print_key(key: &Value) {
+    let labels = key["labels"]
+        .as_object()
+        .map(|labels| {
+            labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v.as_str().unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let functions = key["functions"]
+        .as_array()
+        .map(|functions| {
+            functions
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let expires = match (key["expires_at"].as_str(), key["expired"].as_bool()) {
+        (Some(expires_at), Some(true)) => format!("expired {}", expires_at),
+        (Some(expires_at), _) => format!("expires {}", expires_at),
+        (None, _) => "never expires".to_string(),
+    };
+    println!(
+        "{}  functions={}  labels={}  created {}  {}",
+        key["id"].as_str().unwrap_or_default(),
+        functions,
+        labels,
+        key["created_at"].as_str().unwrap_or_default(),
+        expires
+    );
+}
+
+pub async fn execute(
+    path: Option<String>,
+    url: Option<String>,
+    command: KeysCommand,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let admin_token = config
+        .admin_token()
+        .ok_or(CoupeError::Config(
+            "sentinel.admin_token is required to manage API keys".to_string(),
+        ))?
+        .to_string();
+    let sentinel = Sentinel::new(&config, url, Some(admin_token));
+
+    match command {
+        KeysCommand::List => {
+            let keys = sentinel
+                .send(sentinel.request(Method::GET, "/system/api-keys"))
+                .await?;
+            let keys = keys.as_array().cloned().unwrap_or_default();
+            if keys.is_empty() {
+                println!("No API keys");
+            }
+            keys.iter().for_each(print_key);
+        }
+        KeysCommand::Create {
+            functions,
+            labels,
+            expires_at,
+        } => {
+            let labels: BTreeMap<String, String> = labels.into_iter().collect();
+            let key = sentinel
+                .send(
+                    sentinel
+                        .request(Method::POST, "/system/api-keys")
+                        .json(&json!({
+                            "functions": functions,
+                            "labels": labels,
+                            "expires_at": expires_at,
+                        })),
+                )
+                .await?;
+            print_key(&key);
+            println!();
+            println!("{}", key["key"].as_str().unwrap_or_default());
+            println!();
+            println!("Store this key now, it cannot be shown again.");
+        }
+        KeysCommand::Revoke { id } => {
+            sentinel
+                .send(sentinel.request(Method::DELETE, &format!("/system/api-keys/{}", id)))
+                .await?;
+            println!("Revoked API key {}", id);
+        }
+    }
+
+    Ok(())
+}
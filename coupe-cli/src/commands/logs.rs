@@ -0,0 +1,271 @@
+use coupe::{
+    Config, CoupeError, DeploymentTarget, LogLine, LogSource, LogsOptions, Result, connect_docker,
+    log_containers, split_instance, stream_logs,
+};
+use futures::StreamExt;
+use jiff::{SignedDuration, Timestamp};
+use regex::Regex;
+use serde_json::Value;
+use std::{collections::HashMap, io::IsTerminal, path::PathBuf};
+
+const RESET: &str = "\x1b[0m";
+const SENTINEL_COLOUR: &str = "\x1b[35m";
+const FUNCTION_COLOURS: [&str; 5] = ["\x1b[36m", "\x1b[32m", "\x1b[33m", "\x1b[34m", "\x1b[91m"];
+
+/// Parses `--since` as a duration such as `10m`, `2h` or `1d`, an RFC 3339 timestamp or a Unix
+/// timestamp.
+fn parse_since(since: &str) -> Result<i64> {
+    let invalid = || {
+        CoupeError::Config(format!(
+            "Invalid --since {}, expected a duration such as 10m or a timestamp",
+            since
+        ))
+    };
+    if let Ok(timestamp) = since.parse::<Timestamp>() {
+        return Ok(timestamp.as_second());
+    }
+    if let Ok(timestamp) = since.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    let unit = since.chars().last().ok_or_else(invalid)?;
+    let amount: i64 = since[..since.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let duration = amount
+        .checked_mul(seconds)
+        .map(SignedDuration::from_secs)
+        .ok_or_else(invalid)?;
+    Timestamp::now()
+        .checked_sub(duration)
+        .map(|timestamp| timestamp.as_second())
+        .map_err(|_| invalid())
+}
+
+/// A line of the JSON that `tracing_subscriber` writes, as the sentinel does.
+struct TracingLine {
+    level: String,
+    message: String,
+    fields: Vec<(String, String)>,
+    function_name: Option<String>,
+}
+
+fn parse_tracing_line(message: &str) -> Option<TracingLine> {
+    let Value::Object(mut line) = serde_json::from_str(message).ok()? else {
+        return None;
+    };
+    let level = line.get("level")?.as_str()?.to_string();
+    let Some(Value::Object(fields)) = line.remove("fields") else {
+        return None;
+    };
+    let mut text = String::new();
+    let mut rest = Vec::new();
+    let mut function_name = None;
+    for (key, value) in fields {
+        let value = match value {
+            Value::String(value) => value,
+            value => value.to_string(),
+        };
+        if key == "message" {
+            text = value;
+            continue;
+        }
+        if key == "function_name" {
+            function_name = Some(value.clone());
+        }
+        rest.push((key, value));
+    }
+    Some(TracingLine {
+        level,
+        message: text,
+        fields: rest,
+        function_name,
+    })
+}
+
+struct Printer {
+    colour: bool,
+    width: usize,
+    colours: HashMap<LogSource, &'static str>,
+}
+
+impl Printer {
+    fn new(sources: &[&LogSource]) -> Self {
+        let colours = sources
+            .iter()
+            .filter(|source| !matches!(source, LogSource::Sentinel))
+            .zip(FUNCTION_COLOURS.iter().cycle())
+            .map(|(source, colour)| ((*source).clone(), *colour))
+            .collect();
+        Self {
+            colour: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            width: sources.iter().map(|s| s.name().len()).max().unwrap_or(0),
+            colours,
+        }
+    }
+
+    fn paint(&self, text: &str, colour: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", colour, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn print(&self, line: &LogLine, text: &str, level: Option<&str>) {
+        let colour = match line.source {
+            LogSource::Sentinel => SENTINEL_COLOUR,
+            _ => self.colours.get(&line.source).copied().unwrap_or(RESET),
+        };
+        let prefix = format!("{:width$} |", line.source.name(), width = self.width);
+        let timestamp = line
+            .timestamp
+            .as_deref()
+            .and_then(|t| t.parse::<Timestamp>().ok())
+            .map(|t| format!("{} ", t.strftime("%Y-%m-%d %H:%M:%S%.3f")))
+            .unwrap_or_default();
+        let level = match level {
+            Some(level) => {
+                let padded = format!("{:5}", level);
+                match level {
+                    "ERROR" => format!("{} ", self.paint(&padded, "\x1b[31m")),
+                    "WARN" => format!("{} ", self.paint(&padded, "\x1b[33m")),
+                    _ => format!("{} ", padded),
+                }
+            }
+            None => String::new(),
+        };
+        println!(
+            "{} {}{}{}",
+            self.paint(&prefix, colour),
+            timestamp,
+            level,
+            text
+        );
+    }
+}
+
+pub async fn execute(
+    path: Option<String>,
+    remote: Option<String>,
+    function: Option<String>,
+    follow: bool,
+    since: Option<String>,
+    grep: Option<String>,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+    let grep = grep
+        .map(|pattern| {
+            Regex::new(&pattern)
+                .map_err(|e| CoupeError::Config(format!("Invalid --grep {}: {}", pattern, e)))
+        })
+        .transpose()?;
+    let options = LogsOptions {
+        follow,
+        since: since.as_deref().map(parse_since).transpose()?,
+    };
+
+    let client = connect_docker(&deployment_target)?;
+    let containers = log_containers(&client, &config, function.as_deref()).await?;
+    if containers.is_empty() {
+        println!("No containers found for stack {}", config.name);
+        return Ok(());
+    }
+    let mut sources: Vec<&LogSource> = containers.iter().map(|(_, source)| source).collect();
+    sources.dedup();
+    let printer = Printer::new(&sources);
+
+    let mut lines = stream_logs(&client, &containers, &options).await?;
+    while let Some(line) = lines.next().await {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        let (text, level) = match parse_tracing_line(&line.message) {
+            Some(tracing) => {
+                // With a function given, only the sentinel's lines about it and its versions are
+                // shown.
+                if line.source == LogSource::Sentinel
+                    && function.is_some()
+                    && tracing
+                        .function_name
+                        .as_deref()
+                        .map(|name| split_instance(name).0)
+                        != function.as_deref()
+                {
+                    continue;
+                }
+                let mut text = tracing.message;
+                for (key, value) in tracing.fields {
+                    text.push_str(&format!(" {}={}", key, value));
+                }
+                (text, Some(tracing.level))
+            }
+            None if line.source == LogSource::Sentinel && function.is_some() => continue,
+            None => (line.message.clone(), None),
+        };
+        if grep.as_ref().is_some_and(|grep| !grep.is_match(&text)) {
+            continue;
+        }
+        printer.print(&line, &text, level.as_deref());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since_takes_durations_and_timestamps() {
+        let now = Timestamp::now().as_second();
+        for (since, seconds) in [("30s", 30), ("10m", 600), ("2h", 7200), ("1d", 86400)] {
+            let parsed = parse_since(since).unwrap();
+            assert!(
+                (now - seconds..=now - seconds + 5).contains(&parsed),
+                "{}",
+                since
+            );
+        }
+        assert_eq!(parse_since("2024-05-01T10:00:00Z").unwrap(), 1714557600);
+        assert_eq!(parse_since("1714557600").unwrap(), 1714557600);
+
+        for since in ["", "m", "10x", "abcm", "9223372036854775807d"] {
+            assert!(parse_since(since).is_err(), "{}", since);
+        }
+    }
+
+    #[test]
+    fn tracing_lines_are_split_into_message_and_fields() {
+        let line = parse_tracing_line(
+            r#"{"timestamp":"2024-05-01T10:00:00Z","level":"WARN","fields":{"message":"Circuit opened","function_name":"api","cooldown_secs":30},"target":"coupe_sentinel"}"#,
+        )
+        .unwrap();
+        assert_eq!(line.level, "WARN");
+        assert_eq!(line.message, "Circuit opened");
+        assert_eq!(line.function_name.as_deref(), Some("api"));
+        assert_eq!(
+            line.fields,
+            [
+                ("cooldown_secs".to_string(), "30".to_string()),
+                ("function_name".to_string(), "api".to_string()),
+            ]
+        );
+
+        assert!(parse_tracing_line("plain text").is_none());
+        assert!(parse_tracing_line(r#"["level"]"#).is_none());
+        assert!(parse_tracing_line(r#"{"level":"INFO"}"#).is_none());
+    }
+}
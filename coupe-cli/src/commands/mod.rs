@@ -1,5 +1,14 @@
 pub mod deploy;
+pub mod dev;
+pub mod history;
+pub mod invoke;
+pub mod keys;
+pub mod logs;
 pub mod new;
+pub mod promote;
+pub mod rollback;
+mod sentinel;
+pub mod status;
 pub mod teardown;
 
 use crate::Commands;
@@ -8,7 +17,55 @@ use coupe::Result;
 pub async fn execute(command: Commands) -> Result<()> {
     match command {
         Commands::New { name, path } => new::execute(name, path).await,
-        Commands::Deploy { path, remote } => deploy::execute(path, remote).await,
-        Commands::Teardown { path, remote } => teardown::execute(path, remote).await,
+        Commands::Deploy {
+            path,
+            remote,
+            pin_digests,
+        } => deploy::execute(path, remote, pin_digests).await,
+        Commands::Teardown {
+            path,
+            remote,
+            volumes,
+        } => teardown::execute(path, remote, volumes).await,
+        Commands::History { path, remote } => history::execute(path, remote).await,
+        Commands::Rollback {
+            revision,
+            path,
+            remote,
+        } => rollback::execute(path, remote, revision).await,
+        Commands::Promote {
+            function,
+            version,
+            weight,
+            path,
+            remote,
+        } => promote::execute(path, remote, function, version, weight).await,
+        Commands::Logs {
+            function,
+            path,
+            remote,
+            follow,
+            since,
+            grep,
+        } => logs::execute(path, remote, function, follow, since, grep).await,
+        Commands::Status {
+            path,
+            remote,
+            url,
+            watch,
+            interval,
+            json,
+        } => status::execute(path, remote, url, watch, interval, json).await,
+        Commands::Invoke {
+            function,
+            request_path,
+            path,
+            url,
+            data,
+            headers,
+            method,
+        } => invoke::execute(path, url, function, request_path, data, headers, method).await,
+        Commands::Dev { path } => dev::execute(path).await,
+        Commands::Keys { path, url, command } => keys::execute(path, url, command).await,
     }
 }
@@ -0,0 +1,31 @@
+use coupe::{Config, CoupeError, DeploymentTarget, Result, promote_version};
+use std::path::PathBuf;
+
+pub async fn execute(
+    path: Option<String>,
+    remote: Option<String>,
+    function: String,
+    version: String,
+    weight: Option<u8>,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+
+    let revision =
+        promote_version(&config, &deployment_target, &function, &version, weight).await?;
+    match weight {
+        Some(weight) => println!(
+            "Sending {}% of {}'s requests to {}, deployed as revision {}",
+            weight, function, version, revision.number
+        ),
+        None => println!(
+            "Promoted {} to {}'s own image, deployed as revision {}",
+            version, function, revision.number
+        ),
+    }
+    println!("Update the config file to match before the next deploy");
+
+    Ok(())
+}
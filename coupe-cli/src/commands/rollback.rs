@@ -0,0 +1,22 @@
+use coupe::{Config, CoupeError, DeploymentTarget, Result, rollback_stack};
+use std::path::PathBuf;
+
+pub async fn execute(
+    path: Option<String>,
+    remote: Option<String>,
+    revision: Option<u32>,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+
+    let deployed = rollback_stack(&config, &deployment_target, revision).await?;
+    println!(
+        "Rolled back to revision {}, deployed as revision {}",
+        deployed.rollback_of.unwrap_or_default(),
+        deployed.number
+    );
+
+    Ok(())
+}
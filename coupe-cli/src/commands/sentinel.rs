@@ -0,0 +1,58 @@
+use coupe::{Config, CoupeError, Result};
+use reqwest::{Client, Method, RequestBuilder};
+use serde_json::Value;
+
+/// Client for the sentinel's HTTP API.
+pub struct Sentinel {
+    client: Client,
+    url: String,
+    admin_token: Option<String>,
+}
+
+impl Sentinel {
+    /// Defaults to the sentinel on this machine.
+    pub fn new(config: &Config, url: Option<String>, admin_token: Option<String>) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.unwrap_or_else(|| format!("http://localhost:{}", config.sentinel_port())),
+            admin_token,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(
+            method,
+            format!("{}{}", self.url.trim_end_matches('/'), path),
+        );
+        match &self.admin_token {
+            Some(admin_token) => request.bearer_auth(admin_token),
+            None => request,
+        }
+    }
+
+    pub async fn send(&self, request: RequestBuilder) -> Result<Value> {
+        let response = request
+            .send()
+            .await
+            .map_err(|e| CoupeError::Http(format!("Failed to reach sentinel: {}", e)))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| CoupeError::Http(format!("Invalid sentinel response: {}", e)))?;
+        let body: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+        if !status.is_success() {
+            let message = body["error"].as_str().unwrap_or(&text);
+            return Err(CoupeError::Http(format!("{}: {}", status, message)));
+        }
+        Ok(body)
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Value> {
+        self.send(self.request(Method::GET, path)).await
+    }
+}
@@ -0,0 +1,328 @@
+use super::sentinel::Sentinel;
+use coupe::{
+    Config, CoupeError, DeploymentTarget, Docker, HttpMethod, Result, Target, Trigger,
+    connect_docker, get_container_state,
+};
+use jiff::Timestamp;
+use serde::Serialize;
+use serde_json::Value;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+use tokio::time::sleep;
+
+#[derive(Serialize)]
+struct SentinelStatus {
+    url: String,
+    running: bool,
+    container: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FunctionStatus {
+    name: String,
+    state: String,
+    session_expires_at: Option<Timestamp>,
+    image: String,
+    handler_port: u16,
+    trigger: String,
+    last_cold_start: Option<Timestamp>,
+}
+
+#[derive(Serialize)]
+struct StackStatus {
+    stack: String,
+    /// Whether the functions are described by the sentinel's config rather than the local file.
+    deployed_config: bool,
+    /// Why the sentinel's config could not be used, when it is running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_error: Option<String>,
+    sentinel: SentinelStatus,
+    functions: Vec<FunctionStatus>,
+}
+
+/// The sentinel's URL on a remote target.
+fn remote_sentinel_url(remote: &Target, config: &Config) -> String {
+    format!("http://{}:{}", remote.host, config.sentinel_port())
+}
+
+fn trigger_summary(trigger: &Trigger) -> String {
+    match trigger {
+        Trigger::Http(http) => {
+            let methods = http
+                .method
+                .as_ref()
+                .filter(|methods| !methods.contains(&HttpMethod::Any))
+                .map(|methods| {
+                    methods
+                        .iter()
+                        .map(|m| format!("{:?}", m).to_uppercase())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })
+                .unwrap_or("ANY".to_string());
+            format!("http {} {}", methods, http.path)
+        }
+        Trigger::Queue { queue, .. } => format!("queue {}", queue),
+        Trigger::Stream { stream, .. } => format!("stream {}", stream),
+        Trigger::Timer { schedule, .. } => format!("timer {}", schedule),
+    }
+}
+
+/// Session expiries by function, from `/system/sessions`. Expired sessions are left out.
+fn session_expiries(sessions: &Value) -> HashMap<String, Timestamp> {
+    let now = Timestamp::now();
+    sessions
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|session| {
+            let function_name = session["function_name"].as_str()?;
+            let ends_at = Timestamp::from_nanosecond(session["ends_at"].as_i64()?.into()).ok()?;
+            (ends_at > now).then(|| (function_name.to_string(), ends_at))
+        })
+        .collect()
+}
+
+/// The container's state and when it was last started, which for a function is its last cold
+/// start.
+async fn container_state(
+    client: &std::result::Result<Docker, String>,
+    container_name: &str,
+) -> (String, Option<Timestamp>) {
+    let client = match client {
+        Ok(client) => client,
+        Err(_) => return ("unknown".to_string(), None),
+    };
+    match get_container_state(client, container_name).await {
+        Ok(Some(state)) => {
+            let started_at = state
+                .started_at
+                .as_deref()
+                .and_then(|t| t.parse::<Timestamp>().ok())
+                // Docker reports containers that never started as starting at year 1.
+                .filter(|t| t.as_second() > 0);
+            let status = state
+                .status
+                .map(|s| s.to_string())
+                .unwrap_or("unknown".to_string());
+            (status, started_at)
+        }
+        Ok(None) => ("not created".to_string(), None),
+        Err(_) => ("unknown".to_string(), None),
+    }
+}
+
+async fn stack_status(
+    config: &Config,
+    sentinel: &Sentinel,
+    client: &std::result::Result<Docker, String>,
+) -> StackStatus {
+    let health = sentinel.get("/health").await;
+    let (deployed, config_error) = match &health {
+        Ok(_) => match sentinel.get("/system/config").await.and_then(|config| {
+            serde_json::from_value::<Config>(config)
+                .map_err(|e| CoupeError::Config(format!("Invalid deployed config: {}", e)))
+        }) {
+            Ok(config) => (Some(config), None),
+            Err(e) => (None, Some(e.to_string())),
+        },
+        Err(_) => (None, None),
+    };
+    let sessions = match &health {
+        Ok(_) => sentinel
+            .get("/system/sessions")
+            .await
+            .map(|sessions| session_expiries(&sessions))
+            .unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    };
+    let deployed_config = deployed.is_some();
+    let config = deployed.as_ref().unwrap_or(config);
+
+    let (container, _) = container_state(client, &config.sentinel_container_name()).await;
+    let error = match (&health, client) {
+        (Err(e), _) => Some(e.to_string()),
+        (Ok(_), Err(e)) => Some(e.clone()),
+        _ => None,
+    };
+    let sentinel = SentinelStatus {
+        url: sentinel.url().to_string(),
+        running: health.is_ok_and(|health| health["running"].as_bool() == Some(true)),
+        container,
+        error,
+    };
+
+    let mut host_clients = HashMap::new();
+    let mut functions = Vec::new();
+    for instance in config.instance_names() {
+        let Some(function) = config.instance_definition(&instance) else {
+            continue;
+        };
+        let client = match config.function_host(&instance) {
+            Some(host) => host_clients.entry(host.ssh.clone()).or_insert_with(|| {
+                connect_docker(&DeploymentTarget::Remote(config.host_target(host)))
+                    .map_err(|e| e.to_string())
+            }),
+            None => client,
+        };
+        let (state, last_cold_start) =
+            container_state(client, &config.function_container_name(&instance)).await;
+        functions.push(FunctionStatus {
+            state,
+            session_expires_at: sessions.get(&instance).copied(),
+            image: function.image.clone(),
+            handler_port: config.function_handler_port(&instance).unwrap_or_default(),
+            trigger: trigger_summary(&function.trigger),
+            last_cold_start,
+            name: instance,
+        });
+    }
+
+    StackStatus {
+        stack: config.name.clone(),
+        deployed_config,
+        config_error,
+        sentinel,
+        functions,
+    }
+}
+
+fn relative(time: Timestamp) -> String {
+    let seconds = Timestamp::now().duration_until(time).as_secs();
+    let (amount, future) = (seconds.unsigned_abs(), seconds >= 0);
+    let amount = match amount {
+        0..60 => format!("{}s", amount),
+        60..3600 => format!("{}m", amount / 60),
+        3600..86400 => format!("{}h", amount / 3600),
+        _ => format!("{}d", amount / 86400),
+    };
+    if future {
+        format!("in {}", amount)
+    } else {
+        format!("{} ago", amount)
+    }
+}
+
+fn print_status(status: &StackStatus) {
+    println!("Stack     {}", status.stack);
+    println!(
+        "Sentinel  {}  {}  container {}",
+        status.sentinel.url,
+        if status.sentinel.running {
+            "running"
+        } else {
+            "unreachable"
+        },
+        status.sentinel.container
+    );
+    if let Some(error) = &status.sentinel.error {
+        println!("          {}", error);
+    }
+    if let Some(error) = &status.config_error {
+        println!("          {}", error);
+    }
+    if !status.deployed_config {
+        println!("          Showing the local config, the deployed one could not be read");
+    }
+    println!();
+
+    let headers = [
+        "FUNCTION",
+        "STATE",
+        "SESSION",
+        "IMAGE",
+        "PORT",
+        "TRIGGER",
+        "COLD START",
+    ];
+    let rows: Vec<[String; 7]> = status
+        .functions
+        .iter()
+        .map(|function| {
+            [
+                function.name.clone(),
+                function.state.clone(),
+                function
+                    .session_expires_at
+                    .map(|t| format!("expires {}", relative(t)))
+                    .unwrap_or("-".to_string()),
+                function.image.clone(),
+                function.handler_port.to_string(),
+                function.trigger.clone(),
+                function
+                    .last_cold_start
+                    .map(relative)
+                    .unwrap_or("-".to_string()),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..headers.len())
+        .map(|i| {
+            rows.iter()
+                .map(|row| row[i].len())
+                .chain([headers[i].len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let line = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(headers.to_vec()));
+    for row in &rows {
+        println!("{}", line(row.iter().map(String::as_str).collect()));
+    }
+}
+
+pub async fn execute(
+    path: Option<String>,
+    remote: Option<String>,
+    url: Option<String>,
+    watch: bool,
+    interval: u64,
+    json: bool,
+) -> Result<()> {
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+    let url = url.or_else(|| match &deployment_target {
+        DeploymentTarget::Remote(remote) => Some(remote_sentinel_url(remote, &config)),
+        DeploymentTarget::Local => None,
+    });
+    let sentinel = Sentinel::new(&config, url, None);
+    // Without Docker the sentinel's view is still worth showing.
+    let client = connect_docker(&deployment_target).map_err(|e| e.to_string());
+
+    loop {
+        let status = stack_status(&config, &sentinel, &client).await;
+        if json {
+            let json = if watch {
+                serde_json::to_string(&status)
+            } else {
+                serde_json::to_string_pretty(&status)
+            };
+            println!("{}", json.map_err(|e| CoupeError::Unknown(e.to_string()))?);
+        } else if watch {
+            // Clear the screen and move to the top before redrawing.
+            print!("\x1b[2J\x1b[H");
+            print_status(&status);
+            println!();
+            println!("Refreshing every {}s, press Ctrl+C to stop", interval);
+        } else {
+            print_status(&status);
+        }
+        if !watch {
+            return Ok(());
+        }
+        sleep(Duration::from_secs(interval.max(1))).await;
+    }
+}
@@ -1,5 +1,19 @@
-use coupe::Result;
+use coupe::{Config, CoupeError, DeploymentTarget, Result, teardown_stack};
+use std::path::PathBuf;
 
-pub async fn execute(_path: Option<String>, _remote: Option<String>) -> Result<()> {
-    todo!()
+pub async fn execute(path: Option<String>, remote: Option<String>, volumes: bool) -> Result<()> {
+    println!("Tearing down coupe stack");
+    let config_path = path.unwrap_or("coupe.yaml".to_string());
+    let config =
+        Config::load(PathBuf::from(config_path)).map_err(|e| CoupeError::Config(e.to_string()))?;
+    let deployment_target = DeploymentTarget::resolve(&config, remote.as_deref())?;
+
+    teardown_stack(&config, &deployment_target, volumes).await?;
+    if volumes {
+        println!("Removed stack {} and its volumes", config.name);
+    } else {
+        println!("Removed stack {}, volumes were kept", config.name);
+    }
+
+    Ok(())
 }
@@ -1,21 +1,57 @@
-use crate::{DOCKER_CLIENT, get_all_sessions, start_session};
+use crate::{
+    ANY_FUNCTION, ApiKeyAuth, AsyncInvoker, COLD_START_HEADER, CircuitStatus, DeliveryError,
+    EVENT_ID_HEADER, EventResponse, FALLBACK_PATH, HeaderEditPolicy, InternalApi, Invocation,
+    InvocationOutcome, RateLimiter, Sentinel, StoredRequest, VersionSelector, WebhookVerifier,
+    admit_request, apply_request_headers, apply_response_headers, check_circuit, cors_layer,
+    create_api_key, deliver_event, enforce_rate_limit, event_from_request, function_client,
+    get_all_api_keys, get_all_circuits, get_all_sessions, get_dead_letter, get_dead_letters,
+    get_invocation, get_invocation_metrics, hash_key, purge_dead_letters, record_invocation,
+    request_host, require_api_key, retry_delay, revoke_api_key, route_groups, route_request,
+    save_invocation, select_host, send_event, start_session, take_dead_letter, to_method,
+    trigger_matches_path, trigger_route_paths, validate_event_functions, validate_function_routes,
+    verify_webhook, watch_invocations,
+};
 use axum::{
     Json, Router,
-    body::Body,
+    body::{Body, Bytes, to_bytes},
     extract::State,
-    http::{Request, StatusCode},
+    extract::{ConnectInfo, Path, Query, RawPathParams, rejection::RawPathParamsRejection},
+    http::{
+        HeaderValue, Method, Request, StatusCode,
+        header::{
+            AUTHORIZATION, CONNECTION, CONTENT_LENGTH, LOCATION, RETRY_AFTER, TRANSFER_ENCODING,
+        },
+        request::Parts,
+    },
+    middleware::{Next, from_fn_with_state, map_request_with_state, map_response_with_state},
     response::{IntoResponse, Response},
-    routing::{any, delete, get, patch, post, put},
+    routing::{MethodFilter, MethodRouter, any, delete, get, on, post},
     serve,
 };
 use axum_proxy::{Identity, ReusedService, client::HttpConnector};
-use coupe::{Config, CoupeError, HttpMethod, Result, ensure_function_running};
+use coupe::{
+    Config, CoupeError, HttpAuth, HttpMethod, HttpTrigger, RateLimitKey, Result, Trigger,
+    ensure_function_running, validate_function_versions, validate_function_volumes,
+    validate_placement,
+};
+use http_body_util::LengthLimitError;
+use jiff::Timestamp;
 use serde::Deserialize;
-use serde_json::json;
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::IntoFuture,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    net::TcpListener,
+    spawn,
+    time::{sleep, timeout},
+    try_join,
+};
 use tower_service::Service;
-use tracing::{error, info};
+use tracing::{debug, error, info, instrument};
 
 async fn health() -> impl IntoResponse {
     Json(json!({ "running": true }))
@@ -35,9 +71,41 @@ async fn list_sessions() -> impl IntoResponse {
     }
 }
 
+async fn list_circuits() -> impl IntoResponse {
+    Json(get_all_circuits())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    Json(get_invocation_metrics())
+}
+
+/// Config keys holding credentials, replaced with [`REDACTED`] wherever they appear in
+/// `/system/config`, which needs no auth. The keys are kept so the config still parses.
+const REDACTED_CONFIG_KEYS: [&str; 4] = ["admin_token", "password", "client_secret", "secret"];
+const REDACTED: &str = "<redacted>";
+
+fn redact_config(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if REDACTED_CONFIG_KEYS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact_config(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_config),
+        _ => {}
+    }
+}
+
 async fn get_config(State(config): State<Arc<Config>>) -> impl IntoResponse {
     match serde_json::to_value(&*config) {
-        Ok(res) => (StatusCode::OK, Json(res)),
+        Ok(mut res) => {
+            redact_config(&mut res);
+            (StatusCode::OK, Json(res))
+        }
         Err(e) => {
             error!(error = %e, "Failed to get config");
             (
@@ -49,127 +117,590 @@ async fn get_config(State(config): State<Arc<Config>>) -> impl IntoResponse {
     .into_response()
 }
 
+type FunctionProxy = ReusedService<Identity, HttpConnector, Body>;
+
+enum ProxyRequest {
+    Streaming(Request<Body>),
+    Buffered(Parts, Bytes),
+}
+
+fn rebuild_request(parts: &Parts, body: &Bytes) -> Request<Body> {
+    let mut request = Request::new(Body::from(body.clone()));
+    *request.method_mut() = parts.method.clone();
+    *request.uri_mut() = parts.uri.clone();
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    request
+}
+
+enum ProxyOutcome {
+    Response(Response),
+    Error(String),
+}
+
+/// Reads the request body up front when it has to be size-checked or replayed on retry,
+/// so oversized requests are rejected before the function is woken.
+async fn prepare_request(
+    trigger: &HttpTrigger,
+    request: Request<Body>,
+) -> std::result::Result<ProxyRequest, Response> {
+    let retryable = trigger.retries.is_some() && request.method().is_idempotent();
+    if trigger.max_body_size.is_none() && !retryable {
+        return Ok(ProxyRequest::Streaming(request));
+    }
+
+    let limit = trigger.max_body_size.unwrap_or(DEFAULT_BUFFERED_BODY_SIZE);
+    let (parts, body) = read_body(request, limit).await?;
+    Ok(ProxyRequest::Buffered(parts, body))
+}
+
+/// Body limit for requests that have to be buffered on triggers without `max_body_size`.
+pub const DEFAULT_BUFFERED_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// Buffers the request body, rejecting it with `413` once it exceeds `limit` bytes.
+pub(crate) async fn read_body(
+    request: Request<Body>,
+    limit: usize,
+) -> std::result::Result<(Parts, Bytes), Response> {
+    let content_length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|length| length > limit) {
+        return Err(payload_too_large_response(limit));
+    }
+
+    let (parts, body) = request.into_parts();
+    match to_bytes(body, limit).await {
+        Ok(body) => Ok((parts, body)),
+        Err(e) => {
+            let source: &(dyn std::error::Error + 'static) = &e;
+            if source.source().is_some_and(|s| s.is::<LengthLimitError>()) {
+                Err(payload_too_large_response(limit))
+            } else {
+                Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(
+                        json!({ "error": "Failed to read request body", "message": e.to_string() }),
+                    ),
+                )
+                    .into_response())
+            }
+        }
+    }
+}
+
+fn payload_too_large_response(limit: usize) -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        Json(json!({ "error": "Payload too large", "max_body_size": limit })),
+    )
+        .into_response()
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+async fn forward_request(
+    proxy: &mut FunctionProxy,
+    trigger: &HttpTrigger,
+    function_name: &str,
+    request: ProxyRequest,
+) -> ProxyOutcome {
+    let (parts, body) = match request {
+        ProxyRequest::Streaming(request) => {
+            let Ok(result) = proxy.call(request).await;
+            return match result {
+                Ok(res) => ProxyOutcome::Response(res.into_response()),
+                Err(e) => ProxyOutcome::Error(e.to_string()),
+            };
+        }
+        ProxyRequest::Buffered(parts, body) => (parts, body),
+    };
+    let max_attempts = match &trigger.retries {
+        Some(retries) if parts.method.is_idempotent() => retries.max_attempts(),
+        _ => 1,
+    };
+    let mut attempt = 1;
+
+    loop {
+        let Ok(result) = proxy.call(rebuild_request(&parts, &body)).await;
+        let retryable = match &result {
+            Ok(res) => is_retryable_status(res.status()),
+            Err(_) => true,
+        };
+
+        if !retryable || attempt >= max_attempts {
+            return match result {
+                Ok(res) => ProxyOutcome::Response(res.into_response()),
+                Err(e) => ProxyOutcome::Error(e.to_string()),
+            };
+        }
+
+        let backoff = trigger
+            .retries
+            .as_ref()
+            .map(|retries| retry_delay(retries, attempt))
+            .unwrap_or_default();
+        info!(
+            function_name = %function_name,
+            attempt = attempt,
+            max_attempts = max_attempts,
+            backoff_ms = backoff.as_millis(),
+            "Retrying proxy request"
+        );
+        record_invocation(function_name, InvocationOutcome::Retry);
+        sleep(backoff).await;
+        attempt += 1;
+    }
+}
+
+#[instrument(skip(proxy, config, trigger, request))]
 async fn invoke_function(
-    mut proxy: ReusedService<Identity, HttpConnector, Body>,
+    mut proxy: FunctionProxy,
     config: Arc<Config>,
+    trigger: Arc<HttpTrigger>,
     function_name: String,
     request: Request<Body>,
 ) -> Response {
     info!(function_name = %function_name, "Invoking function");
 
-    if let Err(e) = start_session(&config, function_name.clone()).await {
-        error!(
-            function_name = %function_name,
-            error = %e,
-            "Failed to start session"
-        );
-        let res = Json(json!({ "error": e.to_string() }));
-        return match e {
-            CoupeError::Healthcheck(e) => {
-                (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": e })))
+    // Rejected requests never reach the function, so they are not half-open trials.
+    let request = match prepare_request(&trigger, request).await {
+        Ok(request) => request,
+        Err(res) => {
+            if res.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                info!(function_name = %function_name, "Request body exceeds max_body_size");
+                record_invocation(&function_name, InvocationOutcome::PayloadTooLarge);
             }
-            _ => (StatusCode::INTERNAL_SERVER_ERROR, res),
+            return res;
         }
-        .into_response();
-    }
+    };
+
+    let permit = match check_circuit(&config, &function_name) {
+        Ok(permit) => permit,
+        Err(status) => {
+            record_invocation(&function_name, InvocationOutcome::CircuitOpen);
+            return circuit_open_response(&status);
+        }
+    };
+
+    let coldstarted = match start_session(&config, function_name.clone()).await {
+        Ok(started) => started.coldstarted,
+        Err(e) => {
+            error!(
+                function_name = %function_name,
+                error = %e,
+                "Failed to start session"
+            );
+            permit.failure(e.to_string());
+            record_invocation(&function_name, InvocationOutcome::ColdStartFailed);
+            let res = Json(json!({ "error": e.to_string() }));
+            return match e {
+                CoupeError::Healthcheck(e) => {
+                    (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": e })))
+                }
+                _ => (StatusCode::INTERNAL_SERVER_ERROR, res),
+            }
+            .into_response();
+        }
+    };
 
     info!(function_name = %function_name, "Session started successfully, making proxy request");
 
-    match proxy.call(request).await {
-        Ok(Ok(res)) => {
+    let forward = forward_request(&mut proxy, &trigger, &function_name, request);
+    let outcome = match trigger.timeout() {
+        Some(limit) => match timeout(limit, forward).await {
+            Ok(outcome) => outcome,
+            Err(_) => {
+                error!(
+                    function_name = %function_name,
+                    timeout_secs = limit.as_secs(),
+                    "Function timed out"
+                );
+                permit.failure("Function timed out".to_string());
+                record_invocation(&function_name, InvocationOutcome::Timeout);
+                return (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    Json(json!({ "error": "Function timed out", "timeout": limit.as_secs() })),
+                )
+                    .into_response();
+            }
+        },
+        None => forward.await,
+    };
+
+    match outcome {
+        ProxyOutcome::Response(mut res) => {
+            if coldstarted {
+                res.headers_mut()
+                    .insert(COLD_START_HEADER, HeaderValue::from_static("true"));
+            }
             info!(
                 function_name = %function_name,
                 status = res.status().as_u16(),
                 "Proxy request successful"
             );
-            res.into_response()
+            if res.status().is_server_error() {
+                permit.failure(format!("Function responded with {}", res.status()));
+                record_invocation(&function_name, InvocationOutcome::FunctionError);
+            } else {
+                permit.success();
+                record_invocation(&function_name, InvocationOutcome::Success);
+            }
+            res
         }
-        Ok(Err(e)) => {
+        ProxyOutcome::Error(e) => {
             error!(
                 function_name = %function_name,
                 error = %e,
                 "Proxy error - function may not be reachable"
             );
+            permit.failure(e.clone());
+            record_invocation(&function_name, InvocationOutcome::ProxyError);
             (
                 StatusCode::BAD_GATEWAY,
-                Json(json!({ "error": "Proxy error", "message": e.to_string() })),
-            )
-                .into_response()
-        }
-        Err(e) => {
-            error!(
-                function_name = %function_name,
-                error = %e,
-                "Proxy error - should be unreachable"
-            );
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "error": "Proxy error, should be unreachable", "message": e.to_string() })),
+                Json(json!({ "error": "Proxy error", "message": e })),
             )
                 .into_response()
         }
     }
 }
 
-fn build_function_router(config: Arc<Config>) -> Result<Router> {
-    let mut router = Router::new();
-    for function_name in config.http_functions() {
-        let function = config
-            .functions
-            .get(&function_name)
+/// Fails fast while the circuit is open. The container's diagnostics and the last error stay in
+/// `/system/circuits`, as this response goes to any client.
+fn circuit_open_response(status: &CircuitStatus) -> Response {
+    info!(
+        function_name = %status.function_name,
+        retry_after_secs = status.retry_after_secs,
+        "Circuit open, failing fast"
+    );
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, status.retry_after_secs.to_string())],
+        Json(json!({ "error": "Circuit open", "retry_after_secs": status.retry_after_secs })),
+    )
+        .into_response()
+}
+
+fn method_filter(method: &HttpMethod) -> Option<MethodFilter> {
+    match method {
+        HttpMethod::Any => None,
+        HttpMethod::Get => Some(MethodFilter::GET),
+        HttpMethod::Post => Some(MethodFilter::POST),
+        HttpMethod::Put => Some(MethodFilter::PUT),
+        HttpMethod::Delete => Some(MethodFilter::DELETE),
+        HttpMethod::Patch => Some(MethodFilter::PATCH),
+        HttpMethod::Head => Some(MethodFilter::HEAD),
+        HttpMethod::Options => Some(MethodFilter::OPTIONS),
+    }
+}
+
+/// Method router that proxies to the function, without the trigger's auth, rate limit, header
+/// and CORS layers.
+fn build_proxy_router(config: Arc<Config>, function_name: &str) -> Result<MethodRouter> {
+    let function = config
+        .functions
+        .get(function_name)
+        .ok_or(CoupeError::InvalidInput(format!(
+            "Function not found: {}",
+            function_name
+        )))?;
+    let trigger =
+        function
+            .trigger
+            .as_http()
+            .cloned()
+            .map(Arc::new)
             .ok_or(CoupeError::InvalidInput(format!(
-                "Function not found: {}",
+                "Function {} is not an HTTP function",
                 function_name
             )))?;
-        let (path, method, _schema, _auth) =
-            function
-                .trigger
-                .clone()
-                .as_http()
-                .ok_or(CoupeError::InvalidInput(format!(
-                    "Function {} is not an HTTP function",
-                    function_name
-                )))?;
-
-        let function_url = config.internal_function_url(&function_name).map_err(|e| {
-            CoupeError::InvalidInput(format!("Failed to get function URL: {}", e.to_string()))
-        })?;
+
+    let selector = if function.versions.is_empty() {
+        None
+    } else {
+        Some(Arc::new(VersionSelector::new(function_name, function)?))
+    };
+    let mut proxies = HashMap::new();
+    for instance in config.function_instances(function_name) {
+        let function_url = config
+            .internal_function_url(&instance)
+            .map_err(|e| CoupeError::InvalidInput(format!("Failed to get function URL: {}", e)))?;
 
         info!(
-            function_name = %function_name,
+            function_name = %instance,
             function_url = %function_url,
-            path = %path,
+            path = %trigger.path,
+            methods = ?trigger.methods(),
+            hosts = ?trigger.hosts(),
             "Setting up proxy for function"
         );
 
         let reverse_proxy = axum_proxy::builder_http(function_url)
             .map_err(|e| CoupeError::InvalidInput(format!("Failed to build reverse proxy: {}", e)))?
             .build(Identity);
+        proxies.insert(instance, reverse_proxy);
+    }
+    let proxies = Arc::new(proxies);
 
-        let handler_config = Arc::clone(&config);
-        let handler_function_name = function_name.clone();
+    let handler_trigger = Arc::clone(&trigger);
+    let handler_function_name = function_name.to_string();
 
-        let handler = move |request: Request<Body>| {
-            let proxy = reverse_proxy.clone();
-            let config = handler_config.clone();
-            let function_name = handler_function_name.clone();
+    let handler = move |params: std::result::Result<RawPathParams, RawPathParamsRejection>,
+                        request: Request<Body>| {
+        let config = config.clone();
+        let trigger = handler_trigger.clone();
+        let selector = selector.clone();
+        let selected = selector.as_ref().map(|selector| selector.select(&request));
+        let instance = selected
+            .as_ref()
+            .map_or(handler_function_name.clone(), |selected| {
+                selected.instance.clone()
+            });
+        let proxy = proxies[&instance].clone();
+        let request = route_request(&trigger, params.ok(), request);
 
-            async move { invoke_function(proxy, config, function_name, request).await }
-        };
-        let method_router = match method.unwrap_or(HttpMethod::Any) {
-            HttpMethod::Any => any(handler),
-            HttpMethod::Get => get(handler),
-            HttpMethod::Post => post(handler),
-            HttpMethod::Put => put(handler),
-            HttpMethod::Delete => delete(handler),
-            HttpMethod::Patch => patch(handler),
+        async move {
+            let mut response = invoke_function(proxy, config, trigger, instance, request).await;
+            if let (Some(selector), Some(selected)) = (selector, selected) {
+                selector.annotate(&selected, &mut response);
+            }
+            response
+        }
+    };
+
+    let filter = trigger
+        .methods()
+        .iter()
+        .map(method_filter)
+        .collect::<Option<Vec<_>>>()
+        .and_then(|filters| filters.into_iter().reduce(MethodFilter::or));
+    Ok(match filter {
+        Some(filter) => on(filter, handler),
+        None => any(handler),
+    })
+}
+
+/// Wraps a function's handler in its trigger's rate limits and auth, in the order requests
+/// pass them.
+fn guard_method_router(
+    function_name: &str,
+    trigger: &HttpTrigger,
+    mut method_router: MethodRouter,
+) -> Result<MethodRouter> {
+    let limiter = trigger.rate_limit.as_ref().map(|rate_limit| {
+        Arc::new(RateLimiter {
+            function_name: function_name.to_string(),
+            rate_limit: rate_limit.clone(),
+        })
+    });
+    // Limits keyed by a client identity count requests once authentication has verified it.
+    if let Some(limiter) = limiter
+        .as_ref()
+        .filter(|limiter| limiter.rate_limit.key != RateLimitKey::Ip)
+    {
+        method_router =
+            method_router.layer(from_fn_with_state(Arc::clone(limiter), enforce_rate_limit));
+    }
+
+    if let Some(HttpAuth::ApiKey { header, query }) = &trigger.auth {
+        let auth = Arc::new(ApiKeyAuth::new(
+            function_name,
+            header.as_deref(),
+            query.as_deref(),
+        )?);
+        method_router = method_router.layer(from_fn_with_state(auth, require_api_key));
+    }
+    if let Some(HttpAuth::Webhook(auth)) = &trigger.auth {
+        let verifier = Arc::new(WebhookVerifier::new(
+            function_name,
+            auth,
+            trigger.max_body_size,
+        )?);
+        method_router = method_router.layer(from_fn_with_state(verifier, verify_webhook));
+    }
+    // Limits by address run before authentication so invalid keys count against the client.
+    if let Some(limiter) = limiter.filter(|limiter| limiter.rate_limit.key == RateLimitKey::Ip) {
+        method_router = method_router.layer(from_fn_with_state(limiter, enforce_rate_limit));
+    }
+    Ok(method_router)
+}
+
+fn header_edit_policy(trigger: &HttpTrigger) -> Result<Option<Arc<HeaderEditPolicy>>> {
+    let Some(policy) = &trigger.headers else {
+        return Ok(None);
+    };
+    let policy = HeaderEditPolicy::new(policy)?;
+    Ok((!policy.is_empty()).then(|| Arc::new(policy)))
+}
+
+fn build_method_router(config: Arc<Config>, function_name: &str) -> Result<MethodRouter> {
+    let method_router = build_proxy_router(Arc::clone(&config), function_name)?;
+    let Some(trigger) = config
+        .functions
+        .get(function_name)
+        .and_then(|f| f.trigger.as_http())
+    else {
+        return Ok(method_router);
+    };
+
+    let mut method_router = guard_method_router(function_name, trigger, method_router)?;
+    if let Some(policy) = header_edit_policy(trigger)? {
+        method_router = method_router
+            .layer(map_request_with_state(
+                Arc::clone(&policy),
+                apply_request_headers,
+            ))
+            .layer(map_response_with_state(policy, apply_response_headers));
+    }
+    // CORS is the outermost layer so preflight requests are answered without waking the function.
+    if let Some(cors) = &trigger.cors {
+        method_router = method_router.layer(cors_layer(cors)?);
+    }
+
+    Ok(method_router)
+}
+
+fn build_method_routers(config: Arc<Config>) -> Result<HashMap<String, MethodRouter>> {
+    validate_function_routes(&config)?;
+
+    let mut method_routers = HashMap::new();
+    for function_name in config.http_functions() {
+        let method_router = build_method_router(Arc::clone(&config), &function_name)?;
+        method_routers.insert(function_name, method_router);
+    }
+    Ok(method_routers)
+}
+
+/// Routers for function-to-function calls, which skip the triggers' auth and rate limits.
+fn build_internal_routers(config: Arc<Config>) -> Result<HashMap<String, Router>> {
+    let mut routers = HashMap::new();
+    for function_name in config.http_functions() {
+        let Some(trigger) = config
+            .functions
+            .get(&function_name)
+            .and_then(|f| f.trigger.as_http())
+        else {
+            continue;
         };
-        router = match path.as_str() {
-            "*" => router.fallback(method_router),
-            _ => router.route(&path, method_router),
+        let proxy_router = build_proxy_router(Arc::clone(&config), &function_name)?;
+        let router = build_single_function_router(trigger, &proxy_router);
+        routers.insert(function_name, router);
+    }
+    Ok(routers)
+}
+
+/// Routers for asynchronous invocations, by function. The first passes a request through the
+/// trigger's header edits, rate limits and auth before it is saved, and the second runs the
+/// saved request without counting or verifying it again.
+type InvocationRouters = (HashMap<String, Router>, HashMap<String, Router>);
+
+fn build_invocation_routers(config: Arc<Config>) -> Result<InvocationRouters> {
+    let mut guards = HashMap::new();
+    let mut routers = HashMap::new();
+    for function_name in config.http_functions() {
+        let Some(trigger) = config
+            .functions
+            .get(&function_name)
+            .and_then(|f| f.trigger.as_http())
+        else {
+            continue;
         };
+        let policy = header_edit_policy(trigger)?;
+
+        let mut guard = guard_method_router(&function_name, trigger, any(admit_request))?;
+        let mut method_router = build_proxy_router(Arc::clone(&config), &function_name)?;
+        if let Some(policy) = policy {
+            guard = guard.layer(map_request_with_state(
+                Arc::clone(&policy),
+                apply_request_headers,
+            ));
+            method_router =
+                method_router.layer(map_response_with_state(policy, apply_response_headers));
+        }
+        guards.insert(function_name.clone(), Router::new().fallback(guard));
+        routers.insert(
+            function_name,
+            build_single_function_router(trigger, &method_router),
+        );
+    }
+    Ok((guards, routers))
+}
+
+/// Router with only one function's routes, used to run invocations that do not arrive over
+/// HTTP through the same layers as those that do.
+fn build_single_function_router(trigger: &HttpTrigger, method_router: &MethodRouter) -> Router {
+    let mut router = Router::new();
+    for route_path in trigger_route_paths(trigger) {
+        if route_path == FALLBACK_PATH {
+            router = router.fallback(method_router.clone());
+        } else {
+            router = router.route(&route_path, method_router.clone());
+        }
+    }
+    router
+}
+
+fn build_function_router(
+    config: &Config,
+    method_routers: &HashMap<String, MethodRouter>,
+) -> Router {
+    let mut default_router = Router::new();
+    let mut host_routers = Vec::new();
+    for group in route_groups(config) {
+        let mut router = Router::new();
+        let mut fallback: Option<MethodRouter> = None;
+        for function_name in &group.function_names {
+            let (Some(trigger), Some(method_router)) = (
+                config
+                    .functions
+                    .get(function_name)
+                    .and_then(|f| f.trigger.as_http()),
+                method_routers.get(function_name),
+            ) else {
+                continue;
+            };
+            for route_path in trigger_route_paths(trigger) {
+                if route_path == FALLBACK_PATH {
+                    fallback = Some(match fallback.take() {
+                        Some(existing) => existing.merge(method_router.clone()),
+                        None => method_router.clone(),
+                    });
+                } else {
+                    router = router.route(&route_path, method_router.clone());
+                }
+            }
+        }
+        if let Some(fallback) = fallback {
+            router = router.fallback(fallback);
+        }
+
+        match group.host {
+            Some(host) => host_routers.push((host, router)),
+            None => default_router = router,
+        }
+    }
+
+    if host_routers.is_empty() {
+        return default_router;
     }
-    Ok(router)
+
+    let host_routers = Arc::new(host_routers);
+    Router::new().fallback(move |request: Request<Body>| {
+        let mut router = request_host(&request)
+            .and_then(|host| select_host(&host_routers, &host).cloned())
+            .unwrap_or_else(|| default_router.clone());
+        async move {
+            let Ok(response) = router.call(request).await;
+            response
+        }
+    })
 }
 
 #[derive(Deserialize)]
@@ -183,8 +714,18 @@ async fn start_function(
 ) -> impl IntoResponse {
     let function_name = request.function_name;
 
-    match ensure_function_running(&DOCKER_CLIENT, &config, &function_name).await {
-        Ok(_) => (
+    let result = if config.is_local_function(&function_name) {
+        Ok(())
+    } else {
+        match function_client(&config, &function_name) {
+            Ok(client) => ensure_function_running(&client, &config, &function_name)
+                .await
+                .map(|_| ()),
+            Err(e) => Err(e),
+        }
+    };
+    match result {
+        Ok(()) => (
             StatusCode::OK,
             Json(json!({ "message": "Function started" })),
         ),
@@ -199,22 +740,713 @@ async fn start_function(
     }
 }
 
-pub async fn serve_api(config: Arc<Config>) -> Result<()> {
+/// Rejects the request unless it carries `sentinel.admin_token`. Without a token, management
+/// endpoints are disabled.
+fn check_admin_token(config: &Config, request: &Request<Body>) -> Option<Response> {
+    let Some(admin_token) = config.admin_token() else {
+        return Some(
+            (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "error": "Set sentinel.admin_token to use this endpoint" })),
+            )
+                .into_response(),
+        );
+    };
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| hash_key(token) == hash_key(admin_token));
+    if !authorized {
+        return Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "error": "Invalid admin token" })),
+            )
+                .into_response(),
+        );
+    }
+    None
+}
+
+/// Guards management endpoints with `sentinel.admin_token`.
+async fn require_admin_token(
+    State(config): State<Arc<Config>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    match check_admin_token(&config, &request) {
+        Some(rejection) => rejection,
+        None => next.run(request).await,
+    }
+}
+
+async fn list_api_keys() -> impl IntoResponse {
+    let api_keys = get_all_api_keys().await.and_then(|api_keys| {
+        api_keys
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<Value>>>()
+    });
+    match api_keys {
+        Ok(api_keys) => (StatusCode::OK, Json(json!(api_keys))),
+        Err(e) => {
+            error!(error = %e, "Failed to list API keys");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateApiKeyRequest {
+    functions: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    expires_at: Option<Timestamp>,
+}
+
+async fn issue_api_key(
+    State(config): State<Arc<Config>>,
+    Json(request): Json<CreateApiKeyRequest>,
+) -> impl IntoResponse {
+    if request.functions.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "At least one function is required" })),
+        );
+    }
+    if let Some(unknown) = request
+        .functions
+        .iter()
+        .find(|f| *f != ANY_FUNCTION && !config.functions.contains_key(*f))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": format!("Function not found: {}", unknown) })),
+        );
+    }
+
+    let created = create_api_key(request.functions, request.labels, request.expires_at)
+        .await
+        .and_then(|(api_key, secret)| {
+            let mut value: Value = api_key.try_into()?;
+            value["key"] = json!(secret);
+            Ok(value)
+        });
+    match created {
+        Ok(value) => (StatusCode::CREATED, Json(value)),
+        Err(e) => {
+            error!(error = %e, "Failed to create API key");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+        }
+    }
+}
+
+async fn delete_api_key(Path(id): Path<String>) -> impl IntoResponse {
+    match revoke_api_key(&id).await {
+        Ok(true) => (
+            StatusCode::OK,
+            Json(json!({ "message": "API key revoked" })),
+        ),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("API key not found: {}", id) })),
+        ),
+        Err(e) => {
+            error!(error = %e, "Failed to revoke API key");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct InvokeAsyncQuery {
+    path: Option<String>,
+    method: Option<String>,
+    callback_url: Option<String>,
+}
+
+fn bad_request(message: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+}
+
+/// Delivers one event to a queue, stream or timer function, with the source its trigger would
+/// give it, and returns the function's response. Failures are not retried or dead-lettered.
+async fn invoke_event_function(
+    State(config): State<Arc<Config>>,
+    Path(function_name): Path<String>,
+    request: Request<Body>,
+) -> Response {
+    let Some(function) = config.functions.get(&function_name) else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("Function not found: {}", function_name) })),
+        )
+            .into_response();
+    };
+    let source = match &function.trigger {
+        Trigger::Http(_) => {
+            return bad_request(format!(
+                "Function {} has an http trigger, call its route instead",
+                function_name
+            ));
+        }
+        Trigger::Queue { queue, .. } => format!("queue:{}", queue),
+        Trigger::Stream { stream, .. } => format!("stream:{}", stream),
+        Trigger::Timer { .. } => format!("timer:{}", function_name),
+    };
+    let event = match event_from_request(source, request).await {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    info!(function_name = %function_name, event_id = %event.id, "Invoking event function");
+
+    let EventResponse {
+        response,
+        coldstarted,
+    } = match send_event(&config, &function_name, &event, 1).await {
+        Ok(response) => response,
+        Err(DeliveryError::CircuitOpen(status)) => return circuit_open_response(&status),
+        Err(e) => {
+            let status = match &e {
+                DeliveryError::ColdStartFailed(CoupeError::Healthcheck(_)) => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                DeliveryError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                DeliveryError::Unreachable(_) => StatusCode::BAD_GATEWAY,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return (status, Json(json!({ "error": e.to_string() }))).into_response();
+        }
+    };
+
+    let status = response.status();
+    let mut headers = response.headers().clone();
+    let body = match response.bytes().await {
+        Ok(body) => body,
+        Err(e) => {
+            return (
+                StatusCode::BAD_GATEWAY,
+                Json(json!({ "error": format!("Failed to read function response: {}", e) })),
+            )
+                .into_response();
+        }
+    };
+    headers.remove(TRANSFER_ENCODING);
+    headers.remove(CONNECTION);
+    if let Ok(event_id) = HeaderValue::from_str(&event.id) {
+        headers.insert(EVENT_ID_HEADER, event_id);
+    }
+    if coldstarted {
+        headers.insert(COLD_START_HEADER, HeaderValue::from_static("true"));
+    }
+    (status, headers, body).into_response()
+}
+
+/// Passes the request through the trigger's auth and rate limits, then saves it and runs it in
+/// the background. The function receives it at `path`, defaulting to the trigger path, with the
+/// trigger's first method or `POST`.
+async fn invoke_async(
+    State((config, invoker)): State<(Arc<Config>, Arc<AsyncInvoker>)>,
+    Path(function_name): Path<String>,
+    Query(query): Query<InvokeAsyncQuery>,
+    request: Request<Body>,
+) -> Response {
+    let Some(trigger) = config
+        .functions
+        .get(&function_name)
+        .and_then(|f| f.trigger.as_http())
+        .filter(|_| invoker.has_function(&function_name))
+    else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("HTTP function not found: {}", function_name) })),
+        )
+            .into_response();
+    };
+
+    let path = match query.path {
+        Some(path) if path.starts_with('/') => path,
+        Some(path) => return bad_request(format!("Path {} must start with `/`", path)),
+        None if trigger.path == FALLBACK_PATH => "/".to_string(),
+        None if trigger.path.contains('{') => {
+            return bad_request(format!(
+                "Function {} has path params, so `path` is required",
+                function_name
+            ));
+        }
+        None => trigger.path.clone(),
+    };
+    let route_path = path.split('?').next().unwrap_or_default();
+    if !trigger_matches_path(trigger, route_path) {
+        return bad_request(format!(
+            "Path {} is not routed to function {}",
+            route_path, function_name
+        ));
+    }
+
+    let method = match query.method {
+        Some(method) => match Method::from_bytes(method.to_uppercase().as_bytes()) {
+            Ok(method) => method,
+            Err(_) => return bad_request(format!("Invalid method {}", method)),
+        },
+        None => trigger
+            .methods()
+            .iter()
+            .find_map(to_method)
+            .unwrap_or(Method::POST),
+    };
+
+    if let Some(callback_url) = &query.callback_url
+        && let Err(e) = invoker.check_callback_url(callback_url)
+    {
+        return bad_request(e.to_string());
+    }
+
+    let client_addr = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let limit = trigger.max_body_size.unwrap_or(DEFAULT_BUFFERED_BODY_SIZE);
+    let (parts, body) = match read_body(request, limit).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    let mut request = Request::new(Body::from(body));
+    *request.method_mut() = method;
+    match path.parse() {
+        Ok(uri) => *request.uri_mut() = uri,
+        Err(_) => return bad_request(format!("Invalid path {}", path)),
+    }
+    *request.headers_mut() = parts.headers;
+    if let Some(addr) = client_addr {
+        request.extensions_mut().insert(ConnectInfo(addr));
+    }
+    // The trigger's auth and rate limits run before anything is saved.
+    let admitted = match invoker.admit(&function_name, request).await {
+        Ok(admitted) => admitted,
+        Err(rejection) => return rejection,
+    };
+
+    let headers = admitted
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            !matches!(
+                name.as_str(),
+                "host" | "content-length" | "connection" | "transfer-encoding"
+            )
+        })
+        .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+        .collect();
+    let path_and_query = admitted
+        .uri
+        .path_and_query()
+        .map_or(path, |path_and_query| path_and_query.to_string());
+
+    let invocation = Invocation::new(
+        function_name,
+        StoredRequest::new(
+            admitted.method.to_string(),
+            path_and_query,
+            headers,
+            trigger.auth.as_ref(),
+            admitted.body.to_vec(),
+        ),
+        query.callback_url,
+    );
+    let id = invocation.id.clone();
+    if let Err(e) = save_invocation(invocation.clone()).await {
+        error!(error = %e, "Failed to save invocation");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({ "error": e.to_string() })),
+        )
+            .into_response();
+    }
+    invoker.spawn(invocation, client_addr);
+
+    let status_url = format!("/system/invocations/{}", id);
+    (
+        StatusCode::ACCEPTED,
+        [(LOCATION, status_url.clone())],
+        Json(json!({ "id": id, "status": "pending", "status_url": status_url })),
+    )
+        .into_response()
+}
+
+async fn get_invocation_status(Path(id): Path<String>) -> impl IntoResponse {
+    let invocation = get_invocation(&id)
+        .await
+        .and_then(|invocation| invocation.map(TryInto::<Value>::try_into).transpose());
+    match invocation {
+        Ok(Some(invocation)) => (StatusCode::OK, Json(invocation)),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("Invocation not found: {}", id) })),
+        ),
+        Err(e) => {
+            error!(error = %e, "Failed to get invocation");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DeadLetterQuery {
+    function: Option<String>,
+}
+
+fn internal_error(message: &str, e: CoupeError) -> Response {
+    error!(error = %e, "{}", message);
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": e.to_string() })),
+    )
+        .into_response()
+}
+
+fn dead_letter_not_found(id: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({ "error": format!("Dead letter not found: {}", id) })),
+    )
+        .into_response()
+}
+
+async fn list_dead_letters(Query(query): Query<DeadLetterQuery>) -> Response {
+    let entries = get_dead_letters(query.function.as_deref())
+        .await
+        .and_then(|entries| {
+            entries
+                .iter()
+                .map(|e| e.summary())
+                .collect::<Result<Vec<_>>>()
+        });
+    match entries {
+        Ok(entries) => Json(entries).into_response(),
+        Err(e) => internal_error("Failed to list dead letters", e),
+    }
+}
+
+async fn inspect_dead_letter(Path(id): Path<String>) -> Response {
+    let entry = get_dead_letter(&id)
+        .await
+        .and_then(|entry| entry.map(TryInto::<Value>::try_into).transpose());
+    match entry {
+        Ok(Some(entry)) => Json(entry).into_response(),
+        Ok(None) => dead_letter_not_found(&id),
+        Err(e) => internal_error("Failed to get dead letter", e),
+    }
+}
+
+/// Removes the event from the DLQ and delivers it again in the background. If it fails
+/// again it goes through the function's retries and `dead_letter` target as usual.
+async fn replay_dead_letter(State(config): State<Arc<Config>>, Path(id): Path<String>) -> Response {
+    match take_dead_letter(&id).await {
+        Ok(Some(entry)) => {
+            info!(id = %id, function_name = %entry.function_name, "Replaying dead letter");
+            let event_id = entry.event.id.clone();
+            spawn(deliver_event(config, entry.function_name, entry.event));
+            (
+                StatusCode::ACCEPTED,
+                Json(json!({ "message": "Event replayed", "event_id": event_id })),
+            )
+                .into_response()
+        }
+        Ok(None) => dead_letter_not_found(&id),
+        Err(e) => internal_error("Failed to replay dead letter", e),
+    }
+}
+
+async fn delete_dead_letter(Path(id): Path<String>) -> Response {
+    match take_dead_letter(&id).await {
+        Ok(Some(_)) => Json(json!({ "message": "Dead letter deleted" })).into_response(),
+        Ok(None) => dead_letter_not_found(&id),
+        Err(e) => internal_error("Failed to delete dead letter", e),
+    }
+}
+
+async fn purge_dlq(Query(query): Query<DeadLetterQuery>) -> Response {
+    match purge_dead_letters(query.function.as_deref()).await {
+        Ok(count) => Json(json!({ "purged": count })).into_response(),
+        Err(e) => internal_error("Failed to purge dead letters", e),
+    }
+}
+
+/// Routers built from one config. Requests keep the routers they started with, so new ones
+/// can be swapped in without disturbing them.
+pub struct ApiRouters {
+    pub config: Arc<Config>,
+    router: Router,
+    internal_router: Router,
+    invoker: Arc<AsyncInvoker>,
+}
+
+/// Validates the config and builds the public and internal routers from it.
+pub fn build_api_routers(config: Arc<Config>) -> Result<ApiRouters> {
+    validate_event_functions(&config)?;
+    validate_function_versions(&config)?;
+    validate_function_volumes(&config)?;
+    validate_placement(&config)?;
+
+    let admin_router = Router::new()
+        .route("/system/api-keys", get(list_api_keys).post(issue_api_key))
+        .route("/system/circuits", get(list_circuits))
+        .route("/system/api-keys/{id}", delete(delete_api_key))
+        .route("/system/dlq", get(list_dead_letters).delete(purge_dlq))
+        .route(
+            "/system/dlq/{id}",
+            get(inspect_dead_letter).delete(delete_dead_letter),
+        )
+        .route("/system/dlq/{id}/replay", post(replay_dead_letter))
+        .route(
+            "/system/functions/{name}/invoke",
+            post(invoke_event_function),
+        )
+        .route_layer(from_fn_with_state(Arc::clone(&config), require_admin_token));
     let mut router = Router::new()
         .route("/health", get(health))
         .route("/system/sessions", get(list_sessions))
+        .route("/system/metrics", get(get_metrics))
         .route("/system/config", get(get_config))
         .route("/system/functions/start", post(start_function))
+        .merge(admin_router)
         .with_state(Arc::clone(&config));
-    let function_router = build_function_router(Arc::clone(&config))?;
-    router = router.fallback_service(function_router);
-    let port = config.sentinel_port();
-    let listener = TcpListener::bind(format!("0.0.0.0:{}", port))
+
+    let method_routers = build_method_routers(Arc::clone(&config))?;
+    let (guards, invocation_routers) = build_invocation_routers(Arc::clone(&config))?;
+    let callback_hosts = config
+        .sentinel
+        .as_ref()
+        .map(|sentinel| sentinel.callback_hosts.clone())
+        .unwrap_or_default();
+    let invoker = Arc::new(AsyncInvoker::new(
+        invocation_routers,
+        guards,
+        callback_hosts,
+    ));
+    let invocation_router = Router::new()
+        .route("/system/functions/{name}/invoke-async", post(invoke_async))
+        .route("/system/invocations/{id}", get(get_invocation_status))
+        .with_state((Arc::clone(&config), Arc::clone(&invoker)));
+
+    let function_router = build_function_router(&config, &method_routers);
+    router = router
+        .merge(invocation_router)
+        .fallback_service(function_router);
+    let internal_router = InternalApi::new(
+        Arc::clone(&config),
+        build_internal_routers(Arc::clone(&config))?,
+    )
+    .router();
+
+    Ok(ApiRouters {
+        config,
+        router,
+        internal_router,
+        invoker,
+    })
+}
+
+/// Reloads the config file, as the sentinel does when the file changes.
+async fn reload_config(State(sentinel): State<Arc<Sentinel>>, request: Request<Body>) -> Response {
+    if let Some(rejection) = check_admin_token(&sentinel.config(), &request) {
+        return rejection;
+    }
+    match sentinel.reload().await {
+        Ok(changes) => Json(json!({ "reloaded": true, "changes": changes })).into_response(),
+        Err(e @ (CoupeError::Io(_) | CoupeError::Database(_) | CoupeError::Docker(_))) => {
+            internal_error("Failed to reload config", e)
+        }
+        Err(e) => bad_request(e.to_string()),
+    }
+}
+
+async fn serve_current(State(sentinel): State<Arc<Sentinel>>, request: Request<Body>) -> Response {
+    let mut router = sentinel.routers().router.clone();
+    let Ok(response) = router.call(request).await;
+    response
+}
+
+async fn serve_current_internal(
+    State(sentinel): State<Arc<Sentinel>>,
+    request: Request<Body>,
+) -> Response {
+    let mut router = sentinel.routers().internal_router.clone();
+    let Ok(response) = router.call(request).await;
+    response
+}
+
+/// Serves the sentinel's public and internal APIs, each request with the routers current when
+/// it arrives.
+pub async fn serve_api(sentinel: Arc<Sentinel>) -> Result<()> {
+    let routers = sentinel.routers();
+    spawn(watch_invocations(Arc::clone(&routers.invoker)));
+    let router = Router::new()
+        .route("/system/config/reload", post(reload_config))
+        .fallback(serve_current)
+        .with_state(Arc::clone(&sentinel));
+    let internal_router = Router::new()
+        .fallback(serve_current_internal)
+        .with_state(sentinel);
+
+    // Under `coupe-cli dev` the sentinel runs on the developer's machine, so it is only
+    // reachable from it, and from containers through the Docker gateway.
+    let address = match routers.config.dev {
+        Some(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+    let port = routers.config.sentinel_port();
+    let listener = TcpListener::bind((address, port))
         .await
-        .map_err(|e| CoupeError::Io(e))?;
-    info!(port = port, "Sentinel API server listening");
-    serve(listener, router)
+        .map_err(CoupeError::Io)?;
+    let internal_port = routers.config.sentinel_internal_port();
+    let internal_listener = TcpListener::bind((address, internal_port))
         .await
-        .map_err(|e| CoupeError::Io(e))?;
+        .map_err(CoupeError::Io)?;
+    let gateway = routers.config.dev.as_ref().and_then(|dev| dev.gateway);
+    let gateway_listener = match gateway {
+        Some(gateway) => match TcpListener::bind((gateway, internal_port)).await {
+            Ok(listener) => Some(listener),
+            // Docker Desktop's gateway is in its VM, which forwards to the loopback interface.
+            Err(e) => {
+                debug!(gateway = %gateway, error = %e, "Not listening on the Docker gateway");
+                None
+            }
+        },
+        None => None,
+    };
+    info!(
+        address = %address,
+        port = port,
+        internal_port = internal_port,
+        "Sentinel API server listening"
+    );
+    let gateway_internal_router = internal_router.clone();
+    try_join!(
+        serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .into_future(),
+        serve(
+            internal_listener,
+            internal_router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .into_future(),
+        async move {
+            match gateway_listener {
+                Some(listener) => {
+                    serve(
+                        listener,
+                        gateway_internal_router.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        },
+    )
+    .map_err(CoupeError::Io)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_service::Service;
+
+    #[test]
+    fn redacted_configs_keep_their_keys() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+name: test
+sentinel:
+  admin_token: admin
+functions:
+  hooks:
+    image: hooks
+    trigger:
+      type: http
+      path: /hooks
+      auth:
+        type: webhook
+        secret: whsec
+        signature_header: x-signature
+"#,
+        )
+        .unwrap();
+        let mut value = serde_json::to_value(&config).unwrap();
+        redact_config(&mut value);
+
+        assert_eq!(value["sentinel"]["admin_token"], REDACTED);
+        assert!(!value.to_string().contains("whsec"));
+        let redacted: Config = serde_json::from_value(value).unwrap();
+        assert_eq!(redacted.name, "test");
+    }
+
+    #[tokio::test]
+    async fn async_invocations_are_authenticated_before_they_are_saved() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+name: test
+functions:
+  hooks:
+    image: hooks
+    trigger:
+      type: http
+      path: /hooks
+      auth:
+        type: webhook
+        secret: whsec
+        signature_header: x-signature
+"#,
+        )
+        .unwrap();
+        let mut router = build_api_routers(Arc::new(config)).unwrap().router;
+
+        let request = Request::post("/system/functions/hooks/invoke-async")
+            .header("x-signature", "forged")
+            .body(Body::from("{}"))
+            .unwrap();
+        let Ok(response) = router.call(request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().get(LOCATION).is_none());
+    }
+
+    #[tokio::test]
+    async fn circuits_need_the_admin_token() {
+        let config: Config = serde_yaml::from_str(
+            "name: test\nsentinel: { admin_token: admin }\nfunctions:\n  a: { image: a, trigger: { type: http, path: /a } }",
+        )
+        .unwrap();
+        let mut router = build_api_routers(Arc::new(config)).unwrap().router;
+
+        let request = Request::get("/system/circuits")
+            .body(Body::empty())
+            .unwrap();
+        let Ok(response) = router.call(request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::get("/system/circuits")
+            .header(AUTHORIZATION, "Bearer admin")
+            .body(Body::empty())
+            .unwrap();
+        let Ok(response) = router.call(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{DB, VerifiedClient, hash_key};
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    http::{HeaderName, HeaderValue, Request, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use bincode::{deserialize, serialize};
+use coupe::{CoupeError, DEFAULT_API_KEY_HEADER, Result};
+use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, LazyLock},
+};
+use tracing::{info, instrument, warn};
+
+static API_KEY_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("api_keys", PartitionCreateOptions::default())
+        .expect("Failed to open api keys tree")
+});
+
+/// Key scope that grants access to every function.
+pub const ANY_FUNCTION: &str = "*";
+
+/// Id of the key that authenticated the request, forwarded to the function.
+pub const API_KEY_ID_HEADER: &str = "x-coupe-api-key-id";
+
+const API_KEY_PREFIX: &str = "coupe_";
+
+/// An issued API key. Only the SHA-256 hash of the secret is stored, keyed by that hash.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub functions: Vec<String>,
+    pub labels: BTreeMap<String, String>,
+    /**
+     * Nanoseconds between the UNIX epoch and when the key was created.
+     */
+    pub created_at: i128,
+    /**
+     * Nanoseconds between the UNIX epoch and when the key expires.
+     */
+    pub expires_at: Option<i128>,
+}
+
+impl ApiKey {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at < Timestamp::now().as_nanosecond())
+    }
+
+    pub fn allows(&self, function_name: &str) -> bool {
+        self.functions
+            .iter()
+            .any(|f| f == ANY_FUNCTION || f == function_name)
+    }
+}
+
+fn format_timestamp(nanos: i128) -> Result<String> {
+    Ok(Timestamp::from_nanosecond(nanos)
+        .map_err(|e| CoupeError::DateTime(e.to_string()))?
+        .to_string())
+}
+
+impl TryInto<Value> for ApiKey {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Value> {
+        Ok(json!({
+            "id": self.id,
+            "functions": self.functions,
+            "labels": self.labels,
+            "created_at": format_timestamp(self.created_at)?,
+            "expires_at": self.expires_at.map(format_timestamp).transpose()?,
+            "expired": self.is_expired(),
+        }))
+    }
+}
+
+impl TryFrom<&[u8]> for ApiKey {
+    type Error = CoupeError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        deserialize(value).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+impl TryInto<Vec<u8>> for ApiKey {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Vec<u8>> {
+        serialize(&self).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+/// Issues a new key and returns it with its secret, which is not stored and cannot be
+/// retrieved again.
+#[instrument(skip(labels))]
+pub async fn create_api_key(
+    functions: Vec<String>,
+    labels: BTreeMap<String, String>,
+    expires_at: Option<Timestamp>,
+) -> Result<(ApiKey, String)> {
+    let id = hex_id(rand::random::<[u8; 8]>());
+    let secret = format!(
+        "{}{}_{}",
+        API_KEY_PREFIX,
+        id,
+        URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+    );
+    let api_key = ApiKey {
+        id,
+        functions,
+        labels,
+        created_at: Timestamp::now().as_nanosecond(),
+        expires_at: expires_at.map(|t| t.as_nanosecond()),
+    };
+
+    let mut tx = DB.write_tx();
+    let value: Vec<u8> = api_key.clone().try_into()?;
+    tx.insert(&API_KEY_STORE, hash_key(&secret), value);
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    info!(id = %api_key.id, functions = ?api_key.functions, "API key created");
+    Ok((api_key, secret))
+}
+
+fn hex_id(bytes: [u8; 8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[instrument]
+pub async fn get_all_api_keys() -> Result<Vec<ApiKey>> {
+    let tx = DB.read_tx();
+    let mut api_keys = Vec::new();
+    for item in tx.iter(&API_KEY_STORE) {
+        let (_, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        api_keys.push(ApiKey::try_from(value.as_ref())?);
+    }
+    api_keys.sort_by_key(|k| k.created_at);
+    Ok(api_keys)
+}
+
+/// Deletes the key with the given id. Returns whether a key was found.
+#[instrument]
+pub async fn revoke_api_key(id: &str) -> Result<bool> {
+    let mut tx = DB.write_tx();
+    let mut found = None;
+    for item in tx.iter(&API_KEY_STORE) {
+        let (hash, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        if ApiKey::try_from(value.as_ref())?.id == id {
+            found = Some(hash);
+            break;
+        }
+    }
+    let Some(hash) = found else {
+        return Ok(false);
+    };
+    tx.remove(&API_KEY_STORE, hash);
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    info!(id = %id, "API key revoked");
+    Ok(true)
+}
+
+pub async fn find_api_key(secret: &str) -> Result<Option<ApiKey>> {
+    let tx = DB.read_tx();
+    tx.get(&API_KEY_STORE, hash_key(secret))
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+        .map(|value| ApiKey::try_from(value.as_ref()))
+        .transpose()
+}
+
+/// Where a trigger reads its API key from, parsed once when the router is built.
+pub struct ApiKeyAuth {
+    pub function_name: String,
+    pub header: Option<HeaderName>,
+    pub query: Option<String>,
+}
+
+impl ApiKeyAuth {
+    pub fn new(function_name: &str, header: Option<&str>, query: Option<&str>) -> Result<Self> {
+        let header = match (header, query) {
+            (None, Some(_)) => None,
+            (header, _) => Some(
+                HeaderName::try_from(header.unwrap_or(DEFAULT_API_KEY_HEADER)).map_err(|e| {
+                    CoupeError::Config(format!(
+                        "Invalid API key header for function {}: {}",
+                        function_name, e
+                    ))
+                })?,
+            ),
+        };
+        Ok(Self {
+            function_name: function_name.to_string(),
+            header,
+            query: query.map(str::to_string),
+        })
+    }
+
+    fn query_pairs<'a>(&self, uri: &'a Uri) -> impl Iterator<Item = (&'a str, &'a str)> {
+        uri.query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+    }
+
+    fn secret(&self, request: &Request<Body>) -> Option<String> {
+        let from_header = self.header.as_ref().and_then(|header| {
+            request
+                .headers()
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+        from_header.or_else(|| {
+            let query = self.query.as_deref()?;
+            self.query_pairs(request.uri())
+                .find(|(name, _)| *name == query)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    /// Removes the key from the request so the function never sees it. Other query pairs are
+    /// kept exactly as sent.
+    fn strip_secret(&self, request: &mut Request<Body>) {
+        if let Some(header) = &self.header {
+            request.headers_mut().remove(header);
+        }
+        let Some(query) = self.query.as_deref() else {
+            return;
+        };
+        let remaining: Vec<&str> = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter(|pair| {
+                !pair.is_empty() && pair.split_once('=').map_or(*pair, |(name, _)| name) != query
+            })
+            .collect();
+        let path = request.uri().path();
+        let path_and_query = if remaining.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}?{}", path, remaining.join("&"))
+        };
+        let mut parts = request.uri().clone().into_parts();
+        if let Ok(path_and_query) = path_and_query.parse() {
+            parts.path_and_query = Some(path_and_query);
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *request.uri_mut() = uri;
+            }
+        }
+    }
+}
+
+fn unauthorized(message: &str) -> Response {
+    (StatusCode::UNAUTHORIZED, Json(json!({ "error": message }))).into_response()
+}
+
+/// Middleware that rejects requests without a valid, unexpired key scoped to the function.
+pub async fn require_api_key(
+    State(auth): State<Arc<ApiKeyAuth>>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(secret) = auth.secret(&request) else {
+        return unauthorized("Missing API key");
+    };
+    let api_key = match find_api_key(&secret).await {
+        Ok(api_key) => api_key,
+        Err(e) => {
+            warn!(function_name = %auth.function_name, error = %e, "Failed to look up API key");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": "Failed to verify API key" })),
+            )
+                .into_response();
+        }
+    };
+    let api_key = match api_key {
+        Some(api_key) if api_key.is_expired() => return unauthorized("API key expired"),
+        Some(api_key) if !api_key.allows(&auth.function_name) => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "error": "API key is not allowed to invoke this function" })),
+            )
+                .into_response();
+        }
+        Some(api_key) => api_key,
+        None => return unauthorized("Invalid API key"),
+    };
+
+    auth.strip_secret(&mut request);
+    if let Ok(id) = HeaderValue::from_str(&api_key.id) {
+        request.headers_mut().insert(API_KEY_ID_HEADER, id);
+    }
+    request
+        .extensions_mut()
+        .insert(VerifiedClient::ApiKey(api_key.id.clone()));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, key_header: Option<&str>) -> Request<Body> {
+        let mut request = Request::get(uri);
+        if let Some(key) = key_header {
+            request = request.header(DEFAULT_API_KEY_HEADER, key);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    fn api_key(functions: &[&str], expires_at: Option<i128>) -> ApiKey {
+        ApiKey {
+            id: "k1".to_string(),
+            functions: functions.iter().map(|f| f.to_string()).collect(),
+            labels: BTreeMap::new(),
+            created_at: 0,
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn keys_are_read_from_the_header_then_the_query() {
+        let header = ApiKeyAuth::new("api", None, None).unwrap();
+        assert_eq!(header.header.as_ref().unwrap(), DEFAULT_API_KEY_HEADER);
+        assert_eq!(
+            header.secret(&request("/api", Some("h"))).as_deref(),
+            Some("h")
+        );
+        assert_eq!(header.secret(&request("/api?key=q", None)), None);
+
+        let query = ApiKeyAuth::new("api", None, Some("key")).unwrap();
+        assert!(query.header.is_none());
+        assert_eq!(
+            query
+                .secret(&request("/api?a=1&key=q", Some("h")))
+                .as_deref(),
+            Some("q")
+        );
+
+        let both = ApiKeyAuth::new("api", Some("x-key"), Some("key")).unwrap();
+        let mut with_header = request("/api?key=q", None);
+        with_header
+            .headers_mut()
+            .insert("x-key", HeaderValue::from_static("h"));
+        assert_eq!(both.secret(&with_header).as_deref(), Some("h"));
+        assert_eq!(
+            both.secret(&request("/api?key=q", None)).as_deref(),
+            Some("q")
+        );
+
+        assert!(ApiKeyAuth::new("api", Some("bad header"), None).is_err());
+    }
+
+    #[test]
+    fn stripping_keeps_other_query_pairs_as_sent() {
+        let auth = ApiKeyAuth::new("api", None, Some("key")).unwrap();
+        let mut stripped = request("/api?flag&key=secret&q=a%20b&x=&key", None);
+        auth.strip_secret(&mut stripped);
+        assert_eq!(stripped.uri(), "/api?flag&q=a%20b&x=");
+
+        let mut only_key = request("/api?key=secret", None);
+        auth.strip_secret(&mut only_key);
+        assert_eq!(only_key.uri(), "/api");
+
+        let header = ApiKeyAuth::new("api", None, None).unwrap();
+        let mut with_header = request("/api?key=kept", Some("secret"));
+        header.strip_secret(&mut with_header);
+        assert!(with_header.headers().get(DEFAULT_API_KEY_HEADER).is_none());
+        assert_eq!(with_header.uri(), "/api?key=kept");
+    }
+
+    #[test]
+    fn keys_expire_and_are_scoped_to_functions() {
+        let now = Timestamp::now().as_nanosecond();
+        assert!(!api_key(&["api"], None).is_expired());
+        assert!(!api_key(&["api"], Some(now + 60_000_000_000)).is_expired());
+        assert!(api_key(&["api"], Some(now - 1)).is_expired());
+
+        let scoped = api_key(&["api", "billing"], None);
+        assert!(scoped.allows("api"));
+        assert!(scoped.allows("billing"));
+        assert!(!scoped.allows("admin"));
+        assert!(api_key(&[ANY_FUNCTION], None).allows("admin"));
+        assert!(!api_key(&[], None).allows("api"));
+    }
+}
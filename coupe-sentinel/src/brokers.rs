@@ -0,0 +1,389 @@
+use crate::{
+    DEAD_LETTER_SOURCE_PREFIX, EVENT_ID_HEADER, EVENT_SOURCE_HEADER, Event, deliver_event,
+};
+use async_nats::{
+    HeaderMap,
+    jetstream::{self, AckKind, consumer::pull},
+};
+use coupe::{Broker, Config, CoupeError, Result, Stream};
+use dashmap::DashMap;
+use futures::StreamExt;
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::{pin, select, spawn, time::interval};
+use tracing::{debug, info};
+
+/// Connections by server URL, shared by every broker and config that uses the server. The
+/// client reconnects on its own when the connection drops.
+static NATS_CLIENTS: LazyLock<DashMap<String, async_nats::Client>> = LazyLock::new(DashMap::new);
+
+/// How often a stream event still being handled is reported to the server, so it is not
+/// redelivered to another consumer meanwhile.
+const ACK_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+const NATS_MSG_ID_HEADER: &str = "Nats-Msg-Id";
+
+fn broker_error(e: impl std::fmt::Display) -> CoupeError {
+    CoupeError::Broker(e.to_string())
+}
+
+async fn nats_client(config: &Config, broker_name: &str) -> Result<async_nats::Client> {
+    let Some(Broker::Nats { connection }) = config
+        .brokers
+        .as_ref()
+        .and_then(|brokers| brokers.get(broker_name))
+    else {
+        return Err(CoupeError::Config(format!(
+            "Broker not found: {}",
+            broker_name
+        )));
+    };
+    if let Some(client) = NATS_CLIENTS.get(connection) {
+        return Ok(client.clone());
+    }
+    let client = async_nats::connect(connection.as_str())
+        .await
+        .map_err(|e| CoupeError::Broker(format!("Failed to connect to {}: {}", broker_name, e)))?;
+    info!(broker = %broker_name, "Connected to broker");
+    Ok(NATS_CLIENTS
+        .entry(connection.clone())
+        .or_insert(client)
+        .clone())
+}
+
+fn event_headers(event: &Event) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(EVENT_ID_HEADER, event.id.as_str());
+    headers.insert(EVENT_SOURCE_HEADER, event.source.as_str());
+    // Lets JetStream drop a publish repeated after a lost ack.
+    headers.insert(NATS_MSG_ID_HEADER, event.id.as_str());
+    if let Some(content_type) = &event.content_type {
+        headers.insert("content-type", content_type.as_str());
+    }
+    headers
+}
+
+/// An event from a broker message. Messages from other publishers get a new id and `source`;
+/// the source of dead-lettered events is kept so they are not forwarded again.
+fn event_from_message(source: String, headers: Option<&HeaderMap>, payload: &[u8]) -> Event {
+    let header = |name: &str| {
+        headers
+            .and_then(|headers| headers.get(name))
+            .map(|value| value.as_str().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let mut event = Event::new(source, payload.to_vec(), header("content-type"));
+    if let Some(id) = header(EVENT_ID_HEADER).or_else(|| header(NATS_MSG_ID_HEADER)) {
+        event.id = id;
+    }
+    if let Some(source) =
+        header(EVENT_SOURCE_HEADER).filter(|s| s.starts_with(DEAD_LETTER_SOURCE_PREFIX))
+    {
+        event.source = source;
+    }
+    event
+}
+
+/// Publishes an event to a queue's subject. Returns once the server has received it.
+pub async fn publish_queue_event(config: &Config, queue_name: &str, event: &Event) -> Result<()> {
+    let queue = config
+        .queues
+        .as_ref()
+        .and_then(|queues| queues.get(queue_name))
+        .ok_or(CoupeError::Config(format!(
+            "Queue not found: {}",
+            queue_name
+        )))?;
+    let client = nats_client(config, &queue.broker).await?;
+    client
+        .publish_with_headers(
+            queue.subject.clone(),
+            event_headers(event),
+            event.payload.clone().into(),
+        )
+        .await
+        .map_err(broker_error)?;
+    client.flush().await.map_err(broker_error)
+}
+
+/// Whether a subject matches a NATS subject pattern, where `*` matches one token and a final
+/// `>` one or more.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for pattern_token in pattern.split('.') {
+        match (pattern_token, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(token)) if !token.is_empty() => {}
+            (pattern_token, Some(token)) if pattern_token == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// Subject to publish a stream event on: `subject` when it is one of the stream's subjects,
+/// otherwise the stream's first subject without wildcards.
+fn stream_subject(stream: &Stream, subject: Option<&str>) -> Result<String> {
+    match subject {
+        Some(subject) if subject.contains(['*', '>']) => Err(CoupeError::InvalidInput(format!(
+            "Subject {} cannot contain wildcards",
+            subject
+        ))),
+        Some(subject) if stream.subjects.iter().any(|p| subject_matches(p, subject)) => {
+            Ok(subject.to_string())
+        }
+        Some(subject) => Err(CoupeError::InvalidInput(format!(
+            "Subject {} is not one of the subjects of stream {}",
+            subject, stream.stream
+        ))),
+        None => stream
+            .subjects
+            .iter()
+            .find(|subject| !subject.contains(['*', '>']))
+            .cloned()
+            .ok_or(CoupeError::InvalidInput(format!(
+                "Stream {} has only wildcard subjects, so a subject is required",
+                stream.stream
+            ))),
+    }
+}
+
+/// Publishes an event to a stream, on `subject` or the stream's first literal subject. Returns
+/// once JetStream has acknowledged storing it.
+pub async fn publish_stream_event(
+    config: &Config,
+    stream_name: &str,
+    subject: Option<&str>,
+    event: &Event,
+) -> Result<()> {
+    let stream = config
+        .streams
+        .as_ref()
+        .and_then(|streams| streams.get(stream_name))
+        .ok_or(CoupeError::Config(format!(
+            "Stream not found: {}",
+            stream_name
+        )))?;
+    let subject = stream_subject(stream, subject)?;
+    let client = nats_client(config, &stream.broker).await?;
+    jetstream::new(client)
+        .publish_with_headers(subject, event_headers(event), event.payload.clone().into())
+        .await
+        .map_err(broker_error)?
+        .await
+        .map_err(broker_error)?;
+    Ok(())
+}
+
+/// Name of the queue group a function subscribes with, so each event of a queue reaches one
+/// of its sentinels.
+fn queue_group(config: &Config, function_name: &str) -> String {
+    format!("coupe.{}.{}", config.name, function_name)
+}
+
+/// Durable JetStream consumer of a function. Names cannot hold `.`, `*`, `>` or whitespace.
+fn durable_name(prefix: &str, function_name: &str) -> String {
+    format!("{}-{}", prefix, function_name)
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Delivers the events of a queue to a function, each in the background. Core NATS keeps no
+/// events for a function that is not subscribed.
+pub async fn consume_queue(
+    config: Arc<Config>,
+    function_name: String,
+    queue_name: String,
+) -> Result<()> {
+    let queue = config
+        .queues
+        .as_ref()
+        .and_then(|queues| queues.get(&queue_name))
+        .ok_or(CoupeError::Config(format!(
+            "Queue not found: {}",
+            queue_name
+        )))?;
+    let client = nats_client(&config, &queue.broker).await?;
+    let mut subscriber = client
+        .queue_subscribe(queue.subject.clone(), queue_group(&config, &function_name))
+        .await
+        .map_err(broker_error)?;
+    info!(function_name = %function_name, queue = %queue_name, "Consuming queue");
+
+    while let Some(message) = subscriber.next().await {
+        let event = event_from_message(
+            format!("queue:{}", queue_name),
+            message.headers.as_ref(),
+            &message.payload,
+        );
+        debug!(function_name = %function_name, event_id = %event.id, "Queue event received");
+        spawn(deliver_event(
+            Arc::clone(&config),
+            function_name.clone(),
+            event,
+        ));
+    }
+    Err(CoupeError::Broker(format!(
+        "Subscription to queue {} closed",
+        queue_name
+    )))
+}
+
+/// Delivers the events of a stream to a function in order, through a durable consumer that
+/// resumes where it left off. Events are acknowledged once handled or dead-lettered.
+pub async fn consume_stream(
+    config: Arc<Config>,
+    function_name: String,
+    stream_name: String,
+) -> Result<()> {
+    let stream = config
+        .streams
+        .as_ref()
+        .and_then(|streams| streams.get(&stream_name))
+        .ok_or(CoupeError::Config(format!(
+            "Stream not found: {}",
+            stream_name
+        )))?;
+    let client = nats_client(&config, &stream.broker).await?;
+    let context = jetstream::new(client);
+    let jetstream_stream = context
+        .get_or_create_stream(jetstream::stream::Config {
+            name: stream.stream.clone(),
+            subjects: stream.subjects.clone(),
+            ..Default::default()
+        })
+        .await
+        .map_err(broker_error)?;
+    let durable = durable_name(
+        stream.consumer_name.as_deref().unwrap_or(&config.name),
+        &function_name,
+    );
+    let consumer = jetstream_stream
+        .get_or_create_consumer(
+            &durable,
+            pull::Config {
+                durable_name: Some(durable.clone()),
+                filter_subjects: stream.subjects.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(broker_error)?;
+    let mut messages = consumer.messages().await.map_err(broker_error)?;
+    info!(function_name = %function_name, stream = %stream_name, consumer = %durable, "Consuming stream");
+
+    while let Some(message) = messages.next().await {
+        let message = message.map_err(broker_error)?;
+        let event = event_from_message(
+            format!("stream:{}", stream_name),
+            message.headers.as_ref(),
+            &message.payload,
+        );
+        debug!(function_name = %function_name, event_id = %event.id, "Stream event received");
+
+        let delivery = deliver_event(Arc::clone(&config), function_name.clone(), event);
+        pin!(delivery);
+        let mut progress = interval(ACK_PROGRESS_INTERVAL);
+        progress.tick().await;
+        loop {
+            select! {
+                _ = &mut delivery => break,
+                _ = progress.tick() => {
+                    message.ack_with(AckKind::Progress).await.map_err(broker_error)?;
+                }
+            }
+        }
+        message.ack().await.map_err(broker_error)?;
+    }
+    Err(CoupeError::Broker(format!(
+        "Consumer of stream {} closed",
+        stream_name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_round_trip_through_headers() {
+        let event = Event::new(
+            "queue:orders".to_string(),
+            b"{}".to_vec(),
+            Some("application/json".to_string()),
+        );
+        let headers = event_headers(&event);
+        let received = event_from_message("queue:orders".to_string(), Some(&headers), b"{}");
+        assert_eq!(received.id, event.id);
+        assert_eq!(received.source, "queue:orders");
+        assert_eq!(received.content_type.as_deref(), Some("application/json"));
+        assert_eq!(received.payload, b"{}");
+    }
+
+    #[test]
+    fn messages_without_headers_get_an_id_and_the_consumer_source() {
+        let event = event_from_message("stream:orders".to_string(), None, b"payload");
+        assert_eq!(event.id.len(), 32);
+        assert_eq!(event.source, "stream:orders");
+        assert_eq!(event.content_type, None);
+    }
+
+    #[test]
+    fn only_dead_letter_sources_are_kept() {
+        let mut headers = HeaderMap::new();
+        headers.insert(EVENT_SOURCE_HEADER, "function:billing");
+        let event = event_from_message("queue:orders".to_string(), Some(&headers), b"");
+        assert_eq!(event.source, "queue:orders");
+
+        let source = format!("{}billing", DEAD_LETTER_SOURCE_PREFIX);
+        headers.insert(EVENT_SOURCE_HEADER, source.as_str());
+        let event = event_from_message("queue:orders".to_string(), Some(&headers), b"");
+        assert_eq!(event.source, source);
+    }
+
+    #[test]
+    fn subjects_match_nats_wildcards() {
+        assert!(subject_matches("orders.created", "orders.created"));
+        assert!(subject_matches("orders.*", "orders.created"));
+        assert!(!subject_matches("orders.*", "orders.created.eu"));
+        assert!(subject_matches("orders.>", "orders.created.eu"));
+        assert!(!subject_matches("orders.>", "orders"));
+        assert!(!subject_matches("orders.created", "orders"));
+        assert!(!subject_matches("orders.*", "payments.created"));
+    }
+
+    #[test]
+    fn stream_events_are_published_on_a_subject_of_the_stream() {
+        let stream: Stream = serde_yaml::from_str(
+            "broker: nats\nstream: orders\nsubjects: [\"orders.*.eu\", orders.created]",
+        )
+        .unwrap();
+        assert_eq!(stream_subject(&stream, None).unwrap(), "orders.created");
+        assert_eq!(
+            stream_subject(&stream, Some("orders.updated.eu")).unwrap(),
+            "orders.updated.eu"
+        );
+        assert!(stream_subject(&stream, Some("payments.created")).is_err());
+        assert!(stream_subject(&stream, Some("orders.*.eu")).is_err());
+
+        let wildcards: Stream =
+            serde_yaml::from_str("broker: nats\nstream: orders\nsubjects: [\"orders.>\"]").unwrap();
+        assert!(stream_subject(&wildcards, None).is_err());
+    }
+
+    #[test]
+    fn durable_names_are_valid_consumer_names() {
+        assert_eq!(
+            durable_name("my.stack", "orders worker"),
+            "my_stack-orders_worker"
+        );
+        assert_eq!(durable_name("shop", "billing"), "shop-billing");
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{end_session, function_client, recreate_session};
+use coupe::{
+    CircuitBreaker, CircuitRecovery, Config, ContainerDiagnostics, inspect_function_container,
+};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+use tokio::spawn;
+use tracing::{error, info, warn};
+
+const DIAGNOSTICS_LOG_LINES: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug, Clone)]
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    open_until: Option<Instant>,
+    trial_in_flight: bool,
+    last_error: Option<String>,
+    diagnostics: Option<ContainerDiagnostics>,
+}
+
+impl Default for Circuit {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            failures: 0,
+            open_until: None,
+            trial_in_flight: false,
+            last_error: None,
+            diagnostics: None,
+        }
+    }
+}
+
+impl Circuit {
+    fn retry_after(&self) -> Duration {
+        self.retry_after_at(Instant::now())
+    }
+
+    fn retry_after_at(&self, now: Instant) -> Duration {
+        self.open_until
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default()
+    }
+
+    /// Whether a request may pass at `now`, and if so whether it is the half-open trial. An
+    /// open circuit whose cooldown has elapsed admits a single trial.
+    fn admit(&mut self, now: Instant) -> Option<bool> {
+        match self.state {
+            CircuitState::Closed => Some(false),
+            CircuitState::Open if self.retry_after_at(now).is_zero() => {
+                self.state = CircuitState::HalfOpen;
+                self.trial_in_flight = true;
+                Some(true)
+            }
+            CircuitState::HalfOpen if !self.trial_in_flight => {
+                self.trial_in_flight = true;
+                Some(true)
+            }
+            _ => None,
+        }
+    }
+
+    /// Counts a failure, returning whether it opened the circuit: the threshold was reached
+    /// while closed, or the trial failed.
+    fn fail(&mut self, breaker: &CircuitBreaker, reason: String, now: Instant) -> bool {
+        self.failures += 1;
+        self.last_error = Some(reason);
+        self.trial_in_flight = false;
+
+        let should_open = match self.state {
+            CircuitState::Closed => self.failures >= breaker.failure_threshold(),
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if should_open {
+            self.state = CircuitState::Open;
+            self.open_until = Some(now + breaker.cooldown());
+        }
+        should_open
+    }
+}
+
+static CIRCUITS: LazyLock<DashMap<String, Circuit>> = LazyLock::new(DashMap::new);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitStatus {
+    pub function_name: String,
+    pub state: CircuitState,
+    pub failures: u32,
+    pub retry_after_secs: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container: Option<ContainerDiagnostics>,
+}
+
+impl CircuitStatus {
+    fn new(function_name: &str, circuit: &Circuit) -> Self {
+        Self {
+            function_name: function_name.to_string(),
+            state: circuit.state,
+            failures: circuit.failures,
+            retry_after_secs: circuit.retry_after().as_secs().max(1),
+            last_error: circuit.last_error.clone(),
+            container: circuit.diagnostics.clone(),
+        }
+    }
+}
+
+/// The function's circuit breaker, none unless the function opts in with an enabled
+/// `circuit_breaker`.
+fn circuit_breaker(config: &Config, function_name: &str) -> Option<CircuitBreaker> {
+    config
+        .function(function_name)
+        .and_then(|f| f.circuit_breaker.clone())
+        .filter(|breaker| breaker.enabled)
+}
+
+/// A request admitted through a function's circuit, settled with `success` or `failure`. A
+/// permit dropped unsettled, such as when the client disconnects or the request is rejected
+/// early, frees the half-open trial without counting for or against the function.
+pub struct CircuitPermit {
+    config: Arc<Config>,
+    function_name: String,
+    trial: bool,
+}
+
+impl CircuitPermit {
+    pub fn success(mut self) {
+        self.trial = false;
+        record_success(&self.function_name);
+    }
+
+    pub fn failure(mut self, reason: String) {
+        self.trial = false;
+        record_failure(Arc::clone(&self.config), &self.function_name, reason);
+    }
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        if !self.trial {
+            return;
+        }
+        if let Some(mut circuit) = CIRCUITS.get_mut(&self.function_name) {
+            circuit.trial_in_flight = false;
+        }
+    }
+}
+
+/// Admits a request to the function, or returns the circuit status when it should fail fast.
+/// An open circuit whose cooldown has elapsed admits a single half-open trial request.
+pub fn check_circuit(
+    config: &Arc<Config>,
+    function_name: &str,
+) -> Result<CircuitPermit, Box<CircuitStatus>> {
+    let mut permit = CircuitPermit {
+        config: Arc::clone(config),
+        function_name: function_name.to_string(),
+        trial: false,
+    };
+    if circuit_breaker(config, function_name).is_none() {
+        return Ok(permit);
+    }
+
+    let mut circuit = CIRCUITS.entry(function_name.to_string()).or_default();
+    let was_open = circuit.state == CircuitState::Open;
+    match circuit.admit(Instant::now()) {
+        Some(trial) => {
+            if was_open {
+                info!(function_name = %function_name, "Circuit half-open, admitting trial request");
+            }
+            permit.trial = trial;
+            Ok(permit)
+        }
+        None => Err(Box::new(CircuitStatus::new(function_name, &circuit))),
+    }
+}
+
+fn record_success(function_name: &str) {
+    if let Some(mut circuit) = CIRCUITS.get_mut(function_name) {
+        if circuit.state != CircuitState::Closed {
+            info!(function_name = %function_name, "Circuit closed");
+        }
+        *circuit = Circuit::default();
+    }
+}
+
+fn record_failure(config: Arc<Config>, function_name: &str, reason: String) {
+    let Some(breaker) = circuit_breaker(&config, function_name) else {
+        return;
+    };
+
+    let opened = CIRCUITS.entry(function_name.to_string()).or_default().fail(
+        &breaker,
+        reason,
+        Instant::now(),
+    );
+    if !opened {
+        return;
+    }
+
+    warn!(
+        function_name = %function_name,
+        cooldown_secs = breaker.cooldown().as_secs(),
+        "Circuit opened"
+    );
+
+    let function_name = function_name.to_string();
+    spawn(async move {
+        // Local processes under `coupe-cli dev` have no container to inspect or recover.
+        if config.is_local_function(&function_name) {
+            return;
+        }
+        let diagnostics = match function_client(&config, &function_name) {
+            Ok(client) => {
+                inspect_function_container(&client, &config, &function_name, DIAGNOSTICS_LOG_LINES)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match diagnostics {
+            Ok(diagnostics) => {
+                if let Some(mut circuit) = CIRCUITS.get_mut(&function_name) {
+                    circuit.diagnostics = Some(diagnostics);
+                }
+            }
+            Err(e) => {
+                error!(function_name = %function_name, error = %e, "Failed to inspect function container");
+            }
+        }
+
+        let result = match breaker.recovery {
+            CircuitRecovery::None => return,
+            CircuitRecovery::Restart => end_session(&config, function_name.clone()).await,
+            CircuitRecovery::Recreate => recreate_session(&config, function_name.clone()).await,
+        };
+        match result {
+            Ok(()) => info!(
+                function_name = %function_name,
+                recovery = ?breaker.recovery,
+                "Function container recovered"
+            ),
+            Err(e) => error!(
+                function_name = %function_name,
+                recovery = ?breaker.recovery,
+                error = %e,
+                "Failed to recover function container"
+            ),
+        }
+    });
+}
+
+pub fn get_all_circuits() -> Vec<CircuitStatus> {
+    CIRCUITS
+        .iter()
+        .map(|entry| CircuitStatus::new(entry.key(), entry.value()))
+        .collect()
+}
+
+/// Forgets a function's failures, such as when its definition changes.
+pub fn reset_circuit(function_name: &str) {
+    CIRCUITS.remove(function_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        serde_yaml::from_str("failure_threshold: 2\ncooldown: 30").unwrap()
+    }
+
+    #[test]
+    fn circuits_open_after_the_threshold() {
+        let breaker = breaker();
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+
+        assert_eq!(circuit.admit(now), Some(false));
+        assert!(!circuit.fail(&breaker, "boom".to_string(), now));
+        assert_eq!(circuit.state, CircuitState::Closed);
+        assert!(circuit.fail(&breaker, "boom".to_string(), now));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.retry_after_at(now), Duration::from_secs(30));
+
+        assert_eq!(circuit.admit(now), None);
+        assert_eq!(circuit.admit(now + Duration::from_secs(29)), None);
+        // Failures of requests admitted before the circuit opened do not extend the cooldown.
+        assert!(!circuit.fail(&breaker, "late".to_string(), now + Duration::from_secs(1)));
+        assert_eq!(circuit.retry_after_at(now), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn half_open_circuits_admit_one_trial() {
+        let breaker = breaker();
+        let now = Instant::now();
+        let mut circuit = Circuit::default();
+        circuit.fail(&breaker, "boom".to_string(), now);
+        circuit.fail(&breaker, "boom".to_string(), now);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(circuit.admit(later), Some(true));
+        assert_eq!(circuit.state, CircuitState::HalfOpen);
+        assert_eq!(circuit.admit(later), None);
+
+        // A trial dropped without a result lets the next request be the trial.
+        circuit.trial_in_flight = false;
+        assert_eq!(circuit.admit(later), Some(true));
+
+        assert!(circuit.fail(&breaker, "still failing".to_string(), later));
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert_eq!(circuit.admit(later), None);
+        assert_eq!(circuit.admit(later + Duration::from_secs(30)), Some(true));
+    }
+
+    #[test]
+    fn permits_settle_the_shared_circuit() {
+        let config: Arc<Config> = Arc::new(
+            serde_yaml::from_str(
+                "name: test\nfunctions:\n  flaky: { image: flaky, trigger: { type: http, path: /flaky }, circuit_breaker: { failure_threshold: 1, recovery: None } }\n  steady: { image: steady, trigger: { type: http, path: /steady } }",
+            )
+            .unwrap(),
+        );
+        let name = "flaky";
+        reset_circuit(name);
+        CIRCUITS.insert(
+            name.to_string(),
+            Circuit {
+                state: CircuitState::Open,
+                failures: 1,
+                open_until: Some(Instant::now()),
+                ..Default::default()
+            },
+        );
+
+        let trial = check_circuit(&config, name).unwrap();
+        assert!(trial.trial);
+        assert!(check_circuit(&config, name).is_err());
+        drop(trial);
+        assert!(!CIRCUITS.get(name).unwrap().trial_in_flight);
+
+        check_circuit(&config, name).unwrap().success();
+        assert_eq!(CIRCUITS.get(name).unwrap().state, CircuitState::Closed);
+        assert_eq!(CIRCUITS.get(name).unwrap().failures, 0);
+
+        // Functions without a circuit breaker are never tracked.
+        check_circuit(&config, "steady").unwrap();
+        assert!(CIRCUITS.get("steady").is_none());
+        reset_circuit(name);
+    }
+}
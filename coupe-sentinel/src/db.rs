@@ -1,6 +1,6 @@
 use coupe::{CoupeError, Result};
 use fjall::TransactionalKeyspace;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
 use std::{
     env::{self, current_dir},
     fs,
@@ -8,16 +8,27 @@ use std::{
 };
 use tracing::error;
 
+static DB_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets where the database is kept, over `DB_DIR`, when the sentinel runs in another process
+/// such as `coupe-cli dev`. Has no effect once the database is open.
+pub fn set_db_dir(path: PathBuf) {
+    let _ = DB_DIR_OVERRIDE.set(path);
+}
+
 fn db_dir() -> Result<PathBuf> {
+    if let Some(dir) = DB_DIR_OVERRIDE.get() {
+        return Ok(dir.clone());
+    }
     env::var("DB_DIR")
         .map(PathBuf::from)
         .or_else(|_| current_dir().map(|p| p.join("db")))
-        .map_err(|e| CoupeError::Io(e))
+        .map_err(CoupeError::Io)
 }
 
 fn ensure_db_dir() -> Result<()> {
     let dir = db_dir()?;
-    fs::create_dir_all(&dir).map_err(|e| CoupeError::Io(e))
+    fs::create_dir_all(&dir).map_err(CoupeError::Io)
 }
 
 fn open_db() -> Result<TransactionalKeyspace> {
@@ -0,0 +1,166 @@
+use crate::{DB, Event, body_value};
+use bincode::{deserialize, serialize};
+use coupe::{CoupeError, Result};
+use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::sync::LazyLock;
+use tracing::{info, instrument};
+
+static DEAD_LETTER_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("dead_letters", PartitionCreateOptions::default())
+        .expect("Failed to open dead letters tree")
+});
+
+/// An event a function failed to handle after all retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetterEntry {
+    pub id: String,
+    pub function_name: String,
+    pub event: Event,
+    pub attempts: u32,
+    pub error: String,
+    /**
+     * Nanoseconds between the UNIX epoch and the last failed attempt.
+     */
+    pub failed_at: i128,
+}
+
+fn format_timestamp(nanos: i128) -> Result<String> {
+    Ok(Timestamp::from_nanosecond(nanos)
+        .map_err(|e| CoupeError::DateTime(e.to_string()))?
+        .to_string())
+}
+
+impl DeadLetterEntry {
+    pub fn new(function_name: &str, event: Event, attempts: u32, error: String) -> Self {
+        Self {
+            id: rand::random::<[u8; 16]>()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            function_name: function_name.to_string(),
+            event,
+            attempts,
+            error,
+            failed_at: Timestamp::now().as_nanosecond(),
+        }
+    }
+
+    /// Summary without the payload, for listing.
+    pub fn summary(&self) -> Result<Value> {
+        Ok(json!({
+            "id": self.id,
+            "function_name": self.function_name,
+            "event_id": self.event.id,
+            "source": self.event.source,
+            "attempts": self.attempts,
+            "error": self.error,
+            "size": self.event.payload.len(),
+            "failed_at": format_timestamp(self.failed_at)?,
+        }))
+    }
+}
+
+impl TryInto<Value> for DeadLetterEntry {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Value> {
+        let mut value = self.summary()?;
+        let (payload, encoding) = body_value(&self.event.payload);
+        value["payload"] = payload;
+        value["payload_encoding"] = json!(encoding);
+        value["content_type"] = json!(self.event.content_type);
+        value["created_at"] = json!(format_timestamp(self.event.created_at)?);
+        Ok(value)
+    }
+}
+
+impl TryFrom<&[u8]> for DeadLetterEntry {
+    type Error = CoupeError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        deserialize(value).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+impl TryInto<Vec<u8>> for DeadLetterEntry {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Vec<u8>> {
+        serialize(&self).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+#[instrument(skip(entry), fields(id = %entry.id, function_name = %entry.function_name))]
+pub async fn save_dead_letter(entry: DeadLetterEntry) -> Result<()> {
+    let mut tx = DB.write_tx();
+    let key = entry.id.clone();
+    let value: Vec<u8> = entry.try_into()?;
+    tx.insert(&DEAD_LETTER_STORE, key, value);
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    info!("Event added to dead-letter queue");
+    Ok(())
+}
+
+#[instrument]
+pub async fn get_dead_letter(id: &str) -> Result<Option<DeadLetterEntry>> {
+    let tx = DB.read_tx();
+    tx.get(&DEAD_LETTER_STORE, id)
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+        .map(|value| DeadLetterEntry::try_from(value.as_ref()))
+        .transpose()
+}
+
+/// Dead letters, oldest first, optionally only those of one function.
+#[instrument]
+pub async fn get_dead_letters(function_name: Option<&str>) -> Result<Vec<DeadLetterEntry>> {
+    let tx = DB.read_tx();
+    let mut entries = Vec::new();
+    for item in tx.iter(&DEAD_LETTER_STORE) {
+        let (_, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        let entry = DeadLetterEntry::try_from(value.as_ref())?;
+        if function_name.is_none_or(|name| name == entry.function_name) {
+            entries.push(entry);
+        }
+    }
+    entries.sort_by_key(|entry| entry.failed_at);
+    Ok(entries)
+}
+
+/// Removes and returns a dead letter.
+#[instrument]
+pub async fn take_dead_letter(id: &str) -> Result<Option<DeadLetterEntry>> {
+    let mut tx = DB.write_tx();
+    let Some(value) = tx
+        .get(&DEAD_LETTER_STORE, id)
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+    else {
+        return Ok(None);
+    };
+    let entry = DeadLetterEntry::try_from(value.as_ref())?;
+    tx.remove(&DEAD_LETTER_STORE, id);
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    Ok(Some(entry))
+}
+
+/// Deletes every dead letter, or only those of one function. Returns how many were deleted.
+#[instrument]
+pub async fn purge_dead_letters(function_name: Option<&str>) -> Result<usize> {
+    let ids: Vec<String> = get_dead_letters(function_name)
+        .await?
+        .into_iter()
+        .map(|entry| entry.id)
+        .collect();
+    let mut tx = DB.write_tx();
+    for id in &ids {
+        tx.remove(&DEAD_LETTER_STORE, id.as_str());
+    }
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    info!(count = ids.len(), "Dead letters purged");
+    Ok(ids.len())
+}
@@ -0,0 +1,535 @@
+use crate::{
+    CircuitStatus, DeadLetterEntry, InvocationOutcome, Schedule, Sentinel, check_circuit,
+    consume_queue, consume_stream, publish_queue_event, record_invocation, run_timer,
+    save_dead_letter, start_session,
+};
+use coupe::{Config, CoupeError, DeadLetter, Result, RetryPolicy, Trigger};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::{task::JoinSet, time::sleep};
+use tracing::{error, info, instrument, warn};
+
+/// Prefix of the source of events forwarded to a dead-letter queue. Such events are never
+/// forwarded to another queue again, so dead-letter queues cannot loop.
+pub const DEAD_LETTER_SOURCE_PREFIX: &str = "dead_letter:";
+
+pub const EVENT_ID_HEADER: &str = "x-coupe-event-id";
+pub const EVENT_SOURCE_HEADER: &str = "x-coupe-event-source";
+pub const ATTEMPT_HEADER: &str = "x-coupe-attempt";
+
+static EVENT_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+/// How often the sentinel checks whether its event sources changed on reload.
+const EVENT_SOURCES_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Wait before a consumer or timer that stopped, such as when its broker is down, is restarted.
+const CONSUMER_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// An event delivered to a queue, stream or timer function.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String,
+    /// Where the event came from, such as `queue:orders`.
+    pub source: String,
+    pub payload: Vec<u8>,
+    pub content_type: Option<String>,
+    /**
+     * Nanoseconds between the UNIX epoch and when the event was created.
+     */
+    pub created_at: i128,
+}
+
+impl Event {
+    pub fn new(source: String, payload: Vec<u8>, content_type: Option<String>) -> Self {
+        Self {
+            id: rand::random::<[u8; 16]>()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            source,
+            payload,
+            content_type,
+            created_at: Timestamp::now().as_nanosecond(),
+        }
+    }
+}
+
+/// Backoff before the given retry, randomized when the policy asks for jitter.
+pub fn retry_delay(policy: &RetryPolicy, retry: u32) -> Duration {
+    let backoff = policy.backoff(retry);
+    if policy.jitter {
+        backoff.mul_f64(rand::random::<f64>())
+    } else {
+        backoff
+    }
+}
+
+fn check_broker(config: &Config, kind: &str, name: &str, broker: &str) -> Result<()> {
+    let declared = config
+        .brokers
+        .as_ref()
+        .is_some_and(|brokers| brokers.contains_key(broker));
+    if !declared {
+        return Err(CoupeError::Config(format!(
+            "{} {} uses broker {}, which is not in brokers",
+            kind, name, broker
+        )));
+    }
+    Ok(())
+}
+
+fn check_queue(config: &Config, function_name: &str, queue: &str) -> Result<()> {
+    let Some(declared) = config.queues.as_ref().and_then(|queues| queues.get(queue)) else {
+        return Err(CoupeError::Config(format!(
+            "Function {} uses queue {}, which is not in queues",
+            function_name, queue
+        )));
+    };
+    check_broker(config, "Queue", queue, &declared.broker)
+}
+
+/// Checks that event triggers and dead-letter queues use declared queues, streams and brokers,
+/// that timer schedules parse, and that retries and dead letters are only configured where
+/// they apply.
+pub fn validate_event_functions(config: &Config) -> Result<()> {
+    for (function_name, function) in &config.functions {
+        match &function.trigger {
+            Trigger::Http(_) => {}
+            Trigger::Queue { queue, .. } => check_queue(config, function_name, queue)?,
+            Trigger::Stream { stream, .. } => {
+                let Some(declared) = config
+                    .streams
+                    .as_ref()
+                    .and_then(|streams| streams.get(stream))
+                else {
+                    return Err(CoupeError::Config(format!(
+                        "Function {} uses stream {}, which is not in streams",
+                        function_name, stream
+                    )));
+                };
+                check_broker(config, "Stream", stream, &declared.broker)?;
+            }
+            Trigger::Timer { schedule, .. } => {
+                Schedule::parse(schedule).map_err(|e| {
+                    CoupeError::Config(format!("Function {}: {}", function_name, e))
+                })?;
+            }
+        }
+        if let Some(DeadLetter::Queue { queue }) = &function.dead_letter {
+            check_queue(config, function_name, queue)?;
+        }
+        if matches!(function.trigger, Trigger::Http(_))
+            && (function.retry.is_some() || function.dead_letter.is_some())
+        {
+            return Err(CoupeError::Config(format!(
+                "Function {} has an http trigger, use http.retries instead of retry and dead_letter",
+                function_name
+            )));
+        }
+        match &function.dead_letter {
+            Some(DeadLetter::Queue { queue })
+                if config.queue_consumers(queue).contains(function_name) =>
+            {
+                return Err(CoupeError::Config(format!(
+                    "Function {} cannot dead-letter to queue {}, which triggers it",
+                    function_name, queue
+                )));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Why an event did not get a response from a function.
+#[derive(Debug)]
+pub enum DeliveryError {
+    FunctionNotFound,
+    CircuitOpen(Box<CircuitStatus>),
+    ColdStartFailed(CoupeError),
+    Timeout,
+    Unreachable(String),
+}
+
+impl Display for DeliveryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeliveryError::FunctionNotFound => write!(f, "Function not found"),
+            DeliveryError::CircuitOpen(status) => write!(
+                f,
+                "Circuit open, retry after {} seconds",
+                status.retry_after_secs
+            ),
+            DeliveryError::ColdStartFailed(e) => write!(f, "Failed to start session: {}", e),
+            DeliveryError::Timeout => write!(f, "Function timed out"),
+            DeliveryError::Unreachable(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// A function's response to an event, whatever its status.
+pub struct EventResponse {
+    pub response: reqwest::Response,
+    pub coldstarted: bool,
+}
+
+/// Sends an event to a function once, starting the function if needed, and records the outcome.
+pub async fn send_event(
+    config: &Arc<Config>,
+    function_name: &str,
+    event: &Event,
+    attempt: u32,
+) -> std::result::Result<EventResponse, DeliveryError> {
+    let function = config
+        .functions
+        .get(function_name)
+        .ok_or(DeliveryError::FunctionNotFound)?;
+
+    let permit = match check_circuit(config, function_name) {
+        Ok(permit) => permit,
+        Err(status) => {
+            record_invocation(function_name, InvocationOutcome::CircuitOpen);
+            return Err(DeliveryError::CircuitOpen(status));
+        }
+    };
+
+    let coldstarted = match start_session(config, function_name.to_string()).await {
+        Ok(started) => started.coldstarted,
+        Err(e) => {
+            permit.failure(e.to_string());
+            record_invocation(function_name, InvocationOutcome::ColdStartFailed);
+            return Err(DeliveryError::ColdStartFailed(e));
+        }
+    };
+
+    let url = config
+        .internal_function_url(function_name)
+        .map_err(|e| DeliveryError::Unreachable(e.to_string()))?;
+    let mut request = EVENT_CLIENT
+        .post(format!("http://{}/", url))
+        .timeout(function.trigger.event_timeout())
+        .header(EVENT_ID_HEADER, &event.id)
+        .header(EVENT_SOURCE_HEADER, &event.source)
+        .header(ATTEMPT_HEADER, attempt.to_string())
+        .body(event.payload.clone());
+    if let Some(content_type) = &event.content_type {
+        request = request.header("content-type", content_type);
+    }
+
+    match request.send().await {
+        Ok(response) if response.status().is_success() => {
+            permit.success();
+            record_invocation(function_name, InvocationOutcome::Success);
+            Ok(EventResponse {
+                response,
+                coldstarted,
+            })
+        }
+        Ok(response) => {
+            if response.status().is_server_error() {
+                permit.failure(format!("Function responded with {}", response.status()));
+            } else {
+                permit.success();
+            }
+            record_invocation(function_name, InvocationOutcome::FunctionError);
+            Ok(EventResponse {
+                response,
+                coldstarted,
+            })
+        }
+        Err(e) if e.is_timeout() => {
+            permit.failure("Function timed out".to_string());
+            record_invocation(function_name, InvocationOutcome::Timeout);
+            Err(DeliveryError::Timeout)
+        }
+        Err(e) => {
+            permit.failure(e.to_string());
+            record_invocation(function_name, InvocationOutcome::ProxyError);
+            Err(DeliveryError::Unreachable(e.to_string()))
+        }
+    }
+}
+
+async fn attempt_delivery(
+    config: &Arc<Config>,
+    function_name: &str,
+    event: &Event,
+    attempt: u32,
+) -> std::result::Result<(), String> {
+    let EventResponse { response, .. } = send_event(config, function_name, event, attempt)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("Function responded with {}", response.status()));
+    }
+    Ok(())
+}
+
+/// Delivers an event to a function, retrying with the function's `retry` policy. Events that
+/// still fail go to the function's `dead_letter` target. Returns whether the function handled
+/// the event.
+#[instrument(skip(config, event), fields(event_id = %event.id, source = %event.source))]
+pub async fn deliver_event(config: Arc<Config>, function_name: String, event: Event) -> bool {
+    let Some(function) = config.functions.get(&function_name) else {
+        error!("Function not found, dropping event");
+        return false;
+    };
+    let policy = function.retry.clone().unwrap_or(RetryPolicy {
+        max_attempts: Some(1),
+        ..Default::default()
+    });
+    let max_attempts = policy.max_attempts();
+
+    let mut attempt = 1;
+    let error = loop {
+        match attempt_delivery(&config, &function_name, &event, attempt).await {
+            Ok(()) => {
+                info!(attempt = attempt, "Event delivered");
+                return true;
+            }
+            Err(e) if attempt >= max_attempts => break e,
+            Err(e) => {
+                let delay = retry_delay(&policy, attempt);
+                warn!(
+                    attempt = attempt,
+                    max_attempts = max_attempts,
+                    delay_ms = delay.as_millis(),
+                    error = %e,
+                    "Event delivery failed, retrying"
+                );
+                record_invocation(&function_name, InvocationOutcome::Retry);
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    };
+
+    error!(attempts = attempt, error = %error, "Event delivery failed");
+    dead_letter(&config, &function_name, event, attempt, error).await;
+    false
+}
+
+async fn dead_letter(
+    config: &Arc<Config>,
+    function_name: &str,
+    event: Event,
+    attempts: u32,
+    error: String,
+) {
+    let Some(target) = config
+        .functions
+        .get(function_name)
+        .and_then(|f| f.dead_letter.as_ref())
+    else {
+        warn!(function_name = %function_name, event_id = %event.id, "No dead_letter configured, dropping event");
+        return;
+    };
+    record_invocation(function_name, InvocationOutcome::DeadLettered);
+
+    match target {
+        DeadLetter::Queue { queue } if !event.source.starts_with(DEAD_LETTER_SOURCE_PREFIX) => {
+            let forwarded = Event {
+                source: format!("{}{}", DEAD_LETTER_SOURCE_PREFIX, function_name),
+                ..event.clone()
+            };
+            if config.queue_consumers(queue).is_empty() {
+                warn!(queue = %queue, "Dead-letter queue has no consumers, using the sentinel DLQ");
+            } else {
+                match publish_queue_event(config, queue, &forwarded).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        warn!(queue = %queue, error = %e, "Failed to publish to the dead-letter queue, using the sentinel DLQ")
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let entry = DeadLetterEntry::new(function_name, event, attempts, error);
+    if let Err(e) = save_dead_letter(entry).await {
+        error!(function_name = %function_name, error = %e, "Failed to save dead letter");
+    }
+}
+
+/// The parts of a config the event consumers and timers are started from.
+fn event_sources(config: &Config) -> Value {
+    let functions: BTreeMap<&String, Value> = config
+        .functions
+        .iter()
+        .filter(|(_, function)| !matches!(function.trigger, Trigger::Http(_)))
+        .map(|(name, function)| (name, json!(function)))
+        .collect();
+    json!({
+        "brokers": config.brokers,
+        "queues": config.queues,
+        "streams": config.streams,
+        "functions": functions,
+    })
+}
+
+/// Runs a consumer or timer until the task is aborted, restarting it when it stops.
+async fn keep_running<F, Fut>(name: String, run: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    loop {
+        if let Err(e) = run().await {
+            error!(function_name = %name, error = %e, "Event consumer stopped, restarting");
+        }
+        sleep(CONSUMER_RESTART_DELAY).await;
+    }
+}
+
+fn start_event_sources(config: &Arc<Config>) -> JoinSet<()> {
+    let mut tasks = JoinSet::new();
+    for (function_name, function) in &config.functions {
+        let config = Arc::clone(config);
+        let name = function_name.clone();
+        match function.trigger.clone() {
+            Trigger::Http(_) => {}
+            Trigger::Queue { queue, .. } => {
+                tasks.spawn(keep_running(name.clone(), move || {
+                    consume_queue(Arc::clone(&config), name.clone(), queue.clone())
+                }));
+            }
+            Trigger::Stream { stream, .. } => {
+                tasks.spawn(keep_running(name.clone(), move || {
+                    consume_stream(Arc::clone(&config), name.clone(), stream.clone())
+                }));
+            }
+            Trigger::Timer { schedule, .. } => {
+                let Ok(schedule) = Schedule::parse(&schedule) else {
+                    continue;
+                };
+                tasks.spawn(keep_running(name.clone(), move || {
+                    run_timer(Arc::clone(&config), name.clone(), schedule.clone())
+                }));
+            }
+        }
+    }
+    tasks
+}
+
+/// Delivers events from queues, streams and timers to the functions they trigger. Consumers are
+/// restarted with the new config when a reload changes a broker, queue, stream or event
+/// function; events already being delivered finish with the config they started with.
+pub async fn watch_events(sentinel: Arc<Sentinel>) -> Result<()> {
+    let mut config = sentinel.config();
+    let mut sources = event_sources(&config);
+    let mut tasks = start_event_sources(&config);
+    loop {
+        sleep(EVENT_SOURCES_POLL_INTERVAL).await;
+        let current = sentinel.config();
+        if Arc::ptr_eq(&current, &config) {
+            continue;
+        }
+        config = current;
+        let current_sources = event_sources(&config);
+        if current_sources == sources {
+            continue;
+        }
+        info!("Event sources changed, restarting consumers");
+        sources = current_sources;
+        tasks.shutdown().await;
+        tasks = start_event_sources(&config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(functions: &str) -> Result<()> {
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+name: test
+brokers:
+  nats: {{ type: nats, connection: "nats://localhost:4222" }}
+queues:
+  emails: {{ broker: nats, subject: emails }}
+  failed: {{ broker: nats, subject: failed }}
+  orphaned: {{ broker: missing, subject: orphaned }}
+streams:
+  orders: {{ broker: nats, stream: orders, subjects: [orders.created] }}
+functions:
+{}"#,
+            functions
+        ))
+        .unwrap();
+        validate_event_functions(&config)
+    }
+
+    fn error(functions: &str) -> String {
+        validate(functions).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn event_triggers_use_declared_sources() {
+        assert!(
+            validate(
+                r#"
+  send:
+    image: send
+    trigger: { type: queue, queue: emails }
+    dead_letter: { type: queue, queue: failed }
+  process:
+    image: process
+    trigger: { type: stream, stream: orders }
+  nightly:
+    image: nightly
+    trigger: { type: timer, schedule: "0 0 * * *" }
+"#
+            )
+            .is_ok()
+        );
+        assert!(
+            error("  a: { image: a, trigger: { type: queue, queue: sms } }")
+                .contains("uses queue sms, which is not in queues")
+        );
+        assert!(
+            error("  a: { image: a, trigger: { type: stream, stream: payments } }")
+                .contains("uses stream payments, which is not in streams")
+        );
+        assert!(
+            error("  a: { image: a, trigger: { type: queue, queue: orphaned } }")
+                .contains("uses broker missing, which is not in brokers")
+        );
+    }
+
+    #[test]
+    fn timer_schedules_must_parse() {
+        let error = error(r#"  a: { image: a, trigger: { type: timer, schedule: "0 25 * * *" } }"#);
+        assert!(error.contains("Function a: "), "{}", error);
+        assert!(error.contains("Invalid hour"), "{}", error);
+    }
+
+    #[test]
+    fn dead_letters_go_to_declared_queues_that_do_not_trigger_the_function() {
+        assert!(
+            error(
+                "  a: { image: a, trigger: { type: queue, queue: emails }, dead_letter: { type: queue, queue: sms } }"
+            )
+            .contains("uses queue sms")
+        );
+        assert!(
+            error(
+                "  a: { image: a, trigger: { type: queue, queue: emails }, dead_letter: { type: queue, queue: emails } }"
+            )
+            .contains("cannot dead-letter to queue emails")
+        );
+        assert!(
+            error(r#"  a: { image: a, trigger: { type: http, path: /a }, retry: {} }"#)
+                .contains("use http.retries")
+        );
+    }
+}
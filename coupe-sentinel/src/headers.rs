@@ -0,0 +1,367 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{
+        HeaderName, HeaderValue, Method, Request,
+        header::{CONTENT_SECURITY_POLICY, STRICT_TRANSPORT_SECURITY},
+    },
+    response::Response,
+};
+use coupe::{Cors, CoupeError, HeaderPolicy, HeaderRules, HttpMethod, Result};
+use std::{sync::Arc, time::Duration};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+
+fn parse_header_name(name: &str) -> Result<HeaderName> {
+    HeaderName::try_from(name)
+        .map_err(|e| CoupeError::Config(format!("Invalid header name {}: {}", name, e)))
+}
+
+fn parse_header_value(name: &str, value: &str) -> Result<HeaderValue> {
+    HeaderValue::try_from(value)
+        .map_err(|e| CoupeError::Config(format!("Invalid value for header {}: {}", name, e)))
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once("://*.") {
+        Some((scheme, domain)) => origin
+            .strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .and_then(|host| host.strip_suffix(domain))
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern == origin,
+    }
+}
+
+pub(crate) fn to_method(method: &HttpMethod) -> Option<Method> {
+    match method {
+        HttpMethod::Any => None,
+        HttpMethod::Get => Some(Method::GET),
+        HttpMethod::Post => Some(Method::POST),
+        HttpMethod::Put => Some(Method::PUT),
+        HttpMethod::Delete => Some(Method::DELETE),
+        HttpMethod::Patch => Some(Method::PATCH),
+        HttpMethod::Head => Some(Method::HEAD),
+        HttpMethod::Options => Some(Method::OPTIONS),
+    }
+}
+
+/// Builds the CORS layer for a trigger. Credentials need listed origins; wildcard methods and
+/// headers are then mirrored from the request, since browsers reject `*` on credentialed
+/// requests.
+pub fn cors_layer(cors: &Cors) -> Result<CorsLayer> {
+    let origins = cors
+        .origins
+        .clone()
+        .unwrap_or_else(|| vec!["*".to_string()]);
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        if cors.credentials {
+            return Err(CoupeError::Config(
+                "CORS credentials need origins listed without `*`".to_string(),
+            ));
+        }
+        AllowOrigin::any()
+    } else if origins.iter().any(|o| o.contains("://*.")) {
+        AllowOrigin::predicate(move |origin, _| {
+            origin
+                .to_str()
+                .is_ok_and(|origin| origins.iter().any(|o| origin_matches(o, origin)))
+        })
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|o| parse_header_value("origin", o))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    };
+
+    let methods = cors
+        .methods
+        .as_ref()
+        .and_then(|methods| methods.iter().map(to_method).collect::<Option<Vec<_>>>());
+    let allow_methods = match methods {
+        Some(methods) => AllowMethods::list(methods),
+        None if cors.credentials => AllowMethods::mirror_request(),
+        None => AllowMethods::any(),
+    };
+
+    let headers = cors
+        .headers
+        .clone()
+        .unwrap_or_else(|| vec!["*".to_string()]);
+    let allow_headers = if headers.iter().any(|h| h == "*") {
+        if cors.credentials {
+            AllowHeaders::mirror_request()
+        } else {
+            AllowHeaders::any()
+        }
+    } else {
+        AllowHeaders::list(
+            headers
+                .iter()
+                .map(|h| parse_header_name(h))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    };
+
+    let mut layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(allow_methods)
+        .allow_headers(allow_headers)
+        .allow_credentials(cors.credentials);
+
+    if let Some(expose_headers) = &cors.expose_headers {
+        layer = layer.expose_headers(ExposeHeaders::list(
+            expose_headers
+                .iter()
+                .map(|h| parse_header_name(h))
+                .collect::<Result<Vec<_>>>()?,
+        ));
+    }
+    if let Some(max_age) = cors.max_age {
+        layer = layer.max_age(Duration::from_secs(max_age));
+    }
+    Ok(layer)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HeaderEdits {
+    add: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderEdits {
+    fn new(rules: Option<&HeaderRules>) -> Result<Self> {
+        let Some(rules) = rules else {
+            return Ok(Self::default());
+        };
+        let mut add = Vec::new();
+        for (name, value) in rules.add.iter().flatten() {
+            add.push((parse_header_name(name)?, parse_header_value(name, value)?));
+        }
+        let remove = rules
+            .remove
+            .iter()
+            .flatten()
+            .map(|name| parse_header_name(name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { add, remove })
+    }
+
+    fn apply(&self, headers: &mut axum::http::HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in &self.add {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.remove.is_empty()
+    }
+}
+
+/// Header edits for a trigger, parsed once when the router is built.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderEditPolicy {
+    pub request: HeaderEdits,
+    pub response: HeaderEdits,
+}
+
+impl HeaderEditPolicy {
+    pub fn new(policy: &HeaderPolicy) -> Result<Self> {
+        let request = HeaderEdits::new(policy.request.as_ref())?;
+        let mut response = HeaderEdits::new(policy.response.as_ref())?;
+        if let Some(hsts) = &policy.hsts {
+            response.add.push((
+                STRICT_TRANSPORT_SECURITY,
+                parse_header_value("strict-transport-security", &hsts.header_value())?,
+            ));
+        }
+        if let Some(csp) = &policy.csp {
+            response.add.push((
+                CONTENT_SECURITY_POLICY,
+                parse_header_value("content-security-policy", csp)?,
+            ));
+        }
+        Ok(Self { request, response })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+}
+
+pub async fn apply_request_headers(
+    State(policy): State<Arc<HeaderEditPolicy>>,
+    mut request: Request<Body>,
+) -> Request<Body> {
+    policy.request.apply(request.headers_mut());
+    request
+}
+
+pub async fn apply_response_headers(
+    State(policy): State<Arc<HeaderEditPolicy>>,
+    mut response: Response,
+) -> Response {
+    policy.response.apply(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Router,
+        http::{HeaderMap, StatusCode, header},
+        routing::get,
+    };
+    use tower_service::Service;
+
+    fn cors(yaml: &str) -> Cors {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    async fn request(cors: &Cors, method: Method, origin: &str) -> (StatusCode, HeaderMap) {
+        let mut router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(cors_layer(cors).unwrap());
+        let request = Request::builder()
+            .method(method.clone())
+            .uri("/")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-token")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.call(request).await.unwrap();
+        (response.status(), response.headers().clone())
+    }
+
+    fn allowed_origin(headers: &HeaderMap) -> Option<&str> {
+        headers
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn origins_match_exactly_or_by_subdomain() {
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://app.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://a.b.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://evilexample.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "http://app.example.com"
+        ));
+    }
+
+    #[tokio::test]
+    async fn wildcard_origins_allow_any_site_without_credentials() {
+        let (_, headers) = request(&cors("{}"), Method::GET, "https://any.example").await;
+        assert_eq!(allowed_origin(&headers), Some("*"));
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_CREDENTIALS));
+    }
+
+    #[tokio::test]
+    async fn listed_origins_are_the_only_ones_allowed() {
+        let cors = cors(
+            "{ origins: [\"https://app.example.com\", \"https://*.example.org\"], credentials: true }",
+        );
+        let (_, headers) = request(&cors, Method::GET, "https://app.example.com").await;
+        assert_eq!(allowed_origin(&headers), Some("https://app.example.com"));
+        assert_eq!(
+            headers
+                .get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS)
+                .unwrap(),
+            "true"
+        );
+        let (_, headers) = request(&cors, Method::OPTIONS, "https://api.example.org").await;
+        assert_eq!(allowed_origin(&headers), Some("https://api.example.org"));
+        assert_eq!(
+            headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(),
+            "x-token"
+        );
+        let (_, headers) = request(&cors, Method::GET, "https://evil.example").await;
+        assert_eq!(allowed_origin(&headers), None);
+    }
+
+    #[test]
+    fn credentials_need_listed_origins() {
+        for yaml in [
+            "{ credentials: true }",
+            "{ credentials: true, origins: [\"*\"] }",
+        ] {
+            assert!(cors_layer(&cors(yaml)).is_err(), "{}", yaml);
+        }
+        assert!(cors_layer(&cors("{ origins: [\"bad\\norigin\"] }")).is_err());
+    }
+
+    #[test]
+    fn header_edits_remove_then_add() {
+        let rules: HeaderRules = serde_yaml::from_str(
+            "{ add: { x-added: one, x-replaced: new }, remove: [x-removed, x-replaced] }",
+        )
+        .unwrap();
+        let edits = HeaderEdits::new(Some(&rules)).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-removed", HeaderValue::from_static("gone"));
+        headers.insert("x-replaced", HeaderValue::from_static("old"));
+        headers.insert("x-kept", HeaderValue::from_static("kept"));
+        edits.apply(&mut headers);
+        assert_eq!(headers.get("x-added").unwrap(), "one");
+        assert_eq!(headers.get("x-replaced").unwrap(), "new");
+        assert_eq!(headers.get("x-kept").unwrap(), "kept");
+        assert!(!headers.contains_key("x-removed"));
+        assert!(HeaderEdits::new(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn header_edits_reject_invalid_headers() {
+        for yaml in [
+            "{ add: { \"bad name\": value } }",
+            "{ add: { x-name: \"bad\\nvalue\" } }",
+            "{ remove: [\"bad name\"] }",
+        ] {
+            let rules: HeaderRules = serde_yaml::from_str(yaml).unwrap();
+            assert!(HeaderEdits::new(Some(&rules)).is_err(), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn header_policies_add_hsts_and_csp() {
+        let policy: HeaderPolicy =
+            serde_yaml::from_str("{ hsts: { max_age: 60 }, csp: \"default-src 'self'\" }").unwrap();
+        let policy = HeaderEditPolicy::new(&policy).unwrap();
+        let mut headers = HeaderMap::new();
+        policy.response.apply(&mut headers);
+        assert_eq!(
+            headers.get(STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=60"
+        );
+        assert_eq!(
+            headers.get(CONTENT_SECURITY_POLICY).unwrap(),
+            "default-src 'self'"
+        );
+        assert!(policy.request.is_empty());
+    }
+}
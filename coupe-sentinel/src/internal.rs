@@ -0,0 +1,357 @@
+use crate::{
+    DEFAULT_BUFFERED_BODY_SIZE, DOCKER_CLIENT, Event, deliver_event, publish_queue_event,
+    publish_stream_event, read_body,
+};
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, Request, StatusCode, Uri, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+};
+use coupe::{Config, CoupeError, Trigger, function_at_address};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tower_service::Service;
+use tracing::{error, info};
+
+/// Header a function running as a local process under `coupe-cli dev` names itself with.
+/// Other callers are known by their container's address.
+pub const CALLER_HEADER: &str = "x-coupe-function";
+
+/// State of the internal API, which functions reach through `COUPE_SENTINEL_URL`.
+pub struct InternalApi {
+    config: Arc<Config>,
+    routers: HashMap<String, Router>,
+}
+
+impl InternalApi {
+    /// Takes a router per HTTP function, without the auth and rate limit layers of its trigger.
+    pub fn new(config: Arc<Config>, routers: HashMap<String, Router>) -> Self {
+        Self { config, routers }
+    }
+
+    pub fn router(self) -> Router {
+        Router::new()
+            .route("/health", get(|| async { Json(json!({ "status": "ok" })) }))
+            .route("/functions/{name}", any(invoke))
+            .route("/functions/{name}/{*path}", any(invoke))
+            .route("/queues/{name}", post(publish_to_queue))
+            .route("/streams/{name}", post(publish_to_stream))
+            .with_state(Arc::new(self))
+    }
+}
+
+fn peer_address(request: &Request<Body>) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+}
+
+/// The function making the request, found by the address of its container on the sentinel's
+/// Docker host. Requests from elsewhere are rejected.
+async fn caller(
+    config: &Config,
+    address: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> std::result::Result<String, Response> {
+    let forbidden = || {
+        (
+            StatusCode::FORBIDDEN,
+            Json(json!({ "error": "Caller is not a function of the stack" })),
+        )
+            .into_response()
+    };
+    let Some(address) = address else {
+        return Err(forbidden());
+    };
+    // Containers never call from loopback, only local processes under `coupe-cli dev` do.
+    if address.is_loopback() {
+        return config
+            .dev
+            .as_ref()
+            .and_then(|dev| {
+                headers
+                    .get(CALLER_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .filter(|name| dev.local_functions.contains(*name))
+            })
+            .map(str::to_string)
+            .ok_or_else(forbidden);
+    }
+    match function_at_address(&DOCKER_CLIENT, config, address).await {
+        Ok(Some(function)) => Ok(function),
+        Ok(None) => Err(forbidden()),
+        Err(e) => {
+            error!(error = %e, "Failed to look up the calling container");
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "error": e.to_string() })),
+            )
+                .into_response())
+        }
+    }
+}
+
+fn forbidden_target(caller: &str, target: String) -> Response {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({ "error": format!("Function {} may not reach {}", caller, target) })),
+    )
+        .into_response()
+}
+
+fn not_found(message: String) -> Response {
+    (StatusCode::NOT_FOUND, Json(json!({ "error": message }))).into_response()
+}
+
+/// The request's URI with the `/functions/{name}` prefix removed, keeping the query.
+fn function_uri(uri: &Uri) -> Option<Uri> {
+    let path = uri.path().splitn(4, '/').nth(3).unwrap_or_default();
+    let path_and_query = match uri.query() {
+        Some(query) => format!("/{}?{}", path, query),
+        None => format!("/{}", path),
+    };
+    path_and_query.parse().ok()
+}
+
+/// An event with the request's body and content type.
+pub async fn event_from_request(
+    source: String,
+    request: Request<Body>,
+) -> std::result::Result<Event, Response> {
+    let content_type = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let (_, body) = read_body(request, DEFAULT_BUFFERED_BODY_SIZE).await?;
+    Ok(Event::new(source, body.to_vec(), content_type))
+}
+
+/// Calls a function by name. HTTP functions receive the request at the path after the
+/// function name; other functions receive the body as an event and the call waits for it to be
+/// handled, including retries.
+async fn invoke(
+    State(api): State<Arc<InternalApi>>,
+    Path(params): Path<HashMap<String, String>>,
+    mut request: Request<Body>,
+) -> Response {
+    let function_name = params.get("name").cloned().unwrap_or_default();
+    let address = peer_address(&request);
+    let caller = match caller(&api.config, address, request.headers()).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    let Some(function) = api.config.functions.get(&function_name) else {
+        return not_found(format!("Function not found: {}", function_name));
+    };
+    if !api.config.may_call(&caller, &function_name) {
+        return forbidden_target(&caller, format!("function {}", function_name));
+    }
+    info!(function_name = %function_name, caller = %caller, "Internal function call");
+
+    if let Some(router) = api.routers.get(&function_name) {
+        let Some(uri) = function_uri(request.uri()) else {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "Invalid function path" })),
+            )
+                .into_response();
+        };
+        *request.uri_mut() = uri;
+        let Ok(response) = router.clone().call(request).await;
+        return response;
+    }
+    if matches!(function.trigger, Trigger::Http(_)) {
+        return not_found(format!("HTTP function not found: {}", function_name));
+    }
+
+    let event = match event_from_request(format!("function:{}", caller), request).await {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    let event_id = event.id.clone();
+    if deliver_event(Arc::clone(&api.config), function_name, event).await {
+        Json(json!({ "event_id": event_id, "delivered": true })).into_response()
+    } else {
+        error!(event_id = %event_id, "Internal function call failed");
+        (
+            StatusCode::BAD_GATEWAY,
+            Json(json!({ "event_id": event_id, "delivered": false })),
+        )
+            .into_response()
+    }
+}
+
+fn published(event_id: String, consumers: usize) -> Response {
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "event_id": event_id, "consumers": consumers })),
+    )
+        .into_response()
+}
+
+fn publish_failed(event_id: &str, error: CoupeError) -> Response {
+    error!(event_id = %event_id, error = %error, "Failed to publish event");
+    let status = match error {
+        CoupeError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    (
+        status,
+        Json(json!({ "event_id": event_id, "error": error.to_string() })),
+    )
+        .into_response()
+}
+
+/// Publishes the body to a queue's broker and responds once the broker has it.
+async fn publish_to_queue(
+    State(api): State<Arc<InternalApi>>,
+    Path(queue): Path<String>,
+    request: Request<Body>,
+) -> Response {
+    let declared = api
+        .config
+        .queues
+        .as_ref()
+        .is_some_and(|queues| queues.contains_key(&queue));
+    if !declared {
+        return not_found(format!("Queue not found: {}", queue));
+    }
+    let address = peer_address(&request);
+    let caller = match caller(&api.config, address, request.headers()).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    if !api
+        .config
+        .may_publish(&caller, &api.config.queue_consumers(&queue))
+    {
+        return forbidden_target(&caller, format!("queue {}", queue));
+    }
+    let event = match event_from_request(format!("queue:{}", queue), request).await {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    if let Err(e) = publish_queue_event(&api.config, &queue, &event).await {
+        return publish_failed(&event.id, e);
+    }
+    let consumers = api.config.queue_consumers(&queue).len();
+    info!(queue = %queue, caller = %caller, event_id = %event.id, consumers = consumers, "Event published");
+    published(event.id, consumers)
+}
+
+#[derive(Deserialize)]
+struct PublishStreamQuery {
+    subject: Option<String>,
+}
+
+/// Publishes the body to a stream and responds once JetStream has stored it.
+async fn publish_to_stream(
+    State(api): State<Arc<InternalApi>>,
+    Path(stream): Path<String>,
+    Query(query): Query<PublishStreamQuery>,
+    request: Request<Body>,
+) -> Response {
+    let declared = api
+        .config
+        .streams
+        .as_ref()
+        .is_some_and(|streams| streams.contains_key(&stream));
+    if !declared {
+        return not_found(format!("Stream not found: {}", stream));
+    }
+    let address = peer_address(&request);
+    let caller = match caller(&api.config, address, request.headers()).await {
+        Ok(caller) => caller,
+        Err(response) => return response,
+    };
+    if !api
+        .config
+        .may_publish(&caller, &api.config.stream_consumers(&stream))
+    {
+        return forbidden_target(&caller, format!("stream {}", stream));
+    }
+    let event = match event_from_request(format!("stream:{}", stream), request).await {
+        Ok(event) => event,
+        Err(response) => return response,
+    };
+    if let Err(e) =
+        publish_stream_event(&api.config, &stream, query.subject.as_deref(), &event).await
+    {
+        return publish_failed(&event.id, e);
+    }
+    let consumers = api.config.stream_consumers(&stream).len();
+    info!(stream = %stream, caller = %caller, event_id = %event.id, consumers = consumers, "Event published");
+    published(event.id, consumers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coupe::DevMode;
+    use std::net::Ipv4Addr;
+
+    fn dev_config() -> Config {
+        let mut config: Config = serde_yaml::from_str(
+            "name: test\nfunctions:\n  billing: { image: billing, trigger: { type: queue, queue: q } }\n  orders: { image: orders, trigger: { type: queue, queue: q } }",
+        )
+        .unwrap();
+        config.dev = Some(DevMode {
+            local_functions: ["billing".to_string()].into(),
+            ..Default::default()
+        });
+        config
+    }
+
+    fn headers(caller: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CALLER_HEADER, caller.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn dev_processes_name_themselves_from_loopback() {
+        let config = dev_config();
+        let loopback = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let caller = caller(&config, loopback, &headers("billing")).await;
+        assert_eq!(caller.ok().as_deref(), Some("billing"));
+    }
+
+    #[tokio::test]
+    async fn caller_header_is_not_trusted_otherwise() {
+        let config = dev_config();
+        let loopback = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        // Only functions that run as local processes, and only from loopback.
+        for (address, name) in [
+            (loopback, "orders"),
+            (loopback, "unknown"),
+            (None, "billing"),
+        ] {
+            let response = caller(&config, address, &headers(name)).await.unwrap_err();
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{:?} {}",
+                address,
+                name
+            );
+        }
+
+        let mut deployed = dev_config();
+        deployed.dev = None;
+        let response = caller(&deployed, loopback, &headers("billing"))
+            .await
+            .unwrap_err();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,747 @@
+use crate::{DB, routes::host_matches};
+use axum::{
+    Router,
+    body::{Body, Bytes, to_bytes},
+    extract::ConnectInfo,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Uri},
+    response::IntoResponse,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bincode::{deserialize, serialize};
+use coupe::{CoupeError, DEFAULT_API_KEY_HEADER, HttpAuth, Result};
+use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::{spawn, time::sleep};
+use tower_service::Service;
+use tracing::{debug, error, info, instrument, warn};
+
+static INVOCATION_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("invocations", PartitionCreateOptions::default())
+        .expect("Failed to open invocations tree")
+});
+
+/// How long finished invocations are kept before they are pruned.
+pub const INVOCATION_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Largest function response stored for an invocation.
+const MAX_INVOCATION_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Headers that are always credentials, besides a trigger's API key header.
+const CREDENTIAL_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvocationStatus {
+    Pending,
+    Running,
+    /// The function responded with a status below 500.
+    Completed,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredRequest {
+    pub method: String,
+    pub path_and_query: String,
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// Credential headers and query parameters, kept in memory only so they never reach the
+    /// database.
+    #[serde(skip)]
+    pub credentials: Credentials,
+    /// Names of the credentials, so a resumed invocation knows it lost them.
+    pub credential_names: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    headers: Vec<(String, Vec<u8>)>,
+    query: Vec<String>,
+}
+
+impl StoredRequest {
+    /// Splits the credentials from the request: the headers in [`CREDENTIAL_HEADERS`] and
+    /// the API key header or query parameter of `auth`.
+    pub fn new(
+        method: String,
+        path_and_query: String,
+        headers: Vec<(String, Vec<u8>)>,
+        auth: Option<&HttpAuth>,
+        body: Vec<u8>,
+    ) -> Self {
+        let (api_key_header, api_key_query) = match auth {
+            Some(HttpAuth::ApiKey { header, query }) => (
+                match (header, query) {
+                    (None, Some(_)) => None,
+                    (header, _) => Some(header.as_deref().unwrap_or(DEFAULT_API_KEY_HEADER)),
+                },
+                query.as_deref(),
+            ),
+            _ => (None, None),
+        };
+        let (credential_headers, headers): (Vec<_>, Vec<_>) =
+            headers.into_iter().partition(|(name, _)| {
+                CREDENTIAL_HEADERS.contains(&name.as_str())
+                    || api_key_header.is_some_and(|header| header.eq_ignore_ascii_case(name))
+            });
+        let mut credential_names: Vec<String> = credential_headers
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        let (path, query) = match path_and_query.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (path_and_query.as_str(), ""),
+        };
+        let (credential_query, query): (Vec<_>, Vec<_>) = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .partition(|pair| {
+                api_key_query.is_some_and(|name| pair.split('=').next() == Some(name))
+            });
+        if let Some(name) = api_key_query.filter(|_| !credential_query.is_empty()) {
+            credential_names.push(name.to_string());
+        }
+        let path_and_query = match query.is_empty() {
+            true => path.to_string(),
+            false => format!("{}?{}", path, query.join("&")),
+        };
+
+        Self {
+            method,
+            path_and_query,
+            headers,
+            credentials: Credentials {
+                headers: credential_headers,
+                query: credential_query.into_iter().map(str::to_string).collect(),
+            },
+            credential_names,
+            body,
+        }
+    }
+
+    fn path_and_query_with_credentials(&self) -> String {
+        let mut path_and_query = self.path_and_query.clone();
+        for pair in &self.credentials.query {
+            path_and_query.push(if path_and_query.contains('?') {
+                '&'
+            } else {
+                '?'
+            });
+            path_and_query.push_str(pair);
+        }
+        path_and_query
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invocation {
+    pub id: String,
+    pub function_name: String,
+    pub status: InvocationStatus,
+    pub callback_url: Option<String>,
+    pub request: StoredRequest,
+    pub response: Option<StoredResponse>,
+    pub error: Option<String>,
+    pub callback_status: Option<u16>,
+    pub callback_error: Option<String>,
+    /**
+     * Nanoseconds between the UNIX epoch and when the invocation was accepted.
+     */
+    pub created_at: i128,
+    /**
+     * Nanoseconds between the UNIX epoch and when the invocation finished.
+     */
+    pub finished_at: Option<i128>,
+}
+
+fn format_timestamp(nanos: i128) -> Result<String> {
+    Ok(Timestamp::from_nanosecond(nanos)
+        .map_err(|e| CoupeError::DateTime(e.to_string()))?
+        .to_string())
+}
+
+/// Response bodies are returned as text when they are valid UTF-8 and as base64 otherwise.
+pub(crate) fn body_value(body: &[u8]) -> (Value, &'static str) {
+    match std::str::from_utf8(body) {
+        Ok(text) => (json!(text), "utf8"),
+        Err(_) => (json!(STANDARD.encode(body)), "base64"),
+    }
+}
+
+impl TryInto<Value> for Invocation {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Value> {
+        let response = self.response.map(|response| {
+            let (body, encoding) = body_value(&response.body);
+            json!({
+                "status": response.status,
+                "headers": response
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.clone(), json!(String::from_utf8_lossy(value))))
+                    .collect::<serde_json::Map<_, _>>(),
+                "body": body,
+                "body_encoding": encoding,
+            })
+        });
+        Ok(json!({
+            "id": self.id,
+            "function_name": self.function_name,
+            "status": self.status,
+            "request": {
+                "method": self.request.method,
+                "path": self.request.path_and_query,
+            },
+            "response": response,
+            "error": self.error,
+            "callback_url": self.callback_url,
+            "callback_status": self.callback_status,
+            "callback_error": self.callback_error,
+            "created_at": format_timestamp(self.created_at)?,
+            "finished_at": self.finished_at.map(format_timestamp).transpose()?,
+        }))
+    }
+}
+
+impl TryFrom<&[u8]> for Invocation {
+    type Error = CoupeError;
+
+    fn try_from(value: &[u8]) -> Result<Self> {
+        deserialize(value).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+impl TryInto<Vec<u8>> for Invocation {
+    type Error = CoupeError;
+
+    fn try_into(self) -> Result<Vec<u8>> {
+        serialize(&self).map_err(|e| CoupeError::Database(e.to_string()))
+    }
+}
+
+impl Invocation {
+    pub fn new(
+        function_name: String,
+        request: StoredRequest,
+        callback_url: Option<String>,
+    ) -> Self {
+        Self {
+            id: rand::random::<[u8; 16]>()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            function_name,
+            status: InvocationStatus::Pending,
+            callback_url,
+            request,
+            response: None,
+            error: None,
+            callback_status: None,
+            callback_error: None,
+            created_at: Timestamp::now().as_nanosecond(),
+            finished_at: None,
+        }
+    }
+
+    fn finish(&mut self, status: InvocationStatus) {
+        self.status = status;
+        self.finished_at = Some(Timestamp::now().as_nanosecond());
+    }
+
+    fn to_request(&self) -> Result<Request<Body>> {
+        let method = Method::from_bytes(self.request.method.as_bytes())
+            .map_err(|e| CoupeError::InvalidInput(e.to_string()))?;
+        let mut request = Request::builder()
+            .method(method)
+            .uri(self.request.path_and_query_with_credentials())
+            .body(Body::from(self.request.body.clone()))
+            .map_err(|e| CoupeError::InvalidInput(e.to_string()))?;
+        for (name, value) in self
+            .request
+            .headers
+            .iter()
+            .chain(&self.request.credentials.headers)
+        {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_bytes(value),
+            ) {
+                request.headers_mut().append(name, value);
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[instrument(skip(invocation), fields(id = %invocation.id))]
+pub async fn save_invocation(invocation: Invocation) -> Result<()> {
+    let mut tx = DB.write_tx();
+    let key = invocation.id.clone();
+    let value: Vec<u8> = invocation.try_into()?;
+    tx.insert(&INVOCATION_STORE, key, value);
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    Ok(())
+}
+
+#[instrument]
+pub async fn get_invocation(id: &str) -> Result<Option<Invocation>> {
+    let tx = DB.read_tx();
+    tx.get(&INVOCATION_STORE, id)
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+        .map(|value| Invocation::try_from(value.as_ref()))
+        .transpose()
+}
+
+async fn get_all_invocations() -> Result<Vec<Invocation>> {
+    let tx = DB.read_tx();
+    let mut invocations = Vec::new();
+    for item in tx.iter(&INVOCATION_STORE) {
+        let (_, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        invocations.push(Invocation::try_from(value.as_ref())?);
+    }
+    Ok(invocations)
+}
+
+#[instrument]
+pub async fn prune_invocations() -> Result<usize> {
+    let cutoff = Timestamp::now().as_nanosecond() - INVOCATION_RETENTION.as_nanos() as i128;
+    let expired: Vec<String> = get_all_invocations()
+        .await?
+        .into_iter()
+        .filter(|invocation| invocation.finished_at.is_some_and(|at| at < cutoff))
+        .map(|invocation| invocation.id)
+        .collect();
+    let count = expired.len();
+    if count > 0 {
+        let mut tx = DB.write_tx();
+        for id in expired {
+            tx.remove(&INVOCATION_STORE, id);
+        }
+        tx.commit()
+            .map_err(|e| CoupeError::Database(e.to_string()))?;
+    }
+    Ok(count)
+}
+
+/// A request as a function's auth and rate limits passed it on, with API keys stripped.
+#[derive(Debug, Clone)]
+pub struct AdmittedRequest {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Handler behind an invocation guard, returning the request it was passed in the response's
+/// extensions.
+pub async fn admit_request(request: Request<Body>) -> Response<Body> {
+    let (parts, body) = request.into_parts();
+    // The body was buffered within the trigger's limit before the guard.
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to read request body: {}", e),
+            )
+                .into_response();
+        }
+    };
+    let mut response = StatusCode::OK.into_response();
+    response.extensions_mut().insert(AdmittedRequest {
+        method: parts.method,
+        uri: parts.uri,
+        headers: parts.headers,
+        body,
+    });
+    response
+}
+
+/// Runs asynchronous invocations through each function's router, so they pass the same
+/// circuit breaker and timeouts as synchronous requests. Auth and rate limits are applied by
+/// [`AsyncInvoker::admit`] before an invocation is saved.
+pub struct AsyncInvoker {
+    routers: HashMap<String, Router>,
+    guards: HashMap<String, Router>,
+    callback_hosts: Vec<String>,
+    client: reqwest::Client,
+}
+
+impl AsyncInvoker {
+    pub fn new(
+        routers: HashMap<String, Router>,
+        guards: HashMap<String, Router>,
+        callback_hosts: Vec<String>,
+    ) -> Self {
+        Self {
+            routers,
+            guards,
+            callback_hosts: callback_hosts
+                .into_iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+            // A redirect could send the result to a host outside `callback_hosts`.
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("Failed to build callback client"),
+        }
+    }
+
+    /// Checks that a callback URL is `http` or `https` and its host is in
+    /// `sentinel.callback_hosts`.
+    pub fn check_callback_url(&self, callback_url: &str) -> Result<()> {
+        let url = reqwest::Url::parse(callback_url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or(CoupeError::InvalidInput(format!(
+                "Invalid callback URL {}",
+                callback_url
+            )))?;
+        let host = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        if !self
+            .callback_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host))
+        {
+            return Err(CoupeError::InvalidInput(format!(
+                "Callback host {} is not in sentinel.callback_hosts",
+                host
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn has_function(&self, function_name: &str) -> bool {
+        self.routers.contains_key(function_name)
+    }
+
+    /// Passes a request through the function's guard, returning it as admitted or the guard's
+    /// rejection.
+    pub async fn admit(
+        &self,
+        function_name: &str,
+        request: Request<Body>,
+    ) -> std::result::Result<AdmittedRequest, Response<Body>> {
+        let Some(mut guard) = self.guards.get(function_name).cloned() else {
+            return Err((
+                StatusCode::NOT_FOUND,
+                format!("Function not found: {}", function_name),
+            )
+                .into_response());
+        };
+        let Ok(mut response) = guard.call(request).await;
+        match response.extensions_mut().remove::<AdmittedRequest>() {
+            Some(admitted) => Ok(admitted),
+            None => Err(response),
+        }
+    }
+
+    /// Runs a saved invocation in the background.
+    pub fn spawn(self: &Arc<Self>, invocation: Invocation, client_addr: Option<SocketAddr>) {
+        let invoker = Arc::clone(self);
+        spawn(async move {
+            let id = invocation.id.clone();
+            if let Err(e) = invoker.run(invocation, client_addr).await {
+                error!(id = %id, error = %e, "Failed to run invocation");
+            }
+        });
+    }
+
+    async fn run(&self, mut invocation: Invocation, client_addr: Option<SocketAddr>) -> Result<()> {
+        invocation.status = InvocationStatus::Running;
+        save_invocation(invocation.clone()).await?;
+        info!(id = %invocation.id, function_name = %invocation.function_name, "Running invocation");
+
+        match self.call_function(&invocation, client_addr).await {
+            Ok(response) => {
+                let status = if response.status < 500 {
+                    InvocationStatus::Completed
+                } else {
+                    InvocationStatus::Failed
+                };
+                invocation.response = Some(response);
+                invocation.finish(status);
+            }
+            Err(e) => {
+                invocation.error = Some(e.to_string());
+                invocation.finish(InvocationStatus::Failed);
+            }
+        }
+        info!(
+            id = %invocation.id,
+            function_name = %invocation.function_name,
+            status = ?invocation.status,
+            "Invocation finished"
+        );
+
+        if let Some(callback_url) = invocation.callback_url.clone() {
+            self.send_callback(&mut invocation, &callback_url).await;
+        }
+        save_invocation(invocation).await
+    }
+
+    async fn call_function(
+        &self,
+        invocation: &Invocation,
+        client_addr: Option<SocketAddr>,
+    ) -> Result<StoredResponse> {
+        let mut router = self.routers.get(&invocation.function_name).cloned().ok_or(
+            CoupeError::InvalidInput(format!("Function not found: {}", invocation.function_name)),
+        )?;
+        let mut request = invocation.to_request()?;
+        if let Some(addr) = client_addr {
+            request.extensions_mut().insert(ConnectInfo(addr));
+        }
+
+        let Ok(response) = router.call(request).await;
+        read_response(response).await
+    }
+
+    async fn send_callback(&self, invocation: &mut Invocation, callback_url: &str) {
+        // The allowed hosts may have changed since a resumed invocation was accepted.
+        if let Err(e) = self.check_callback_url(callback_url) {
+            invocation.callback_error = Some(e.to_string());
+            return;
+        }
+        let payload: Result<Value> = invocation.clone().try_into();
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => {
+                invocation.callback_error = Some(e.to_string());
+                return;
+            }
+        };
+        match self
+            .client
+            .post(callback_url)
+            .timeout(CALLBACK_TIMEOUT)
+            .json(&payload)
+            .send()
+            .await
+        {
+            Ok(response) => invocation.callback_status = Some(response.status().as_u16()),
+            Err(e) => {
+                warn!(id = %invocation.id, error = %e, "Invocation callback failed");
+                invocation.callback_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+async fn read_response(response: Response<Body>) -> Result<StoredResponse> {
+    let (parts, body) = response.into_parts();
+    let body = to_bytes(body, MAX_INVOCATION_RESPONSE_SIZE)
+        .await
+        .map_err(|e| CoupeError::Unknown(format!("Failed to read function response: {}", e)))?;
+    Ok(StoredResponse {
+        status: parts.status.as_u16(),
+        headers: parts
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect(),
+        body: body.to_vec(),
+    })
+}
+
+/// Resumes invocations interrupted by a restart and prunes old results. Pending invocations
+/// are run again; running ones may have reached the function, so they are marked failed
+/// instead of being repeated.
+pub async fn watch_invocations(invoker: Arc<AsyncInvoker>) -> Result<()> {
+    for mut invocation in get_all_invocations().await? {
+        match invocation.status {
+            InvocationStatus::Pending if !invocation.request.credential_names.is_empty() => {
+                invocation.error = Some(format!(
+                    "Sentinel restarted before the invocation ran, and its {} credentials are not stored",
+                    invocation.request.credential_names.join(", ")
+                ));
+                invocation.finish(InvocationStatus::Failed);
+                save_invocation(invocation).await?;
+            }
+            InvocationStatus::Pending => invoker.spawn(invocation, None),
+            InvocationStatus::Running => {
+                invocation.error = Some("Sentinel restarted during the invocation".to_string());
+                invocation.finish(InvocationStatus::Failed);
+                save_invocation(invocation).await?;
+            }
+            InvocationStatus::Completed | InvocationStatus::Failed => {}
+        }
+    }
+
+    loop {
+        match prune_invocations().await {
+            Ok(count) => debug!(count = count, "Expired invocations pruned"),
+            Err(e) => error!(error = %e, "Failed to prune invocations"),
+        }
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        middleware::{Next, from_fn},
+        routing::any,
+    };
+
+    fn stored_request(path_and_query: &str, auth: Option<&str>) -> StoredRequest {
+        let auth: Option<HttpAuth> = auth.map(|yaml| serde_yaml::from_str(yaml).unwrap());
+        StoredRequest::new(
+            "POST".to_string(),
+            path_and_query.to_string(),
+            vec![
+                ("authorization".to_string(), b"Bearer token".to_vec()),
+                ("x-api-key".to_string(), b"key".to_vec()),
+                ("content-type".to_string(), b"application/json".to_vec()),
+            ],
+            auth.as_ref(),
+            b"{}".to_vec(),
+        )
+    }
+
+    fn invoker(callback_hosts: &[&str]) -> AsyncInvoker {
+        AsyncInvoker::new(
+            HashMap::new(),
+            HashMap::new(),
+            callback_hosts.iter().map(|host| host.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn credential_headers_are_not_serialized() {
+        let request = stored_request("/orders", Some("type: api_key"));
+        assert_eq!(request.credential_names, vec!["authorization", "x-api-key"]);
+        assert_eq!(request.headers.len(), 1);
+
+        let stored: StoredRequest = deserialize(&serialize(&request).unwrap()).unwrap();
+        assert_eq!(stored.headers, request.headers);
+        assert!(stored.credentials.headers.is_empty());
+        assert_eq!(stored.credential_names, request.credential_names);
+    }
+
+    #[test]
+    fn api_key_header_is_only_a_credential_for_api_key_auth() {
+        let request = stored_request("/orders", None);
+        assert_eq!(request.credential_names, vec!["authorization"]);
+        assert!(request.headers.iter().any(|(name, _)| name == "x-api-key"));
+    }
+
+    #[test]
+    fn api_key_query_parameter_is_kept_in_memory() {
+        let request = stored_request(
+            "/orders?page=2&key=secret",
+            Some("type: api_key\nquery: key"),
+        );
+        assert_eq!(request.path_and_query, "/orders?page=2");
+        assert_eq!(request.credential_names, vec!["authorization", "key"]);
+        assert_eq!(
+            request.path_and_query_with_credentials(),
+            "/orders?page=2&key=secret"
+        );
+
+        let request = stored_request("/orders?key=secret", Some("type: api_key\nquery: key"));
+        assert_eq!(request.path_and_query, "/orders");
+        assert_eq!(
+            request.path_and_query_with_credentials(),
+            "/orders?key=secret"
+        );
+    }
+
+    #[test]
+    fn callbacks_need_an_allowed_host() {
+        let invoker = invoker(&["hooks.example.com", "*.internal.example.com"]);
+        assert!(
+            invoker
+                .check_callback_url("https://hooks.example.com/done")
+                .is_ok()
+        );
+        assert!(
+            invoker
+                .check_callback_url("http://HOOKS.example.com:8080/")
+                .is_ok()
+        );
+        assert!(
+            invoker
+                .check_callback_url("https://a.internal.example.com/")
+                .is_ok()
+        );
+        assert!(invoker.check_callback_url("https://example.com/").is_err());
+        assert!(
+            invoker
+                .check_callback_url("http://169.254.169.254/latest/meta-data")
+                .is_err()
+        );
+        assert!(
+            invoker
+                .check_callback_url("http://localhost:52345/system/config")
+                .is_err()
+        );
+        assert!(invoker.check_callback_url("file:///etc/passwd").is_err());
+        assert!(invoker.check_callback_url("not a url").is_err());
+    }
+
+    #[test]
+    fn callbacks_are_refused_without_allowed_hosts() {
+        let error = invoker(&[])
+            .check_callback_url("https://hooks.example.com/done")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("sentinel.callback_hosts"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn guards_admit_or_reject_requests() {
+        async fn require_key(request: Request<Body>, next: Next) -> Response<Body> {
+            if request.headers().contains_key("x-api-key") {
+                next.run(request).await
+            } else {
+                StatusCode::UNAUTHORIZED.into_response()
+            }
+        }
+        let guard = Router::new().fallback(any(admit_request).layer(from_fn(require_key)));
+        let invoker = AsyncInvoker::new(
+            HashMap::new(),
+            HashMap::from([("api".to_string(), guard)]),
+            Vec::new(),
+        );
+        let request = |key: bool| {
+            let mut request = Request::put("/api/1?a=b");
+            if key {
+                request = request.header("x-api-key", "key");
+            }
+            request.body(Body::from("{}")).unwrap()
+        };
+
+        let admitted = invoker.admit("api", request(true)).await.unwrap();
+        assert_eq!(admitted.method, Method::PUT);
+        assert_eq!(admitted.uri, "/api/1?a=b");
+        assert_eq!(admitted.body, "{}");
+
+        let rejection = invoker.admit("api", request(false)).await.unwrap_err();
+        assert_eq!(rejection.status(), StatusCode::UNAUTHORIZED);
+        let missing = invoker.admit("other", request(true)).await.unwrap_err();
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,7 +1,37 @@
 mod api;
+mod apikeys;
+mod brokers;
+mod circuit;
 mod db;
+mod dlq;
+mod events;
+mod headers;
+mod internal;
+mod invocations;
+mod ratelimit;
+mod reload;
+mod routes;
 mod sessions;
+mod telemetry;
+mod timers;
+mod versions;
+mod webhooks;
 
 pub use api::*;
+pub use apikeys::*;
+pub use brokers::*;
+pub use circuit::*;
 pub use db::*;
+pub use dlq::*;
+pub use events::*;
+pub use headers::*;
+pub use internal::*;
+pub use invocations::*;
+pub use ratelimit::*;
+pub use reload::*;
+pub use routes::*;
 pub use sessions::*;
+pub use telemetry::*;
+pub use timers::*;
+pub use versions::*;
+pub use webhooks::*;
@@ -1,16 +1,18 @@
 use clap::Parser;
 use coupe::{Config, Result};
-use coupe_sentinel::{serve_api, watch_sessions};
+use coupe_sentinel::{
+    Sentinel, serve_api, watch_config, watch_events, watch_rate_limits, watch_sessions,
+    watch_webhook_nonces,
+};
 use mimalloc::MiMalloc;
 use std::{path::PathBuf, sync::Arc};
 use tokio::spawn;
 use tracing::{error, info};
-use tracing_subscriber;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-async fn run(config: Config) -> Result<()> {
+async fn run(config: Config, config_path: PathBuf) -> Result<()> {
     let config = Arc::new(config);
 
     info!(
@@ -19,8 +21,13 @@ async fn run(config: Config) -> Result<()> {
         "Starting coupe-sentinel services"
     );
 
-    spawn(watch_sessions(Arc::clone(&config)));
-    serve_api(config).await
+    let sentinel = Arc::new(Sentinel::new(config, Some(config_path))?);
+    spawn(watch_sessions(Arc::clone(&sentinel)));
+    spawn(watch_webhook_nonces());
+    spawn(watch_rate_limits());
+    spawn(watch_config(Arc::clone(&sentinel)));
+    spawn(watch_events(Arc::clone(&sentinel)));
+    serve_api(sentinel).await
 }
 
 #[derive(Parser)]
@@ -50,7 +57,7 @@ async fn main() {
     let cli = Cli::parse();
     info!(config_path = %cli.config.display(), "Loading configuration");
 
-    let config = match Config::load(cli.config) {
+    let config = match Config::load(cli.config.clone()) {
         Ok(config) => {
             info!(stack_name = %config.name, "Configuration loaded successfully");
             config
@@ -62,7 +69,7 @@ async fn main() {
         }
     };
 
-    if let Err(e) = run(config).await {
+    if let Err(e) = run(config, cli.config).await {
         error!(error = %e, "Runtime error occurred");
         eprintln!("Error: {}", e);
         std::process::exit(1);
@@ -0,0 +1,518 @@
+use crate::{DB, InvocationOutcome, record_invocation};
+use axum::{
+    Json,
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bincode::{deserialize, serialize};
+use coupe::{CoupeError, Quota, QuotaPeriod, RateLimit, RateLimitKey, Result};
+use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
+use jiff::{Timestamp, ToSpan, tz::TimeZone};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::{debug, error, info, instrument};
+
+static RATE_LIMIT_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("rate_limits", PartitionCreateOptions::default())
+        .expect("Failed to open rate limits tree")
+});
+
+static QUOTA_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("quotas", PartitionCreateOptions::default())
+        .expect("Failed to open quotas tree")
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TokenBucket {
+    tokens: f64,
+    /**
+     * Nanoseconds between the UNIX epoch and the last refill.
+     */
+    updated_at: i128,
+    /**
+     * Nanoseconds between the UNIX epoch and when the bucket is full again, after which it is
+     * no different from a new one and can be pruned.
+     */
+    expires_at: i128,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, now: i128) -> Self {
+        Self {
+            tokens: capacity,
+            updated_at: now,
+            expires_at: now,
+        }
+    }
+
+    /// Adds the tokens refilled since the last update, up to `capacity`.
+    fn refill(&mut self, capacity: f64, refill_per_sec: f64, now: i128) {
+        let elapsed = (now - self.updated_at).max(0) as f64 / 1e9;
+        self.tokens = (self.tokens + elapsed * refill_per_sec).min(capacity);
+        self.updated_at = now;
+    }
+
+    /// Time until the bucket holds `tokens` again.
+    fn time_until(&self, tokens: f64, refill_per_sec: f64) -> Duration {
+        let missing = (tokens - self.tokens).max(0.0);
+        if missing == 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(missing / refill_per_sec.max(f64::EPSILON))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QuotaUsage {
+    period: String,
+    used: u64,
+    /**
+     * Nanoseconds between the UNIX epoch and the end of the period.
+     */
+    expires_at: i128,
+}
+
+fn decode<'a, T: Deserialize<'a>>(value: &'a [u8]) -> Result<T> {
+    deserialize(value).map_err(|e| CoupeError::Database(e.to_string()))
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    serialize(value).map_err(|e| CoupeError::Database(e.to_string()))
+}
+
+pub fn hash_key(value: &str) -> String {
+    Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Identifier of the current quota period and the time left until it resets.
+fn quota_period(period: QuotaPeriod, now: Timestamp) -> Result<(String, Duration)> {
+    let today = now.to_zoned(TimeZone::UTC).date();
+    let (id, next) = match period {
+        QuotaPeriod::Daily => (today.strftime("%Y-%m-%d").to_string(), today.tomorrow()),
+        QuotaPeriod::Monthly => (
+            today.strftime("%Y-%m").to_string(),
+            today.first_of_month().checked_add(1.month()),
+        ),
+    };
+    let next = next
+        .and_then(|date| date.to_zoned(TimeZone::UTC))
+        .map_err(|e| CoupeError::DateTime(e.to_string()))?;
+    let remaining = next.timestamp().as_nanosecond() - now.as_nanosecond();
+    Ok((id, Duration::from_nanos(remaining.max(0) as u64)))
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u64,
+    pub remaining: u64,
+    pub reset: Duration,
+    pub reason: Option<&'static str>,
+}
+
+impl RateLimitDecision {
+    fn apply_headers(&self, headers: &mut HeaderMap) {
+        let values = [
+            ("ratelimit-limit", self.limit),
+            ("ratelimit-remaining", self.remaining),
+            ("ratelimit-reset", self.reset.as_secs_f64().ceil() as u64),
+        ];
+        for (name, value) in values {
+            headers.insert(name, HeaderValue::from(value));
+        }
+    }
+}
+
+/// A client's quota usage in the current period, with the period's limit and time to reset.
+struct QuotaState {
+    usage: QuotaUsage,
+    limit: u64,
+    reset: Duration,
+}
+
+/// Takes a token from the bucket and counts the request against the quota when both allow
+/// it. The bucket is refilled up to `now` first.
+fn take_token(
+    rate_limit: &RateLimit,
+    bucket: &mut TokenBucket,
+    quota: Option<&mut QuotaState>,
+    now: i128,
+) -> RateLimitDecision {
+    let capacity = rate_limit.burst() as f64;
+    let refill_per_sec = rate_limit.requests as f64 / rate_limit.window().as_secs_f64();
+    bucket.refill(capacity, refill_per_sec, now);
+
+    let decision = match quota {
+        Some(quota) if quota.usage.used >= quota.limit => RateLimitDecision {
+            allowed: false,
+            limit: quota.limit,
+            remaining: 0,
+            reset: quota.reset,
+            reason: Some("Quota exceeded"),
+        },
+        _ if bucket.tokens < 1.0 => RateLimitDecision {
+            allowed: false,
+            limit: rate_limit.burst(),
+            remaining: 0,
+            reset: bucket.time_until(1.0, refill_per_sec),
+            reason: Some("Rate limit exceeded"),
+        },
+        quota => {
+            bucket.tokens -= 1.0;
+            let mut decision = RateLimitDecision {
+                allowed: true,
+                limit: rate_limit.burst(),
+                remaining: bucket.tokens.floor() as u64,
+                reset: bucket.time_until(capacity, refill_per_sec),
+                reason: None,
+            };
+            if let Some(quota) = quota {
+                quota.usage.used += 1;
+                let quota_remaining = quota.limit.saturating_sub(quota.usage.used);
+                if quota_remaining < decision.remaining {
+                    decision.limit = quota.limit;
+                    decision.remaining = quota_remaining;
+                    decision.reset = quota.reset;
+                }
+            }
+            decision
+        }
+    };
+    bucket.expires_at = now + bucket.time_until(capacity, refill_per_sec).as_nanos() as i128;
+    decision
+}
+
+#[instrument(skip(rate_limit))]
+pub async fn check_rate_limit(
+    function_name: &str,
+    rate_limit: &RateLimit,
+    client: &str,
+) -> Result<RateLimitDecision> {
+    let key = format!("{}/{}", function_name, client);
+    let now = Timestamp::now();
+    let mut tx = DB.write_tx();
+
+    let mut bucket = match tx
+        .get(&RATE_LIMIT_STORE, &key)
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+    {
+        Some(value) => decode::<TokenBucket>(value.as_ref())?,
+        None => TokenBucket::new(rate_limit.burst() as f64, now.as_nanosecond()),
+    };
+
+    let mut quota = None;
+    if let Some(Quota { limit, period }) = &rate_limit.quota {
+        let (period_id, reset) = quota_period(*period, now)?;
+        let usage = match tx
+            .get(&QUOTA_STORE, &key)
+            .map_err(|e| CoupeError::Database(e.to_string()))?
+        {
+            Some(value) => Some(decode::<QuotaUsage>(value.as_ref())?),
+            None => None,
+        };
+        let usage = usage
+            .filter(|usage| usage.period == period_id)
+            .unwrap_or(QuotaUsage {
+                period: period_id,
+                used: 0,
+                expires_at: now.as_nanosecond() + reset.as_nanos() as i128,
+            });
+        quota = Some(QuotaState {
+            usage,
+            limit: *limit,
+            reset,
+        });
+    }
+
+    let decision = take_token(rate_limit, &mut bucket, quota.as_mut(), now.as_nanosecond());
+
+    tx.insert(&RATE_LIMIT_STORE, &key, encode(&bucket)?);
+    if let Some(quota) = &quota {
+        tx.insert(&QUOTA_STORE, &key, encode(&quota.usage)?);
+    }
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+
+    Ok(decision)
+}
+
+/// Removes buckets that have refilled and quota usage from past periods, which count the same
+/// as having none, along with entries that can no longer be read.
+#[instrument]
+pub async fn prune_rate_limits() -> Result<usize> {
+    let now = Timestamp::now().as_nanosecond();
+    let mut tx = DB.write_tx();
+    let mut expired = Vec::new();
+    for item in tx.iter(&RATE_LIMIT_STORE) {
+        let (key, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        if decode::<TokenBucket>(&value).map_or(true, |bucket| bucket.expires_at <= now) {
+            expired.push((&*RATE_LIMIT_STORE, key));
+        }
+    }
+    for item in tx.iter(&QUOTA_STORE) {
+        let (key, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        if decode::<QuotaUsage>(&value).map_or(true, |usage| usage.expires_at <= now) {
+            expired.push((&*QUOTA_STORE, key));
+        }
+    }
+    let count = expired.len();
+    for (store, key) in expired {
+        tx.remove(store, key);
+    }
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    Ok(count)
+}
+
+pub async fn watch_rate_limits() -> Result<()> {
+    loop {
+        match prune_rate_limits().await {
+            Ok(count) => debug!(count = count, "Expired rate limit counters pruned"),
+            Err(e) => error!(error = %e, "Failed to prune rate limit counters"),
+        }
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+/// A client identity verified by the trigger's auth, which identity-keyed rate limits count
+/// requests by.
+#[derive(Debug, Clone)]
+pub enum VerifiedClient {
+    ApiKey(String),
+}
+
+/// Address of the client. Behind a trusted proxy it is the last `X-Forwarded-For` entry, the
+/// one the proxy added; earlier entries are whatever the client sent.
+fn client_ip(rate_limit: &RateLimit, request: &Request<Body>) -> String {
+    let forwarded = rate_limit
+        .trust_forwarded_for
+        .then(|| {
+            request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(str::trim)
+                .rfind(|v| !v.is_empty())
+                .map(str::to_string)
+        })
+        .flatten();
+    forwarded
+        .or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Key the request is counted against. Requests without an identity verified by the
+/// trigger's auth are counted by IP.
+pub fn rate_limit_client(rate_limit: &RateLimit, request: &Request<Body>) -> String {
+    let client = match (rate_limit.key, request.extensions().get::<VerifiedClient>()) {
+        (RateLimitKey::ApiKey, Some(VerifiedClient::ApiKey(id))) => Some(format!("key:{}", id)),
+        _ => None,
+    };
+    client.unwrap_or_else(|| format!("ip:{}", client_ip(rate_limit, request)))
+}
+
+pub struct RateLimiter {
+    pub function_name: String,
+    pub rate_limit: RateLimit,
+}
+
+/// Middleware that rejects requests over the trigger's rate limit or quota before the function
+/// is woken.
+pub async fn enforce_rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let client = rate_limit_client(&limiter.rate_limit, &request);
+    let decision =
+        match check_rate_limit(&limiter.function_name, &limiter.rate_limit, &client).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!(
+                    function_name = %limiter.function_name,
+                    error = %e,
+                    "Failed to check rate limit, allowing request"
+                );
+                return next.run(request).await;
+            }
+        };
+
+    if !decision.allowed {
+        info!(
+            function_name = %limiter.function_name,
+            client = %client,
+            reason = decision.reason.unwrap_or_default(),
+            "Request rate limited"
+        );
+        record_invocation(&limiter.function_name, InvocationOutcome::RateLimited);
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(json!({ "error": decision.reason.unwrap_or("Too many requests") })),
+        )
+            .into_response();
+        decision.apply_headers(response.headers_mut());
+        response.headers_mut().insert(
+            "retry-after",
+            HeaderValue::from(decision.reset.as_secs_f64().ceil() as u64),
+        );
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    decision.apply_headers(response.headers_mut());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i128 = 1_000_000_000;
+
+    fn rate_limit(yaml: &str) -> RateLimit {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request(forwarded_for: &[&str]) -> Request<Body> {
+        let mut builder = Request::builder().uri("/");
+        for value in forwarded_for {
+            builder = builder.header("x-forwarded-for", *value);
+        }
+        let mut request = builder.body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+        request
+    }
+
+    #[test]
+    fn bucket_refills_after_running_empty() {
+        // 2 requests per 10 seconds, so a token every 5 seconds.
+        let rate_limit = rate_limit("requests: 2\nwindow: 10");
+        let mut bucket = TokenBucket::new(2.0, 0);
+
+        assert!(take_token(&rate_limit, &mut bucket, None, 0).allowed);
+        assert!(take_token(&rate_limit, &mut bucket, None, 0).allowed);
+        let limited = take_token(&rate_limit, &mut bucket, None, SECOND);
+        assert!(!limited.allowed);
+        assert_eq!(limited.reset, Duration::from_secs(4));
+
+        assert!(take_token(&rate_limit, &mut bucket, None, 5 * SECOND).allowed);
+        assert!(!take_token(&rate_limit, &mut bucket, None, 5 * SECOND).allowed);
+    }
+
+    #[test]
+    fn bucket_never_exceeds_burst() {
+        let rate_limit = rate_limit("requests: 10\nwindow: 1\nburst: 3");
+        let mut bucket = TokenBucket::new(3.0, 0);
+        let decision = take_token(&rate_limit, &mut bucket, None, 3600 * SECOND);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 2);
+        assert_eq!(decision.limit, 3);
+    }
+
+    #[test]
+    fn bucket_expires_once_full_again() {
+        let rate_limit = rate_limit("requests: 1\nwindow: 60");
+        let mut bucket = TokenBucket::new(1.0, 0);
+        take_token(&rate_limit, &mut bucket, None, 0);
+        assert_eq!(bucket.expires_at, 60 * SECOND);
+    }
+
+    #[test]
+    fn quota_limits_even_with_tokens_left() {
+        let rate_limit = rate_limit("requests: 100\nquota: { limit: 1, period: Daily }");
+        let mut bucket = TokenBucket::new(100.0, 0);
+        let mut quota = QuotaState {
+            usage: QuotaUsage {
+                period: "2026-10-19".to_string(),
+                used: 0,
+                expires_at: 0,
+            },
+            limit: 1,
+            reset: Duration::from_secs(3600),
+        };
+
+        let first = take_token(&rate_limit, &mut bucket, Some(&mut quota), 0);
+        assert!(first.allowed);
+        assert_eq!((first.limit, first.remaining), (1, 0));
+
+        let second = take_token(&rate_limit, &mut bucket, Some(&mut quota), 0);
+        assert!(!second.allowed);
+        assert_eq!(second.reason, Some("Quota exceeded"));
+        assert_eq!(second.reset, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn quota_periods_reset_at_utc_boundaries() {
+        let now: Timestamp = "2026-12-31T23:00:00Z".parse().unwrap();
+        assert_eq!(
+            quota_period(QuotaPeriod::Daily, now).unwrap(),
+            ("2026-12-31".to_string(), Duration::from_secs(3600))
+        );
+        assert_eq!(
+            quota_period(QuotaPeriod::Monthly, now).unwrap(),
+            ("2026-12".to_string(), Duration::from_secs(3600))
+        );
+
+        let now: Timestamp = "2026-02-01T00:00:00Z".parse().unwrap();
+        let (id, reset) = quota_period(QuotaPeriod::Monthly, now).unwrap();
+        assert_eq!(id, "2026-02");
+        assert_eq!(reset, Duration::from_secs(28 * 24 * 3600));
+    }
+
+    #[test]
+    fn forwarded_for_uses_the_entry_added_by_the_proxy() {
+        let trusted = rate_limit("requests: 1\ntrust_forwarded_for: true");
+        let request = request(&["1.1.1.1, 2.2.2.2", "3.3.3.3"]);
+        assert_eq!(client_ip(&trusted, &request), "3.3.3.3");
+
+        let untrusted = rate_limit("requests: 1");
+        assert_eq!(client_ip(&untrusted, &request), "10.0.0.1");
+    }
+
+    #[test]
+    fn unverified_identities_are_counted_by_ip() {
+        let rate_limit = rate_limit("requests: 1\nkey: ApiKey");
+        let mut request = request(&[]);
+        request
+            .headers_mut()
+            .insert("x-api-key", HeaderValue::from_static("random"));
+        assert_eq!(rate_limit_client(&rate_limit, &request), "ip:10.0.0.1");
+
+        request
+            .extensions_mut()
+            .insert(VerifiedClient::ApiKey("k1".to_string()));
+        assert_eq!(rate_limit_client(&rate_limit, &request), "key:k1");
+    }
+
+    #[test]
+    fn identities_of_another_kind_are_counted_by_ip() {
+        let rate_limit = rate_limit("requests: 1\nkey: Ip");
+        let mut request = request(&[]);
+        request
+            .extensions_mut()
+            .insert(VerifiedClient::ApiKey("k1".to_string()));
+        assert_eq!(rate_limit_client(&rate_limit, &request), "ip:10.0.0.1");
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{
+    ApiRouters, build_api_routers, end_session, function_client, get_session, mark_function_stale,
+    reset_circuit,
+};
+use arc_swap::ArcSwap;
+use coupe::{Config, CoupeError, Result, ensure_function_network, remove_function_container};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::sleep};
+use tracing::{error, info, warn};
+
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings that only change how the sentinel routes to and retries a function, not its
+/// container.
+const ROUTING_FIELDS: [&str; 5] = [
+    "trigger",
+    "scaling",
+    "circuit_breaker",
+    "retry",
+    "dead_letter",
+];
+
+/// What a function's or version's container is made from.
+fn container_definition(config: &Config, instance: &str) -> Option<Value> {
+    let mut definition = serde_json::to_value(config.instance_definition(instance)?).ok()?;
+    let fields = definition.as_object_mut()?;
+    for field in ROUTING_FIELDS {
+        fields.remove(field);
+    }
+    fields.insert(
+        "image".to_string(),
+        json!(config.function_image(instance).ok()),
+    );
+    fields.insert(
+        "networks".to_string(),
+        json!(config.function_networks(instance)),
+    );
+    fields.insert(
+        "published_port".to_string(),
+        json!(config.published_port(instance)),
+    );
+    Some(definition)
+}
+
+/// Functions and versions whose containers differ between two configs, by instance name.
+/// Changes to triggers, scaling, retries, circuit breakers and version weights and rules only
+/// affect routing, so they are not listed.
+#[derive(Debug, Default, Serialize)]
+pub struct ConfigChanges {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ConfigChanges {
+    pub fn between(previous: &Config, config: &Config) -> Self {
+        let previous_instances = previous.instance_names();
+        let instances = config.instance_names();
+        let mut changes = Self::default();
+        for instance in &instances {
+            if !previous_instances.contains(instance) {
+                changes.added.push(instance.clone());
+            } else if container_definition(previous, instance)
+                != container_definition(config, instance)
+            {
+                changes.changed.push(instance.clone());
+            }
+        }
+        changes.removed = previous_instances
+            .into_iter()
+            .filter(|instance| !instances.contains(instance))
+            .collect();
+        changes
+    }
+}
+
+/// The sentinel's config and the routers built from it, replaced together on reload.
+pub struct Sentinel {
+    routers: ArcSwap<ApiRouters>,
+    config_path: Option<PathBuf>,
+    reloading: Mutex<()>,
+}
+
+impl Sentinel {
+    /// Without a config path, the config can only be replaced with [`Sentinel::swap`].
+    pub fn new(config: Arc<Config>, config_path: Option<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            routers: ArcSwap::from_pointee(build_api_routers(config)?),
+            config_path,
+            reloading: Mutex::new(()),
+        })
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.routers.load().config)
+    }
+
+    pub fn routers(&self) -> Arc<ApiRouters> {
+        self.routers.load_full()
+    }
+
+    /// Builds routers for the config and hands new requests to them. Requests in flight finish
+    /// with the routers they started with. An invalid config leaves the current one in place.
+    pub fn swap(&self, config: Arc<Config>) -> Result<ConfigChanges> {
+        let routers = build_api_routers(Arc::clone(&config))?;
+        let changes = ConfigChanges::between(&self.config(), &config);
+        self.store(routers, &changes);
+        Ok(changes)
+    }
+
+    fn store(&self, routers: ApiRouters, changes: &ConfigChanges) {
+        let previous = self.config();
+        let config = Arc::clone(&routers.config);
+        if previous.sentinel_port() != config.sentinel_port()
+            || previous.sentinel_internal_port() != config.sentinel_internal_port()
+        {
+            warn!("Sentinel ports changed, the new ones are used after a restart");
+        }
+        self.routers.store(Arc::new(routers));
+        for function_name in changes.changed.iter().chain(&changes.removed) {
+            reset_circuit(function_name);
+        }
+        info!(
+            stack_name = %config.name,
+            added = ?changes.added,
+            changed = ?changes.changed,
+            removed = ?changes.removed,
+            "Config swapped"
+        );
+    }
+
+    /// Loads the config file again and swaps it in. Changed and added functions and versions are
+    /// marked to get a container made from their new definition when they next start, and the
+    /// sessions of changed and removed ones are ended, before new requests are routed with the
+    /// new config. Others keep their sessions.
+    pub async fn reload(&self) -> Result<ConfigChanges> {
+        let _reloading = self.reloading.lock().await;
+        let config_path = self.config_path.clone().ok_or(CoupeError::InvalidInput(
+            "The sentinel was not started from a config file".to_string(),
+        ))?;
+        info!(config_path = %config_path.display(), "Reloading configuration");
+        let config = Arc::new(Config::load(config_path)?);
+        let routers = build_api_routers(Arc::clone(&config))?;
+        let previous = self.config();
+        let changes = ConfigChanges::between(&previous, &config);
+
+        for function_name in changes.added.iter().chain(&changes.changed) {
+            let result = match function_client(&config, function_name) {
+                Ok(client) => ensure_function_network(&client, &config, function_name).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(function_name = %function_name, error = %e, "Failed to create function network");
+            }
+            mark_function_stale(function_name, Arc::clone(&config));
+        }
+        for function_name in changes.changed.iter().chain(&changes.removed) {
+            if get_session(function_name.clone()).await?.is_none() {
+                continue;
+            }
+            if let Err(e) = end_session(&previous, function_name.clone()).await {
+                error!(function_name = %function_name, error = %e, "Failed to end session");
+            }
+        }
+        self.store(routers, &changes);
+
+        // Containers of functions moved to another host are left behind on the old one.
+        let moved = changes.changed.iter().filter(|function_name| {
+            previous.function_host(function_name).map(|host| &host.ssh)
+                != config.function_host(function_name).map(|host| &host.ssh)
+        });
+        for function_name in changes.removed.iter().chain(moved) {
+            let result = match function_client(&previous, function_name) {
+                Ok(client) => remove_function_container(&client, &previous, function_name).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(function_name = %function_name, error = %e, "Failed to remove function container");
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// Reloads the config when its file changes, such as when `coupe-cli deploy` writes a new one.
+pub async fn watch_config(sentinel: Arc<Sentinel>) -> Result<()> {
+    let Some(config_path) = sentinel.config_path.clone() else {
+        return Ok(());
+    };
+    let modified = || {
+        fs::metadata(&config_path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    let mut last_modified = modified();
+    loop {
+        sleep(CONFIG_POLL_INTERVAL).await;
+        let current = modified();
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+        if let Err(e) = sentinel.reload().await {
+            error!(error = %e, "Failed to reload configuration, keeping the current one");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(api: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            r#"
+name: test
+functions:
+  api:
+{}
+  worker:
+    image: worker:1
+    trigger: {{ type: http, path: /worker }}
+"#,
+            api
+        ))
+        .unwrap()
+    }
+
+    const API: &str = r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    versions:
+      canary: { image: api:2, weight: 10 }
+"#;
+
+    #[test]
+    fn routing_changes_keep_containers() {
+        let changes = ConfigChanges::between(
+            &config(API),
+            &config(
+                r#"
+    image: api:1
+    trigger:
+      type: http
+      path: /v2/api
+      rate_limit: { requests: 10 }
+      cors: { origins: ["https://example.com"] }
+    scaling: { session_duration: 60 }
+    circuit_breaker: { failure_threshold: 3 }
+    versions:
+      canary: { image: api:2, weight: 50 }
+"#,
+            ),
+        );
+        assert!(changes.added.is_empty());
+        assert!(changes.changed.is_empty());
+        assert!(changes.removed.is_empty());
+    }
+
+    #[test]
+    fn container_changes_are_listed() {
+        let changes = ConfigChanges::between(
+            &config(API),
+            &config(
+                r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    handler_port: 9000
+    versions:
+      canary: { image: api:3, weight: 10 }
+      next: { image: api:4 }
+"#,
+            ),
+        );
+        assert_eq!(changes.added, ["api@next"]);
+        assert_eq!(changes.changed, ["api", "api@canary"]);
+        assert!(changes.removed.is_empty());
+
+        let changes = ConfigChanges::between(
+            &config(API),
+            &config("    image: api:1\n    trigger: { type: http, path: /api }"),
+        );
+        assert_eq!(changes.removed, ["api@canary"]);
+        assert!(changes.changed.is_empty());
+    }
+
+    #[test]
+    fn network_changes_of_allowed_functions_are_listed() {
+        let previous = config(
+            r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    network: { allow: [worker] }
+"#,
+        );
+        let mut isolated = previous.clone();
+        isolated.functions.get_mut("worker").unwrap().network =
+            Some(serde_yaml::from_str("{ internal_only: true }").unwrap());
+        let changes = ConfigChanges::between(&previous, &isolated);
+        assert_eq!(changes.changed, ["api", "worker"]);
+    }
+}
@@ -0,0 +1,638 @@
+use axum::{
+    body::Body,
+    extract::RawPathParams,
+    http::{HeaderName, HeaderValue, Request, Uri, header::HOST},
+};
+use coupe::{Config, CoupeError, HttpAuth, HttpMethod, HttpTrigger, RateLimitKey, Result};
+use matchit::InsertError;
+use std::collections::{BTreeMap, HashMap};
+
+/// Path that routes every request not matched by another function.
+pub const FALLBACK_PATH: &str = "*";
+
+/// Path params are forwarded to the function as `x-coupe-param-<name>` headers.
+pub const PARAM_HEADER_PREFIX: &str = "x-coupe-param-";
+
+const REST_PARAM: &str = "coupe_rest";
+
+/// Paths registered with the router for a trigger. Prefix triggers also register a catch-all
+/// below their path.
+pub fn trigger_route_paths(trigger: &HttpTrigger) -> Vec<String> {
+    if trigger.path == FALLBACK_PATH || !trigger.prefix {
+        return vec![trigger.path.clone()];
+    }
+
+    let base = trigger.path.trim_end_matches('/');
+    vec![
+        if base.is_empty() { "/" } else { base }.to_string(),
+        format!("{}/{{*{}}}", base, REST_PARAM),
+    ]
+}
+
+fn path_param_names(path: &str) -> Vec<String> {
+    path.split('{')
+        .skip(1)
+        .filter_map(|segment| segment.split_once('}'))
+        .map(|(name, _)| name.trim_start_matches('*').to_string())
+        .collect()
+}
+
+fn validate_trigger(function_name: &str, trigger: &HttpTrigger) -> Result<()> {
+    if trigger.path == FALLBACK_PATH {
+        if trigger.prefix || trigger.strip_prefix {
+            return Err(CoupeError::Config(format!(
+                "Function {} uses the `{}` fallback path, which cannot be combined with prefix or strip_prefix",
+                function_name, FALLBACK_PATH
+            )));
+        }
+    } else if !trigger.path.starts_with('/') {
+        return Err(CoupeError::Config(format!(
+            "Path {} of function {} must start with `/`",
+            trigger.path, function_name
+        )));
+    } else if let Some(segment) = trigger
+        .path
+        .split('/')
+        .find(|segment| segment.starts_with(':') || segment.starts_with('*'))
+    {
+        return Err(CoupeError::Config(format!(
+            "Path segment {} of function {} is not supported, use `{{name}}` or `{{*name}}` instead",
+            segment, function_name
+        )));
+    }
+
+    // With credentials, any site could otherwise make credentialed requests and read responses.
+    if let Some(cors) = &trigger.cors
+        && cors.credentials
+        && cors
+            .origins
+            .as_ref()
+            .is_none_or(|origins| origins.iter().any(|origin| origin == "*"))
+    {
+        return Err(CoupeError::Config(format!(
+            "Function {} allows CORS credentials, which needs its origins listed without `*`",
+            function_name
+        )));
+    }
+
+    // Rate limits may only count by identities the trigger's auth verifies.
+    match trigger.rate_limit.as_ref().map(|rate_limit| rate_limit.key) {
+        Some(RateLimitKey::JwtSubject) => {
+            return Err(CoupeError::Config(format!(
+                "Function {} limits requests by JwtSubject, but the sentinel does not verify `jwt` auth yet",
+                function_name
+            )));
+        }
+        Some(RateLimitKey::ApiKey) if !matches!(trigger.auth, Some(HttpAuth::ApiKey { .. })) => {
+            return Err(CoupeError::Config(format!(
+                "Function {} limits requests by ApiKey, which needs `api_key` auth",
+                function_name
+            )));
+        }
+        _ => {}
+    }
+
+    if let Some(rewrite) = &trigger.rewrite {
+        if !rewrite.starts_with('/') {
+            return Err(CoupeError::Config(format!(
+                "Rewrite {} of function {} must start with `/`",
+                rewrite, function_name
+            )));
+        }
+        let params = path_param_names(&trigger.path);
+        for name in path_param_names(rewrite) {
+            let known = params.contains(&name) || (trigger.prefix && name == "rest");
+            if !known {
+                return Err(CoupeError::Config(format!(
+                    "Rewrite {} of function {} references unknown path param {}",
+                    rewrite, function_name, name
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_host(function_name: &str, host: &str) -> Result<()> {
+    let name = host.strip_prefix("*.").unwrap_or(host);
+    if name.is_empty() || name.contains('*') || name.contains('/') || name.contains(':') {
+        return Err(CoupeError::Config(format!(
+            "Host {} of function {} is not a valid host name or `*.` wildcard",
+            host, function_name
+        )));
+    }
+    Ok(())
+}
+
+fn methods_overlap(a: &[HttpMethod], b: &[HttpMethod]) -> bool {
+    a.iter().any(|a| {
+        b.iter()
+            .any(|b| matches!(a, HttpMethod::Any) || matches!(b, HttpMethod::Any) || a == b)
+    })
+}
+
+/// Functions that share a router. Functions without hosts are part of every group, so a host
+/// only needs to list the functions specific to it.
+pub struct RouteGroup {
+    pub host: Option<String>,
+    pub function_names: Vec<String>,
+}
+
+pub fn route_groups(config: &Config) -> Vec<RouteGroup> {
+    let mut function_names = config.http_functions();
+    function_names.sort();
+
+    let mut any_host = Vec::new();
+    let mut by_host: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for function_name in function_names {
+        let Some(trigger) = config
+            .functions
+            .get(&function_name)
+            .and_then(|f| f.trigger.as_http())
+        else {
+            continue;
+        };
+        let hosts = trigger.hosts();
+        if hosts.is_empty() {
+            any_host.push(function_name);
+        } else {
+            for host in hosts {
+                by_host.entry(host).or_default().push(function_name.clone());
+            }
+        }
+    }
+
+    let mut groups = vec![RouteGroup {
+        host: None,
+        function_names: any_host.clone(),
+    }];
+    for (host, mut function_names) in by_host {
+        function_names.extend(any_host.iter().cloned());
+        groups.push(RouteGroup {
+            host: Some(host),
+            function_names,
+        });
+    }
+    groups
+}
+
+fn validate_route_group(config: &Config, group: &RouteGroup) -> Result<()> {
+    let mut registered: HashMap<String, Vec<(String, Vec<HttpMethod>)>> = HashMap::new();
+    let mut matcher = matchit::Router::new();
+    let on_host = group
+        .host
+        .as_ref()
+        .map(|host| format!(" on host {}", host))
+        .unwrap_or_default();
+
+    for function_name in &group.function_names {
+        let Some(trigger) = config
+            .functions
+            .get(function_name)
+            .and_then(|f| f.trigger.as_http())
+        else {
+            continue;
+        };
+
+        let methods = trigger.methods();
+        for path in trigger_route_paths(trigger) {
+            let entries = registered.entry(path.clone()).or_default();
+            if let Some((other, _)) = entries.iter().find(|(_, m)| methods_overlap(m, &methods)) {
+                return Err(CoupeError::Config(format!(
+                    "Route {} of function {} conflicts with function {}{}",
+                    path, function_name, other, on_host
+                )));
+            }
+
+            if entries.is_empty() && path != FALLBACK_PATH {
+                matcher
+                    .insert(path.clone(), function_name.clone())
+                    .map_err(|e| {
+                        CoupeError::Config(match e {
+                            InsertError::Conflict { with } => format!(
+                                "Route {} of function {} conflicts with route {} of function {}{}",
+                                path,
+                                function_name,
+                                with,
+                                registered
+                                    .get(&with)
+                                    .and_then(|entries| entries.first())
+                                    .map(|(name, _)| name.as_str())
+                                    .unwrap_or("unknown"),
+                                on_host
+                            ),
+                            e => format!(
+                                "Invalid route {} for function {}: {}",
+                                path, function_name, e
+                            ),
+                        })
+                    })?;
+            }
+            registered
+                .entry(path)
+                .or_default()
+                .push((function_name.clone(), methods.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks every HTTP trigger for invalid paths and hosts, and for routes that would conflict
+/// with each other, which axum would otherwise only report by panicking while building the
+/// router.
+pub fn validate_function_routes(config: &Config) -> Result<()> {
+    for function_name in config.http_functions() {
+        if let Some(trigger) = config
+            .functions
+            .get(&function_name)
+            .and_then(|f| f.trigger.as_http())
+        {
+            validate_trigger(&function_name, trigger)?;
+            for host in trigger.hosts() {
+                validate_host(&function_name, &host)?;
+            }
+        }
+    }
+
+    for group in route_groups(config) {
+        validate_route_group(config, &group)?;
+    }
+
+    Ok(())
+}
+
+/// Whether a request path would be routed to the trigger.
+pub fn trigger_matches_path(trigger: &HttpTrigger, path: &str) -> bool {
+    let mut matcher = matchit::Router::new();
+    for route_path in trigger_route_paths(trigger) {
+        if route_path == FALLBACK_PATH {
+            return true;
+        }
+        if matcher.insert(route_path, ()).is_err() {
+            return false;
+        }
+    }
+    matcher.at(path).is_ok()
+}
+
+/// Host of the request without its port, taken from the `Host` header or the request URI.
+pub fn request_host(request: &Request<Body>) -> Option<String> {
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| request.uri().host())?;
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next().unwrap_or(ipv6),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    Some(host.to_lowercase())
+}
+
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+/// Finds the most specific host pattern for a request host: an exact match first, then the
+/// longest matching wildcard.
+pub fn select_host<'a, T>(patterns: &'a [(String, T)], host: &str) -> Option<&'a T> {
+    patterns
+        .iter()
+        .filter(|(pattern, _)| host_matches(pattern, host))
+        .max_by_key(|(pattern, _)| (!pattern.starts_with("*."), pattern.len()))
+        .map(|(_, value)| value)
+}
+
+/// Percent-encodes a decoded path param so it can be placed back into a path.
+fn encode_path_value(value: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            b'/' if keep_slashes => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn render_template(template: &str, params: &[(String, String)], rest: &str) -> String {
+    let mut rendered = template.to_string();
+    for (name, value) in params {
+        rendered = rendered.replace(&format!("{{{}}}", name), &encode_path_value(value, false));
+    }
+    rendered.replace("{rest}", &encode_path_value(rest, true))
+}
+
+/// Applies the trigger's path rewriting to the request and forwards its path params as headers.
+pub fn route_request(
+    trigger: &HttpTrigger,
+    params: Option<RawPathParams>,
+    mut request: Request<Body>,
+) -> Request<Body> {
+    let mut rest = String::new();
+    let mut named = Vec::new();
+    for (name, value) in params.iter().flat_map(|p| p.iter()) {
+        if name == REST_PARAM {
+            rest = value.to_string();
+        } else {
+            named.push((name.to_string(), value.to_string()));
+        }
+    }
+
+    let headers = request.headers_mut();
+    let spoofed: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(PARAM_HEADER_PREFIX))
+        .cloned()
+        .collect();
+    for name in spoofed {
+        headers.remove(name);
+    }
+    for (name, value) in &named {
+        let header_name =
+            HeaderName::try_from(format!("{}{}", PARAM_HEADER_PREFIX, name.to_lowercase()));
+        if let (Ok(header_name), Ok(value)) = (header_name, HeaderValue::from_str(value)) {
+            headers.insert(header_name, value);
+        }
+    }
+
+    let path = match (&trigger.rewrite, trigger.strip_prefix) {
+        (Some(template), _) => render_template(template, &named, &rest),
+        (None, true) => format!("/{}", encode_path_value(&rest, true)),
+        (None, false) => return request,
+    };
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let mut parts = request.uri().clone().into_parts();
+    match path_and_query.parse() {
+        Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+        Err(_) => return request,
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(functions: &str) -> Config {
+        serde_yaml::from_str(&format!("name: test\nfunctions:\n{}", functions)).unwrap()
+    }
+
+    fn http_trigger(yaml: &str) -> HttpTrigger {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn prefix_triggers_register_a_catch_all() {
+        let trigger = http_trigger("path: /api/\nprefix: true");
+        assert_eq!(
+            trigger_route_paths(&trigger),
+            vec!["/api".to_string(), format!("/api/{{*{}}}", REST_PARAM)]
+        );
+
+        let root = http_trigger("path: /\nprefix: true");
+        assert_eq!(trigger_route_paths(&root)[0], "/");
+    }
+
+    #[test]
+    fn overlapping_methods_on_the_same_path_conflict() {
+        let config = config(
+            r#"
+  a:
+    image: a
+    trigger: { type: http, path: /users, method: Get }
+  b:
+    image: b
+    trigger: { type: http, path: /users, method: [Post, Get] }
+"#,
+        );
+        let error = validate_function_routes(&config).unwrap_err().to_string();
+        assert!(error.contains("conflicts with function a"), "{}", error);
+    }
+
+    #[test]
+    fn any_method_overlaps_every_method() {
+        assert!(methods_overlap(&[HttpMethod::Any], &[HttpMethod::Delete]));
+        assert!(methods_overlap(&[HttpMethod::Put], &[HttpMethod::Any]));
+        assert!(!methods_overlap(&[HttpMethod::Get], &[HttpMethod::Post]));
+    }
+
+    #[test]
+    fn distinct_methods_share_a_path() {
+        let config = config(
+            r#"
+  read:
+    image: read
+    trigger: { type: http, path: "/users/{id}", method: Get }
+  write:
+    image: write
+    trigger: { type: http, path: "/users/{id}", method: [Put, Delete] }
+"#,
+        );
+        assert!(validate_function_routes(&config).is_ok());
+    }
+
+    #[test]
+    fn differently_named_params_conflict() {
+        let config = config(
+            r#"
+  a:
+    image: a
+    trigger: { type: http, path: "/users/{id}" }
+  b:
+    image: b
+    trigger: { type: http, path: "/users/{name}" }
+"#,
+        );
+        let error = validate_function_routes(&config).unwrap_err().to_string();
+        assert!(error.contains("conflicts with route"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_invalid_paths_and_rewrites() {
+        let cases = [
+            ("path: users", "must start with"),
+            ("path: /users/:id", "is not supported"),
+            ("path: '*'\nprefix: true", "fallback path"),
+            ("path: /users\nrewrite: v2/users", "must start with"),
+            (
+                "path: \"/users/{id}\"\nrewrite: \"/v2/{name}\"",
+                "unknown path param",
+            ),
+            (
+                "path: /users\nrewrite: \"/v2/{rest}\"",
+                "unknown path param",
+            ),
+        ];
+        for (yaml, message) in cases {
+            let error = validate_trigger("f", &http_trigger(yaml))
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains(message), "{}: {}", yaml, error);
+        }
+        assert!(
+            validate_trigger(
+                "f",
+                &http_trigger("path: /api\nprefix: true\nrewrite: \"/v2/{rest}\"")
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn rewrite_encodes_params_and_keeps_slashes_in_rest() {
+        let params = vec![("id".to_string(), "a b/c".to_string())];
+        assert_eq!(
+            render_template("/v2/accounts/{id}/{rest}", &params, "files/x y"),
+            "/v2/accounts/a%20b%2Fc/files/x%20y"
+        );
+    }
+
+    #[test]
+    fn wildcard_hosts_match_subdomains_only() {
+        assert!(host_matches("*.example.com", "api.example.com"));
+        assert!(host_matches("*.example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(host_matches("example.com", "example.com"));
+        assert!(!host_matches("example.com", "api.example.com"));
+    }
+
+    #[test]
+    fn exact_hosts_win_over_wildcards() {
+        let patterns = vec![
+            ("*.example.com".to_string(), "wildcard"),
+            ("*.api.example.com".to_string(), "api wildcard"),
+            ("v1.api.example.com".to_string(), "exact"),
+        ];
+        assert_eq!(select_host(&patterns, "v1.api.example.com"), Some(&"exact"));
+        assert_eq!(
+            select_host(&patterns, "v2.api.example.com"),
+            Some(&"api wildcard")
+        );
+        assert_eq!(select_host(&patterns, "www.example.com"), Some(&"wildcard"));
+        assert_eq!(select_host(&patterns, "example.org"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_host_strings() {
+        for host in [
+            "",
+            "*.",
+            "api.*.com",
+            "*example.com",
+            "example.com:8080",
+            "example.com/api",
+        ] {
+            assert!(validate_host("f", host).is_err(), "{}", host);
+        }
+        assert!(validate_host("f", "*.example.com").is_ok());
+    }
+
+    #[test]
+    fn same_route_on_different_hosts_does_not_conflict() {
+        let config = config(
+            r#"
+  a:
+    image: a
+    trigger: { type: http, path: /, hosts: [a.example.com] }
+  b:
+    image: b
+    trigger: { type: http, path: /, hosts: [b.example.com] }
+"#,
+        );
+        assert!(validate_function_routes(&config).is_ok());
+    }
+
+    #[test]
+    fn host_routes_conflict_with_routes_on_every_host() {
+        let config = config(
+            r#"
+  a:
+    image: a
+    trigger: { type: http, path: /, hosts: [a.example.com] }
+  b:
+    image: b
+    trigger: { type: http, path: / }
+"#,
+        );
+        let error = validate_function_routes(&config).unwrap_err().to_string();
+        assert!(error.contains("on host a.example.com"), "{}", error);
+    }
+
+    #[test]
+    fn trigger_matches_its_routed_paths() {
+        let trigger = http_trigger("path: \"/orders/{id}\"");
+        assert!(trigger_matches_path(&trigger, "/orders/42"));
+        assert!(!trigger_matches_path(&trigger, "/orders"));
+        assert!(!trigger_matches_path(&trigger, "/orders/42/items"));
+
+        let prefix = http_trigger("path: /api\nprefix: true");
+        assert!(trigger_matches_path(&prefix, "/api"));
+        assert!(trigger_matches_path(&prefix, "/api/v1/users"));
+        assert!(!trigger_matches_path(&prefix, "/apis"));
+
+        let fallback = http_trigger(&format!("path: \"{}\"", FALLBACK_PATH));
+        assert!(trigger_matches_path(&fallback, "/anything"));
+    }
+
+    #[test]
+    fn cors_credentials_need_listed_origins() {
+        for cors in [
+            "{ credentials: true }",
+            "{ credentials: true, origins: [\"*\"] }",
+        ] {
+            let config = config(&format!(
+                "  a: {{ image: a, trigger: {{ type: http, path: /a, cors: {} }} }}",
+                cors
+            ));
+            let error = validate_function_routes(&config).unwrap_err().to_string();
+            assert!(error.contains("allows CORS credentials"), "{}", error);
+        }
+        let config = config(
+            "  a: { image: a, trigger: { type: http, path: /a, cors: { credentials: true, origins: [\"https://*.example.com\"] } } }",
+        );
+        assert!(validate_function_routes(&config).is_ok());
+    }
+
+    #[test]
+    fn rate_limits_count_verified_identities() {
+        for (trigger, error) in [
+            (
+                "rate_limit: { requests: 1, key: JwtSubject }, auth: { type: jwt, scopes: [], policies: [] }",
+                "does not verify `jwt` auth",
+            ),
+            (
+                "rate_limit: { requests: 1, key: ApiKey }",
+                "needs `api_key` auth",
+            ),
+        ] {
+            let config = config(&format!(
+                "  a: {{ image: a, trigger: {{ type: http, path: /a, {} }} }}",
+                trigger
+            ));
+            let message = validate_function_routes(&config).unwrap_err().to_string();
+            assert!(message.contains(error), "{}", message);
+        }
+        let config = config(
+            "  a: { image: a, trigger: { type: http, path: /a, rate_limit: { requests: 1, key: ApiKey }, auth: { type: api_key } } }",
+        );
+        assert!(validate_function_routes(&config).is_ok());
+    }
+}
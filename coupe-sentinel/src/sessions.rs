@@ -1,8 +1,8 @@
-use crate::DB;
+use crate::{DB, Sentinel};
 use bincode::{deserialize, serialize};
 use coupe::{
-    Config, CoupeError, Docker, Result, connect_docker, ensure_function_running,
-    stop_function_container,
+    Config, CoupeError, DeploymentTarget, Docker, Result, connect_docker, ensure_function_running,
+    recreate_function_container, stop_function_container,
 };
 use dashmap::DashMap;
 use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
@@ -27,12 +27,40 @@ static SESSION_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(||
 });
 
 pub static DOCKER_CLIENT: LazyLock<Docker> = LazyLock::new(|| {
-    connect_docker(&coupe::DeploymentTarget::Local).expect("Failed to connect to Docker")
+    connect_docker(&DeploymentTarget::Local).expect("Failed to connect to Docker")
 });
 
+/// Clients of the other hosts functions are placed on, keyed by their name in `hosts`.
+static HOST_CLIENTS: LazyLock<DashMap<String, Docker>> = LazyLock::new(DashMap::new);
+
+/// Docker client of the daemon a function's container runs on.
+pub fn function_client(config: &Config, function_name: &str) -> Result<Docker> {
+    let Some(host_name) = config.function_host_name(function_name) else {
+        return Ok(DOCKER_CLIENT.clone());
+    };
+    let client = HOST_CLIENTS
+        .entry(host_name.to_string())
+        .or_try_insert_with(|| {
+            let target = config
+                .sentinel_host_target(host_name)
+                .ok_or_else(|| CoupeError::Config(format!("Host {} not found", host_name)))?;
+            connect_docker(&DeploymentTarget::Remote(target))
+        })?;
+    Ok(client.clone())
+}
+
 type FunctionLock = Arc<TokioMutex<()>>;
 
-static FUNCTION_LOCKS: LazyLock<DashMap<String, FunctionLock>> = LazyLock::new(|| DashMap::new());
+static FUNCTION_LOCKS: LazyLock<DashMap<String, FunctionLock>> = LazyLock::new(DashMap::new);
+
+/// Functions whose container no longer matches their definition after a config reload, with
+/// the config it is to be recreated from.
+static STALE_FUNCTIONS: LazyLock<DashMap<String, Arc<Config>>> = LazyLock::new(DashMap::new);
+
+/// Has the function's container recreated from `config` the next time it starts.
+pub fn mark_function_stale(function_name: &str, config: Arc<Config>) {
+    STALE_FUNCTIONS.insert(function_name.to_string(), config);
+}
 
 async fn get_function_lock(function_name: &str) -> FunctionLock {
     FUNCTION_LOCKS
@@ -111,10 +139,8 @@ pub async fn save_session(input_session: Session) -> Result<Session> {
     let mut next_session = input_session.clone();
     if let Some(existing) = existing_session {
         let existing = Session::try_from(existing.as_ref())?;
-        if !existing.is_expired() {
-            if existing.ends_at > input_session.ends_at {
-                next_session.ends_at = existing.ends_at;
-            }
+        if !existing.is_expired() && existing.ends_at > input_session.ends_at {
+            next_session.ends_at = existing.ends_at;
         }
     }
     info!(
@@ -154,10 +180,8 @@ pub async fn get_session(function_name: String) -> Result<Option<Session>> {
 #[instrument]
 pub async fn get_all_sessions() -> Result<Vec<Session>> {
     let mut sessions = Vec::new();
-    for node in DB.read_tx().iter(&SESSION_STORE) {
-        if let Ok((_, session)) = node {
-            sessions.push(Session::try_from(session.as_ref())?);
-        }
+    for (_, session) in DB.read_tx().iter(&SESSION_STORE).flatten() {
+        sessions.push(Session::try_from(session.as_ref())?);
     }
     Ok(sessions)
 }
@@ -165,26 +189,32 @@ pub async fn get_all_sessions() -> Result<Vec<Session>> {
 #[instrument]
 pub async fn get_expired_sessions() -> Result<Vec<Session>> {
     let mut sessions = Vec::new();
-    for node in DB.read_tx().iter(&SESSION_STORE) {
-        if let Ok((_, session)) = node {
-            let session = Session::try_from(session.as_ref())?;
-            if session.is_expired() {
-                sessions.push(session);
-            }
+    for (_, session) in DB.read_tx().iter(&SESSION_STORE).flatten() {
+        let session = Session::try_from(session.as_ref())?;
+        if session.is_expired() {
+            sessions.push(session);
         }
     }
     Ok(sessions)
 }
 
+/// Response header set to `true` when the function was cold started to handle the request.
+pub const COLD_START_HEADER: &str = "x-coupe-cold-start";
+
+pub struct StartedSession {
+    pub session: Session,
+    /// Whether the function's container had to be started for the session.
+    pub coldstarted: bool,
+}
+
 #[instrument]
-pub async fn start_session(config: &Config, function_name: String) -> Result<Session> {
+pub async fn start_session(config: &Config, function_name: String) -> Result<StartedSession> {
     let function_lock = get_function_lock(&function_name).await;
     let _lock_guard = function_lock.lock().await;
 
     let function_config =
         config
-            .functions
-            .get(function_name.as_str())
+            .function(function_name.as_str())
             .ok_or(CoupeError::InvalidInput(format!(
                 "Function {} not found",
                 function_name
@@ -197,10 +227,29 @@ pub async fn start_session(config: &Config, function_name: String) -> Result<Ses
         "Starting session"
     );
     let start = Instant::now();
-    let run_result =
-        ensure_function_running(&DOCKER_CLIENT, config, function_name.as_str()).await?;
+    // Requests still routed with the config from before a reload get the container of the new
+    // one.
+    let stale_config = STALE_FUNCTIONS
+        .get(&function_name)
+        .map(|config| Arc::clone(&config));
+    let config = stale_config.as_deref().unwrap_or(config);
+    // Local processes are kept running by `coupe-cli dev`.
+    let coldstarted = if config.is_local_function(&function_name) {
+        false
+    } else {
+        let client = function_client(config, &function_name)?;
+        if let Some(stale_config) = &stale_config {
+            recreate_function_container(&client, config, function_name.as_str()).await?;
+            STALE_FUNCTIONS.remove_if(&function_name, |_, marked| {
+                Arc::ptr_eq(marked, stale_config)
+            });
+        }
+        ensure_function_running(&client, config, function_name.as_str())
+            .await?
+            .coldstarted
+    };
     let session = save_session(Session::new(function_name.clone(), session_duration)).await?;
-    if run_result.coldstarted {
+    if coldstarted {
         wait_for_healthcheck(&config.internal_function_healthcheck_url(function_name.as_str())?)
             .await?;
     }
@@ -208,11 +257,14 @@ pub async fn start_session(config: &Config, function_name: String) -> Result<Ses
     info!(
         function_name = %function_name,
         duration = elapsed.as_secs(),
-        coldstarted = run_result.coldstarted,
+        coldstarted = coldstarted,
         "Session started"
     );
 
-    Ok(session)
+    Ok(StartedSession {
+        session,
+        coldstarted,
+    })
 }
 
 async fn wait_for_healthcheck(url: &str) -> Result<()> {
@@ -320,14 +372,35 @@ pub async fn end_session(config: &Config, function_name: String) -> Result<()> {
 
     delete_session(function_name.clone()).await?;
 
-    stop_function_container(&DOCKER_CLIENT, config, function_name.as_str()).await?;
+    if !config.is_local_function(&function_name) {
+        let client = function_client(config, &function_name)?;
+        stop_function_container(&client, config, function_name.as_str()).await?;
+    }
+
+    Ok(())
+}
+
+#[instrument]
+pub async fn recreate_session(config: &Config, function_name: String) -> Result<()> {
+    let function_lock = get_function_lock(&function_name).await;
+    let _lock_guard = function_lock.lock().await;
+
+    info!(function_name = %function_name, "Recreating session");
+
+    delete_session(function_name.clone()).await?;
+
+    if !config.is_local_function(&function_name) {
+        let client = function_client(config, &function_name)?;
+        recreate_function_container(&client, config, function_name.as_str()).await?;
+    }
 
     Ok(())
 }
 
-pub async fn watch_sessions(config: Arc<Config>) -> Result<()> {
+pub async fn watch_sessions(sentinel: Arc<Sentinel>) -> Result<()> {
     loop {
         debug!("Checking for expired sessions");
+        let config = sentinel.config();
         let expired_sessions = get_expired_sessions().await?;
         debug!(count = expired_sessions.len(), "Expired sessions found");
 
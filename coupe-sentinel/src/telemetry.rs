@@ -0,0 +1,41 @@
+use dashmap::DashMap;
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::LazyLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvocationOutcome {
+    Success,
+    FunctionError,
+    ProxyError,
+    Timeout,
+    PayloadTooLarge,
+    ColdStartFailed,
+    CircuitOpen,
+    RateLimited,
+    DeadLettered,
+    Retry,
+}
+
+static INVOCATION_COUNTERS: LazyLock<DashMap<(String, InvocationOutcome), u64>> =
+    LazyLock::new(DashMap::new);
+
+pub fn record_invocation(function_name: &str, outcome: InvocationOutcome) {
+    *INVOCATION_COUNTERS
+        .entry((function_name.to_string(), outcome))
+        .or_default() += 1;
+}
+
+pub type InvocationMetrics = BTreeMap<String, BTreeMap<InvocationOutcome, u64>>;
+
+pub fn get_invocation_metrics() -> InvocationMetrics {
+    let mut metrics = InvocationMetrics::new();
+    for entry in INVOCATION_COUNTERS.iter() {
+        let (function_name, outcome) = entry.key();
+        metrics
+            .entry(function_name.clone())
+            .or_default()
+            .insert(*outcome, *entry.value());
+    }
+    metrics
+}
@@ -0,0 +1,239 @@
+use crate::{Event, deliver_event};
+use coupe::{Config, CoupeError, Result};
+use jiff::{Timestamp, ToSpan, civil::DateTime, tz::TimeZone};
+use std::{sync::Arc, time::Duration};
+use tokio::{spawn, time::sleep};
+use tracing::info;
+
+/// How far ahead a schedule is searched, enough for `0 0 29 2 *` across a skipped leap year.
+const MAX_SCHEDULE_DAYS: i32 = 8 * 366;
+
+/// A five-field cron schedule, `minute hour day-of-month month day-of-week`, evaluated in UTC.
+/// Fields take `*`, numbers, ranges such as `1-5`, steps such as `*/15` and lists of those.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Sunday is 0, and 7 is read as Sunday too.
+    weekdays: u64,
+    /// With both day fields restricted, a day matching either runs, as in cron.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step.parse::<u32>().ok().filter(|s| *s > 0)?)),
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+            None => {
+                let start = range.parse().ok()?;
+                (start, if step.is_some() { max } else { start })
+            }
+        };
+        if start < min || end > max || start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self> {
+        let invalid = |field: &str| {
+            CoupeError::Config(format!("Invalid {} in schedule `{}`", field, schedule))
+        };
+        let fields: Vec<&str> = schedule.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(CoupeError::Config(format!(
+                "Schedule `{}` must have five fields: minute hour day-of-month month day-of-week",
+                schedule
+            )));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7).ok_or(invalid("day of week"))?;
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59).ok_or(invalid("minute"))?,
+            hours: parse_field(hours, 0, 23).ok_or(invalid("hour"))?,
+            days: parse_field(days, 1, 31).ok_or(invalid("day of month"))?,
+            months: parse_field(months, 1, 12).ok_or(invalid("month"))?,
+            weekdays: weekday_bits,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+
+    fn runs_on(&self, date: jiff::civil::Date) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().to_sunday_zero_offset()) != 0;
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time the schedule runs after `time`, or `None` if it never does, such as on
+    /// the 31st of February.
+    pub fn next_after(&self, time: Timestamp) -> Option<Timestamp> {
+        let start: DateTime = time
+            .to_zoned(TimeZone::UTC)
+            .datetime()
+            .with()
+            .second(0)
+            .subsec_nanosecond(0)
+            .build()
+            .ok()?
+            .checked_add(1.minute())
+            .ok()?;
+        let mut date = start.date();
+        for _ in 0..MAX_SCHEDULE_DAYS {
+            if self.runs_on(date) {
+                let first = if date == start.date() {
+                    (start.hour(), start.minute())
+                } else {
+                    (0, 0)
+                };
+                for hour in first.0..24 {
+                    if self.hours & (1 << hour) == 0 {
+                        continue;
+                    }
+                    let from = if hour == first.0 { first.1 } else { 0 };
+                    if let Some(minute) =
+                        (from..60).find(|minute| self.minutes & (1 << minute) != 0)
+                    {
+                        return date
+                            .at(hour, minute, 0, 0)
+                            .to_zoned(TimeZone::UTC)
+                            .ok()
+                            .map(|zoned| zoned.timestamp());
+                    }
+                }
+            }
+            date = date.tomorrow().ok()?;
+        }
+        None
+    }
+}
+
+/// Sends an event to a timer function each time its schedule runs. Deliveries run in the
+/// background, so a slow one does not delay the next run.
+pub async fn run_timer(
+    config: Arc<Config>,
+    function_name: String,
+    schedule: Schedule,
+) -> Result<()> {
+    loop {
+        let now = Timestamp::now();
+        let next = schedule.next_after(now).ok_or(CoupeError::Config(format!(
+            "Schedule of function {} never runs",
+            function_name
+        )))?;
+        let wait = now.duration_until(next);
+        sleep(Duration::try_from(wait).unwrap_or_default()).await;
+
+        let event = Event::new(format!("timer:{}", function_name), Vec::new(), None);
+        info!(function_name = %function_name, event_id = %event.id, "Timer fired");
+        spawn(deliver_event(
+            Arc::clone(&config),
+            function_name.clone(),
+            event,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(schedule: &str, after: &str) -> String {
+        Schedule::parse(schedule)
+            .unwrap()
+            .next_after(after.parse().unwrap())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn runs_at_the_next_matching_minute() {
+        assert_eq!(
+            next("* * * * *", "2026-03-01T10:15:30Z"),
+            "2026-03-01T10:16:00Z"
+        );
+        assert_eq!(
+            next("0 0 * * *", "2026-03-01T10:15:00Z"),
+            "2026-03-02T00:00:00Z"
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-03-01T10:15:00Z"),
+            "2026-03-01T10:30:00Z"
+        );
+        assert_eq!(
+            next("30 9-17 * * *", "2026-03-01T17:30:00Z"),
+            "2026-03-02T09:30:00Z"
+        );
+        assert_eq!(
+            next("0 0 1 1 *", "2026-03-01T00:00:00Z"),
+            "2027-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn day_fields_match_either_when_both_are_set() {
+        // 2026-03-02 is a Monday.
+        assert_eq!(
+            next("0 0 * * 1", "2026-03-01T00:00:00Z"),
+            "2026-03-02T00:00:00Z"
+        );
+        assert_eq!(
+            next("0 0 * * 7", "2026-03-02T00:00:00Z"),
+            "2026-03-08T00:00:00Z"
+        );
+        assert_eq!(
+            next("0 0 15 * 1", "2026-03-02T00:00:00Z"),
+            "2026-03-09T00:00:00Z"
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2026-03-01T00:00:00Z"),
+            "2028-02-29T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn impossible_schedules_never_run() {
+        let schedule = Schedule::parse("0 0 31 2 *").unwrap();
+        assert_eq!(schedule.next_after(Timestamp::UNIX_EPOCH), None);
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for schedule in [
+            "0 0 * *",
+            "0 0 * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Schedule::parse(schedule).is_err(), "{}", schedule);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use axum::{
+    body::Body,
+    http::{
+        HeaderName, HeaderValue, Request,
+        header::{COOKIE, SET_COOKIE},
+    },
+    response::Response,
+};
+use coupe::{CoupeError, Function, PRIMARY_VERSION, Result, VersionRule, version_instance};
+
+/// Response header naming the version of the function that handled the request.
+pub const VERSION_HEADER: &str = "x-coupe-version";
+
+/// Cookie that keeps a client on the version of a function it was first sent to.
+pub fn version_cookie_name(function_name: &str) -> String {
+    format!("coupe_version_{}", function_name)
+}
+
+/// Value of a cookie sent with the request.
+fn request_cookie<'a>(request: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+enum Rule {
+    Header {
+        name: HeaderName,
+        value: Option<String>,
+    },
+    Cookie {
+        name: String,
+        value: Option<String>,
+    },
+}
+
+impl Rule {
+    fn new(rule: &VersionRule) -> Result<Self> {
+        Ok(match rule {
+            VersionRule::Header { name, value } => Rule::Header {
+                name: HeaderName::try_from(name.as_str()).map_err(|e| {
+                    CoupeError::Config(format!("Invalid header name {}: {}", name, e))
+                })?,
+                value: value.clone(),
+            },
+            VersionRule::Cookie { name, value } => Rule::Cookie {
+                name: name.clone(),
+                value: value.clone(),
+            },
+        })
+    }
+
+    fn matches(&self, request: &Request<Body>) -> bool {
+        let (found, expected) = match self {
+            Rule::Header { name, value } => (
+                request.headers().get(name).and_then(|v| v.to_str().ok()),
+                value,
+            ),
+            Rule::Cookie { name, value } => (request_cookie(request, name), value),
+        };
+        match (found, expected) {
+            (Some(found), Some(expected)) => found == expected,
+            (found, None) => found.is_some(),
+            (None, Some(_)) => false,
+        }
+    }
+}
+
+/// The version of a function a request is sent to.
+pub struct SelectedVersion {
+    pub version: String,
+    /// Name the version's session, circuit and metrics are kept under.
+    pub instance: String,
+    /// Whether the response should set the sticky cookie, for clients new to the version.
+    pub sticky: bool,
+}
+
+/// Picks the version of a function each request is sent to: the first version with a rule
+/// matching the request, then with `sticky_versions` the one named by the client's cookie, and
+/// otherwise one at random by weight.
+pub struct VersionSelector {
+    function_name: String,
+    sticky: bool,
+    rules: Vec<(String, Vec<Rule>)>,
+    /// Versions with a weight, ending with the primary version when the others leave it some.
+    weights: Vec<(String, u32)>,
+}
+
+impl VersionSelector {
+    pub fn new(function_name: &str, function: &Function) -> Result<Self> {
+        let mut rules = Vec::new();
+        let mut weights = Vec::new();
+        for (version, definition) in &function.versions {
+            if !definition.rules.is_empty() {
+                rules.push((
+                    version.clone(),
+                    definition
+                        .rules
+                        .iter()
+                        .map(Rule::new)
+                        .collect::<Result<_>>()?,
+                ));
+            }
+            if definition.weight > 0 {
+                weights.push((version.clone(), u32::from(definition.weight)));
+            }
+        }
+        let primary_weight = 100u32.saturating_sub(weights.iter().map(|(_, weight)| weight).sum());
+        if primary_weight > 0 {
+            weights.push((PRIMARY_VERSION.to_string(), primary_weight));
+        }
+        Ok(Self {
+            function_name: function_name.to_string(),
+            sticky: function.sticky_versions,
+            rules,
+            weights,
+        })
+    }
+
+    fn selected(&self, version: &str, sticky: bool) -> SelectedVersion {
+        SelectedVersion {
+            version: version.to_string(),
+            instance: version_instance(&self.function_name, version),
+            sticky,
+        }
+    }
+
+    pub fn select(&self, request: &Request<Body>) -> SelectedVersion {
+        if let Some((version, _)) = self
+            .rules
+            .iter()
+            .find(|(_, rules)| rules.iter().any(|rule| rule.matches(request)))
+        {
+            return self.selected(version, false);
+        }
+        // Versions without a weight only get the requests their rules select.
+        if self.sticky {
+            let cookie = request_cookie(request, &version_cookie_name(&self.function_name));
+            if let Some(version) =
+                cookie.filter(|cookie| self.weights.iter().any(|(version, _)| version == cookie))
+            {
+                return self.selected(version, false);
+            }
+        }
+        self.selected(self.weighted(rand::random_range(0..100)), self.sticky)
+    }
+
+    /// The version a roll in `0..100` lands on, each taking as many values as its weight.
+    fn weighted(&self, mut roll: u32) -> &str {
+        for (version, weight) in &self.weights {
+            if roll < *weight {
+                return version;
+            }
+            roll -= weight;
+        }
+        PRIMARY_VERSION
+    }
+
+    /// Names the version on the response, and sets the sticky cookie when the client is new
+    /// to it.
+    pub fn annotate(&self, selected: &SelectedVersion, response: &mut Response) {
+        if let Ok(value) = HeaderValue::try_from(selected.version.as_str()) {
+            response.headers_mut().insert(VERSION_HEADER, value);
+        }
+        if !selected.sticky {
+            return;
+        }
+        let cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax",
+            version_cookie_name(&self.function_name),
+            selected.version
+        );
+        if let Ok(value) = HeaderValue::try_from(cookie) {
+            response.headers_mut().append(SET_COOKIE, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version_selector(yaml: &str) -> VersionSelector {
+        let function: Function = serde_yaml::from_str(&format!(
+            "image: api\ntrigger: {{ type: http, path: /api }}\n{}",
+            yaml
+        ))
+        .unwrap();
+        VersionSelector::new("api", &function).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::get("/api");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn rolls_land_on_versions_by_weight() {
+        let selector = version_selector(
+            "versions:\n  blue: { image: api:2, weight: 10 }\n  green: { image: api:3, weight: 20 }\n  dark: { image: api:4 }",
+        );
+        assert_eq!(selector.weighted(0), "blue");
+        assert_eq!(selector.weighted(9), "blue");
+        assert_eq!(selector.weighted(10), "green");
+        assert_eq!(selector.weighted(29), "green");
+        assert_eq!(selector.weighted(30), PRIMARY_VERSION);
+        assert_eq!(selector.weighted(99), PRIMARY_VERSION);
+
+        let full = version_selector("versions:\n  blue: { image: api:2, weight: 100 }");
+        assert_eq!(full.weighted(99), "blue");
+        assert!(
+            full.weights
+                .iter()
+                .all(|(version, _)| version != PRIMARY_VERSION)
+        );
+    }
+
+    #[test]
+    fn cookies_are_read_from_every_cookie_header() {
+        let request = request(&[("cookie", "a=1; b = 2"), ("cookie", "c=3;d=4")]);
+        assert_eq!(request_cookie(&request, "a"), Some("1"));
+        assert_eq!(request_cookie(&request, "d"), Some("4"));
+        assert_eq!(request_cookie(&request, "b"), None);
+        assert_eq!(request_cookie(&request, "e"), None);
+    }
+
+    #[test]
+    fn rules_match_headers_and_cookies() {
+        let rule = |yaml: &str| Rule::new(&serde_yaml::from_str(yaml).unwrap()).unwrap();
+        let header = rule("{ type: header, name: x-beta, value: \"yes\" }");
+        assert!(header.matches(&request(&[("x-beta", "yes")])));
+        assert!(!header.matches(&request(&[("x-beta", "no")])));
+        assert!(!header.matches(&request(&[])));
+
+        let any_cookie = rule("{ type: cookie, name: beta }");
+        assert!(any_cookie.matches(&request(&[("cookie", "beta=anything")])));
+        assert!(!any_cookie.matches(&request(&[("cookie", "other=1")])));
+    }
+
+    #[test]
+    fn sticky_cookies_only_keep_weighted_versions() {
+        let selector = version_selector(
+            "sticky_versions: true\nversions:\n  blue: { image: api:2, weight: 10 }\n  dark:\n    image: api:4\n    match: [{ type: header, name: x-dark }]",
+        );
+        let cookie = |version: &str| format!("{}={}", version_cookie_name("api"), version);
+
+        let kept = selector.select(&request(&[("cookie", &cookie("blue"))]));
+        assert_eq!((kept.version.as_str(), kept.sticky), ("blue", false));
+        let primary = selector.select(&request(&[("cookie", &cookie(PRIMARY_VERSION))]));
+        assert_eq!(primary.version, PRIMARY_VERSION);
+        assert!(!primary.sticky);
+
+        // A cookie cannot reach a version only its rules select, and a new one is set instead.
+        let rerolled = selector.select(&request(&[("cookie", &cookie("dark"))]));
+        assert_ne!(rerolled.version, "dark");
+        assert!(rerolled.sticky);
+        let ruled = selector.select(&request(&[("x-dark", "1"), ("cookie", &cookie("blue"))]));
+        assert_eq!((ruled.version.as_str(), ruled.sticky), ("dark", false));
+        assert_eq!(ruled.instance, version_instance("api", "dark"));
+    }
+}
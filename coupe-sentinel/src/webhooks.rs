@@ -0,0 +1,474 @@
+use crate::{DB, DEFAULT_BUFFERED_BODY_SIZE, read_body};
+use axum::{
+    Json,
+    body::Body,
+    extract::State,
+    http::{HeaderMap, HeaderName, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bincode::{deserialize, serialize};
+use coupe::{CoupeError, Result, SignatureEncoding, WebhookAlgorithm, WebhookAuth};
+use fjall::{PartitionCreateOptions, TransactionalPartitionHandle};
+use hmac::{Hmac, Mac};
+use jiff::Timestamp;
+use serde_json::json;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+use tokio::time::sleep;
+use tracing::{debug, error, info, instrument};
+
+static NONCE_STORE: LazyLock<TransactionalPartitionHandle> = LazyLock::new(|| {
+    DB.open_partition("webhook_nonces", PartitionCreateOptions::default())
+        .expect("Failed to open webhook nonces tree")
+});
+
+fn parse_header_name(function_name: &str, name: &str) -> Result<HeaderName> {
+    HeaderName::try_from(name).map_err(|e| {
+        CoupeError::Config(format!(
+            "Invalid webhook header {} for function {}: {}",
+            name, function_name, e
+        ))
+    })
+}
+
+/// Webhook verification settings for a trigger, parsed once when the router is built.
+pub struct WebhookVerifier {
+    function_name: String,
+    auth: WebhookAuth,
+    signature_header: HeaderName,
+    timestamp_header: Option<HeaderName>,
+    nonce_header: Option<HeaderName>,
+    max_body_size: usize,
+}
+
+impl WebhookVerifier {
+    pub fn new(
+        function_name: &str,
+        auth: &WebhookAuth,
+        max_body_size: Option<usize>,
+    ) -> Result<Self> {
+        let payload = auth.signed_payload();
+        if !payload.contains("{body}") {
+            return Err(CoupeError::Config(format!(
+                "Webhook signed_payload of function {} must contain `{{body}}`",
+                function_name
+            )));
+        }
+        // An unsigned timestamp or nonce can be replaced on a captured delivery to replay it.
+        for (placeholder, header, key) in [
+            ("{timestamp}", &auth.timestamp_header, "timestamp_header"),
+            ("{nonce}", &auth.nonce_header, "nonce_header"),
+        ] {
+            match (payload.contains(placeholder), header.is_some()) {
+                (true, false) => {
+                    return Err(CoupeError::Config(format!(
+                        "Webhook signed_payload of function {} uses {} but {} is not set",
+                        function_name, placeholder, key
+                    )));
+                }
+                (false, true) => {
+                    return Err(CoupeError::Config(format!(
+                        "Webhook {} of function {} is not signed, add {} to signed_payload",
+                        key, function_name, placeholder
+                    )));
+                }
+                _ => {}
+            }
+        }
+        // Nonces are only remembered for a while, so without a timestamp an old delivery could be
+        // replayed once its nonce is forgotten.
+        if auth.nonce_header.is_some() && auth.timestamp_header.is_none() {
+            return Err(CoupeError::Config(format!(
+                "Webhook nonce_header of function {} needs timestamp_header",
+                function_name
+            )));
+        }
+
+        Ok(Self {
+            function_name: function_name.to_string(),
+            signature_header: parse_header_name(function_name, &auth.signature_header)?,
+            timestamp_header: auth
+                .timestamp_header
+                .as_deref()
+                .map(|h| parse_header_name(function_name, h))
+                .transpose()?,
+            nonce_header: auth
+                .nonce_header
+                .as_deref()
+                .map(|h| parse_header_name(function_name, h))
+                .transpose()?,
+            auth: auth.clone(),
+            max_body_size: max_body_size.unwrap_or(DEFAULT_BUFFERED_BODY_SIZE),
+        })
+    }
+
+    fn signed_payload(&self, body: &[u8], timestamp: &str, nonce: &str) -> Vec<u8> {
+        let (before, after) = self
+            .auth
+            .signed_payload()
+            .split_once("{body}")
+            .unwrap_or(("", ""));
+        let fill = |part: &str| {
+            part.replace("{timestamp}", timestamp)
+                .replace("{nonce}", nonce)
+                .into_bytes()
+        };
+        [fill(before), body.to_vec(), fill(after)].concat()
+    }
+
+    fn signatures(&self, value: &str) -> Vec<Vec<u8>> {
+        value
+            .split_whitespace()
+            .filter_map(|candidate| match &self.auth.signature_prefix {
+                Some(prefix) => candidate.strip_prefix(prefix.as_str()),
+                None => Some(candidate),
+            })
+            .filter_map(|signature| match self.auth.encoding {
+                SignatureEncoding::Hex => decode_hex(signature),
+                SignatureEncoding::Base64 => STANDARD.decode(signature).ok(),
+            })
+            .collect()
+    }
+
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> bool {
+        let secret = self.auth.secret.as_bytes();
+        match self.auth.algorithm {
+            WebhookAlgorithm::HmacSha1 => verify_hmac::<Hmac<Sha1>>(secret, payload, signature),
+            WebhookAlgorithm::HmacSha256 => verify_hmac::<Hmac<Sha256>>(secret, payload, signature),
+            WebhookAlgorithm::HmacSha512 => verify_hmac::<Hmac<Sha512>>(secret, payload, signature),
+        }
+    }
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(
+    secret: &[u8],
+    payload: &[u8],
+    signature: &[u8],
+) -> bool {
+    let Ok(mut mac) = <M as Mac>::new_from_slice(secret) else {
+        return false;
+    };
+    mac.update(payload);
+    mac.verify_slice(signature).is_ok()
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Records a delivery id, returning `false` if it was already seen within its lifetime.
+#[instrument]
+pub async fn claim_webhook_nonce(function_name: &str, nonce: &str, ttl: Duration) -> Result<bool> {
+    let key = format!("{}/{}", function_name, nonce);
+    let now = Timestamp::now().as_nanosecond();
+    let mut tx = DB.write_tx();
+    if let Some(value) = tx
+        .get(&NONCE_STORE, &key)
+        .map_err(|e| CoupeError::Database(e.to_string()))?
+    {
+        let expires_at: i128 =
+            deserialize(&value).map_err(|e| CoupeError::Database(e.to_string()))?;
+        if expires_at > now {
+            return Ok(false);
+        }
+    }
+    let expires_at = now + ttl.as_nanos() as i128;
+    tx.insert(
+        &NONCE_STORE,
+        &key,
+        serialize(&expires_at).map_err(|e| CoupeError::Database(e.to_string()))?,
+    );
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    Ok(true)
+}
+
+#[instrument]
+pub async fn prune_webhook_nonces() -> Result<usize> {
+    let now = Timestamp::now().as_nanosecond();
+    let mut tx = DB.write_tx();
+    let mut expired = Vec::new();
+    for item in tx.iter(&NONCE_STORE) {
+        let (key, value) = item.map_err(|e| CoupeError::Database(e.to_string()))?;
+        let expires_at: i128 =
+            deserialize(&value).map_err(|e| CoupeError::Database(e.to_string()))?;
+        if expires_at <= now {
+            expired.push(key);
+        }
+    }
+    let count = expired.len();
+    for key in expired {
+        tx.remove(&NONCE_STORE, key);
+    }
+    tx.commit()
+        .map_err(|e| CoupeError::Database(e.to_string()))?;
+    Ok(count)
+}
+
+pub async fn watch_webhook_nonces() -> Result<()> {
+    loop {
+        match prune_webhook_nonces().await {
+            Ok(count) => debug!(count = count, "Expired webhook nonces pruned"),
+            Err(e) => error!(error = %e, "Failed to prune webhook nonces"),
+        }
+        sleep(Duration::from_secs(60)).await;
+    }
+}
+
+fn rejected(status: StatusCode, message: &str) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+/// Middleware that only lets webhooks with a valid signature, a recent timestamp and an unseen
+/// delivery id through to the function.
+pub async fn verify_webhook(
+    State(verifier): State<Arc<WebhookVerifier>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let headers = request.headers();
+    let Some(signature) = header_str(headers, &verifier.signature_header).map(str::to_string)
+    else {
+        return rejected(StatusCode::UNAUTHORIZED, "Missing webhook signature");
+    };
+
+    let timestamp = match &verifier.timestamp_header {
+        Some(header) => {
+            let Some(timestamp) = header_str(headers, header) else {
+                return rejected(StatusCode::UNAUTHORIZED, "Missing webhook timestamp");
+            };
+            let Ok(seconds) = timestamp.trim().parse::<i64>() else {
+                return rejected(StatusCode::UNAUTHORIZED, "Invalid webhook timestamp");
+            };
+            let skew = Timestamp::now().as_second().abs_diff(seconds);
+            if skew > verifier.auth.tolerance().as_secs() {
+                return rejected(
+                    StatusCode::UNAUTHORIZED,
+                    "Webhook timestamp is outside the tolerance",
+                );
+            }
+            timestamp.trim().to_string()
+        }
+        None => String::new(),
+    };
+
+    let nonce = match &verifier.nonce_header {
+        Some(header) => match header_str(headers, header) {
+            Some(nonce) => Some(nonce.to_string()),
+            None => return rejected(StatusCode::UNAUTHORIZED, "Missing webhook delivery id"),
+        },
+        None => None,
+    };
+
+    let (parts, body) = match read_body(request, verifier.max_body_size).await {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+
+    let payload = verifier.signed_payload(&body, &timestamp, nonce.as_deref().unwrap_or_default());
+    let verified = verifier
+        .signatures(&signature)
+        .iter()
+        .any(|signature| verifier.verify(&payload, signature));
+    if !verified {
+        info!(function_name = %verifier.function_name, "Webhook signature rejected");
+        return rejected(StatusCode::UNAUTHORIZED, "Invalid webhook signature");
+    }
+
+    // Nonces are claimed only after the signature checks out, so unsigned requests cannot
+    // fill the cache or block real deliveries.
+    if let Some(nonce) = &nonce {
+        // A timestamp is accepted from `tolerance` before now until `tolerance` after, so its
+        // nonce is kept for both.
+        let retention = verifier.auth.tolerance() * 2;
+        match claim_webhook_nonce(&verifier.function_name, nonce, retention).await {
+            Ok(true) => {}
+            Ok(false) => {
+                info!(function_name = %verifier.function_name, nonce = %nonce, "Webhook replay rejected");
+                return rejected(StatusCode::CONFLICT, "Webhook already delivered");
+            }
+            Err(e) => {
+                error!(function_name = %verifier.function_name, error = %e, "Failed to check webhook nonce");
+                return rejected(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to check webhook delivery id",
+                );
+            }
+        }
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set_db_dir;
+    use axum::{Router, middleware::from_fn_with_state, routing::post};
+    use std::{env, process};
+    use tower_service::Service;
+
+    const SECRET: &str = "whsec";
+
+    fn auth(yaml: &str) -> WebhookAuth {
+        serde_yaml::from_str(&format!(
+            "secret: {}\nsignature_header: x-signature\n{}",
+            SECRET, yaml
+        ))
+        .unwrap()
+    }
+
+    fn sign(payload: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn router(auth: &WebhookAuth) -> Router {
+        set_db_dir(env::temp_dir().join(format!("coupe-sentinel-test-{}", process::id())));
+        let verifier = Arc::new(WebhookVerifier::new("hooks", auth, None).unwrap());
+        Router::new()
+            .route("/", post(|| async { "delivered" }))
+            .layer(from_fn_with_state(verifier, verify_webhook))
+    }
+
+    async fn deliver(router: &mut Router, headers: &[(&str, &str)], body: &str) -> StatusCode {
+        let mut request = Request::post("/");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        router.call(request).await.unwrap().status()
+    }
+
+    #[test]
+    fn decodes_hex_signatures() {
+        assert_eq!(decode_hex("00ffA0"), Some(vec![0x00, 0xff, 0xa0]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("é0"), None);
+    }
+
+    #[test]
+    fn timestamp_and_nonce_headers_must_be_signed() {
+        for yaml in [
+            "timestamp_header: x-timestamp",
+            "nonce_header: x-delivery",
+            "timestamp_header: x-timestamp\nnonce_header: x-delivery\nsigned_payload: \"{timestamp}.{body}\"",
+        ] {
+            let error = WebhookVerifier::new("hooks", &auth(yaml), None)
+                .err()
+                .unwrap()
+                .to_string();
+            assert!(error.contains("is not signed"), "{}: {}", yaml, error);
+        }
+    }
+
+    #[test]
+    fn nonces_need_a_timestamp() {
+        let error = WebhookVerifier::new(
+            "hooks",
+            &auth("nonce_header: x-delivery\nsigned_payload: \"{nonce}.{body}\""),
+            None,
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(error.contains("needs timestamp_header"), "{}", error);
+    }
+
+    #[test]
+    fn signed_placeholders_need_their_headers() {
+        let error =
+            WebhookVerifier::new("hooks", &auth("signed_payload: \"{nonce}.{body}\""), None)
+                .err()
+                .unwrap()
+                .to_string();
+        assert!(error.contains("nonce_header is not set"), "{}", error);
+
+        let error = WebhookVerifier::new("hooks", &auth("signed_payload: \"{timestamp}\""), None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("must contain `{body}`"), "{}", error);
+    }
+
+    #[test]
+    fn verifies_any_of_several_prefixed_signatures() {
+        let verifier =
+            WebhookVerifier::new("hooks", &auth("signature_prefix: \"v1=\""), None).unwrap();
+        let header = format!("v0=00 v1={}", sign("payload"));
+        let signatures = verifier.signatures(&header);
+        assert_eq!(signatures.len(), 1);
+        assert!(verifier.verify(b"payload", &signatures[0]));
+        assert!(!verifier.verify(b"tampered", &signatures[0]));
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_and_re_stamped_deliveries() {
+        let mut router = router(&auth(
+            "timestamp_header: x-timestamp\nnonce_header: x-delivery\nsigned_payload: \"{timestamp}.{nonce}.{body}\"",
+        ));
+        let timestamp = Timestamp::now().as_second().to_string();
+        let nonce = Timestamp::now().as_nanosecond().to_string();
+        let signature = sign(&format!("{}.{}.{{}}", timestamp, nonce));
+        let headers = [
+            ("x-signature", signature.as_str()),
+            ("x-timestamp", timestamp.as_str()),
+            ("x-delivery", nonce.as_str()),
+        ];
+
+        assert_eq!(deliver(&mut router, &headers, "{}").await, StatusCode::OK);
+        assert_eq!(
+            deliver(&mut router, &headers, "{}").await,
+            StatusCode::CONFLICT
+        );
+
+        // A fresh delivery id or timestamp breaks the signature.
+        let fresh_nonce = format!("{}-2", nonce);
+        let restamped = [
+            ("x-signature", signature.as_str()),
+            ("x-timestamp", timestamp.as_str()),
+            ("x-delivery", fresh_nonce.as_str()),
+        ];
+        assert_eq!(
+            deliver(&mut router, &restamped, "{}").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_timestamps() {
+        let mut router = router(&auth(
+            "timestamp_header: x-timestamp\nsigned_payload: \"{timestamp}.{body}\"\ntolerance: 60",
+        ));
+        let timestamp = (Timestamp::now().as_second() - 120).to_string();
+        let signature = sign(&format!("{}.{{}}", timestamp));
+        let headers = [
+            ("x-signature", signature.as_str()),
+            ("x-timestamp", timestamp.as_str()),
+        ];
+        assert_eq!(
+            deliver(&mut router, &headers, "{}").await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+}
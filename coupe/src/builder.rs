@@ -0,0 +1,457 @@
+use crate::{
+    Build, Config, CoupeError, Result, function_docker, registry_credentials, split_image,
+};
+use bollard::{Docker, body_full, query_parameters::BuildImageOptionsBuilder};
+use bytes::Bytes;
+use futures::StreamExt;
+use glob::{MatchOptions, Pattern};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info};
+
+const DOCKERIGNORE: &str = ".dockerignore";
+
+/// A `.dockerignore` rule. Later rules override earlier ones.
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+}
+
+fn read_dockerignore(context: &Path) -> Result<Vec<IgnoreRule>> {
+    let Ok(content) = fs::read_to_string(context.join(DOCKERIGNORE)) else {
+        return Ok(Vec::new());
+    };
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern.trim()),
+                None => (false, line),
+            };
+            let pattern = pattern
+                .trim_start_matches("./")
+                .trim_start_matches('/')
+                .trim_end_matches('/');
+            let pattern = Pattern::new(pattern).map_err(|e| {
+                CoupeError::Config(format!("Invalid .dockerignore pattern {}: {}", line, e))
+            })?;
+            Ok(IgnoreRule { pattern, negated })
+        })
+        .collect()
+}
+
+/// Whether a path, or one of the directories it is in, is excluded by the rules.
+fn is_ignored(rules: &[IgnoreRule], path: &str) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let mut ignored = false;
+    for rule in rules {
+        let matches = path
+            .match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain([path])
+            .any(|prefix| rule.pattern.matches_with(prefix, options));
+        if matches {
+            ignored = !rule.negated;
+        }
+    }
+    ignored
+}
+
+/// Files of a build context, as paths relative to it, in a stable order.
+fn context_files(context: &Path, rules: &[IgnoreRule], dockerfile: &str) -> Result<Vec<String>> {
+    // Ignored directories can only be skipped when no rule adds files back.
+    let can_prune = rules.iter().all(|rule| !rule.negated);
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(context.join(&dir)).map_err(CoupeError::Io)? {
+            let entry = entry.map_err(CoupeError::Io)?;
+            let path = dir.join(entry.file_name());
+            let relative = path.to_string_lossy().replace('\\', "/");
+            let file_type = entry.file_type().map_err(CoupeError::Io)?;
+            let ignored =
+                is_ignored(rules, &relative) && relative != dockerfile && relative != DOCKERIGNORE;
+            if file_type.is_dir() {
+                if !(ignored && can_prune) {
+                    dirs.push(path);
+                }
+            } else if !ignored {
+                files.push(relative);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Hash of everything that affects the image: the context files and the build settings.
+fn context_hash(context: &Path, files: &[String], build: &Build) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        let path = context.join(file);
+        hasher.update(file.as_bytes());
+        hasher.update([0]);
+        let metadata = fs::symlink_metadata(&path).map_err(CoupeError::Io)?;
+        if metadata.is_symlink() {
+            let target = fs::read_link(&path).map_err(CoupeError::Io)?;
+            hasher.update(target.to_string_lossy().as_bytes());
+        } else {
+            hasher.update(fs::read(&path).map_err(CoupeError::Io)?);
+        }
+        hasher.update([0]);
+    }
+    hasher.update(build.dockerfile().as_bytes());
+    let mut args: Vec<_> = build.args.iter().collect();
+    args.sort();
+    for (key, value) in args {
+        hasher.update(format!("\0{}={}", key, value).as_bytes());
+    }
+    if let Some(target) = &build.target {
+        hasher.update(format!("\0target={}", target).as_bytes());
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Cheap fingerprint of a build context from the size and modification time of its files, to
+/// notice changes without reading them.
+pub fn context_fingerprint(context: &Path, dockerfile: &str) -> Result<String> {
+    let rules = read_dockerignore(context)?;
+    let files = context_files(context, &rules, dockerfile)?;
+    let mut hasher = Sha256::new();
+    for file in &files {
+        let metadata = fs::symlink_metadata(context.join(file)).map_err(CoupeError::Io)?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        hasher
+            .update(format!("{}\0{}\0{}\0", file, metadata.len(), modified.as_nanos()).as_bytes());
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+fn context_archive(context: &Path, files: &[String]) -> Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(Vec::new());
+    archive.follow_symlinks(false);
+    for file in files {
+        archive
+            .append_path_with_name(context.join(file), file)
+            .map_err(CoupeError::Io)?;
+    }
+    archive.into_inner().map_err(CoupeError::Io)
+}
+
+/// Tag for a build: the stack version, if any, and the start of the context hash.
+fn build_tag(config: &Config, hash: &str) -> String {
+    let hash = &hash[..12];
+    match &config.version {
+        Some(version) => {
+            let version: String = version
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' | '-' => c,
+                    _ => '-',
+                })
+                .collect();
+            format!("{}-{}", version, hash)
+        }
+        None => hash.to_string(),
+    }
+}
+
+/// Builds a function's image on the Docker daemon, unless an image of the same context hash
+/// already exists there. Returns the image tag.
+pub async fn build_function_image(
+    client: &Docker,
+    config: &Config,
+    function_name: &str,
+    base_dir: &Path,
+) -> Result<String> {
+    let build = config
+        .functions
+        .get(function_name)
+        .and_then(|f| f.build.as_ref())
+        .ok_or_else(|| {
+            CoupeError::Config(format!("Function {} has no build section", function_name))
+        })?;
+    let context = base_dir.join(&build.context);
+    if !context.is_dir() {
+        return Err(CoupeError::Config(format!(
+            "Build context {} of function {} is not a directory",
+            context.display(),
+            function_name
+        )));
+    }
+
+    let rules = read_dockerignore(&context)?;
+    let files = context_files(&context, &rules, build.dockerfile())?;
+    if !files.iter().any(|file| file == build.dockerfile()) {
+        return Err(CoupeError::Config(format!(
+            "Dockerfile {} not found in build context of function {}",
+            build.dockerfile(),
+            function_name
+        )));
+    }
+    let hash = context_hash(&context, &files, build)?;
+    let image = config.function_image(function_name)?;
+    let (repository, _) = split_image(image.split('@').next().unwrap_or(&image));
+    let tag = format!("{}:{}", repository, build_tag(config, &hash));
+
+    if client.inspect_image(&tag).await.is_ok() {
+        info!(function_name = %function_name, tag = %tag, "Build context unchanged, skipping build");
+        println!("{} is up to date", tag);
+        return Ok(tag);
+    }
+
+    info!(
+        function_name = %function_name,
+        tag = %tag,
+        files = files.len(),
+        "Building function image"
+    );
+    println!("Building {} from {}", tag, context.display());
+    let archive = context_archive(&context, &files)?;
+    debug!(function_name = %function_name, size = archive.len(), "Build context archived");
+
+    let mut options = BuildImageOptionsBuilder::new()
+        .dockerfile(build.dockerfile())
+        .t(&tag)
+        .rm(true)
+        .buildargs(&build.args)
+        .labels(&HashMap::from([
+            ("coupe.stack", config.name.as_str()),
+            ("coupe.function", function_name),
+        ]));
+    if let Some(target) = &build.target {
+        options = options.target(target);
+    }
+    let credentials = config.registry().and_then(|registry| {
+        registry_credentials(config, &registry.url)
+            .map(|credentials| HashMap::from([(registry.url.clone(), credentials)]))
+    });
+
+    let mut output = client.build_image(
+        options.build(),
+        credentials,
+        Some(body_full(Bytes::from(archive))),
+    );
+    while let Some(info) = output.next().await {
+        let info = info.map_err(|e| {
+            error!(function_name = %function_name, error = %e, "Failed to build image");
+            CoupeError::Docker(format!("Failed to build {}: {}", tag, e))
+        })?;
+        if let Some(e) = info.error {
+            error!(function_name = %function_name, error = %e, "Failed to build image");
+            return Err(CoupeError::Docker(format!(
+                "Failed to build {}: {}",
+                tag, e
+            )));
+        }
+        if let Some(stream) = info.stream {
+            print!("{}", stream);
+            std::io::stdout().flush().ok();
+        }
+    }
+
+    info!(function_name = %function_name, tag = %tag, "Function image built");
+    Ok(tag)
+}
+
+/// Builds the images of functions with a `build` section, on the host each is placed on.
+/// Returns the config with those functions referring to the built tags, ready to be deployed.
+pub async fn build_images(client: &Docker, config: &Config, base_dir: &Path) -> Result<Config> {
+    let mut config = config.clone();
+    let mut function_names: Vec<String> = config
+        .functions
+        .iter()
+        .filter(|(_, function)| function.build.is_some())
+        .map(|(name, _)| name.clone())
+        .collect();
+    function_names.sort();
+
+    for function_name in function_names {
+        let client = function_docker(&config, client, &function_name)?;
+        let tag = build_function_image(&client, &config, &function_name, base_dir).await?;
+        if let Some(function) = config.functions.get_mut(&function_name) {
+            function.image = tag;
+            function.build = None;
+        }
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_DOCKERFILE;
+    use std::{
+        env, process,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    /// A build context in the temp directory, removed when dropped.
+    struct Context(PathBuf);
+
+    impl Context {
+        fn new(files: &[(&str, &str)]) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = env::temp_dir().join(format!(
+                "coupe-build-{}-{}",
+                process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            for (file, content) in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, content).unwrap();
+            }
+            Self(dir)
+        }
+
+        fn files(&self) -> Vec<String> {
+            let rules = read_dockerignore(&self.0).unwrap();
+            context_files(&self.0, &rules, DEFAULT_DOCKERFILE).unwrap()
+        }
+    }
+
+    impl Drop for Context {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn rules(lines: &[&str]) -> Vec<IgnoreRule> {
+        let context = Context::new(&[(DOCKERIGNORE, &lines.join("\n"))]);
+        read_dockerignore(&context.0).unwrap()
+    }
+
+    fn build(yaml: &str) -> Build {
+        serde_yaml::from_str(&format!("context: .\n{}", yaml)).unwrap()
+    }
+
+    #[test]
+    fn dockerignore_lines_are_normalised() {
+        let rules = rules(&["# comment", "", "./dist/", "/target", " ! dist/keep "]);
+        let patterns: Vec<_> = rules
+            .iter()
+            .map(|rule| (rule.pattern.as_str(), rule.negated))
+            .collect();
+        assert_eq!(
+            patterns,
+            [("dist", false), ("target", false), ("dist/keep", true)]
+        );
+
+        let context = Context::new(&[(DOCKERIGNORE, "[")]);
+        assert!(read_dockerignore(&context.0).is_err());
+        let missing = Context::new(&[]);
+        assert!(read_dockerignore(&missing.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rules_match_globs_directories_and_negations() {
+        let rules = rules(&["*.log", "**/*.tmp", "node_modules", "!keep.log"]);
+        assert!(is_ignored(&rules, "debug.log"));
+        assert!(!is_ignored(&rules, "logs/debug.log"));
+        assert!(is_ignored(&rules, "a.tmp"));
+        assert!(is_ignored(&rules, "deep/down/a.tmp"));
+        assert!(is_ignored(&rules, "node_modules"));
+        assert!(is_ignored(&rules, "node_modules/pkg/index.js"));
+        assert!(!is_ignored(&rules, "src/node_modules_list.rs"));
+        assert!(!is_ignored(&rules, "keep.log"));
+
+        // The last matching rule wins.
+        let rules = self::rules(&["!keep.log", "*.log"]);
+        assert!(is_ignored(&rules, "keep.log"));
+    }
+
+    #[test]
+    fn context_files_skip_ignored_paths() {
+        let context = Context::new(&[
+            (DOCKERIGNORE, "*\n!src\n"),
+            (DEFAULT_DOCKERFILE, "FROM scratch"),
+            ("src/main.rs", ""),
+            ("src/lib/mod.rs", ""),
+            ("notes.txt", ""),
+        ]);
+        // The Dockerfile and .dockerignore are sent even when ignored.
+        assert_eq!(
+            context.files(),
+            [
+                DOCKERIGNORE,
+                DEFAULT_DOCKERFILE,
+                "src/lib/mod.rs",
+                "src/main.rs"
+            ]
+        );
+
+        let negated = Context::new(&[
+            (DOCKERIGNORE, "vendor\n!vendor/keep.txt\n"),
+            ("vendor/drop.txt", ""),
+            ("vendor/keep.txt", ""),
+        ]);
+        assert_eq!(negated.files(), [DOCKERIGNORE, "vendor/keep.txt"]);
+    }
+
+    #[test]
+    fn context_hashes_follow_content_and_settings() {
+        let context = Context::new(&[("a.txt", "a"), ("b/c.txt", "c")]);
+        let files = context.files();
+        let build = build("args: { A: \"1\", B: \"2\" }");
+        let hash = context_hash(&context.0, &files, &build).unwrap();
+        assert_eq!(hash, context_hash(&context.0, &files, &build).unwrap());
+        assert_eq!(
+            hash,
+            context_hash(
+                &context.0,
+                &files,
+                &self::build("args: { B: \"2\", A: \"1\" }")
+            )
+            .unwrap()
+        );
+        for other in [
+            "args: { A: \"1\" }",
+            "args: { A: \"1\", B: \"2\" }\ntarget: dev",
+        ] {
+            assert_ne!(
+                hash,
+                context_hash(&context.0, &files, &self::build(other)).unwrap()
+            );
+        }
+
+        fs::write(context.0.join("a.txt"), "changed").unwrap();
+        assert_ne!(hash, context_hash(&context.0, &files, &build).unwrap());
+    }
+
+    #[test]
+    fn tags_join_the_version_and_hash() {
+        let hash = "0123456789abcdef";
+        let config = |version: &str| -> Config {
+            serde_yaml::from_str(&format!("name: test\n{}functions: {{}}", version)).unwrap()
+        };
+        assert_eq!(build_tag(&config(""), hash), "0123456789ab");
+        assert_eq!(
+            build_tag(&config("version: 1.0+beta/x\n"), hash),
+            "1.0-beta-x-0123456789ab"
+        );
+    }
+}
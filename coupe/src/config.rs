@@ -1,7 +1,16 @@
-use crate::{CoupeError, Result};
+use crate::{
+    CoupeError, HOST_IDENTITY_FILE, HOST_KNOWN_HOSTS_FILE, HOST_TLS_DIR, Result, SENTINEL_HOSTS_DIR,
+};
 use openapi::{Operations, Schema};
 use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::SerializeMap};
-use std::{collections::HashMap, fs, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -28,6 +37,75 @@ pub enum HttpAuth {
         scopes: Vec<String>,
         policies: Vec<String>,
     },
+    /// Keys issued through the sentinel's `/system/api-keys` endpoints. Keys are read from
+    /// `header`, or from the `query` parameter when one is set.
+    #[serde(rename = "api_key")]
+    ApiKey {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        header: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        query: Option<String>,
+    },
+    /// HMAC-signed webhooks, as sent by payment and git providers.
+    #[serde(rename = "webhook")]
+    Webhook(Box<WebhookAuth>),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum WebhookAlgorithm {
+    HmacSha1,
+    #[default]
+    HmacSha256,
+    HmacSha512,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+pub const DEFAULT_WEBHOOK_TOLERANCE_SECS: u64 = 300;
+pub const DEFAULT_SIGNED_PAYLOAD: &str = "{body}";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookAuth {
+    pub secret: String,
+    #[serde(default)]
+    pub algorithm: WebhookAlgorithm,
+    pub signature_header: String,
+    /// Prefix before the signature in the header, such as `sha256=`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature_prefix: Option<String>,
+    #[serde(default)]
+    pub encoding: SignatureEncoding,
+    /// Header holding the UNIX timestamp of the delivery, in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp_header: Option<String>,
+    /// Header holding a unique delivery id, which needs `timestamp_header`. Seen ids are
+    /// rejected for twice `tolerance`, as long as a delivery's timestamp can be accepted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce_header: Option<String>,
+    /// What is signed, with `{body}`, `{timestamp}` and `{nonce}` placeholders. Defaults to
+    /// the body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_payload: Option<String>,
+    /// Seconds a timestamp may differ from the sentinel's clock.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<u64>,
+}
+
+impl WebhookAuth {
+    pub fn tolerance(&self) -> Duration {
+        Duration::from_secs(self.tolerance.unwrap_or(DEFAULT_WEBHOOK_TOLERANCE_SECS))
+    }
+
+    pub fn signed_payload(&self) -> &str {
+        self.signed_payload
+            .as_deref()
+            .unwrap_or(DEFAULT_SIGNED_PAYLOAD)
+    }
 }
 
 fn serialize_schema<T, S>(
@@ -66,7 +144,7 @@ where
     }))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum HttpMethod {
     Any,
     Get,
@@ -74,94 +152,929 @@ pub enum HttpMethod {
     Put,
     Delete,
     Patch,
+    Head,
+    Options,
+}
+
+fn deserialize_methods<'de, D>(deserializer: D) -> Result<Option<Vec<HttpMethod>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(HttpMethod),
+        Many(Vec<HttpMethod>),
+    }
+
+    let raw: Option<OneOrMany> = Option::deserialize(deserializer)?;
+    Ok(raw.map(|methods| match methods {
+        OneOrMany::One(method) => vec![method],
+        OneOrMany::Many(methods) => methods,
+    }))
+}
+
+pub const DEFAULT_RETRY_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_BACKOFF_MS: u64 = 100;
+pub const DEFAULT_RETRY_MAX_BACKOFF_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RetryPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_attempts: Option<u32>,
+    /// Delay in milliseconds before the first retry, doubled on every following attempt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_backoff: Option<u64>,
+    /// Wait a random time up to the backoff instead of the full backoff, so retries from many
+    /// clients are spread out.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub jitter: bool,
+}
+
+impl RetryPolicy {
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+            .unwrap_or(DEFAULT_RETRY_MAX_ATTEMPTS)
+            .max(1)
+    }
+
+    /// Backoff before the given retry, where `retry` starts at 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let initial = self.backoff.unwrap_or(DEFAULT_RETRY_BACKOFF_MS);
+        let max = self.max_backoff.unwrap_or(DEFAULT_RETRY_MAX_BACKOFF_MS);
+        let delay = initial.saturating_mul(1u64 << retry.saturating_sub(1).min(32));
+        Duration::from_millis(delay.min(max))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Cors {
+    /// Allowed origins. `*` allows any origin and `https://*.example.com` any subdomain.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origins: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub methods: Option<Vec<HttpMethod>>,
+    /// Allowed request headers. `*` allows any header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expose_headers: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub credentials: bool,
+    /// Seconds browsers may cache a preflight response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct HeaderRules {
+    /// Headers to set, replacing any existing value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub add: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remove: Option<Vec<String>>,
+}
+
+pub const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Hsts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub include_subdomains: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preload: bool,
+}
+
+impl Hsts {
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.unwrap_or(DEFAULT_HSTS_MAX_AGE));
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct HeaderPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request: Option<HeaderRules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<HeaderRules>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<Hsts>,
+    /// Value of the `Content-Security-Policy` response header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub csp: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum RateLimitKey {
+    /// The client address, or the last `X-Forwarded-For` entry when `trust_forwarded_for` is set.
+    #[default]
+    Ip,
+    /// The `sub` claim of a bearer token verified by the trigger's auth. Rejected until the
+    /// sentinel verifies `jwt` auth.
+    JwtSubject,
+    /// The API key verified by the trigger's `api_key` auth.
+    ApiKey,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub limit: u64,
+    pub period: QuotaPeriod,
+}
+
+pub const DEFAULT_RATE_LIMIT_WINDOW_SECS: u64 = 60;
+pub const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Requests allowed per window, refilled continuously.
+    pub requests: u64,
+    /// Window length in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<u64>,
+    /// Bucket size, the number of requests that can be made at once. Defaults to `requests`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trust_forwarded_for: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Quota>,
+}
+
+impl RateLimit {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window.unwrap_or(DEFAULT_RATE_LIMIT_WINDOW_SECS).max(1))
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.requests).max(1)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpTrigger {
+    pub path: String,
+    /// A single method or a list of methods.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_methods",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub method: Option<Vec<HttpMethod>>,
+    /// Hosts the trigger answers on, such as `api.example.com` or `*.example.com`. Triggers
+    /// without hosts answer on every host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hosts: Option<Vec<String>>,
+    #[serde(
+        default,
+        serialize_with = "serialize_schema",
+        deserialize_with = "deserialize_schema",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub schema: Option<HashMap<String, Arc<Operations>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<HttpAuth>,
+    /// Seconds to wait for the function to respond, excluding cold start.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Maximum request body size in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<usize>,
+    /// Retries for idempotent requests that fail to reach the function or get a 502, 503 or 504.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<RetryPolicy>,
+    /// Also match every path below `path`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefix: bool,
+    /// Remove the part of the request path matched by `path` before forwarding it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strip_prefix: bool,
+    /// Template for the forwarded path. `{name}` is replaced by the path param of that name and
+    /// `{rest}` by the remainder of a prefix match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cors: Option<Cors>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HeaderPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl HttpTrigger {
+    pub fn methods(&self) -> Vec<HttpMethod> {
+        match &self.method {
+            Some(methods) if !methods.is_empty() && !methods.contains(&HttpMethod::Any) => {
+                methods.clone()
+            }
+            _ => vec![HttpMethod::Any],
+        }
+    }
+
+    pub fn hosts(&self) -> Vec<String> {
+        self.hosts
+            .iter()
+            .flatten()
+            .map(|host| host.trim().to_lowercase())
+            .collect()
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Trigger {
+    #[serde(rename = "http")]
+    Http(Box<HttpTrigger>),
+    #[serde(rename = "queue")]
+    Queue {
+        queue: String,
+        /// Seconds to wait for the function to handle an event, excluding cold start.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+    #[serde(rename = "stream")]
+    Stream {
+        stream: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+    #[serde(rename = "timer")]
+    Timer {
+        schedule: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+    },
+}
+
+pub const DEFAULT_EVENT_TIMEOUT_SECS: u64 = 30;
+
+impl Trigger {
+    pub fn as_http(&self) -> Option<&HttpTrigger> {
+        if let Trigger::Http(http) = self {
+            Some(http.as_ref())
+        } else {
+            None
+        }
+    }
+
+    /// Time an event-driven function has to handle an event.
+    pub fn event_timeout(&self) -> Duration {
+        let timeout = match self {
+            Trigger::Http(_) => None,
+            Trigger::Queue { timeout, .. }
+            | Trigger::Stream { timeout, .. }
+            | Trigger::Timer { timeout, .. } => *timeout,
+        };
+        Duration::from_secs(timeout.unwrap_or(DEFAULT_EVENT_TIMEOUT_SECS))
+    }
+}
+
+/// Where events go once a function has used up its retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DeadLetter {
+    /// The sentinel's dead-letter queue, managed through `/system/dlq`.
+    #[serde(rename = "dlq")]
+    Dlq,
+    /// Another queue, delivered to the functions it triggers.
+    #[serde(rename = "queue")]
+    Queue { queue: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Scaling {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check_interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+pub enum CircuitRecovery {
+    None,
+    /// Stop the container so the half-open trial cold-starts it.
+    #[default]
+    Restart,
+    /// Remove the container and create it again from its image.
+    Recreate,
+}
+
+pub const DEFAULT_CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_CIRCUIT_COOLDOWN_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    #[serde(default = "default_circuit_enabled")]
+    pub enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_threshold: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown: Option<u64>,
+    #[serde(default)]
+    pub recovery: CircuitRecovery,
+}
+
+fn default_circuit_enabled() -> bool {
+    true
+}
+
+impl CircuitBreaker {
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+            .unwrap_or(DEFAULT_CIRCUIT_FAILURE_THRESHOLD)
+            .max(1)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown.unwrap_or(DEFAULT_CIRCUIT_COOLDOWN_SECS))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionNetwork {
+    /// Only the sentinel, and functions that allow this one, can reach the function.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub internal_only: bool,
+    /// Functions, or existing Docker networks of other services, the function may reach.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// Whether the function may reach the internet.
+    #[serde(default = "default_egress")]
+    pub egress: bool,
+}
+
+fn default_egress() -> bool {
+    true
+}
+
+impl Default for FunctionNetwork {
+    fn default() -> Self {
+        Self {
+            internal_only: false,
+            allow: Vec::new(),
+            egress: default_egress(),
+        }
+    }
+}
+
+impl FunctionNetwork {
+    /// Whether the function gets its own network instead of the stack network.
+    pub fn isolated(&self) -> bool {
+        self.internal_only || !self.egress
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Volume {
+    /// A Docker volume owned by the stack, kept until `teardown --volumes`.
+    #[serde(rename = "volume")]
+    Named {
+        name: String,
+        target: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        read_only: bool,
+    },
+    /// A path on the Docker host.
+    #[serde(rename = "bind")]
+    Bind {
+        source: String,
+        target: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        read_only: bool,
+    },
+    /// In-memory storage, lost when the container stops.
+    #[serde(rename = "tmpfs")]
+    Tmpfs {
+        target: String,
+        /// Size limit in bytes.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+}
+
+pub const DEFAULT_DOCKERFILE: &str = "Dockerfile";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Build {
+    /// Directory sent to Docker, relative to the config file.
+    pub context: String,
+    /// Path of the Dockerfile within the context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dockerfile: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub args: HashMap<String, String>,
+    /// Build stage to stop at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl Build {
+    pub fn dockerfile(&self) -> &str {
+        self.dockerfile.as_deref().unwrap_or(DEFAULT_DOCKERFILE)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Function {
+    pub image: String,
+    /// Pulls `image` from `sentinel.registry` although it is not under the registry's
+    /// namespace.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub from_registry: bool,
+    /// Builds `image` from source on deploy instead of pulling it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub build: Option<Build>,
+    pub trigger: Trigger,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scaling: Option<Scaling>,
+    /// Fail-fast for a failing function, off unless set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Retries for events from queue, stream and timer triggers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dead_letter: Option<DeadLetter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<FunctionNetwork>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<Volume>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handler_port: Option<u16>,
+    /// Runs the function's containers on another host in `hosts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placement: Option<Placement>,
+    /// Runs the function as a process under `coupe-cli dev` instead of its container.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dev: Option<LocalProcess>,
+    /// Other images of the function, such as a canary, each run in its own container and sent
+    /// part of the function's requests.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub versions: BTreeMap<String, FunctionVersion>,
+    /// Keeps clients on the version they were first sent to, with a cookie.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sticky_versions: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionVersion {
+    pub image: String,
+    /// Percentage of requests sent to the version. The function's own image gets the rest.
+    #[serde(default)]
+    pub weight: u8,
+    /// Requests matching any rule are sent to the version whatever its weight.
+    #[serde(default, rename = "match", skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<VersionRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum VersionRule {
+    /// Matches requests with the header, or with the header set to `value`.
+    #[serde(rename = "header")]
+    Header {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+    /// Matches requests with the cookie, or with the cookie set to `value`.
+    #[serde(rename = "cookie")]
+    Cookie {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        value: Option<String>,
+    },
+}
+
+/// Name of the function's own image among its versions.
+pub const PRIMARY_VERSION: &str = "primary";
+
+/// Joins a function and one of its versions in the instance names that sessions, circuits and
+/// metrics are kept under, such as `orders@canary`.
+pub const VERSION_SEPARATOR: char = '@';
+
+/// Instance name of a version of a function. The primary version's is the function's name.
+pub fn version_instance(function_name: &str, version: &str) -> String {
+    if version == PRIMARY_VERSION {
+        function_name.to_string()
+    } else {
+        format!("{}{}{}", function_name, VERSION_SEPARATOR, version)
+    }
+}
+
+/// Checks that versions are only given to HTTP functions, have names usable in container names
+/// and cookies, and that their weights leave a share, possibly none, for the function's own
+/// image.
+pub fn validate_function_versions(config: &Config) -> Result<()> {
+    let mut function_names: Vec<&String> = config.functions.keys().collect();
+    function_names.sort();
+    for function_name in function_names {
+        let function = &config.functions[function_name];
+        if function.versions.is_empty() {
+            continue;
+        }
+        if function.trigger.as_http().is_none() {
+            return Err(CoupeError::Config(format!(
+                "Function {} has versions, which are only supported on http triggers",
+                function_name
+            )));
+        }
+        for (version, definition) in &function.versions {
+            let valid_name = !version.is_empty()
+                && version
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+            if !valid_name || version == PRIMARY_VERSION {
+                return Err(CoupeError::Config(format!(
+                    "Function {} has a version named {}, use lowercase letters, digits and dashes other than `{}`",
+                    function_name, version, PRIMARY_VERSION
+                )));
+            }
+            if definition.weight > 100 {
+                return Err(CoupeError::Config(format!(
+                    "Version {} of function {} has a weight above 100",
+                    version, function_name
+                )));
+            }
+        }
+        let total: u32 = function
+            .versions
+            .values()
+            .map(|version| u32::from(version.weight))
+            .sum();
+        if total > 100 {
+            return Err(CoupeError::Config(format!(
+                "Versions of function {} have weights adding up to {}, more than 100",
+                function_name, total
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that named volumes have names Docker accepts, that targets and bind sources are
+/// absolute paths, and that no function mounts two volumes at the same target.
+pub fn validate_function_volumes(config: &Config) -> Result<()> {
+    let mut function_names: Vec<&String> = config.functions.keys().collect();
+    function_names.sort();
+    for function_name in function_names {
+        let mut targets = HashSet::new();
+        for volume in &config.functions[function_name].volumes {
+            let target = match volume {
+                Volume::Named { name, target, .. } => {
+                    let valid_name = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+                    if !valid_name {
+                        return Err(CoupeError::Config(format!(
+                            "Function {} has a volume named {}, use letters, digits, `_`, `.` and `-`, starting with a letter or digit",
+                            function_name, name
+                        )));
+                    }
+                    target
+                }
+                Volume::Bind { source, target, .. } => {
+                    if !source.starts_with('/') {
+                        return Err(CoupeError::Config(format!(
+                            "Bind source {} of function {} must be an absolute path",
+                            source, function_name
+                        )));
+                    }
+                    target
+                }
+                Volume::Tmpfs { target, .. } => target,
+            };
+            let normalised = target.trim_end_matches('/');
+            if !target.starts_with('/') || normalised.is_empty() {
+                return Err(CoupeError::Config(format!(
+                    "Volume target {} of function {} must be an absolute path other than `/`",
+                    target, function_name
+                )));
+            }
+            if !targets.insert(normalised) {
+                return Err(CoupeError::Config(format!(
+                    "Function {} mounts more than one volume at {}",
+                    function_name, target
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that hosts have a private address and, when reached over SSH, a key for the sentinel,
+/// and that functions are placed
+/// on hosts in `hosts`, on ports no other function or version is published on, and without
+/// network settings that cannot work across hosts.
+pub fn validate_placement(config: &Config) -> Result<()> {
+    // Under `coupe-cli dev` every function runs locally.
+    if config.dev.is_some() {
+        return Ok(());
+    }
+    let mut host_names: Vec<&String> = config.hosts.keys().collect();
+    host_names.sort();
+    for host_name in host_names {
+        let target = config.host_target(&config.hosts[host_name]);
+        let over_tcp = target
+            .docker_host
+            .as_deref()
+            .is_some_and(|url| url.starts_with("tcp://"));
+        if target.identity_file.is_none() && !over_tcp {
+            return Err(CoupeError::Config(format!(
+                "Host {} needs an identity_file in its target, the only key the sentinel is given",
+                host_name
+            )));
+        }
+        let address = config.hosts[host_name].address;
+        if !is_private_address(address) {
+            return Err(CoupeError::Config(format!(
+                "Host {} has address {}, which is not private, so ports published on it could bypass the sentinel",
+                host_name, address
+            )));
+        }
+    }
+    let mut published: HashMap<(&str, u16), String> = HashMap::new();
+    for instance in config.instance_names() {
+        let Some(function) = config.function(&instance) else {
+            continue;
+        };
+        let Some(placement) = &function.placement else {
+            continue;
+        };
+        if !config.hosts.contains_key(&placement.host) {
+            return Err(CoupeError::Config(format!(
+                "Function {} is placed on host {}, which is not in hosts",
+                instance, placement.host
+            )));
+        }
+        if let Some(network) = &function.network {
+            let (function_name, _) = split_instance(&instance);
+            let joins_function = network.allow.iter().any(|name| {
+                name != function_name
+                    && config.functions.get(name).is_some_and(|f| {
+                        f.placement
+                            .as_ref()
+                            .is_none_or(|p| p.host != placement.host)
+                    })
+            });
+            if network.isolated() || joins_function {
+                return Err(CoupeError::Config(format!(
+                    "Function {} is placed on host {} and reached through a published port, so it cannot use internal_only, disable egress or join the network of a function on another host",
+                    function_name, placement.host
+                )));
+            }
+        }
+        let Some(port) = config.published_port(&instance) else {
+            return Err(CoupeError::Config(format!(
+                "Function {} has no port left to publish on host {}",
+                instance, placement.host
+            )));
+        };
+        if let Some(other) = published.insert((placement.host.as_str(), port), instance.clone()) {
+            return Err(CoupeError::Config(format!(
+                "Functions {} and {} are both published on port {} of host {}",
+                other, instance, port, placement.host
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether an address is on a private network: IPv4 private and shared (carrier-grade NAT, as
+/// used by Tailscale) ranges, and IPv6 unique local addresses.
+fn is_private_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let [first, second, ..] = address.octets();
+            address.is_private() || (first == 100 && second & 0xc0 == 64)
+        }
+        IpAddr::V6(address) => address.segments()[0] & 0xfe00 == 0xfc00,
+    }
+}
+
+/// The function and version an instance name refers to, without a version for the primary.
+pub fn split_instance(instance: &str) -> (&str, Option<&str>) {
+    match instance.split_once(VERSION_SEPARATOR) {
+        Some((function_name, version)) => (function_name, Some(version)),
+        None => (instance, None),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Placement {
+    /// Name of the host in `hosts`.
+    pub host: String,
+    /// Port the function's handler is published on, on the host. Versions are published on
+    /// the ports after it, in name order.
+    pub port: u16,
+}
+
+/// Another Docker daemon functions can be placed on, reached over SSH like a remote deploy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Host {
+    /// Name of a target in `targets`, or a destination as `[ssh://][user@]host[:port]`.
+    pub ssh: String,
+    /// Private address of the host, such as on a VPC, VPN or WireGuard network. Placed
+    /// functions' ports are only published on it, and the sentinel reaches them there.
+    pub address: IpAddr,
+}
+
+/// A remote host reached over SSH, for deploys and for hosts functions are placed on. Every
+/// connection goes through the system `ssh` client, so `~/.ssh/config` still applies to
+/// anything not set here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Target {
+    /// Host name or address.
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Private key to authenticate with, instead of the keys ssh would try.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// ssh-agent socket, by default `SSH_AUTH_SOCK`. Keys with a passphrase must be added to an
+    /// agent, as connections never prompt for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// Hosts to connect through, as for ssh's `ProxyJump`, separated by commas.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    #[serde(default)]
+    pub host_key_check: HostKeyCheck,
+    /// Known hosts file to check host keys against, by default ssh's own.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_hosts_file: Option<String>,
+    /// Container engine on the host, as `unix:///path` for a socket reached over SSH, such as
+    /// Podman's, or `tcp://host:port` to connect to directly. By default the docker CLI on the
+    /// host is used through `docker system dial-stdio`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docker_host: Option<String>,
+    /// Directory with `ca.pem`, `cert.pem` and `key.pem` to connect to a `tcp://` engine over
+    /// TLS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cert_path: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type")]
-pub enum Trigger {
-    #[serde(rename = "http")]
-    Http {
-        path: String,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        method: Option<HttpMethod>,
-        #[serde(
-            default,
-            serialize_with = "serialize_schema",
-            deserialize_with = "deserialize_schema",
-            skip_serializing_if = "Option::is_none"
-        )]
-        schema: Option<HashMap<String, Arc<Operations>>>,
-        #[serde(skip_serializing_if = "Option::is_none")]
-        auth: Option<HttpAuth>,
-    },
-    #[serde(rename = "queue")]
-    Queue { queue: String },
-    #[serde(rename = "stream")]
-    Stream { stream: String },
-    #[serde(rename = "timer")]
-    Timer { schedule: String },
+impl Target {
+    /// Target of a destination given as `[ssh://][user@]host[:port]`, with IPv6 addresses in
+    /// brackets when a port follows.
+    pub fn from_destination(destination: &str) -> Self {
+        let host = destination.trim_start_matches("ssh://");
+        let (user, host) = match host.rsplit_once('@') {
+            Some((user, host)) => (Some(user.to_string()), host),
+            None => (None, host),
+        };
+        let (host, port) = match host.strip_prefix('[').and_then(|host| host.split_once(']')) {
+            Some((address, rest)) => (
+                address,
+                rest.strip_prefix(':').and_then(|port| port.parse().ok()),
+            ),
+            // An IPv6 address without brackets has no port.
+            None if host.matches(':').count() > 1 => (host, None),
+            None => match host.rsplit_once(':') {
+                Some((name, port)) if port.parse::<u16>().is_ok() => (name, port.parse().ok()),
+                _ => (host, None),
+            },
+        };
+        Self {
+            host: host.to_string(),
+            user,
+            port,
+            identity_file: None,
+            agent: None,
+            proxy_jump: None,
+            host_key_check: HostKeyCheck::default(),
+            known_hosts_file: None,
+            docker_host: None,
+            tls_cert_path: None,
+        }
+    }
 }
 
-impl Trigger {
-    pub fn as_http(
-        self,
-    ) -> Option<(
-        String,
-        Option<HttpMethod>,
-        Option<HashMap<String, Arc<Operations>>>,
-        Option<HttpAuth>,
-    )> {
-        if let Trigger::Http {
-            path,
-            method,
-            schema,
-            auth,
-        } = self
-        {
-            Some((path, method, schema, auth))
-        } else {
-            None
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "{}@", user)?;
         }
+        match self.port {
+            Some(port) if self.host.contains(':') => write!(f, "[{}]:{}", self.host, port),
+            Some(port) => write!(f, "{}:{}", self.host, port),
+            None => write!(f, "{}", self.host),
+        }?;
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct Scaling {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub session_duration: Option<u64>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub health_check_interval: Option<u64>,
+/// How the key a host presents is checked against the known hosts file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyCheck {
+    /// Only connect to hosts already in the known hosts file.
+    Strict,
+    /// Add hosts seen for the first time, and refuse hosts whose key changed.
+    #[default]
+    AcceptNew,
+    /// Accept any key.
+    Off,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Function {
-    pub image: String,
-    pub trigger: Trigger,
+pub struct LocalProcess {
+    /// Shell command that starts the function's HTTP server.
+    pub command: String,
+    /// Working directory, relative to the config file.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub scaling: Option<Scaling>,
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Port the process listens on. A free port is passed in `PORT` when not set.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub handler_port: Option<u16>,
+    pub port: Option<u16>,
+}
+
+/// How the sentinel reaches functions when it runs on the host under `coupe-cli dev`, rather
+/// than in a container on the stack network.
+#[derive(Debug, Clone, Default)]
+pub struct DevMode {
+    /// Host port each function is reached on, published from its container or listened on by
+    /// its process.
+    pub ports: HashMap<String, u16>,
+    /// Functions running as processes, which the sentinel does not start or stop.
+    pub local_functions: HashSet<String>,
+    /// Address containers reach the host at as `host.docker.internal`, where the internal API
+    /// listens besides the loopback interface.
+    pub gateway: Option<IpAddr>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContainerRegistry {
     pub url: String,
     pub namespace: Option<String>,
+    /// Overridden by `COUPE_REGISTRY_USERNAME`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Overridden by `COUPE_REGISTRY_PASSWORD`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
 }
 
 pub const DEFAULT_SENTINEL_PORT: u16 = 52345;
+pub const DEFAULT_SENTINEL_IMAGE: &str = "coupe/sentinel:latest";
+pub const DEFAULT_SENTINEL_INTERNAL_PORT: u16 = 52346;
+/// Name containers resolve to the Docker host by, mapped with `host-gateway`.
+pub const DOCKER_HOST_GATEWAY: &str = "host.docker.internal";
 pub const DEFAULT_FUNCTION_HANDLER_PORT: u16 = 80;
+pub const DEFAULT_REVISION_HISTORY: usize = 10;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Sentinel {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub registry: Option<ContainerRegistry>,
+    /// Sentinel image, instead of `coupe-sentinel:latest` from the registry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub port: Option<u16>,
+    /// Port for calls from functions, only reachable from the stack network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_port: Option<u16>,
     #[serde(default = "default_fluentbit_port")]
     pub fluentbit_port: u16,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub otel_endpoint: Option<String>,
+    /// Bearer token for the sentinel's management endpoints, such as `/system/api-keys`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// Hosts asynchronous invocation callbacks may be sent to, such as `hooks.example.com` or
+    /// `*.example.com`. Callbacks are refused without it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub callback_hosts: Vec<String>,
 }
 
 fn default_fluentbit_port() -> u16 {
@@ -228,8 +1141,20 @@ pub struct Config {
     pub streams: Option<HashMap<String, Stream>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub openapi: Option<OpenApi>,
+    /// Remote hosts by name, for `--remote` and `hosts`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub targets: HashMap<String, Target>,
+    /// Other Docker daemons functions can be placed on, by name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub hosts: HashMap<String, Host>,
+    /// How many deploy revisions are kept for rollback.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision_history: Option<usize>,
     #[serde(default)]
     pub functions: HashMap<String, Function>,
+    /// Set by `coupe-cli dev`, never read from the config file.
+    #[serde(skip)]
+    pub dev: Option<DevMode>,
 }
 
 impl Config {
@@ -241,22 +1166,242 @@ impl Config {
             )));
         }
 
-        let config_content = fs::read_to_string(&path).map_err(|e| CoupeError::Io(e))?;
-        let config: Config =
-            serde_yaml::from_str(&config_content).map_err(|e| CoupeError::Yaml(e))?;
+        let config_content = fs::read_to_string(&path).map_err(CoupeError::Io)?;
+        let config: Config = serde_yaml::from_str(&config_content).map_err(CoupeError::Yaml)?;
 
         Ok(config)
     }
 
     pub fn to_yaml(&self) -> Result<String> {
-        let yaml = serde_yaml::to_string(self).map_err(|e| CoupeError::Yaml(e))?;
+        let yaml = serde_yaml::to_string(self).map_err(CoupeError::Yaml)?;
         Ok(yaml)
     }
 
+    /// The function an instance name refers to, for versions the function they belong to.
+    pub fn function(&self, instance: &str) -> Option<&Function> {
+        let (function_name, version) = split_instance(instance);
+        let function = self.functions.get(function_name)?;
+        match version {
+            Some(version) if !function.versions.contains_key(version) => None,
+            _ => Some(function),
+        }
+    }
+
+    /// Instance names of the function and each of its versions, the function first.
+    pub fn function_instances(&self, function_name: &str) -> Vec<String> {
+        let mut instances = vec![function_name.to_string()];
+        if let Some(function) = self.functions.get(function_name) {
+            instances.extend(
+                function
+                    .versions
+                    .keys()
+                    .map(|version| version_instance(function_name, version)),
+            );
+        }
+        instances
+    }
+
+    /// Instance names of every function and version, each run in its own container.
+    pub fn instance_names(&self) -> Vec<String> {
+        let mut function_names: Vec<&String> = self.functions.keys().collect();
+        function_names.sort();
+        function_names
+            .into_iter()
+            .flat_map(|function_name| self.function_instances(function_name))
+            .collect()
+    }
+
+    /// The function as an instance runs it, with the version's image and without versions.
+    pub fn instance_definition(&self, instance: &str) -> Option<Function> {
+        let mut function = self.function(instance)?.clone();
+        if let (_, Some(version)) = split_instance(instance) {
+            function.image = function.versions.get(version)?.image.clone();
+            function.build = None;
+        }
+        function.versions.clear();
+        function.sticky_versions = false;
+        Some(function)
+    }
+
+    /// The host a function or version is placed on. `None` for the stack's own host, and under
+    /// `coupe-cli dev`, which runs every function locally.
+    pub fn function_host(&self, function_name: &str) -> Option<&Host> {
+        self.hosts.get(self.function_host_name(function_name)?)
+    }
+
+    /// Name in `hosts` of the host a function or version is placed on, as for
+    /// [`Config::function_host`].
+    pub fn function_host_name(&self, function_name: &str) -> Option<&str> {
+        if self.dev.is_some() {
+            return None;
+        }
+        let placement = self.function(function_name)?.placement.as_ref()?;
+        self.hosts
+            .contains_key(&placement.host)
+            .then_some(placement.host.as_str())
+    }
+
+    /// SSH target of a host, from `targets` when `ssh` names one.
+    pub fn host_target(&self, host: &Host) -> Target {
+        self.targets
+            .get(&host.ssh)
+            .cloned()
+            .unwrap_or_else(|| Target::from_destination(&host.ssh))
+    }
+
+    /// SSH target the sentinel reaches a host with from its container, using the files copied
+    /// there on deploy instead of the deploying user's. Host keys are only checked against the
+    /// copied known hosts, unless the target turns checking off.
+    pub fn sentinel_host_target(&self, host_name: &str) -> Option<Target> {
+        let mut target = self.host_target(self.hosts.get(host_name)?);
+        let dir = Path::new(SENTINEL_HOSTS_DIR).join(host_name);
+        let path = |name: &str| Some(dir.join(name).display().to_string());
+        if target.identity_file.is_some() {
+            target.identity_file = path(HOST_IDENTITY_FILE);
+        }
+        if target.tls_cert_path.is_some() {
+            target.tls_cert_path = path(HOST_TLS_DIR);
+        }
+        target.known_hosts_file = path(HOST_KNOWN_HOSTS_FILE);
+        if target.host_key_check == HostKeyCheck::AcceptNew {
+            target.host_key_check = HostKeyCheck::Strict;
+        }
+        target.agent = None;
+        Some(target)
+    }
+
+    /// Port a function or version placed on another host is published on there.
+    pub fn published_port(&self, function_name: &str) -> Option<u16> {
+        self.function_host(function_name)?;
+        let function = self.function(function_name)?;
+        let offset = match split_instance(function_name) {
+            (_, Some(version)) => function.versions.keys().position(|v| v == version)? + 1,
+            (_, None) => 0,
+        };
+        let port = function.placement.as_ref()?.port;
+        port.checked_add(u16::try_from(offset).ok()?)
+    }
+
+    /// Instance names of the functions and versions placed on the host.
+    pub fn host_instances(&self, host_name: &str) -> Vec<String> {
+        self.instance_names()
+            .into_iter()
+            .filter(|instance| {
+                self.function(instance)
+                    .and_then(|f| f.placement.as_ref())
+                    .is_some_and(|p| p.host == host_name)
+            })
+            .collect()
+    }
+
+    /// Instance names of the functions and versions on the stack's own host.
+    pub fn local_instances(&self) -> Vec<String> {
+        self.instance_names()
+            .into_iter()
+            .filter(|instance| self.function_host(instance).is_none())
+            .collect()
+    }
+
     pub fn stack_network_name(&self) -> String {
         format!("coupe-{}-network", self.name)
     }
 
+    /// Whether a function may call another through the sentinel. An isolated caller, or an
+    /// `internal_only` target, needs the target in the caller's `allow`.
+    pub fn may_call(&self, caller: &str, target: &str) -> bool {
+        let network = |name: &str| self.functions.get(name).and_then(|f| f.network.as_ref());
+        let caller_network = network(caller);
+        let restricted = caller_network.is_some_and(FunctionNetwork::isolated)
+            || network(target).is_some_and(|n| n.internal_only);
+        !restricted || caller_network.is_some_and(|n| n.allow.iter().any(|name| name == target))
+    }
+
+    /// Whether a function may publish events to the given consumers. An isolated caller may
+    /// not publish to a queue or stream without consumers in the stack, as its events would
+    /// leave the stack.
+    pub fn may_publish(&self, caller: &str, consumers: &[String]) -> bool {
+        let isolated = self
+            .functions
+            .get(caller)
+            .and_then(|f| f.network.as_ref())
+            .is_some_and(FunctionNetwork::isolated);
+        !(isolated && consumers.is_empty())
+            && consumers
+                .iter()
+                .all(|consumer| self.may_call(caller, consumer))
+    }
+
+    /// Functions with their own network instead of the stack network.
+    pub fn isolated_functions(&self) -> Vec<String> {
+        let mut functions: Vec<String> = self
+            .functions
+            .iter()
+            .filter(|(_, func)| func.network.as_ref().is_some_and(|n| n.isolated()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        functions.sort();
+        functions
+    }
+
+    /// The network a function's container is created on, which the sentinel is attached to.
+    pub fn function_network_name(&self, function_name: &str) -> String {
+        // Versions share the function's network.
+        let (function_name, _) = split_instance(function_name);
+        let isolated = self
+            .functions
+            .get(function_name)
+            .and_then(|f| f.network.as_ref())
+            .is_some_and(|n| n.isolated());
+        if isolated {
+            format!("coupe-{}-function-{}-network", self.name, function_name)
+        } else {
+            self.stack_network_name()
+        }
+    }
+
+    /// Every network a function's container is attached to, its own network first. Allowed
+    /// functions are reached by joining their network.
+    pub fn function_networks(&self, function_name: &str) -> Vec<String> {
+        let mut networks = vec![self.function_network_name(function_name)];
+        let allow = self
+            .function(function_name)
+            .and_then(|f| f.network.as_ref())
+            .map(|n| n.allow.as_slice())
+            .unwrap_or_default();
+        for name in allow {
+            let network = if self.functions.contains_key(name) {
+                self.function_network_name(name)
+            } else {
+                name.clone()
+            };
+            if !networks.contains(&network) {
+                networks.push(network);
+            }
+        }
+        networks
+    }
+
+    /// Docker name of a named volume, scoped to the stack so functions can share it by name.
+    pub fn volume_name(&self, name: &str) -> String {
+        format!("coupe-{}-{}", self.name, name)
+    }
+
+    /// Names of the named volumes used by any function.
+    pub fn named_volumes(&self) -> Vec<String> {
+        let mut volumes: Vec<String> = self
+            .functions
+            .values()
+            .flat_map(|func| &func.volumes)
+            .filter_map(|volume| match volume {
+                Volume::Named { name, .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        volumes.sort();
+        volumes.dedup();
+        volumes
+    }
+
     pub fn sentinel_container_name(&self) -> String {
         format!("coupe-{}-sentinel", self.name)
     }
@@ -266,7 +1411,86 @@ impl Config {
     }
 
     pub fn function_container_name(&self, function_name: &str) -> String {
-        format!("coupe-{}-function-{}", self.name, function_name)
+        match split_instance(function_name) {
+            (function_name, Some(version)) => {
+                format!(
+                    "coupe-{}-function-{}-version-{}",
+                    self.name, function_name, version
+                )
+            }
+            (function_name, None) => format!("coupe-{}-function-{}", self.name, function_name),
+        }
+    }
+
+    pub fn registry(&self) -> Option<&ContainerRegistry> {
+        self.sentinel.as_ref().and_then(|s| s.registry.as_ref())
+    }
+
+    pub fn sentinel_image(&self) -> String {
+        if let Some(image) = self.sentinel.as_ref().and_then(|s| s.image.clone()) {
+            return image;
+        }
+        self.registry()
+            .map(|r| {
+                format!(
+                    "{}/{}/coupe-sentinel:latest",
+                    r.url,
+                    r.namespace.as_deref().unwrap_or("library")
+                )
+            })
+            .unwrap_or_else(|| DEFAULT_SENTINEL_IMAGE.to_string())
+    }
+
+    /// The function's image, pulled from the registry when it is under the registry's namespace
+    /// or the function sets `from_registry`, and as written otherwise, such as `nginx` from
+    /// Docker Hub. For a version's instance name, the version's image.
+    pub fn function_image(&self, function_name: &str) -> Result<String> {
+        let function = self
+            .function(function_name)
+            .ok_or(CoupeError::InvalidInput(format!(
+                "Function {} not found",
+                function_name
+            )))?;
+        let image = match split_instance(function_name) {
+            (_, Some(version)) => &function.versions[version].image,
+            (_, None) => &function.image,
+        };
+        let Some(registry) = self.registry() else {
+            return Ok(image.clone());
+        };
+        let has_host = image.split_once('/').is_some_and(|(host, _)| {
+            host.contains('.') || host.contains(':') || host == "localhost"
+        });
+        let in_namespace = registry
+            .namespace
+            .as_ref()
+            .is_some_and(|namespace| image.starts_with(&format!("{}/", namespace)));
+        Ok(match (has_host, in_namespace, function.from_registry) {
+            (true, _, _) | (false, false, false) => image.clone(),
+            (false, true, _) => format!("{}/{}", registry.url, image),
+            (false, false, true) => match &registry.namespace {
+                Some(namespace) if !image.contains('/') => {
+                    format!("{}/{}/{}", registry.url, namespace, image)
+                }
+                _ => format!("{}/{}", registry.url, image),
+            },
+        })
+    }
+
+    /// Replaces the image of a function, or of a version given its instance name.
+    pub fn set_function_image(&mut self, function_name: &str, image: String) {
+        let (function_name, version) = split_instance(function_name);
+        let Some(function) = self.functions.get_mut(function_name) else {
+            return;
+        };
+        match version {
+            Some(version) => {
+                if let Some(version) = function.versions.get_mut(version) {
+                    version.image = image;
+                }
+            }
+            None => function.image = image,
+        }
     }
 
     pub fn sentinel_port(&self) -> u16 {
@@ -276,6 +1500,36 @@ impl Config {
             .unwrap_or(DEFAULT_SENTINEL_PORT)
     }
 
+    pub fn sentinel_internal_port(&self) -> u16 {
+        self.sentinel
+            .as_ref()
+            .and_then(|s| s.internal_port)
+            .unwrap_or(DEFAULT_SENTINEL_INTERNAL_PORT)
+    }
+
+    /// URL functions use to reach the sentinel, passed to them as `COUPE_SENTINEL_URL`.
+    pub fn internal_sentinel_url(&self) -> String {
+        // Under `coupe-cli dev` the sentinel runs on the Docker host.
+        let host = match self.dev {
+            Some(_) => DOCKER_HOST_GATEWAY.to_string(),
+            None => self.sentinel_container_name(),
+        };
+        format!("http://{}:{}", host, self.sentinel_internal_port())
+    }
+
+    pub fn revision_history(&self) -> usize {
+        // The current revision is always kept.
+        self.revision_history
+            .unwrap_or(DEFAULT_REVISION_HISTORY)
+            .max(1)
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.sentinel
+            .as_ref()
+            .and_then(|s| s.admin_token.as_deref())
+    }
+
     pub fn fluentbit_port(&self) -> u16 {
         self.sentinel
             .as_ref()
@@ -283,11 +1537,39 @@ impl Config {
             .unwrap_or_else(default_fluentbit_port)
     }
 
+    /// Functions triggered by the given queue.
+    pub fn queue_consumers(&self, queue: &str) -> Vec<String> {
+        let mut consumers: Vec<String> = self
+            .functions
+            .iter()
+            .filter(
+                |(_, func)| matches!(&func.trigger, Trigger::Queue { queue: q, .. } if q == queue),
+            )
+            .map(|(name, _)| name.clone())
+            .collect();
+        consumers.sort();
+        consumers
+    }
+
+    /// Functions triggered by the given stream.
+    pub fn stream_consumers(&self, stream: &str) -> Vec<String> {
+        let mut consumers: Vec<String> = self
+            .functions
+            .iter()
+            .filter(
+                |(_, func)| matches!(&func.trigger, Trigger::Stream { stream: s, .. } if s == stream),
+            )
+            .map(|(name, _)| name.clone())
+            .collect();
+        consumers.sort();
+        consumers
+    }
+
     pub fn http_functions(&self) -> Vec<String> {
         self.functions
             .iter()
             .filter_map(|(name, func)| {
-                if let Trigger::Http { .. } = &func.trigger {
+                if let Trigger::Http(_) = &func.trigger {
                     Some(name.clone())
                 } else {
                     None
@@ -297,6 +1579,19 @@ impl Config {
     }
 
     pub fn internal_function_url(&self, function_name: &str) -> Result<String> {
+        if let Some(port) = self
+            .dev
+            .as_ref()
+            .and_then(|dev| dev.ports.get(function_name))
+        {
+            return Ok(format!("127.0.0.1:{}", port));
+        }
+        if let (Some(host), Some(port)) = (
+            self.function_host(function_name),
+            self.published_port(function_name),
+        ) {
+            return Ok(SocketAddr::new(host.address, port).to_string());
+        }
         let function_url = format!(
             "{}:{}",
             self.function_container_name(function_name),
@@ -305,10 +1600,16 @@ impl Config {
         Ok(function_url)
     }
 
+    /// Whether the function runs as a process under `coupe-cli dev`.
+    pub fn is_local_function(&self, function_name: &str) -> bool {
+        self.dev
+            .as_ref()
+            .is_some_and(|dev| dev.local_functions.contains(function_name))
+    }
+
     pub fn function_handler_port(&self, function_name: &str) -> Result<u16> {
         let function = self
-            .functions
-            .get(function_name)
+            .function(function_name)
             .ok_or(CoupeError::InvalidInput(format!(
                 "Function {} not found",
                 function_name
@@ -325,3 +1626,553 @@ impl Config {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_from_the_first_retry() {
+        let policy = RetryPolicy {
+            backoff: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(50));
+        assert_eq!(policy.backoff(2), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_backoff() {
+        let policy = RetryPolicy {
+            backoff: Some(100),
+            max_backoff: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn backoff_defaults() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.max_attempts(), DEFAULT_RETRY_MAX_ATTEMPTS);
+        assert_eq!(
+            policy.backoff(0),
+            Duration::from_millis(DEFAULT_RETRY_BACKOFF_MS)
+        );
+        assert_eq!(
+            policy.backoff(64),
+            Duration::from_millis(DEFAULT_RETRY_MAX_BACKOFF_MS)
+        );
+    }
+
+    #[test]
+    fn max_attempts_is_at_least_one() {
+        let policy = RetryPolicy {
+            max_attempts: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(policy.max_attempts(), 1);
+    }
+
+    fn network_config() -> Config {
+        serde_yaml::from_str(
+            r#"
+name: test
+functions:
+  open: { image: open, trigger: { type: queue, queue: jobs } }
+  vault:
+    image: vault
+    trigger: { type: queue, queue: secrets }
+    network: { internal_only: true }
+  offline:
+    image: offline
+    trigger: { type: queue, queue: jobs }
+    network: { egress: false, allow: [sealed] }
+  sealed:
+    image: sealed
+    trigger: { type: queue, queue: jobs }
+    network: { egress: false }
+  admin:
+    image: admin
+    trigger: { type: queue, queue: jobs }
+    network: { allow: [vault] }
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn unrestricted_functions_call_each_other() {
+        let config = network_config();
+        assert!(config.may_call("open", "admin"));
+        assert!(config.may_call("admin", "offline"));
+    }
+
+    #[test]
+    fn internal_only_targets_need_the_caller_to_allow_them() {
+        let config = network_config();
+        assert!(!config.may_call("open", "vault"));
+        assert!(!config.may_call("offline", "vault"));
+        assert!(config.may_call("admin", "vault"));
+    }
+
+    #[test]
+    fn isolated_callers_only_reach_allowed_functions() {
+        let config = network_config();
+        assert!(config.may_call("offline", "sealed"));
+        assert!(!config.may_call("offline", "open"));
+        assert!(!config.may_call("sealed", "offline"));
+    }
+
+    #[test]
+    fn publishing_needs_every_consumer_reachable() {
+        let config = network_config();
+        let consumers = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(config.may_publish("open", &consumers(&["admin", "offline"])));
+        assert!(!config.may_publish("open", &consumers(&["vault"])));
+        assert!(config.may_publish("open", &[]));
+        assert!(config.may_publish("offline", &consumers(&["sealed"])));
+        assert!(!config.may_publish("offline", &consumers(&["sealed", "open"])));
+        assert!(!config.may_publish("offline", &[]));
+    }
+
+    fn registry_config(functions: &str) -> Config {
+        serde_yaml::from_str(&format!(
+            "name: test\nsentinel:\n  registry: {{ url: registry.example.com, namespace: team }}\nfunctions:\n{}",
+            functions
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn public_images_are_not_pulled_from_the_registry() {
+        let config = registry_config(
+            "  web: { image: \"nginx:1.27\", trigger: { type: http, path: / } }\n  cache: { image: bitnami/redis, trigger: { type: http, path: /c } }",
+        );
+        assert_eq!(config.function_image("web").unwrap(), "nginx:1.27");
+        assert_eq!(config.function_image("cache").unwrap(), "bitnami/redis");
+    }
+
+    #[test]
+    fn images_in_the_registry_namespace_are_pulled_from_it() {
+        let config = registry_config(
+            "  blog: { image: \"team/blog:1\", trigger: { type: http, path: / } }\n  ghcr: { image: \"ghcr.io/team/blog:1\", trigger: { type: http, path: /g } }",
+        );
+        assert_eq!(
+            config.function_image("blog").unwrap(),
+            "registry.example.com/team/blog:1"
+        );
+        assert_eq!(
+            config.function_image("ghcr").unwrap(),
+            "ghcr.io/team/blog:1"
+        );
+    }
+
+    #[test]
+    fn functions_opt_in_to_the_registry() {
+        let config = registry_config(
+            "  blog: { image: \"blog:1\", from_registry: true, trigger: { type: http, path: / } }\n  shared: { image: other/blog, from_registry: true, trigger: { type: http, path: /s } }",
+        );
+        assert_eq!(
+            config.function_image("blog").unwrap(),
+            "registry.example.com/team/blog:1"
+        );
+        assert_eq!(
+            config.function_image("shared").unwrap(),
+            "registry.example.com/other/blog"
+        );
+    }
+
+    fn validate_volumes(volumes: &str) -> Result<()> {
+        let config: Config = serde_yaml::from_str(&format!(
+            "name: test\nfunctions:\n  api:\n    image: api:1\n    trigger: {{ type: http, path: /api }}\n    volumes:\n{}",
+            volumes
+        ))
+        .unwrap();
+        validate_function_volumes(&config)
+    }
+
+    #[test]
+    fn volumes_with_absolute_paths_are_valid() {
+        validate_volumes(
+            r#"
+      - { type: volume, name: cache_1.v-2, target: /var/cache }
+      - { type: bind, source: /srv/config, target: /etc/app/, read_only: true }
+      - { type: tmpfs, target: /tmp }
+"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn invalid_volumes_are_rejected() {
+        for (volumes, error) in [
+            (
+                "      - { type: volume, name: \"\", target: /data }",
+                "volume named",
+            ),
+            (
+                "      - { type: volume, name: -cache, target: /data }",
+                "volume named",
+            ),
+            (
+                "      - { type: volume, name: my/cache, target: /data }",
+                "volume named",
+            ),
+            (
+                "      - { type: volume, name: cache, target: data }",
+                "absolute path",
+            ),
+            ("      - { type: tmpfs, target: / }", "other than `/`"),
+            (
+                "      - { type: bind, source: ./config, target: /etc/app }",
+                "Bind source",
+            ),
+            (
+                "      - { type: bind, source: ~/config, target: /etc/app }",
+                "Bind source",
+            ),
+            (
+                "      - { type: volume, name: cache, target: /data }\n      - { type: tmpfs, target: /data/ }",
+                "more than one volume at /data/",
+            ),
+        ] {
+            let message = validate_volumes(volumes).unwrap_err().to_string();
+            assert!(message.contains(error), "{}: {}", volumes, message);
+        }
+    }
+
+    fn validate_versions(function: &str) -> Result<()> {
+        let config: Config =
+            serde_yaml::from_str(&format!("name: test\nfunctions:\n  api:\n{}", function)).unwrap();
+        validate_function_versions(&config)
+    }
+
+    #[test]
+    fn versions_with_weights_up_to_100_are_valid() {
+        validate_versions(
+            r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    versions:
+      canary: { image: api:2, weight: 40 }
+      next-2: { image: api:3, weight: 60 }
+"#,
+        )
+        .unwrap();
+        validate_versions("    image: api:1\n    trigger: { type: queue, queue: jobs }").unwrap();
+    }
+
+    #[test]
+    fn versions_need_an_http_trigger() {
+        let error = validate_versions(
+            r#"
+    image: api:1
+    trigger: { type: queue, queue: jobs }
+    versions:
+      canary: { image: api:2, weight: 10 }
+"#,
+        )
+        .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("only supported on http triggers")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_version_names() {
+        for name in ["primary", "Canary", "canary_2", "v.2", "\"\""] {
+            let error = validate_versions(&format!(
+                "    image: api:1\n    trigger: {{ type: http, path: /api }}\n    versions:\n      {}: {{ image: api:2 }}",
+                name
+            ))
+            .unwrap_err();
+            assert!(
+                error.to_string().contains("has a version named"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_weights_above_100() {
+        let error = validate_versions(
+            r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    versions:
+      canary: { image: api:2, weight: 101 }
+"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("weight above 100"));
+
+        let error = validate_versions(
+            r#"
+    image: api:1
+    trigger: { type: http, path: /api }
+    versions:
+      canary: { image: api:2, weight: 60 }
+      next: { image: api:3, weight: 50 }
+"#,
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("adding up to 110"));
+    }
+
+    #[test]
+    fn sentinel_reaches_hosts_with_copied_files() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+name: test
+targets:
+  gpu:
+    host: gpu.example.com
+    identity_file: ~/.ssh/deploy_ed25519
+    agent: /tmp/agent.sock
+    tls_cert_path: ~/.docker/gpu
+hosts:
+  gpu: { ssh: gpu, address: 10.0.0.12 }
+functions: {}
+"#,
+        )
+        .unwrap();
+        let target = config.sentinel_host_target("gpu").unwrap();
+        assert_eq!(target.host, "gpu.example.com");
+        assert_eq!(
+            target.identity_file.as_deref(),
+            Some("/run/coupe/hosts/gpu/identity")
+        );
+        assert_eq!(
+            target.known_hosts_file.as_deref(),
+            Some("/run/coupe/hosts/gpu/known_hosts")
+        );
+        assert_eq!(
+            target.tls_cert_path.as_deref(),
+            Some("/run/coupe/hosts/gpu/tls")
+        );
+        assert_eq!(target.agent, None);
+        assert_eq!(target.host_key_check, HostKeyCheck::Strict);
+        assert!(config.sentinel_host_target("missing").is_none());
+    }
+
+    fn validate_hosts(hosts: &str, functions: &str) -> Result<()> {
+        let config: Config = serde_yaml::from_str(&format!(
+            r#"
+name: test
+targets:
+  gpu: {{ host: gpu.example.com, identity_file: ~/.ssh/deploy }}
+  tcp: {{ host: tcp.example.com, docker_host: "tcp://10.0.0.13:2376" }}
+  keyless: {{ host: keyless.example.com }}
+hosts:
+{}
+functions:
+{}"#,
+            hosts, functions
+        ))
+        .unwrap();
+        validate_placement(&config)
+    }
+
+    fn placement_error(hosts: &str, functions: &str) -> String {
+        validate_hosts(hosts, functions).unwrap_err().to_string()
+    }
+
+    const GPU: &str = "  gpu: { ssh: gpu, address: 10.0.0.12 }";
+
+    #[test]
+    fn placed_functions_get_their_own_ports() {
+        validate_hosts(
+            GPU,
+            r#"
+  transcribe:
+    image: transcribe
+    trigger: { type: http, path: /transcribe }
+    placement: { host: gpu, port: 8101 }
+    versions:
+      canary: { image: transcribe:2 }
+  embed:
+    image: embed
+    trigger: { type: http, path: /embed }
+    placement: { host: gpu, port: 8103 }
+    network: { allow: [transcribe] }
+"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_conflicting_ports() {
+        let error = placement_error(
+            GPU,
+            r#"
+  transcribe:
+    image: transcribe
+    trigger: { type: http, path: /transcribe }
+    placement: { host: gpu, port: 8101 }
+    versions:
+      canary: { image: transcribe:2 }
+  embed:
+    image: embed
+    trigger: { type: http, path: /embed }
+    placement: { host: gpu, port: 8102 }
+"#,
+        );
+        assert!(
+            error.contains("both published on port 8102 of host gpu"),
+            "{}",
+            error
+        );
+
+        let error = placement_error(
+            GPU,
+            r#"
+  last:
+    image: last
+    trigger: { type: http, path: /last }
+    placement: { host: gpu, port: 65535 }
+    versions:
+      canary: { image: last:2 }
+"#,
+        );
+        assert!(error.contains("no port left"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unknown_hosts_and_networks_across_hosts() {
+        let error = placement_error(
+            GPU,
+            "  api: { image: api, trigger: { type: http, path: / }, placement: { host: cpu, port: 8101 } }",
+        );
+        assert!(error.contains("which is not in hosts"), "{}", error);
+
+        let error = placement_error(
+            GPU,
+            r#"
+  db: { image: db, trigger: { type: http, path: /db } }
+  api:
+    image: api
+    trigger: { type: http, path: /api }
+    placement: { host: gpu, port: 8101 }
+    network: { allow: [db] }
+"#,
+        );
+        assert!(error.contains("cannot use internal_only"), "{}", error);
+
+        let error = placement_error(
+            GPU,
+            r#"
+  api:
+    image: api
+    trigger: { type: http, path: /api }
+    placement: { host: gpu, port: 8101 }
+    network: { egress: false }
+"#,
+        );
+        assert!(error.contains("cannot use internal_only"), "{}", error);
+    }
+
+    #[test]
+    fn hosts_need_a_private_address_and_a_key() {
+        for address in ["10.0.0.12", "192.168.1.2", "100.100.1.2", "\"fd00::12\""] {
+            validate_hosts(
+                &format!("  gpu: {{ ssh: gpu, address: {} }}", address),
+                "  {}",
+            )
+            .unwrap();
+        }
+        validate_hosts("  tcp: { ssh: tcp, address: 10.0.0.13 }", "  {}").unwrap();
+        for address in [
+            "0.0.0.0",
+            "203.0.113.5",
+            "127.0.0.1",
+            "100.128.0.1",
+            "\"2001:db8::1\"",
+        ] {
+            let error = placement_error(
+                &format!("  gpu: {{ ssh: gpu, address: {} }}", address),
+                "  {}",
+            );
+            assert!(error.contains("which is not private"), "{}", address);
+        }
+        let error = placement_error("  keyless: { ssh: keyless, address: 10.0.0.14 }", "  {}");
+        assert!(error.contains("needs an identity_file"), "{}", error);
+    }
+
+    fn destination(destination: &str) -> (Option<String>, String, Option<u16>) {
+        let target = Target::from_destination(destination);
+        (target.user, target.host, target.port)
+    }
+
+    #[test]
+    fn destinations_have_an_optional_user_and_port() {
+        assert_eq!(
+            destination("app.example.com"),
+            (None, "app.example.com".to_string(), None)
+        );
+        assert_eq!(
+            destination("ssh://deploy@app.example.com:2222"),
+            (
+                Some("deploy".to_string()),
+                "app.example.com".to_string(),
+                Some(2222)
+            )
+        );
+        assert_eq!(
+            destination("deploy@10.0.0.12"),
+            (Some("deploy".to_string()), "10.0.0.12".to_string(), None)
+        );
+        assert_eq!(
+            destination("app.example.com:ssh"),
+            (None, "app.example.com:ssh".to_string(), None)
+        );
+        assert_eq!(
+            destination("app.example.com:99999"),
+            (None, "app.example.com:99999".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn destinations_take_ipv6_addresses() {
+        assert_eq!(
+            destination("fd00::12"),
+            (None, "fd00::12".to_string(), None)
+        );
+        assert_eq!(
+            destination("deploy@[fd00::12]:2222"),
+            (
+                Some("deploy".to_string()),
+                "fd00::12".to_string(),
+                Some(2222)
+            )
+        );
+        assert_eq!(
+            destination("[fd00::12]"),
+            (None, "fd00::12".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn destinations_display_as_given() {
+        for destination in [
+            "app.example.com",
+            "deploy@app.example.com:2222",
+            "deploy@[fd00::12]:2222",
+            "fd00::12",
+        ] {
+            assert_eq!(
+                Target::from_destination(destination).to_string(),
+                destination
+            );
+        }
+        assert_eq!(
+            Target::from_destination("ssh://deploy@app.example.com").to_string(),
+            "deploy@app.example.com"
+        );
+    }
+}
@@ -1,27 +1,54 @@
-use crate::{Config, CoupeError, Result, build_fluentbit_config, recreate_docker_stack};
-use remotefs::{
-    RemoteErrorType, RemoteFs,
-    fs::{Metadata, UnixPex},
+use crate::{
+    Config, CoupeError, Result, Revision, SshClient, Target, build_fluentbit_config, build_images,
+    connect_docker, expand_home, function_docker, list_revisions, pull_images, record_revision,
+    recreate_docker_stack, remove_function_container, remove_volumes, rollback_target, teardown,
+    teardown_host, validate_function_versions,
 };
-use remotefs_ssh::{SftpFs, SshOpts};
+use bollard::Docker;
 use std::{
+    collections::HashMap,
     env,
     path::{Path, PathBuf},
 };
-use tokio::fs;
+use tokio::{fs, io::AsyncWriteExt};
 
 #[derive(Debug)]
 pub enum DeploymentTarget {
     Local,
-    Remote(String),
+    Remote(Target),
 }
 
-fn connect_ssh(host: &str) -> Result<SftpFs> {
-    let mut client: SftpFs = SshOpts::new(host).into();
-    client
-        .connect()
-        .map_err(|e| CoupeError::SshConnection(e.to_string()))?;
-    Ok(client)
+impl DeploymentTarget {
+    /// Target of `--remote`, which names a target in the config's `targets` or in
+    /// `~/.coupe/targets.yaml`, or is an SSH destination. Local without one.
+    pub fn resolve(config: &Config, remote: Option<&str>) -> Result<Self> {
+        let Some(remote) = remote else {
+            return Ok(DeploymentTarget::Local);
+        };
+        if let Some(target) = config.targets.get(remote) {
+            return Ok(DeploymentTarget::Remote(target.clone()));
+        }
+        let target = load_targets()?
+            .remove(remote)
+            .unwrap_or_else(|| Target::from_destination(remote));
+        Ok(DeploymentTarget::Remote(target))
+    }
+}
+
+fn targets_path() -> PathBuf {
+    let home = env::home_dir().unwrap_or("/home".into());
+    Path::new(&home).join(".coupe").join("targets.yaml")
+}
+
+/// Targets shared by every stack, from `~/.coupe/targets.yaml`.
+pub fn load_targets() -> Result<HashMap<String, Target>> {
+    let path = targets_path();
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let content = std::fs::read_to_string(&path)?;
+    serde_yaml::from_str(&content)
+        .map_err(|e| CoupeError::Config(format!("Invalid {}: {}", path.display(), e)))
 }
 
 pub fn deployment_path(config: &Config) -> PathBuf {
@@ -37,43 +64,132 @@ pub fn fluentbit_path(config: &Config) -> PathBuf {
     deployment_path(config).join("fluentbit.yaml")
 }
 
+/// Where the sentinel's container has the files it reaches the hosts in `hosts` with.
+pub const SENTINEL_HOSTS_DIR: &str = "/run/coupe/hosts";
+pub const HOST_IDENTITY_FILE: &str = "identity";
+pub const HOST_KNOWN_HOSTS_FILE: &str = "known_hosts";
+pub const HOST_TLS_DIR: &str = "tls";
+const TLS_FILES: [&str; 3] = ["ca.pem", "cert.pem", "key.pem"];
+
+/// Files the sentinel reaches the hosts in `hosts` with, one directory per host.
+pub fn hosts_path(config: &Config) -> PathBuf {
+    deployment_path(config).join("hosts")
+}
+
+/// Writes the config to the target, and to every host in `hosts`.
 pub async fn deploy_config(config: &Config, target: &DeploymentTarget) -> Result<()> {
-    if let DeploymentTarget::Remote(host) = target {
-        let mut client = connect_ssh(host)?;
-        client
-            .create_dir(deployment_path(config).as_path(), UnixPex::from(0o755))
-            .or_else(|e| {
-                if let RemoteErrorType::DirectoryAlreadyExists = e.kind {
-                    Ok(())
-                } else {
-                    Err(CoupeError::SshCommand(e.to_string()))
-                }
-            })?;
-
-        let yaml_content = serde_yaml::to_string(config).map_err(|e| CoupeError::Yaml(e))?;
-        let reader = std::io::Cursor::new(yaml_content);
-        client
-            .create_file(
-                config_path(config).as_path(),
-                &Metadata::default(),
-                Box::new(reader),
-            )
-            .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
+    write_config(config, target).await?;
+    for host in host_targets(config) {
+        write_config(config, &host).await?;
+    }
+    // Written after connecting to the hosts, which adds new ones to the known hosts file.
+    write_host_files(config, target).await
+}
 
-        let fluentbit_content = serde_yaml::to_string(&build_fluentbit_config(config)?)
-            .map_err(|e| CoupeError::Yaml(e))?;
-        let reader = std::io::Cursor::new(fluentbit_content);
-        client
-            .create_file(
-                fluentbit_path(config).as_path(),
-                &Metadata::default(),
-                Box::new(reader),
-            )
-            .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
+/// The identity file, known hosts file and TLS certificates of each host's target, read from
+/// this machine, by their path under [`hosts_path`].
+async fn host_files(config: &Config) -> Result<Vec<(PathBuf, String)>> {
+    let read = |path: PathBuf, host_name: &str| {
+        let host_name = host_name.to_string();
+        async move {
+            fs::read_to_string(&path).await.map_err(|e| {
+                CoupeError::Config(format!(
+                    "Cannot read {} for host {}: {}",
+                    path.display(),
+                    host_name,
+                    e
+                ))
+            })
+        }
+    };
+    let mut host_names: Vec<&String> = config.hosts.keys().collect();
+    host_names.sort();
+    let mut files = Vec::new();
+    for host_name in host_names {
+        let target = config.host_target(&config.hosts[host_name]);
+        let dir = hosts_path(config).join(host_name);
+        if let Some(identity_file) = &target.identity_file {
+            let content = read(expand_home(identity_file), host_name).await?;
+            files.push((dir.join(HOST_IDENTITY_FILE), content));
+        }
+        let known_hosts = expand_home(
+            target
+                .known_hosts_file
+                .as_deref()
+                .unwrap_or("~/.ssh/known_hosts"),
+        );
+        if fs::try_exists(&known_hosts).await? {
+            let content = read(known_hosts, host_name).await?;
+            files.push((dir.join(HOST_KNOWN_HOSTS_FILE), content));
+        }
+        if let Some(tls_cert_path) = &target.tls_cert_path {
+            for name in TLS_FILES {
+                let content = read(expand_home(tls_cert_path).join(name), host_name).await?;
+                files.push((dir.join(HOST_TLS_DIR).join(name), content));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Replaces the files the sentinel reaches the hosts in `hosts` with on the target, readable by
+/// their owner only. Only the files named by each host's target are copied.
+async fn write_host_files(config: &Config, target: &DeploymentTarget) -> Result<()> {
+    let files = host_files(config).await?;
+    if let DeploymentTarget::Remote(target) = target {
+        let client = SshClient::connect(target).await?;
+        client.remove_dir_all(&hosts_path(config)).await?;
+        for (path, content) in &files {
+            if let Some(dir) = path.parent() {
+                client.create_dir_all(dir).await?;
+            }
+            client.write_private_file(path, content).await?;
+        }
+        client.close().await?;
+    } else {
+        if fs::try_exists(hosts_path(config)).await? {
+            fs::remove_dir_all(hosts_path(config)).await?;
+        }
+        for (path, content) in &files {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .await?;
+            file.write_all(content.as_bytes()).await?;
+        }
+    }
+    Ok(())
+}
 
+/// Targets of the hosts in `hosts`, in name order.
+fn host_targets(config: &Config) -> Vec<DeploymentTarget> {
+    let mut host_names: Vec<&String> = config.hosts.keys().collect();
+    host_names.sort();
+    host_names
+        .into_iter()
+        .map(|host_name| DeploymentTarget::Remote(config.host_target(&config.hosts[host_name])))
+        .collect()
+}
+
+async fn write_config(config: &Config, target: &DeploymentTarget) -> Result<()> {
+    if let DeploymentTarget::Remote(target) = target {
+        let client = SshClient::connect(target).await?;
+        client.create_dir_all(&deployment_path(config)).await?;
+        client
+            .write_file(&config_path(config), &serde_yaml::to_string(config)?)
+            .await?;
         client
-            .disconnect()
-            .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
+            .write_file(
+                &fluentbit_path(config),
+                &serde_yaml::to_string(&build_fluentbit_config(config)?)?,
+            )
+            .await?;
+        client.close().await?;
     } else {
         println!("Deploying to local filesystem");
         fs::create_dir_all(deployment_path(config)).await?;
@@ -88,23 +204,10 @@ pub async fn deploy_config(config: &Config, target: &DeploymentTarget) -> Result
 }
 
 pub async fn remove_config(config: &Config, target: &DeploymentTarget) -> Result<()> {
-    if let DeploymentTarget::Remote(host) = target {
-        let mut client = connect_ssh(host)?;
-        if client
-            .exists(deployment_path(config).as_path())
-            .inspect_err(|e| println!("Error checking if directory exists: {}", e))
-            .unwrap_or(false)
-        {
-            client
-                .remove_dir(deployment_path(config).as_path())
-                .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
-            client
-                .remove_file(fluentbit_path(config).as_path())
-                .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
-            client
-                .disconnect()
-                .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
-        }
+    if let DeploymentTarget::Remote(target) = target {
+        let client = SshClient::connect(target).await?;
+        client.remove_dir_all(&deployment_path(config)).await?;
+        client.close().await?;
     } else {
         if fs::metadata(deployment_path(config)).await.is_ok() {
             fs::remove_dir_all(deployment_path(config)).await?;
@@ -113,8 +216,143 @@ pub async fn remove_config(config: &Config, target: &DeploymentTarget) -> Result
     Ok(())
 }
 
-pub async fn deploy_stack(config: &Config, target: &DeploymentTarget) -> Result<()> {
+/// Pulls and builds the stack's images, then deploys the config, records it as a new revision
+/// and recreates the containers. Build contexts are relative to `base_dir`. With `pin_digests`,
+/// the deployed config refers to pulled images by the digests that were pulled.
+pub async fn deploy_stack(
+    config: &Config,
+    target: &DeploymentTarget,
+    base_dir: &Path,
+    pin_digests: bool,
+) -> Result<Revision> {
+    let client = connect_docker(target)?;
+    let config = pull_images(&client, config, pin_digests).await?;
+    let config = build_images(&client, &config, base_dir).await?;
+    release(&client, &config, target, None).await
+}
+
+/// Redeploys a recorded revision with the images it was deployed with, as a new revision.
+/// Defaults to the revision before the current config was first deployed, see
+/// [`rollback_target`].
+pub async fn rollback_stack(
+    config: &Config,
+    target: &DeploymentTarget,
+    revision: Option<u32>,
+) -> Result<Revision> {
+    let revisions = list_revisions(config, target).await?;
+    let revision = match revision {
+        Some(number) => revisions.iter().find(|revision| revision.number == number),
+        None => rollback_target(&revisions),
+    }
+    .ok_or(CoupeError::InvalidInput(match revision {
+        Some(number) => format!("Revision {} not found", number),
+        None => "No previous revision to roll back to".to_string(),
+    }))?;
+
+    let client = connect_docker(target)?;
+    let config = pull_images(&client, &revision.pinned_config(), false).await?;
+    release(&client, &config, target, Some(revision.number)).await
+}
+
+async fn release(
+    client: &Docker,
+    config: &Config,
+    target: &DeploymentTarget,
+    rollback_of: Option<u32>,
+) -> Result<Revision> {
+    let previous = list_revisions(config, target).await?.pop();
     deploy_config(config, target).await?;
+    let revision = record_revision(client, config, target, rollback_of).await?;
+    // Functions and versions dropped from the config, or moved off a host, would otherwise keep
+    // running there.
+    if let Some(previous) = previous {
+        let instances = config.instance_names();
+        for instance in previous.config.instance_names() {
+            let previous_host = previous.config.function_host(&instance).map(|h| &h.ssh);
+            let moved = previous_host != config.function_host(&instance).map(|h| &h.ssh);
+            if !instances.contains(&instance) || moved {
+                let client = function_docker(&previous.config, client, &instance)?;
+                remove_function_container(&client, &previous.config, &instance).await?;
+            }
+        }
+    }
     recreate_docker_stack(config, target).await?;
+    Ok(revision)
+}
+
+/// Shifts requests of a deployed function to one of its versions. With a weight, the version
+/// is sent that percentage of requests; without, its image replaces the function's own and the
+/// version is removed. The new config is deployed and recorded as a revision, and the sentinel
+/// reloads it without restarting the stack.
+pub async fn promote_version(
+    config: &Config,
+    target: &DeploymentTarget,
+    function_name: &str,
+    version: &str,
+    weight: Option<u8>,
+) -> Result<Revision> {
+    let current = list_revisions(config, target)
+        .await?
+        .pop()
+        .ok_or(CoupeError::InvalidInput(format!(
+            "Stack {} has no recorded deploy",
+            config.name
+        )))?;
+    let mut deployed = current.config;
+    let function = deployed
+        .functions
+        .get_mut(function_name)
+        .ok_or(CoupeError::InvalidInput(format!(
+            "Function {} is not deployed",
+            function_name
+        )))?;
+    let not_found = || {
+        CoupeError::InvalidInput(format!(
+            "Function {} has no deployed version {}",
+            function_name, version
+        ))
+    };
+    match weight {
+        Some(weight) => {
+            function
+                .versions
+                .get_mut(version)
+                .ok_or_else(not_found)?
+                .weight = weight;
+        }
+        None => {
+            let promoted = function.versions.remove(version).ok_or_else(not_found)?;
+            function.image = promoted.image;
+            function.build = None;
+        }
+    }
+    validate_function_versions(&deployed)?;
+
+    let client = connect_docker(target)?;
+    deploy_config(&deployed, target).await?;
+    record_revision(&client, &deployed, target, None).await
+}
+
+/// Removes the stack's containers, networks and deployed config, from the target and every host
+/// in `hosts`. Named volumes are kept unless `volumes` is set.
+pub async fn teardown_stack(
+    config: &Config,
+    target: &DeploymentTarget,
+    volumes: bool,
+) -> Result<()> {
+    let client = connect_docker(target)?;
+    teardown(&client, config).await?;
+    if volumes {
+        remove_volumes(&client, config).await?;
+    }
+    remove_config(config, target).await?;
+    for host in host_targets(config) {
+        let client = connect_docker(&host)?;
+        teardown_host(&client, config).await?;
+        if volumes {
+            remove_volumes(&client, config).await?;
+        }
+        remove_config(config, &host).await?;
+    }
     Ok(())
 }
@@ -1,31 +1,229 @@
-use crate::{Config, CoupeError, DeploymentTarget, Result, deployment_path, fluentbit_path};
+use crate::{
+    Config, CoupeError, DOCKER_HOST_GATEWAY, DeploymentTarget, DevMode, PRIMARY_VERSION, Result,
+    SENTINEL_HOSTS_DIR, Target, Volume, connect_docker_ssh, deployment_path, expand_home,
+    fluentbit_path, hosts_path, split_instance, validate_function_versions,
+    validate_function_volumes, validate_placement,
+};
 use bollard::API_DEFAULT_VERSION;
 pub use bollard::Docker;
 use bollard::errors::Error as BollardError;
-use bollard::models::{ContainerCreateBody, ContainerStateStatusEnum, NetworkCreateRequest};
+use bollard::models::{
+    ContainerCreateBody, ContainerState, ContainerStateStatusEnum, HostConfigLogConfig, Mount,
+    MountTmpfsOptions, MountTypeEnum, NetworkConnectRequest, NetworkCreateRequest,
+    VolumeCreateOptions,
+};
 use bollard::query_parameters::{
-    CreateContainerOptionsBuilder, InspectContainerOptions, RemoveContainerOptionsBuilder,
-    StartContainerOptions, StopContainerOptions,
+    CreateContainerOptionsBuilder, InspectContainerOptions, InspectNetworkOptions,
+    ListContainersOptionsBuilder, ListVolumesOptionsBuilder, LogsOptionsBuilder,
+    RemoveContainerOptionsBuilder, StartContainerOptions, StopContainerOptions,
 };
 use bollard::secret::PortBinding;
+use futures::StreamExt;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::time::{Instant, sleep};
 use tracing::{debug, error, info};
 
-const DEFAULT_SENTINEL_IMAGE: &str = "coupe/sentinel:latest";
+pub const FLUENTBIT_IMAGE: &str = "fluent/fluent-bit:latest";
+
+/// Socket Docker listens on by default, which the sentinel also expects its engine on.
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Seconds a request to a local or TCP engine may take, as in bollard's defaults.
+const DOCKER_TIMEOUT: u64 = 120;
+
+/// Where a container engine listens, in `DOCKER_HOST` form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DockerHost {
+    /// A unix socket, `unix:///var/run/docker.sock` or Podman's
+    /// `unix:///run/user/1000/podman/podman.sock`.
+    Unix(PathBuf),
+    /// `tcp://host:port`, over TLS when a directory with `ca.pem`, `cert.pem` and `key.pem` is
+    /// given.
+    Tcp {
+        address: String,
+        tls_cert_path: Option<PathBuf>,
+    },
+}
+
+impl DockerHost {
+    pub fn parse(url: &str, tls_cert_path: Option<PathBuf>) -> Result<Self> {
+        let invalid = |expected: &str| {
+            CoupeError::Config(format!(
+                "Invalid Docker host {}, expected {}",
+                url, expected
+            ))
+        };
+        if let Some(address) = url.strip_prefix("tcp://") {
+            let address = address.strip_suffix('/').unwrap_or(address);
+            let valid = address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+            if !valid || address.contains('/') {
+                return Err(invalid("tcp://host:port"));
+            }
+            return Ok(Self::Tcp {
+                address: address.to_string(),
+                tls_cert_path,
+            });
+        }
+        let path = match url.strip_prefix("unix://") {
+            Some(path) => path,
+            None if url.starts_with('/') => url,
+            None => return Err(invalid("unix:// or tcp://")),
+        };
+        if !path.starts_with('/') {
+            return Err(invalid("an absolute socket path after unix://"));
+        }
+        Ok(Self::Unix(PathBuf::from(path)))
+    }
+
+    /// The engine of this machine, from `DOCKER_HOST`, `DOCKER_TLS_VERIFY` and
+    /// `DOCKER_CERT_PATH` as the docker CLI reads them.
+    pub fn local() -> Result<Self> {
+        let url = match env::var("DOCKER_HOST") {
+            Ok(url) if !url.is_empty() => url,
+            _ => return Ok(Self::Unix(PathBuf::from(DEFAULT_DOCKER_SOCKET))),
+        };
+        if url.starts_with("ssh://") {
+            return Err(CoupeError::Config(format!(
+                "DOCKER_HOST {} is an SSH host, deploy to it with --remote instead",
+                url
+            )));
+        }
+        let tls_cert_path = match env::var("DOCKER_TLS_VERIFY") {
+            Ok(verify) if !verify.is_empty() => Some(match env::var("DOCKER_CERT_PATH") {
+                Ok(path) if !path.is_empty() => PathBuf::from(path),
+                _ => env::home_dir().unwrap_or("/home".into()).join(".docker"),
+            }),
+            _ => None,
+        };
+        Self::parse(&url, tls_cert_path)
+    }
+
+    /// The engine a target's `docker_host` names, none when it is reached with
+    /// `docker system dial-stdio` over SSH.
+    pub fn of_target(target: &Target) -> Result<Option<Self>> {
+        target
+            .docker_host
+            .as_deref()
+            .map(|url| {
+                let tls_cert_path = target.tls_cert_path.as_deref().map(expand_home);
+                Self::parse(url, tls_cert_path)
+            })
+            .transpose()
+    }
+
+    /// Socket on the engine's host for the containers that talk to the engine themselves. An
+    /// engine only reached over TCP is expected on Docker's default socket there as well.
+    pub fn socket(&self) -> &Path {
+        match self {
+            Self::Unix(path) => path,
+            Self::Tcp { .. } => Path::new(DEFAULT_DOCKER_SOCKET),
+        }
+    }
+
+    fn connect(&self) -> std::result::Result<Docker, BollardError> {
+        match self {
+            Self::Unix(path) => Docker::connect_with_unix(
+                &path.to_string_lossy(),
+                DOCKER_TIMEOUT,
+                API_DEFAULT_VERSION,
+            ),
+            Self::Tcp {
+                address,
+                tls_cert_path: None,
+            } => Docker::connect_with_http(address, DOCKER_TIMEOUT, API_DEFAULT_VERSION),
+            Self::Tcp {
+                address,
+                tls_cert_path: Some(path),
+            } => Docker::connect_with_ssl(
+                address,
+                &path.join("key.pem"),
+                &path.join("cert.pem"),
+                &path.join("ca.pem"),
+                DOCKER_TIMEOUT,
+                API_DEFAULT_VERSION,
+            ),
+        }
+    }
+}
+
+/// Socket of the engine a target deploys to, on the engine's own host.
+pub fn engine_socket(target: &DeploymentTarget) -> Result<PathBuf> {
+    let host = match target {
+        DeploymentTarget::Local => Some(DockerHost::local()?),
+        DeploymentTarget::Remote(remote) => DockerHost::of_target(remote)?,
+    };
+    Ok(host.map_or(PathBuf::from(DEFAULT_DOCKER_SOCKET), |host| {
+        host.socket().to_path_buf()
+    }))
+}
+
+/// Container engines, where their APIs differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Docker,
+    Podman,
+}
+
+impl Engine {
+    /// Podman names itself among the components of its version.
+    pub async fn detect(client: &Docker) -> Result<Self> {
+        let version = client
+            .version()
+            .await
+            .map_err(|e| CoupeError::Docker(e.to_string()))?;
+        let podman = version
+            .components
+            .unwrap_or_default()
+            .iter()
+            .any(|component| component.name.to_lowercase().contains("podman"));
+        Ok(if podman { Self::Podman } else { Self::Docker })
+    }
+
+    /// Podman has no fluentd log driver, so Fluent Bit only runs next to Docker.
+    pub fn has_fluentbit(self) -> bool {
+        self == Self::Docker
+    }
+}
+
+/// Log driver sending a container's output to Fluent Bit, none to keep the engine's default
+/// driver.
+fn fluentd_log_config(engine: Engine, config: &Config, tag: &str) -> Option<HostConfigLogConfig> {
+    engine.has_fluentbit().then(|| HostConfigLogConfig {
+        typ: Some("fluentd".to_string()),
+        config: Some(HashMap::from([
+            (
+                "fluentd-address".to_string(),
+                format!("localhost:{}", config.fluentbit_port()),
+            ),
+            ("tag".to_string(), tag.to_string()),
+            ("fluentd-async".to_string(), "true".to_string()),
+        ])),
+    })
+}
 
 pub fn connect_docker(target: &DeploymentTarget) -> Result<Docker> {
     info!(target = ?target, "Connecting to Docker");
 
     let result = match target {
         DeploymentTarget::Local => {
-            debug!("Using local Docker connection");
-            Docker::connect_with_unix_defaults()
+            let host = DockerHost::local()?;
+            debug!(host = ?host, "Using local Docker connection");
+            host.connect()
         }
-        DeploymentTarget::Remote(host) => {
-            info!(host = %host, "Connecting to remote Docker host");
-            Docker::connect_with_ssh(host, 30, API_DEFAULT_VERSION)
+        DeploymentTarget::Remote(remote) => {
+            info!(remote = %remote, "Connecting to remote Docker host");
+            match DockerHost::of_target(remote)? {
+                Some(DockerHost::Unix(socket)) => connect_docker_ssh(remote, Some(socket)),
+                Some(host) => host.connect(),
+                None => connect_docker_ssh(remote, None),
+            }
         }
     };
 
@@ -41,7 +239,21 @@ pub fn connect_docker(target: &DeploymentTarget) -> Result<Docker> {
     }
 }
 
-pub async fn create_fluentbit_container(client: &Docker, config: &Config) -> Result<()> {
+/// Docker client of the host a function or version is placed on, or `main` for those on the
+/// stack's own host.
+pub fn function_docker(config: &Config, main: &Docker, function_name: &str) -> Result<Docker> {
+    match config.function_host(function_name) {
+        Some(host) => connect_docker(&DeploymentTarget::Remote(config.host_target(host))),
+        None => Ok(main.clone()),
+    }
+}
+
+/// Creates the Fluent Bit container, reading engine events from `socket` on its host.
+pub async fn create_fluentbit_container(
+    client: &Docker,
+    config: &Config,
+    socket: &Path,
+) -> Result<()> {
     let container_name = config.fluentbit_container_name();
     let network_name = config.stack_network_name();
 
@@ -53,7 +265,7 @@ pub async fn create_fluentbit_container(client: &Docker, config: &Config) -> Res
     );
 
     let container_config = ContainerCreateBody {
-        image: Some("fluent/fluent-bit:latest".to_string()),
+        image: Some(FLUENTBIT_IMAGE.to_string()),
         exposed_ports: Some(HashMap::from([(
             format!("{}/tcp", config.fluentbit_port()),
             HashMap::<(), ()>::new(),
@@ -69,7 +281,7 @@ pub async fn create_fluentbit_container(client: &Docker, config: &Config) -> Res
                     "{}:/fluent-bit/etc/fluent-bit.yaml:ro",
                     fluentbit_path(config).display()
                 ),
-                "/var/run/docker.sock:/var/run/docker.sock".to_string(),
+                format!("{}:/var/run/docker.sock", socket.display()),
                 "/var/lib/docker/containers:/var/lib/docker/containers:ro".to_string(),
             ]),
             port_bindings: Some(HashMap::from([(
@@ -108,22 +320,18 @@ pub async fn create_fluentbit_container(client: &Docker, config: &Config) -> Res
     }
 }
 
-pub async fn create_sentinel_container(client: &Docker, config: &Config) -> Result<()> {
+/// Creates the sentinel container, with the engine's `socket` on its host mounted where the
+/// sentinel expects Docker's.
+pub async fn create_sentinel_container(
+    client: &Docker,
+    config: &Config,
+    socket: &Path,
+) -> Result<()> {
     let container_name = config.sentinel_container_name();
     let network_name = config.stack_network_name();
 
-    let sentinel_image = config
-        .sentinel
-        .as_ref()
-        .and_then(|s| s.registry.as_ref())
-        .map(|r| {
-            format!(
-                "{}/{}/coupe-sentinel:latest",
-                r.url,
-                r.namespace.as_deref().unwrap_or("library")
-            )
-        })
-        .unwrap_or_else(|| DEFAULT_SENTINEL_IMAGE.to_string());
+    let sentinel_image = config.sentinel_image();
+    let engine = Engine::detect(client).await?;
 
     info!(
         container_name = %container_name,
@@ -133,6 +341,20 @@ pub async fn create_sentinel_container(client: &Docker, config: &Config) -> Resu
         "Creating Sentinel container"
     );
 
+    let mut binds = vec![
+        format!("{}:/usr/app:rw", deployment_path(config).display()),
+        format!("{}:/var/run/docker.sock", socket.display()),
+    ];
+    // The sentinel reaches the Docker daemons of other hosts with the keys copied for them on
+    // deploy.
+    if !config.hosts.is_empty() {
+        binds.push(format!(
+            "{}:{}:ro",
+            hosts_path(config).display(),
+            SENTINEL_HOSTS_DIR
+        ));
+    }
+
     let container_config = ContainerCreateBody {
         image: Some(sentinel_image.clone()),
         env: Some(vec![format!("COUPE_STACK={}", config.name)]),
@@ -152,10 +374,7 @@ pub async fn create_sentinel_container(client: &Docker, config: &Config) -> Resu
                 ..Default::default()
             }),
             network_mode: Some(network_name),
-            binds: Some(vec![
-                format!("{}:/usr/app:rw", deployment_path(config).display()),
-                "/var/run/docker.sock:/var/run/docker.sock".to_string(),
-            ]),
+            binds: Some(binds),
             port_bindings: Some(HashMap::from([(
                 format!("{}/tcp", config.sentinel_port()),
                 Some(vec![PortBinding {
@@ -163,17 +382,7 @@ pub async fn create_sentinel_container(client: &Docker, config: &Config) -> Resu
                     host_port: Some(config.sentinel_port().to_string()),
                 }]),
             )])),
-            log_config: Some(bollard::models::HostConfigLogConfig {
-                typ: Some("fluentd".to_string()),
-                config: Some(HashMap::from([
-                    (
-                        "fluentd-address".to_string(),
-                        format!("localhost:{}", config.fluentbit_port()),
-                    ),
-                    ("tag".to_string(), config.sentinel_container_name()),
-                    ("fluentd-async".to_string(), "true".to_string()),
-                ])),
-            }),
+            log_config: fluentd_log_config(engine, config, &container_name),
             ..Default::default()
         }),
         ..Default::default()
@@ -189,13 +398,19 @@ pub async fn create_sentinel_container(client: &Docker, config: &Config) -> Resu
     {
         Ok(_) => {
             info!(container_name = %container_name, image = %sentinel_image, "Sentinel container created successfully");
-            Ok(())
         }
         Err(e) => {
             error!(container_name = %container_name, image = %sentinel_image, error = %e, "Failed to create Sentinel container");
-            Err(CoupeError::Docker(e.to_string()))
+            return Err(CoupeError::Docker(e.to_string()));
         }
     }
+
+    // The sentinel proxies to every function, so it joins each function's own network.
+    for function_name in config.isolated_functions() {
+        let network_name = config.function_network_name(&function_name);
+        connect_container(client, &container_name, &network_name).await?;
+    }
+    Ok(())
 }
 
 pub async fn create_function_container(
@@ -204,51 +419,67 @@ pub async fn create_function_container(
     function_name: &str,
 ) -> Result<()> {
     let function_config = config
-        .functions
-        .get(function_name)
+        .function(function_name)
         .ok_or_else(|| CoupeError::Config(format!("Function {} not found", function_name)))?;
+    let (base_name, version) = split_instance(function_name);
 
+    let image = config.function_image(function_name)?;
     let container_name = config.function_container_name(function_name);
-    let network_name = config.stack_network_name();
+    let networks = config.function_networks(function_name);
+    let network_name = networks[0].clone();
+    let engine = Engine::detect(client).await?;
 
     info!(
         function_name = %function_name,
         container_name = %container_name,
         network_name = %network_name,
-        image = %function_config.image,
+        image = %image,
         "Creating function container"
     );
 
+    let mut env = vec![
+        format!("COUPE_STACK={}", config.name),
+        format!("COUPE_FUNCTION={}", base_name),
+        format!("COUPE_VERSION={}", version.unwrap_or(PRIMARY_VERSION)),
+    ];
+    // The sentinel's internal API is only reachable from its own host.
+    if config.function_host(function_name).is_none() {
+        env.push(format!(
+            "COUPE_SENTINEL_URL={}",
+            config.internal_sentinel_url()
+        ));
+    }
     let container_config = ContainerCreateBody {
-        image: Some(function_config.image.clone()),
-        env: Some(vec![
-            format!("COUPE_STACK={}", config.name),
-            format!("COUPE_FUNCTION={}", function_name),
-        ]),
+        image: Some(image.clone()),
+        env: Some(env),
         labels: Some({
             let mut labels = HashMap::new();
             labels.insert("coupe.stack".to_string(), config.name.clone());
             labels.insert("coupe.role".to_string(), "function".to_string());
-            labels.insert("coupe.function".to_string(), function_name.to_string());
+            labels.insert("coupe.function".to_string(), base_name.to_string());
+            labels.insert(
+                "coupe.version".to_string(),
+                version.unwrap_or(PRIMARY_VERSION).to_string(),
+            );
             labels
         }),
         host_config: Some(bollard::models::HostConfig {
             network_mode: Some(network_name),
-            log_config: Some(bollard::models::HostConfigLogConfig {
-                typ: Some("fluentd".to_string()),
-                config: Some(HashMap::from([
-                    (
-                        "fluentd-address".to_string(),
-                        format!("localhost:{}", config.fluentbit_port()),
-                    ),
-                    ("tag".to_string(), container_name.clone()),
-                    ("fluentd-async".to_string(), "true".to_string()),
-                ])),
-            }),
+            mounts: Some(function_mounts(config, &function_config.volumes)),
+            log_config: fluentd_log_config(engine, config, &container_name),
             ..Default::default()
         }),
         ..Default::default()
     };
+    let container_config = match (&config.dev, config.published_port(function_name)) {
+        (Some(dev), _) => {
+            dev_function_container(config, dev, engine, function_name, container_config)?
+        }
+        (None, Some(port)) => {
+            placed_function_container(config, function_name, port, container_config)?
+        }
+        (None, None) => container_config,
+    };
 
     let options = CreateContainerOptionsBuilder::new()
         .name(&container_name)
@@ -262,32 +493,231 @@ pub async fn create_function_container(
             info!(
                 function_name = %function_name,
                 container_name = %container_name,
-                image = %function_config.image,
+                image = %image,
                 "Function container created successfully"
             );
-            Ok(())
         }
         Err(e) => {
             error!(
                 function_name = %function_name,
                 container_name = %container_name,
-                image = %function_config.image,
+                image = %image,
                 error = %e,
                 "Failed to create function container"
             );
-            Err(CoupeError::Docker(e.to_string()))
+            return Err(CoupeError::Docker(e.to_string()));
         }
     }
+
+    for network_name in &networks[1..] {
+        connect_container(client, &container_name, network_name).await?;
+    }
+    Ok(())
+}
+
+/// Adapts a function container to a sentinel on the host: the handler port is published on
+/// the loopback interface, the host is reachable as `host.docker.internal`, and logs stay with
+/// Docker's default driver since there is no Fluent Bit.
+fn dev_function_container(
+    config: &Config,
+    dev: &DevMode,
+    engine: Engine,
+    function_name: &str,
+    mut container_config: ContainerCreateBody,
+) -> Result<ContainerCreateBody> {
+    let handler_port = format!("{}/tcp", config.function_handler_port(function_name)?);
+    let host_config = container_config.host_config.get_or_insert_default();
+    host_config.log_config = None;
+    // Podman maps host.docker.internal itself, and older versions reject host-gateway.
+    if engine == Engine::Docker {
+        host_config.extra_hosts = Some(vec![format!("{}:host-gateway", DOCKER_HOST_GATEWAY)]);
+    }
+    if let Some(port) = dev.ports.get(function_name) {
+        host_config.port_bindings = Some(HashMap::from([(
+            handler_port.clone(),
+            Some(vec![PortBinding {
+                host_ip: Some("127.0.0.1".to_string()),
+                host_port: Some(port.to_string()),
+            }]),
+        )]));
+        container_config.exposed_ports = Some(HashMap::from([(handler_port, HashMap::new())]));
+    }
+    Ok(container_config)
+}
+
+/// Adapts a function container to another host than the sentinel's: the handler port is
+/// published on `port` of the host's private address only, and logs stay with Docker's
+/// default driver since Fluent Bit only runs on the sentinel's host.
+fn placed_function_container(
+    config: &Config,
+    function_name: &str,
+    port: u16,
+    mut container_config: ContainerCreateBody,
+) -> Result<ContainerCreateBody> {
+    let handler_port = format!("{}/tcp", config.function_handler_port(function_name)?);
+    let host = config
+        .function_host(function_name)
+        .ok_or_else(|| CoupeError::Config(format!("Function {} is not placed", function_name)))?;
+    let host_config = container_config.host_config.get_or_insert_default();
+    host_config.log_config = None;
+    host_config.port_bindings = Some(HashMap::from([(
+        handler_port.clone(),
+        Some(vec![PortBinding {
+            host_ip: Some(host.address.to_string()),
+            host_port: Some(port.to_string()),
+        }]),
+    )]));
+    container_config.exposed_ports = Some(HashMap::from([(handler_port, HashMap::new())]));
+    Ok(container_config)
 }
 
-pub async fn create_containers(client: &Docker, config: &Config) -> Result<()> {
+fn function_mounts(config: &Config, volumes: &[Volume]) -> Vec<Mount> {
+    volumes
+        .iter()
+        .map(|volume| match volume {
+            Volume::Named {
+                name,
+                target,
+                read_only,
+            } => Mount {
+                typ: Some(MountTypeEnum::VOLUME),
+                source: Some(config.volume_name(name)),
+                target: Some(target.clone()),
+                read_only: Some(*read_only),
+                ..Default::default()
+            },
+            Volume::Bind {
+                source,
+                target,
+                read_only,
+            } => Mount {
+                typ: Some(MountTypeEnum::BIND),
+                source: Some(source.clone()),
+                target: Some(target.clone()),
+                read_only: Some(*read_only),
+                ..Default::default()
+            },
+            Volume::Tmpfs { target, size } => Mount {
+                typ: Some(MountTypeEnum::TMPFS),
+                target: Some(target.clone()),
+                tmpfs_options: size.map(|size| MountTmpfsOptions {
+                    size_bytes: Some(size as i64),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        })
+        .collect()
+}
+
+/// Creates the stack's named volumes. Existing volumes are kept with their data.
+pub async fn create_volumes(client: &Docker, config: &Config) -> Result<()> {
+    for name in config.named_volumes() {
+        let volume_name = config.volume_name(&name);
+        info!(volume_name = %volume_name, "Creating volume");
+
+        let options = VolumeCreateOptions {
+            name: Some(volume_name.clone()),
+            labels: Some(HashMap::from([
+                ("coupe.stack".to_string(), config.name.clone()),
+                ("coupe.volume".to_string(), name.clone()),
+            ])),
+            ..Default::default()
+        };
+        client.create_volume(options).await.map_err(|e| {
+            error!(volume_name = %volume_name, error = %e, "Failed to create volume");
+            CoupeError::Docker(e.to_string())
+        })?;
+    }
+    Ok(())
+}
+
+/// Removes every volume labelled with the stack, including those no longer in the config.
+pub async fn remove_volumes(client: &Docker, config: &Config) -> Result<()> {
+    info!(stack_name = %config.name, "Removing stack volumes");
+
+    let filters = HashMap::from([("label", vec![format!("coupe.stack={}", config.name)])]);
+    let options = ListVolumesOptionsBuilder::new().filters(&filters).build();
+    let volumes = client
+        .list_volumes(Some(options))
+        .await
+        .map_err(|e| CoupeError::Docker(e.to_string()))?
+        .volumes
+        .unwrap_or_default();
+
+    // bollard 0.19.1 only accepts the deprecated options type for this call.
+    #[allow(deprecated)]
+    let no_options = None::<bollard::volume::RemoveVolumeOptions>;
+    for volume in volumes {
+        info!(volume_name = %volume.name, "Removing volume");
+        client
+            .remove_volume(&volume.name, no_options)
+            .await
+            .map_err(|e| {
+                error!(volume_name = %volume.name, error = %e, "Failed to remove volume");
+                CoupeError::Docker(e.to_string())
+            })?;
+    }
+    Ok(())
+}
+
+async fn connect_container(
+    client: &Docker,
+    container_name: &str,
+    network_name: &str,
+) -> Result<()> {
+    info!(
+        container_name = %container_name,
+        network_name = %network_name,
+        "Connecting container to network"
+    );
+
+    let request = NetworkConnectRequest {
+        container: Some(container_name.to_string()),
+        ..Default::default()
+    };
+    client
+        .connect_network(network_name, request)
+        .await
+        .map_err(|e| {
+            error!(container_name = %container_name, network_name = %network_name, error = %e, "Failed to connect container to network");
+            CoupeError::Docker(e.to_string())
+        })
+}
+
+/// Checks that functions without egress only join networks without egress.
+pub fn validate_function_networks(config: &Config) -> Result<()> {
+    for (function_name, function) in &config.functions {
+        let Some(network) = function.network.as_ref().filter(|n| !n.egress) else {
+            continue;
+        };
+        for name in &network.allow {
+            let target_egress = match config.functions.get(name) {
+                Some(target) => target.network.as_ref().is_none_or(|n| n.egress),
+                None => true,
+            };
+            if name != function_name && target_egress {
+                return Err(CoupeError::Config(format!(
+                    "Function {} has egress disabled, so it cannot join the network of {}, which has egress",
+                    function_name, name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Creates the stack's containers on the engine listening on `socket`.
+pub async fn create_containers(client: &Docker, config: &Config, socket: &Path) -> Result<()> {
     info!(stack_name = %config.name, "Creating all containers");
 
-    create_fluentbit_container(client, config).await?;
-    create_sentinel_container(client, config).await?;
+    if Engine::detect(client).await?.has_fluentbit() {
+        create_fluentbit_container(client, config, socket).await?;
+    }
+    create_sentinel_container(client, config, socket).await?;
 
-    for name in config.functions.keys() {
-        create_function_container(client, config, name).await?;
+    for name in config.local_instances() {
+        create_function_container(client, config, &name).await?;
     }
 
     info!(stack_name = %config.name, "All containers created successfully");
@@ -295,20 +725,124 @@ pub async fn create_containers(client: &Docker, config: &Config) -> Result<()> {
 }
 
 pub async fn create_network(client: &Docker, config: &Config) -> Result<()> {
-    let network_name = config.stack_network_name();
+    create_bridge_network(client, config, &config.stack_network_name(), None).await?;
+
+    for function_name in config.isolated_functions() {
+        create_bridge_network(
+            client,
+            config,
+            &config.function_network_name(&function_name),
+            Some(&function_name),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Gateway of Docker's default bridge, which `host-gateway` maps `host.docker.internal` to.
+/// Podman maps the name itself, so it has none.
+pub async fn host_gateway(client: &Docker) -> Result<Option<IpAddr>> {
+    if Engine::detect(client).await? == Engine::Podman {
+        return Ok(None);
+    }
+    let network = client
+        .inspect_network("bridge", None::<InspectNetworkOptions>)
+        .await
+        .map_err(|e| {
+            error!(network_name = "bridge", error = %e, "Failed to inspect network");
+            CoupeError::Docker(e.to_string())
+        })?;
+    Ok(network
+        .ipam
+        .and_then(|ipam| ipam.config)
+        .unwrap_or_default()
+        .into_iter()
+        .find_map(|config| config.gateway?.parse().ok()))
+}
+
+/// Creates the network of an isolated function if it does not exist, such as when a config
+/// reload added the function, and attaches the sentinel to it when both are on this host.
+pub async fn ensure_function_network(
+    client: &Docker,
+    config: &Config,
+    function_name: &str,
+) -> Result<()> {
+    let (base_name, _) = split_instance(function_name);
+    if !config
+        .isolated_functions()
+        .iter()
+        .any(|name| name == base_name)
+    {
+        return Ok(());
+    }
+    let network_name = config.function_network_name(function_name);
+    match client
+        .inspect_network(&network_name, None::<InspectNetworkOptions>)
+        .await
+    {
+        Ok(_) => {}
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => create_bridge_network(client, config, &network_name, Some(base_name)).await?,
+        Err(e) => {
+            error!(network_name = %network_name, error = %e, "Failed to inspect network");
+            return Err(CoupeError::Docker(e.to_string()));
+        }
+    }
+
+    // Under `coupe-cli dev` the sentinel runs on the host and reaches functions by their ports.
+    if config.dev.is_some() || config.function_host(function_name).is_some() {
+        return Ok(());
+    }
+    let sentinel_container_name = config.sentinel_container_name();
+    let attached = client
+        .inspect_container(&sentinel_container_name, None::<InspectContainerOptions>)
+        .await
+        .map_err(|e| {
+            error!(container_name = %sentinel_container_name, error = %e, "Failed to inspect container");
+            CoupeError::Docker(e.to_string())
+        })?
+        .network_settings
+        .and_then(|settings| settings.networks)
+        .is_some_and(|networks| networks.contains_key(&network_name));
+    if !attached {
+        connect_container(client, &sentinel_container_name, &network_name).await?;
+    }
+    Ok(())
+}
+
+/// Creates a network for the stack, or for a single function, which is internal when the
+/// function has egress disabled.
+async fn create_bridge_network(
+    client: &Docker,
+    config: &Config,
+    network_name: &str,
+    function_name: Option<&str>,
+) -> Result<()> {
+    // Ports cannot be published from internal networks, which the sentinel needs in dev.
+    let internal = config.dev.is_none()
+        && function_name
+            .and_then(|name| config.functions.get(name))
+            .and_then(|f| f.network.as_ref())
+            .is_some_and(|n| !n.egress);
 
     info!(
         stack_name = %config.name,
         network_name = %network_name,
+        internal = internal,
         "Creating Docker network"
     );
 
     let options = NetworkCreateRequest {
-        name: network_name.clone(),
+        name: network_name.to_string(),
         driver: Some("bridge".to_string()),
+        internal: Some(internal),
         labels: Some({
             let mut labels = HashMap::new();
             labels.insert("coupe.stack".to_string(), config.name.clone());
+            if let Some(function_name) = function_name {
+                labels.insert("coupe.function".to_string(), function_name.to_string());
+            }
             labels
         }),
         ..Default::default()
@@ -326,27 +860,161 @@ pub async fn create_network(client: &Docker, config: &Config) -> Result<()> {
     }
 }
 
+/// State of a container, or `None` when there is no such container.
+pub async fn get_container_state(
+    client: &Docker,
+    container_id: &str,
+) -> Result<Option<ContainerState>> {
+    debug!(container_id = %container_id, "Getting container state");
+
+    match client
+        .inspect_container(container_id, None::<InspectContainerOptions>)
+        .await
+    {
+        Ok(inspect_result) => Ok(Some(inspect_result.state.unwrap_or_default())),
+        Err(BollardError::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(None),
+        Err(e) => {
+            error!(container_id = %container_id, error = %e, "Failed to inspect container");
+            Err(CoupeError::Docker(e.to_string()))
+        }
+    }
+}
+
 pub async fn get_container_status(
     client: &Docker,
     container_id: &str,
 ) -> Result<ContainerStateStatusEnum> {
     debug!(container_id = %container_id, "Getting container status");
 
+    let state = get_container_state(client, container_id)
+        .await?
+        .ok_or_else(|| {
+            error!(container_id = %container_id, "Container not found");
+            CoupeError::Docker(format!("No such container: {}", container_id))
+        })?;
+    let status = state.status.unwrap_or(ContainerStateStatusEnum::EMPTY);
+
+    debug!(container_id = %container_id, status = ?status, "Container status retrieved");
+    Ok(status)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ContainerDiagnostics {
+    pub status: Option<String>,
+    pub exit_code: Option<i64>,
+    pub error: Option<String>,
+    pub oom_killed: Option<bool>,
+    pub finished_at: Option<String>,
+    pub log_tail: Vec<String>,
+}
+
+/// The function whose running container has the address on one of its networks, found by the
+/// container's `coupe.*` labels. Versions are reported by their function's name.
+pub async fn function_at_address(
+    client: &Docker,
+    config: &Config,
+    address: IpAddr,
+) -> Result<Option<String>> {
+    let filters = HashMap::from([(
+        "label",
+        vec![
+            format!("coupe.stack={}", config.name),
+            "coupe.role=function".to_string(),
+        ],
+    )]);
+    let options = ListContainersOptionsBuilder::new()
+        .filters(&filters)
+        .build();
+    let containers = client
+        .list_containers(Some(options))
+        .await
+        .map_err(|e| CoupeError::Docker(e.to_string()))?;
+    for container in containers {
+        let has_address = container
+            .network_settings
+            .and_then(|settings| settings.networks)
+            .unwrap_or_default()
+            .values()
+            .filter_map(|network| network.ip_address.as_deref())
+            .any(|ip| ip.parse::<IpAddr>().is_ok_and(|ip| ip == address));
+        if has_address {
+            return Ok(container
+                .labels
+                .and_then(|mut labels| labels.remove("coupe.function")));
+        }
+    }
+    Ok(None)
+}
+
+pub async fn inspect_function_container(
+    client: &Docker,
+    config: &Config,
+    function_name: &str,
+    log_lines: usize,
+) -> Result<ContainerDiagnostics> {
+    let container_name = config.function_container_name(function_name);
+    debug!(container_name = %container_name, "Inspecting function container");
+
     let inspect_result = client
-        .inspect_container(container_id, None::<InspectContainerOptions>)
+        .inspect_container(&container_name, None::<InspectContainerOptions>)
         .await
         .map_err(|e| {
-            error!(container_id = %container_id, error = %e, "Failed to inspect container");
+            error!(container_name = %container_name, error = %e, "Failed to inspect container");
             CoupeError::Docker(e.to_string())
         })?;
+    let state = inspect_result.state.unwrap_or_default();
 
-    let status = inspect_result
-        .state
-        .and_then(|state| state.status)
-        .unwrap_or(ContainerStateStatusEnum::EMPTY);
+    let options = LogsOptionsBuilder::new()
+        .stdout(true)
+        .stderr(true)
+        .tail(&log_lines.to_string())
+        .build();
+    let mut log_tail = Vec::new();
+    let mut logs = client.logs(&container_name, Some(options));
+    while let Some(output) = logs.next().await {
+        match output {
+            Ok(output) => log_tail.extend(output.to_string().lines().map(str::to_string)),
+            Err(e) => {
+                // Log drivers without local read support cannot serve logs; keep the state.
+                debug!(container_name = %container_name, error = %e, "Failed to read container logs");
+                break;
+            }
+        }
+    }
 
-    debug!(container_id = %container_id, status = ?status, "Container status retrieved");
-    Ok(status)
+    Ok(ContainerDiagnostics {
+        status: state.status.map(|s| s.to_string()),
+        exit_code: state.exit_code,
+        error: state.error.filter(|e| !e.is_empty()),
+        oom_killed: state.oom_killed,
+        finished_at: state.finished_at,
+        log_tail,
+    })
+}
+
+pub async fn recreate_function_container(
+    client: &Docker,
+    config: &Config,
+    function_name: &str,
+) -> Result<()> {
+    let container_name = config.function_container_name(function_name);
+    info!(
+        function_name = %function_name,
+        container_name = %container_name,
+        "Recreating function container"
+    );
+    remove_container_if_exists(client, &container_name).await?;
+    create_function_container(client, config, function_name).await
+}
+
+pub async fn remove_function_container(
+    client: &Docker,
+    config: &Config,
+    function_name: &str,
+) -> Result<()> {
+    remove_container_if_exists(client, &config.function_container_name(function_name)).await
 }
 
 pub struct ContainerRunResult {
@@ -452,13 +1120,28 @@ pub async fn recreate_docker_stack(config: &Config, target: &DeploymentTarget) -
         "Recreating Docker stack"
     );
 
+    validate_function_networks(config)?;
+    validate_function_versions(config)?;
+    validate_function_volumes(config)?;
+    validate_placement(config)?;
     let client = connect_docker(target)?;
     teardown(&client, config).await?;
     create_network(&client, config).await?;
-    create_containers(&client, config).await?;
-    ensure_fluentbit_running(&client, config).await?;
+    create_volumes(&client, config).await?;
+    create_containers(&client, config, &engine_socket(target)?).await?;
+    if Engine::detect(&client).await?.has_fluentbit() {
+        ensure_fluentbit_running(&client, config).await?;
+    }
     ensure_sentinel_running(&client, config).await?;
 
+    let mut host_names: Vec<&String> = config.hosts.keys().collect();
+    host_names.sort();
+    for host_name in host_names {
+        let host_target = config.host_target(&config.hosts[host_name]);
+        let host_client = connect_docker(&DeploymentTarget::Remote(host_target))?;
+        recreate_host(&host_client, config, host_name).await?;
+    }
+
     info!(stack_name = %config.name, "Docker stack recreated successfully");
     Ok(())
 }
@@ -511,8 +1194,8 @@ async fn poll_until_running(client: &Docker, container_id: &str) -> Result<()> {
 pub async fn teardown(client: &Docker, config: &Config) -> Result<()> {
     info!(stack_name = %config.name, "Tearing down Docker stack");
 
-    for name in config.functions.keys() {
-        let container_name = config.function_container_name(name);
+    for name in config.instance_names() {
+        let container_name = config.function_container_name(&name);
         info!(container_name = %container_name, "Removing function container");
         remove_container_if_exists(client, &container_name).await?;
     }
@@ -525,6 +1208,12 @@ pub async fn teardown(client: &Docker, config: &Config) -> Result<()> {
     info!(container_name = %fluentbit_container_name, "Removing Fluent Bit container");
     remove_container_if_exists(client, &fluentbit_container_name).await?;
 
+    for function_name in config.isolated_functions() {
+        let network_name = config.function_network_name(&function_name);
+        info!(network_name = %network_name, "Removing function network");
+        remove_network_if_exists(client, &network_name).await?;
+    }
+
     let network_name = config.stack_network_name();
     info!(network_name = %network_name, "Removing Docker network");
     remove_network_if_exists(client, &network_name).await?;
@@ -533,6 +1222,28 @@ pub async fn teardown(client: &Docker, config: &Config) -> Result<()> {
     Ok(())
 }
 
+/// Recreates the stack network, volumes and the containers of the functions placed on another
+/// host. The containers are started on demand by the sentinel.
+pub async fn recreate_host(client: &Docker, config: &Config, host_name: &str) -> Result<()> {
+    info!(stack_name = %config.name, host = %host_name, "Recreating host");
+    teardown_host(client, config).await?;
+    create_bridge_network(client, config, &config.stack_network_name(), None).await?;
+    create_volumes(client, config).await?;
+    for name in config.host_instances(host_name) {
+        create_function_container(client, config, &name).await?;
+    }
+    Ok(())
+}
+
+/// Removes the stack's function containers and network from another host.
+pub async fn teardown_host(client: &Docker, config: &Config) -> Result<()> {
+    // Every function is removed, in case it was placed on this host before.
+    for name in config.instance_names() {
+        remove_container_if_exists(client, &config.function_container_name(&name)).await?;
+    }
+    remove_network_if_exists(client, &config.stack_network_name()).await
+}
+
 async fn remove_container_if_exists(client: &Docker, container_name: &str) -> Result<()> {
     info!(container_name = %container_name, "Removing container if exists");
 
@@ -544,11 +1255,11 @@ async fn remove_container_if_exists(client: &Docker, container_name: &str) -> Re
             Ok(())
         }
         Err(e) => {
-            if let BollardError::DockerResponseServerError { status_code, .. } = &e {
-                if *status_code == 404 {
-                    debug!(container_name = %container_name, "Container not found (already removed)");
-                    return Ok(());
-                }
+            if let BollardError::DockerResponseServerError { status_code, .. } = &e
+                && *status_code == 404
+            {
+                debug!(container_name = %container_name, "Container not found (already removed)");
+                return Ok(());
             }
             error!(container_name = %container_name, error = %e, "Failed to remove container");
             Err(CoupeError::Docker(e.to_string()))
@@ -565,11 +1276,11 @@ async fn remove_network_if_exists(client: &Docker, network_name: &str) -> Result
             Ok(())
         }
         Err(e) => {
-            if let BollardError::DockerResponseServerError { status_code, .. } = &e {
-                if *status_code == 404 {
-                    debug!(network_name = %network_name, "Network not found (already removed)");
-                    return Ok(());
-                }
+            if let BollardError::DockerResponseServerError { status_code, .. } = &e
+                && *status_code == 404
+            {
+                debug!(network_name = %network_name, "Network not found (already removed)");
+                return Ok(());
             }
             error!(network_name = %network_name, error = %e, "Failed to remove network");
             Err(CoupeError::Docker(e.to_string()))
@@ -600,7 +1311,7 @@ pub async fn stop_function_container(
     config: &Config,
     function_name: &str,
 ) -> Result<()> {
-    if !config.functions.contains_key(function_name) {
+    if config.function(function_name).is_none() {
         error!(function_name = %function_name, "Function not found in config");
         return Err(CoupeError::InvalidInput(format!(
             "Function {} not found",
@@ -617,3 +1328,56 @@ pub async fn stop_function_container(
 
     stop_container(client, &container_name).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unix_sockets() {
+        for url in ["unix:///var/run/docker.sock", "/var/run/docker.sock"] {
+            assert_eq!(
+                DockerHost::parse(url, None).unwrap(),
+                DockerHost::Unix(PathBuf::from("/var/run/docker.sock"))
+            );
+        }
+        assert_eq!(
+            DockerHost::parse("unix:///run/user/1000/podman/podman.sock", None)
+                .unwrap()
+                .socket(),
+            Path::new("/run/user/1000/podman/podman.sock")
+        );
+    }
+
+    #[test]
+    fn parses_tcp_addresses() {
+        assert_eq!(
+            DockerHost::parse("tcp://10.0.0.13:2376/", Some(PathBuf::from("/certs"))).unwrap(),
+            DockerHost::Tcp {
+                address: "10.0.0.13:2376".to_string(),
+                tls_cert_path: Some(PathBuf::from("/certs")),
+            }
+        );
+        let host = DockerHost::parse("tcp://docker.example.com:2375", None).unwrap();
+        assert_eq!(host.socket(), Path::new(DEFAULT_DOCKER_SOCKET));
+    }
+
+    #[test]
+    fn rejects_invalid_hosts() {
+        for url in [
+            "",
+            "docker.sock",
+            "ssh://deploy@example.com",
+            "http://example.com:2375",
+            "unix://",
+            "unix://docker.sock",
+            "tcp://",
+            "tcp://example.com",
+            "tcp://:2375",
+            "tcp://example.com:docker",
+            "tcp://example.com:2375/v1.45",
+        ] {
+            assert!(DockerHost::parse(url, None).is_err(), "{}", url);
+        }
+    }
+}
@@ -37,6 +37,12 @@ pub enum CoupeError {
     #[error("Unknown error: {0}")]
     Unknown(String),
 
+    #[error("HTTP error: {0}")]
+    Http(String),
+
     #[error("Healthcheck error: {0}")]
     Healthcheck(String),
+
+    #[error("Broker error: {0}")]
+    Broker(String),
 }
@@ -1,11 +1,21 @@
+mod builder;
 mod config;
 mod deployment;
 mod docker;
 mod error;
 mod fluentbit;
+mod logs;
+mod registry;
+mod revisions;
+mod ssh;
 
+pub use builder::*;
 pub use config::*;
 pub use deployment::*;
 pub use docker::*;
 pub use error::*;
 pub use fluentbit::*;
+pub use logs::*;
+pub use registry::*;
+pub use revisions::*;
+pub(crate) use ssh::*;
@@ -0,0 +1,265 @@
+use crate::{Config, CoupeError, Result, split_instance, version_instance};
+use bollard::Docker;
+use bollard::container::LogOutput;
+use bollard::query_parameters::{ListContainersOptionsBuilder, LogsOptionsBuilder};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use tracing::{debug, error};
+
+/// Where a log line came from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LogSource {
+    Sentinel,
+    Function(String),
+}
+
+impl LogSource {
+    pub fn name(&self) -> &str {
+        match self {
+            LogSource::Sentinel => "sentinel",
+            LogSource::Function(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogLine {
+    pub source: LogSource,
+    /// RFC 3339 timestamp with fixed nanosecond precision, as added by Docker.
+    pub timestamp: Option<String>,
+    pub message: String,
+    pub stderr: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LogsOptions {
+    /// Keep streaming new lines instead of stopping at the current end of the logs.
+    pub follow: bool,
+    /// Only lines since this Unix timestamp.
+    pub since: Option<i64>,
+}
+
+/// Containers of the stack to read logs from, found by their `coupe.*` labels: the sentinel
+/// and either every function or the named one. Versions' containers are named by their
+/// instance name, such as `orders@canary`.
+pub async fn log_containers(
+    client: &Docker,
+    config: &Config,
+    function_name: Option<&str>,
+) -> Result<Vec<(String, LogSource)>> {
+    match function_name {
+        Some(function_name) if !config.functions.contains_key(function_name) => {
+            return Err(CoupeError::Config(format!(
+                "Function not found: {}",
+                function_name
+            )));
+        }
+        _ => {}
+    }
+
+    let filters = HashMap::from([("label", vec![format!("coupe.stack={}", config.name)])]);
+    let options = ListContainersOptionsBuilder::new()
+        .all(true)
+        .filters(&filters)
+        .build();
+    let containers = client.list_containers(Some(options)).await.map_err(|e| {
+        error!(stack = %config.name, error = %e, "Failed to list containers");
+        CoupeError::Docker(e.to_string())
+    })?;
+
+    let mut found = Vec::new();
+    for container in containers {
+        let labels = container.labels.unwrap_or_default();
+        let source = match labels.get("coupe.role").map(String::as_str) {
+            Some("sentinel") => LogSource::Sentinel,
+            Some("function") => match labels.get("coupe.function") {
+                Some(name) if function_name.is_none_or(|f| f == name) => {
+                    LogSource::Function(match labels.get("coupe.version") {
+                        Some(version) => version_instance(name, version),
+                        None => name.clone(),
+                    })
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let Some(id) = container.id else { continue };
+        debug!(container_id = %id, source = %source.name(), "Found log container");
+        found.push((id, source));
+    }
+
+    if let Some(function_name) = function_name {
+        let started = found.iter().any(|(_, source)| {
+            *source != LogSource::Sentinel && split_instance(source.name()).0 == function_name
+        });
+        if !started {
+            return Err(CoupeError::Docker(format!(
+                "No container for function {}, it has not been started yet",
+                function_name
+            )));
+        }
+    }
+    found.sort_by(|(_, a), (_, b)| a.name().cmp(b.name()));
+    Ok(found)
+}
+
+/// Splits a Docker log frame into lines, taking off the timestamp Docker puts before each one.
+fn log_lines(source: &LogSource, output: LogOutput) -> Vec<LogLine> {
+    let (message, stderr) = match output {
+        LogOutput::StdErr { message } => (message, true),
+        LogOutput::StdOut { message } | LogOutput::Console { message } => (message, false),
+        LogOutput::StdIn { .. } => return Vec::new(),
+    };
+    String::from_utf8_lossy(&message)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (timestamp, message) = match line.split_once(' ') {
+                Some((timestamp, message)) if timestamp.ends_with('Z') => {
+                    (Some(timestamp.to_string()), message)
+                }
+                _ => (None, line),
+            };
+            LogLine {
+                source: source.clone(),
+                timestamp,
+                message: message.to_string(),
+                stderr,
+            }
+        })
+        .collect()
+}
+
+/// Streams the logs of the containers merged into one. Without `follow`, the stream ends at the
+/// current end of the logs and lines are in timestamp order; when following, lines are merged as
+/// they arrive. Containers whose log driver cannot be read back end their stream with an error.
+/// Fails when `since` is outside what Docker accepts.
+pub async fn stream_logs(
+    client: &Docker,
+    containers: &[(String, LogSource)],
+    options: &LogsOptions,
+) -> Result<BoxStream<'static, Result<LogLine>>> {
+    let since = options
+        .since
+        .map(|since| {
+            i32::try_from(since).map_err(|_| {
+                CoupeError::InvalidInput(format!("Log timestamp {} is out of range", since))
+            })
+        })
+        .transpose()?;
+    let streams = containers.iter().map(|(id, source)| {
+        let mut builder = LogsOptionsBuilder::new()
+            .stdout(true)
+            .stderr(true)
+            .timestamps(true)
+            .follow(options.follow);
+        if let Some(since) = since {
+            builder = builder.since(since);
+        }
+        let source = source.clone();
+        let id = id.clone();
+        client
+            .logs(&id, Some(builder.build()))
+            .map(move |output| match output {
+                Ok(output) => Ok(stream::iter(log_lines(&source, output).into_iter().map(Ok))),
+                Err(e) => Err(CoupeError::Docker(format!(
+                    "Failed to read {} logs: {}",
+                    source.name(),
+                    e
+                ))),
+            })
+            .map(|lines| match lines {
+                Ok(lines) => lines.left_stream(),
+                Err(e) => stream::once(async { Err(e) }).right_stream(),
+            })
+            .flatten()
+            .boxed()
+    });
+    let merged = stream::select_all(streams);
+    if options.follow {
+        return Ok(merged.boxed());
+    }
+
+    let mut lines: Vec<Result<LogLine>> = merged.collect().await;
+    lines.sort_by(|a, b| match (a, b) {
+        (Ok(a), Ok(b)) => a.timestamp.cmp(&b.timestamp),
+        // Errors go last, after the logs that could be read.
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+    Ok(stream::iter(lines).boxed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn lines(output: LogOutput) -> Vec<(Option<String>, String, bool)> {
+        log_lines(&LogSource::Function("api".to_string()), output)
+            .into_iter()
+            .map(|line| (line.timestamp, line.message, line.stderr))
+            .collect()
+    }
+
+    #[test]
+    fn frames_are_split_into_timestamped_lines() {
+        let message = Bytes::from(
+            "2024-05-01T10:00:00.000000000Z first line\n\n2024-05-01T10:00:01.000000000Z second one\n",
+        );
+        assert_eq!(
+            lines(LogOutput::StdOut { message }),
+            [
+                (
+                    Some("2024-05-01T10:00:00.000000000Z".to_string()),
+                    "first line".to_string(),
+                    false
+                ),
+                (
+                    Some("2024-05-01T10:00:01.000000000Z".to_string()),
+                    "second one".to_string(),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_without_timestamps_are_kept_whole() {
+        let message = Bytes::from("no timestamp here\nplain");
+        assert_eq!(
+            lines(LogOutput::StdErr { message }),
+            [
+                (None, "no timestamp here".to_string(), true),
+                (None, "plain".to_string(), true),
+            ]
+        );
+        let message = Bytes::from("ignored");
+        assert!(lines(LogOutput::StdIn { message }).is_empty());
+    }
+
+    #[tokio::test]
+    async fn since_must_fit_docker_timestamps() {
+        // Nothing is sent to the engine without containers.
+        let client =
+            Docker::connect_with_http("http://127.0.0.1:1", 1, bollard::API_DEFAULT_VERSION)
+                .unwrap();
+        let options = |since| LogsOptions {
+            follow: false,
+            since: Some(since),
+        };
+        assert!(
+            stream_logs(&client, &[], &options(1_700_000_000))
+                .await
+                .is_ok()
+        );
+        for since in [i64::from(i32::MAX) + 1, i64::from(i32::MIN) - 1] {
+            let error = stream_logs(&client, &[], &options(since))
+                .await
+                .err()
+                .unwrap();
+            assert!(error.to_string().contains("out of range"), "{}", error);
+        }
+    }
+}
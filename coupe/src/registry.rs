@@ -0,0 +1,249 @@
+use crate::{
+    Config, CoupeError, FLUENTBIT_IMAGE, Result, Sentinel, function_docker, split_instance,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bollard::{Docker, auth::DockerCredentials, query_parameters::CreateImageOptionsBuilder};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs, path::PathBuf};
+use tracing::{debug, error, info};
+
+const DOCKER_HUB: &str = "docker.io";
+
+/// Registry host of an image reference, `docker.io` for images without one.
+pub fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains('.') || host.contains(':') || host == "localhost" => host,
+        _ => DOCKER_HUB,
+    }
+}
+
+/// Splits an image reference into the repository and its tag, defaulting to `latest`. Digest
+/// references are returned whole, since Docker pulls them without a tag.
+pub(crate) fn split_image(image: &str) -> (&str, Option<&str>) {
+    if image.contains('@') {
+        return (image, None);
+    }
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match image[name_start..].rfind(':') {
+        Some(i) => (&image[..name_start + i], Some(&image[name_start + i + 1..])),
+        None => (image, Some("latest")),
+    }
+}
+
+/// Host of a registry URL or `~/.docker/config.json` key, with Docker Hub's aliases unified.
+fn normalize_registry(registry: &str) -> &str {
+    let host = registry
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => DOCKER_HUB,
+        host => host,
+    }
+}
+
+#[derive(Deserialize)]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, DockerConfigAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+    identitytoken: Option<String>,
+}
+
+fn docker_config_path() -> PathBuf {
+    match env::var("DOCKER_CONFIG") {
+        Ok(dir) => PathBuf::from(dir).join("config.json"),
+        Err(_) => env::home_dir()
+            .unwrap_or("/home".into())
+            .join(".docker")
+            .join("config.json"),
+    }
+}
+
+/// Credentials stored by `docker login`. Credential helpers are not supported.
+fn docker_config_credentials(registry: &str) -> Option<DockerCredentials> {
+    let path = docker_config_path();
+    let content = fs::read_to_string(&path).ok()?;
+    let file: DockerConfigFile = serde_json::from_str(&content)
+        .inspect_err(
+            |e| debug!(path = %path.display(), error = %e, "Failed to parse Docker config"),
+        )
+        .ok()?;
+    let (key, auth) = file
+        .auths
+        .iter()
+        .find(|(key, _)| normalize_registry(key) == registry)?;
+
+    if let Some(identitytoken) = &auth.identitytoken {
+        return Some(DockerCredentials {
+            identitytoken: Some(identitytoken.clone()),
+            serveraddress: Some(key.clone()),
+            ..Default::default()
+        });
+    }
+    let decoded = STANDARD.decode(auth.auth.as_ref()?).ok()?;
+    let (username, password) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(username, password)| (username.to_string(), password.to_string()))?;
+    Some(DockerCredentials {
+        username: Some(username),
+        password: Some(password),
+        serveraddress: Some(key.clone()),
+        ..Default::default()
+    })
+}
+
+/// Credentials for a registry host: `sentinel.registry` and the `COUPE_REGISTRY_*` variables for
+/// the configured registry, then `~/.docker/config.json`.
+pub fn registry_credentials(config: &Config, registry: &str) -> Option<DockerCredentials> {
+    let configured = config
+        .registry()
+        .filter(|r| normalize_registry(&r.url) == registry);
+    if let Some(configured) = configured {
+        let username = env::var("COUPE_REGISTRY_USERNAME")
+            .ok()
+            .or(configured.username.clone());
+        let password = env::var("COUPE_REGISTRY_PASSWORD")
+            .ok()
+            .or(configured.password.clone());
+        if let (Some(username), Some(password)) = (username, password) {
+            return Some(DockerCredentials {
+                username: Some(username),
+                password: Some(password),
+                serveraddress: Some(configured.url.clone()),
+                ..Default::default()
+            });
+        }
+    }
+    docker_config_credentials(registry)
+}
+
+/// Pulls an image, printing the progress of each layer.
+pub async fn pull_image(client: &Docker, config: &Config, image: &str) -> Result<()> {
+    let registry = normalize_registry(image_registry(image));
+    let credentials = registry_credentials(config, registry);
+    info!(image = %image, registry = %registry, authenticated = credentials.is_some(), "Pulling image");
+    println!("Pulling {}", image);
+
+    let (repository, tag) = split_image(image);
+    let mut options = CreateImageOptionsBuilder::new().from_image(repository);
+    if let Some(tag) = tag {
+        options = options.tag(tag);
+    }
+    let mut pull = client.create_image(Some(options.build()), None, credentials);
+    let mut layers: HashMap<String, String> = HashMap::new();
+    while let Some(progress) = pull.next().await {
+        let progress = progress.map_err(|e| {
+            error!(image = %image, error = %e, "Failed to pull image");
+            CoupeError::Docker(format!("Failed to pull {}: {}", image, e))
+        })?;
+        if let Some(e) = progress.error {
+            error!(image = %image, error = %e, "Failed to pull image");
+            return Err(CoupeError::Docker(format!(
+                "Failed to pull {}: {}",
+                image, e
+            )));
+        }
+        match (progress.id, progress.status) {
+            // Only status changes are printed, not every byte count.
+            (Some(id), Some(status)) if layers.get(&id) != Some(&status) => {
+                println!("  {}: {}", id, status);
+                layers.insert(id, status);
+            }
+            (None, Some(status)) => println!("  {}", status),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Pulls an image, falling back to a local image of the same name when the pull fails, such as
+/// one built with `docker build` that was never pushed.
+pub async fn ensure_image(client: &Docker, config: &Config, image: &str) -> Result<()> {
+    let Err(e) = pull_image(client, config, image).await else {
+        return Ok(());
+    };
+    if client.inspect_image(image).await.is_ok() {
+        println!("Using local image {}: {}", image, e);
+        return Ok(());
+    }
+    Err(e)
+}
+
+/// The digest reference of a pulled image, such as `registry.example.com/app@sha256:...`.
+pub async fn image_digest(client: &Docker, image: &str) -> Result<String> {
+    let inspect = client
+        .inspect_image(image)
+        .await
+        .map_err(|e| CoupeError::Docker(e.to_string()))?;
+    let repo_digests = inspect.repo_digests.unwrap_or_default();
+    let (repository, _) = split_image(image);
+    let digest = repo_digests
+        .iter()
+        .find(|digest| digest.split('@').next() == Some(repository))
+        .or(repo_digests.first())
+        .ok_or(CoupeError::Docker(format!(
+            "Image {} has no digest, it may have been built locally",
+            image
+        )))?;
+    // Docker Hub digests have no host, which would send them to `sentinel.registry`.
+    if image_registry(digest) == DOCKER_HUB && !digest.starts_with("docker.io/") {
+        return Ok(format!("{}/{}", DOCKER_HUB, digest));
+    }
+    Ok(digest.clone())
+}
+
+/// Pulls every image of the stack, function images on the host each function is placed on.
+/// With `pin_digests`, returns the config with the sentinel
+/// and function images replaced by the digests that were pulled.
+pub async fn pull_images(client: &Docker, config: &Config, pin_digests: bool) -> Result<Config> {
+    let mut config = config.clone();
+    ensure_image(client, &config, FLUENTBIT_IMAGE).await?;
+
+    let sentinel_image = config.sentinel_image();
+    ensure_image(client, &config, &sentinel_image).await?;
+    if pin_digests {
+        let digest = image_digest(client, &sentinel_image).await?;
+        println!("Pinned {} to {}", sentinel_image, digest);
+        let fluentbit_port = config.fluentbit_port();
+        config
+            .sentinel
+            .get_or_insert_with(|| Sentinel {
+                fluentbit_port,
+                ..Default::default()
+            })
+            .image = Some(digest);
+    }
+
+    // Functions with `build` are built on the daemon instead of pulled, their versions are not.
+    let instances: Vec<String> = config
+        .instance_names()
+        .into_iter()
+        .filter(|instance| {
+            split_instance(instance).1.is_some()
+                || config
+                    .functions
+                    .get(instance.as_str())
+                    .is_some_and(|f| f.build.is_none())
+        })
+        .collect();
+    for instance in instances {
+        let image = config.function_image(&instance)?;
+        let client = function_docker(&config, client, &instance)?;
+        ensure_image(&client, &config, &image).await?;
+        if pin_digests {
+            let digest = image_digest(&client, &image).await?;
+            println!("Pinned {} to {}", image, digest);
+            config.set_function_image(&instance, digest);
+        }
+    }
+    Ok(config)
+}
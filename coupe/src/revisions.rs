@@ -0,0 +1,318 @@
+use crate::{
+    Config, CoupeError, DeploymentTarget, Result, Sentinel, SshClient, deployment_path,
+    function_docker, image_digest,
+};
+use bollard::Docker;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, io::ErrorKind, path::PathBuf};
+use tokio::fs;
+use tracing::{info, warn};
+
+/// A deploy of the stack, kept so it can be rolled back to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub number: u32,
+    pub deployed_at: Timestamp,
+    pub deployed_by: String,
+    /// The revision this deploy rolled back to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollback_of: Option<u32>,
+    /// Image of the sentinel and of each function and version, by digest when the registry gave
+    /// one.
+    pub images: BTreeMap<String, String>,
+    pub config: Config,
+}
+
+impl Revision {
+    /// The revision's config with its images pinned to the ones that were deployed.
+    pub fn pinned_config(&self) -> Config {
+        let mut config = self.config.clone();
+        for (name, image) in &self.images {
+            if name == SENTINEL_IMAGE_KEY {
+                let fluentbit_port = config.fluentbit_port();
+                config
+                    .sentinel
+                    .get_or_insert_with(|| Sentinel {
+                        fluentbit_port,
+                        ..Default::default()
+                    })
+                    .image = Some(image.clone());
+            } else {
+                config.set_function_image(name, image.clone());
+            }
+        }
+        config
+    }
+}
+
+/// Key of the sentinel's image in [`Revision::images`].
+const SENTINEL_IMAGE_KEY: &str = "sentinel";
+
+fn revisions_path(config: &Config) -> PathBuf {
+    deployment_path(config).join("revisions")
+}
+
+fn revision_path(config: &Config, number: u32) -> PathBuf {
+    revisions_path(config).join(format!("{}.yaml", number))
+}
+
+fn revision_number(file_name: &str) -> Option<u32> {
+    file_name.strip_suffix(".yaml")?.parse().ok()
+}
+
+/// Numbers of the revisions to remove before recording a new one, so `history` are left with
+/// it. `existing` is oldest first.
+fn expired_revisions(existing: &[Revision], history: usize) -> Vec<u32> {
+    existing
+        .iter()
+        .rev()
+        .skip(history.saturating_sub(1))
+        .map(|revision| revision.number)
+        .collect()
+}
+
+/// The revision a rollback without a number returns to: the one deployed before the current
+/// revision's config was first deployed. Rollbacks are followed to the revision they restored,
+/// so rolling back twice does not return to the revision rolled back from. `revisions` is oldest
+/// first.
+pub fn rollback_target(revisions: &[Revision]) -> Option<&Revision> {
+    let mut origin = revisions.last()?.number;
+    while let Some(rollback_of) = revisions
+        .iter()
+        .find(|revision| revision.number == origin)
+        .and_then(|revision| revision.rollback_of)
+        .filter(|rollback_of| *rollback_of < origin)
+    {
+        origin = rollback_of;
+    }
+    revisions
+        .iter()
+        .rev()
+        .find(|revision| revision.number < origin)
+}
+
+fn deploying_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or("unknown".to_string())
+}
+
+/// The image by digest, or as given for images without one, such as those built on deploy.
+async fn resolve_image(client: &Docker, image: &str) -> String {
+    if image.contains('@') {
+        return image.to_string();
+    }
+    image_digest(client, image)
+        .await
+        .unwrap_or_else(|_| image.to_string())
+}
+
+async fn resolve_images(client: &Docker, config: &Config) -> Result<BTreeMap<String, String>> {
+    let mut images = BTreeMap::new();
+    images.insert(
+        SENTINEL_IMAGE_KEY.to_string(),
+        resolve_image(client, &config.sentinel_image()).await,
+    );
+    for instance in config.instance_names() {
+        let image = config.function_image(&instance)?;
+        let client = function_docker(config, client, &instance)?;
+        images.insert(instance, resolve_image(&client, &image).await);
+    }
+    Ok(images)
+}
+
+fn parse_revision(file_name: &str, content: &str) -> Option<Revision> {
+    serde_yaml::from_str(content)
+        .inspect_err(|e| warn!(file_name = %file_name, error = %e, "Skipping unreadable revision"))
+        .ok()
+}
+
+/// The stack's revisions on the target, oldest first.
+pub async fn list_revisions(config: &Config, target: &DeploymentTarget) -> Result<Vec<Revision>> {
+    let mut revisions = Vec::new();
+    if let DeploymentTarget::Remote(target) = target {
+        let client = SshClient::connect(target).await?;
+        let revisions_path = revisions_path(config);
+        for file_name in client.list_dir(&revisions_path).await? {
+            if revision_number(&file_name).is_none() {
+                continue;
+            }
+            let content = client.read_file(&revisions_path.join(&file_name)).await?;
+            revisions.extend(parse_revision(&file_name, &content));
+        }
+        client.close().await?;
+    } else {
+        let mut entries = match fs::read_dir(revisions_path(config)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(revisions),
+            Err(e) => return Err(CoupeError::Io(e)),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if revision_number(&file_name).is_none() {
+                continue;
+            }
+            let content = fs::read_to_string(entry.path()).await?;
+            revisions.extend(parse_revision(&file_name, &content));
+        }
+    }
+    revisions.sort_by_key(|revision| revision.number);
+    Ok(revisions)
+}
+
+/// Saves the deployed config as the next revision, then removes the oldest revisions beyond
+/// `revision_history`.
+pub async fn record_revision(
+    client: &Docker,
+    config: &Config,
+    target: &DeploymentTarget,
+    rollback_of: Option<u32>,
+) -> Result<Revision> {
+    let existing = list_revisions(config, target).await?;
+    let revision = Revision {
+        number: existing.last().map_or(1, |revision| revision.number + 1),
+        deployed_at: Timestamp::now(),
+        deployed_by: deploying_user(),
+        rollback_of,
+        images: resolve_images(client, config).await?,
+        config: config.clone(),
+    };
+    let content = serde_yaml::to_string(&revision)?;
+    let expired = expired_revisions(&existing, config.revision_history());
+
+    if let DeploymentTarget::Remote(target) = target {
+        let client = SshClient::connect(target).await?;
+        client.create_dir_all(&revisions_path(config)).await?;
+        client
+            .write_file(&revision_path(config, revision.number), &content)
+            .await?;
+        for number in &expired {
+            client.remove_file(&revision_path(config, *number)).await?;
+        }
+        client.close().await?;
+    } else {
+        fs::create_dir_all(revisions_path(config)).await?;
+        fs::write(revision_path(config, revision.number), content).await?;
+        for number in &expired {
+            fs::remove_file(revision_path(config, *number)).await?;
+        }
+    }
+
+    info!(
+        stack_name = %config.name,
+        revision = revision.number,
+        expired = ?expired,
+        "Revision recorded"
+    );
+    Ok(revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        serde_yaml::from_str(
+            r#"
+name: test
+functions:
+  api:
+    image: api:latest
+    trigger: { type: http, path: /api }
+    versions:
+      canary: { image: api:next, weight: 10 }
+"#,
+        )
+        .unwrap()
+    }
+
+    fn revision(number: u32, rollback_of: Option<u32>) -> Revision {
+        Revision {
+            number,
+            deployed_at: Timestamp::UNIX_EPOCH,
+            deployed_by: "test".to_string(),
+            rollback_of,
+            images: BTreeMap::new(),
+            config: config(),
+        }
+    }
+
+    fn target(revisions: &[Revision]) -> Option<u32> {
+        rollback_target(revisions).map(|revision| revision.number)
+    }
+
+    #[test]
+    fn revision_files_are_numbered() {
+        assert_eq!(revision_number("12.yaml"), Some(12));
+        assert_eq!(revision_number("12.yml"), None);
+        assert_eq!(revision_number("12"), None);
+        assert_eq!(revision_number("latest.yaml"), None);
+        assert_eq!(revision_number("-1.yaml"), None);
+    }
+
+    #[test]
+    fn old_revisions_expire_with_room_for_the_new_one() {
+        let existing: Vec<_> = (1..=5).map(|number| revision(number, None)).collect();
+        assert_eq!(expired_revisions(&existing, 3), [3, 2, 1]);
+        assert_eq!(expired_revisions(&existing, 6), [] as [u32; 0]);
+        assert_eq!(expired_revisions(&existing, 1), [5, 4, 3, 2, 1]);
+        assert!(expired_revisions(&[], 1).is_empty());
+    }
+
+    #[test]
+    fn pinned_configs_use_the_deployed_images() {
+        let mut revision = revision(1, None);
+        revision.images = BTreeMap::from([
+            (
+                SENTINEL_IMAGE_KEY.to_string(),
+                "sentinel@sha256:aa".to_string(),
+            ),
+            ("api".to_string(), "api@sha256:bb".to_string()),
+            ("api@canary".to_string(), "api@sha256:cc".to_string()),
+            ("removed".to_string(), "removed:1".to_string()),
+        ]);
+        let config = revision.pinned_config();
+        assert_eq!(config.sentinel_image(), "sentinel@sha256:aa");
+        assert_eq!(config.functions["api"].image, "api@sha256:bb");
+        assert_eq!(
+            config.functions["api"].versions["canary"].image,
+            "api@sha256:cc"
+        );
+        assert!(!config.functions.contains_key("removed"));
+    }
+
+    #[test]
+    fn rollbacks_return_before_the_restored_revision() {
+        assert_eq!(target(&[]), None);
+        assert_eq!(target(&[revision(1, None)]), None);
+        assert_eq!(target(&[revision(1, None), revision(2, None)]), Some(1));
+
+        // Rolling back from 2 to 1 recorded 3; another rollback does not go back to 2.
+        let rolled_back = [revision(1, None), revision(2, None), revision(3, Some(1))];
+        assert_eq!(target(&rolled_back), None);
+        let rolled_back = [
+            revision(1, None),
+            revision(2, None),
+            revision(3, None),
+            revision(4, Some(2)),
+        ];
+        assert_eq!(target(&rolled_back), Some(1));
+
+        // Chains of rollbacks are followed, and a deploy after a rollback starts a new chain.
+        let chained = [
+            revision(1, None),
+            revision(2, None),
+            revision(3, None),
+            revision(4, Some(2)),
+            revision(5, Some(4)),
+        ];
+        assert_eq!(target(&chained), Some(1));
+        let redeployed = [revision(1, None), revision(2, Some(1)), revision(3, None)];
+        assert_eq!(target(&redeployed), Some(2));
+
+        // A restored revision that was pruned still bounds the target.
+        let pruned = [revision(4, None), revision(6, None), revision(7, Some(5))];
+        assert_eq!(target(&pruned), Some(4));
+    }
+}
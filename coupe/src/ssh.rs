@@ -0,0 +1,339 @@
+use crate::{CoupeError, HostKeyCheck, Result, Target};
+use bollard::{API_DEFAULT_VERSION, BollardRequest, Docker, errors::Error as DockerError};
+use hyper::{
+    Request, Response, Uri,
+    body::Incoming,
+    client::conn::http1,
+    header::{HOST, HeaderValue},
+};
+use hyper_util::rt::TokioIo;
+use openssh::{Child, ForwardType, KnownHosts, Session, SessionBuilder, Socket, Stdio};
+use std::{
+    borrow::Cow,
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::{io::AsyncWriteExt, net::UnixStream, sync::Mutex};
+use tracing::{debug, info};
+
+/// Seconds a Docker request over SSH may take.
+const DOCKER_SSH_TIMEOUT: u64 = 30;
+
+/// Path with a leading `~/` taken from the home directory.
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(path) => env::home_dir().unwrap_or("/home".into()).join(path),
+        None => PathBuf::from(path),
+    }
+}
+
+fn session_builder(target: &Target) -> SessionBuilder {
+    let mut builder = SessionBuilder::default();
+    if let Some(user) = &target.user {
+        builder.user(user.clone());
+    }
+    if let Some(port) = target.port {
+        builder.port(port);
+    }
+    if let Some(identity_file) = &target.identity_file {
+        builder.keyfile(expand_home(identity_file));
+    }
+    if let Some(agent) = &target.agent {
+        builder.ssh_auth_sock(expand_home(agent));
+    }
+    if let Some(proxy_jump) = &target.proxy_jump {
+        builder.jump_hosts(proxy_jump.split(',').map(str::trim));
+    }
+    if let Some(known_hosts_file) = &target.known_hosts_file {
+        builder.user_known_hosts_file(expand_home(known_hosts_file));
+    }
+    builder.known_hosts_check(match target.host_key_check {
+        HostKeyCheck::Strict => KnownHosts::Strict,
+        HostKeyCheck::AcceptNew => KnownHosts::Add,
+        HostKeyCheck::Off => KnownHosts::Accept,
+    });
+    builder
+}
+
+async fn open_session(target: &Target) -> std::result::Result<Session, openssh::Error> {
+    info!(target = %target, "Opening SSH connection");
+    session_builder(target).connect(&target.host).await
+}
+
+/// Files on a remote host, read and written with shell commands over SSH.
+pub(crate) struct SshClient {
+    session: Session,
+}
+
+impl SshClient {
+    pub async fn connect(target: &Target) -> Result<Self> {
+        let session = open_session(target)
+            .await
+            .map_err(|e| CoupeError::SshConnection(format!("{}: {}", target, e)))?;
+        Ok(Self { session })
+    }
+
+    /// Runs a shell script with the path as `$0`, feeding it `input`, and returns its output.
+    async fn run(&self, script: &str, path: &Path, input: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut command = self.session.command("sh");
+        command
+            .arg("-c")
+            .arg(script)
+            .arg(path.to_string_lossy())
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = command
+            .spawn()
+            .await
+            .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin().take()) {
+            stdin.write_all(input).await?;
+            stdin.shutdown().await?;
+        }
+        let output = child
+            .wait_with_output()
+            .await
+            .map_err(|e| CoupeError::SshCommand(e.to_string()))?;
+        if !output.status.success() {
+            return Err(CoupeError::SshCommand(format!(
+                "{} failed for {}: {}",
+                script,
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    pub async fn create_dir_all(&self, path: &Path) -> Result<()> {
+        self.run(r#"mkdir -p "$0""#, path, None).await?;
+        Ok(())
+    }
+
+    pub async fn write_file(&self, path: &Path, content: &str) -> Result<()> {
+        self.run(r#"cat > "$0""#, path, Some(content.as_bytes()))
+            .await?;
+        Ok(())
+    }
+
+    /// Writes a file only its owner can read, such as a private key.
+    pub async fn write_private_file(&self, path: &Path, content: &str) -> Result<()> {
+        self.run(r#"umask 077 && cat > "$0""#, path, Some(content.as_bytes()))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn read_file(&self, path: &Path) -> Result<String> {
+        let content = self.run(r#"cat "$0""#, path, None).await?;
+        String::from_utf8(content).map_err(|e| CoupeError::SshCommand(e.to_string()))
+    }
+
+    /// Names of the entries in a directory, none when it does not exist.
+    pub async fn list_dir(&self, path: &Path) -> Result<Vec<String>> {
+        let names = self
+            .run(r#"test ! -d "$0" || ls -1A "$0""#, path, None)
+            .await?;
+        Ok(String::from_utf8_lossy(&names)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+
+    pub async fn remove_file(&self, path: &Path) -> Result<()> {
+        self.run(r#"rm -f "$0""#, path, None).await?;
+        Ok(())
+    }
+
+    pub async fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        self.run(r#"rm -rf "$0""#, path, None).await?;
+        Ok(())
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.session
+            .close()
+            .await
+            .map_err(|e| CoupeError::SshConnection(e.to_string()))
+    }
+}
+
+/// Sends Docker API requests over a shared SSH connection, through a forward of the engine's
+/// socket when one is set, otherwise through `docker system dial-stdio` on the target, one
+/// process per request.
+#[derive(Clone)]
+struct SshTransport {
+    target: Arc<Target>,
+    /// Engine socket on the target.
+    socket: Option<PathBuf>,
+    /// Opened on the first request, and again when it can no longer reach the engine.
+    connection: Arc<Mutex<Option<SshConnection>>>,
+}
+
+#[derive(Clone)]
+struct SshConnection {
+    session: Arc<Session>,
+    forward: Option<Arc<LocalSocket>>,
+}
+
+/// Local end of a socket forward, removed once the connection is dropped.
+struct LocalSocket(PathBuf);
+
+impl LocalSocket {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        Self(env::temp_dir().join(format!(
+            "coupe-docker-{}-{}.sock",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        )))
+    }
+}
+
+impl Drop for LocalSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+enum EngineStream {
+    Socket(UnixStream),
+    DialStdio(Box<Child<Arc<Session>>>),
+}
+
+impl SshTransport {
+    async fn connection(
+        &self,
+        reconnect: bool,
+    ) -> std::result::Result<SshConnection, openssh::Error> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection.as_ref().filter(|_| !reconnect) {
+            return Ok(open.clone());
+        }
+        let session = Arc::new(open_session(&self.target).await?);
+        let forward = match &self.socket {
+            Some(socket) => {
+                let local = LocalSocket::new();
+                session
+                    .request_port_forward(
+                        ForwardType::Local,
+                        Socket::UnixSocket {
+                            path: Cow::Borrowed(&local.0),
+                        },
+                        Socket::UnixSocket {
+                            path: Cow::Borrowed(socket),
+                        },
+                    )
+                    .await?;
+                Some(Arc::new(local))
+            }
+            None => None,
+        };
+        let open = SshConnection { session, forward };
+        *connection = Some(open.clone());
+        Ok(open)
+    }
+
+    async fn open(&self, reconnect: bool) -> std::io::Result<EngineStream> {
+        let connection = self
+            .connection(reconnect)
+            .await
+            .map_err(std::io::Error::other)?;
+        if let Some(forward) = &connection.forward {
+            return Ok(EngineStream::Socket(UnixStream::connect(&forward.0).await?));
+        }
+        let child = connection
+            .session
+            .arc_command("docker")
+            .arg("system")
+            .arg("dial-stdio")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .await
+            .map_err(std::io::Error::other)?;
+        Ok(EngineStream::DialStdio(Box::new(child)))
+    }
+
+    async fn request(
+        self,
+        request: BollardRequest,
+    ) -> std::result::Result<Response<Incoming>, DockerError> {
+        let stream = match self.open(false).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!(target = %self.target, error = %e, "Reconnecting to Docker over SSH");
+                self.open(true).await?
+            }
+        };
+        match stream {
+            EngineStream::Socket(stream) => send(TokioIo::new(stream), request, ()).await,
+            EngineStream::DialStdio(mut child) => {
+                let (Some(stdin), Some(stdout)) = (child.stdin().take(), child.stdout().take())
+                else {
+                    return Err(
+                        std::io::Error::other("docker system dial-stdio has no stdio").into(),
+                    );
+                };
+                // The process carries the connection, so it lives as long as the connection.
+                send(TokioIo::new(tokio::io::join(stdout, stdin)), request, child).await
+            }
+        }
+    }
+}
+
+/// Sends a request over its own HTTP connection, keeping `carrier` until the connection closes.
+async fn send<T>(
+    io: T,
+    request: BollardRequest,
+    carrier: impl Send + 'static,
+) -> std::result::Result<Response<Incoming>, DockerError>
+where
+    T: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = http1::handshake(io).await?;
+    tokio::spawn(async move {
+        let _carrier = carrier;
+        if let Err(e) = connection.with_upgrades().await {
+            debug!(error = %e, "Docker connection over SSH failed");
+        }
+    });
+
+    let (mut parts, body) = request.into_parts();
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    parts.uri = Uri::try_from(path).map_err(hyper::http::Error::from)?;
+    parts
+        .headers
+        .insert(HOST, HeaderValue::from_static("docker"));
+    Ok(sender
+        .send_request(Request::from_parts(parts, body))
+        .await?)
+}
+
+/// Docker client of the target's engine, connecting over SSH on its first request. The engine
+/// is reached at `socket` on the target when given.
+pub(crate) fn connect_docker_ssh(
+    target: &Target,
+    socket: Option<PathBuf>,
+) -> std::result::Result<Docker, DockerError> {
+    let transport = SshTransport {
+        target: Arc::new(target.clone()),
+        socket,
+        connection: Arc::new(Mutex::new(None)),
+    };
+    Docker::connect_with_custom_transport(
+        move |request| transport.clone().request(request),
+        Some("http://docker"),
+        DOCKER_SSH_TIMEOUT,
+        API_DEFAULT_VERSION,
+    )
+}